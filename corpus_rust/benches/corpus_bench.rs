@@ -103,6 +103,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             result_index: 0,
             result_id: 0,
             candidate_index: 0,
+            sample_seed: None,
         };
         let dedit_oscula_nato = "@lemma:do oscula @case:dat";
         let bitmask_query = "@case:nom @case:dat @case:acc";
@@ -110,7 +111,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let options = corpus::api::QueryOptions {
             page_size,
             context_len: 25,
-            strict_mode: false,
+            ..Default::default()
         };
         query_benches.bench_function("dedit oscula nato", |b| {
             b.iter(|| {
@@ -31,6 +31,22 @@ impl LatinInflection {
         }
     }
 
+    /// Returns the full name of the value, like `dative` or `futureperfect`, which
+    /// can be parsed back into the inflection along with the label.
+    pub fn get_value_name(&self) -> String {
+        let name = match self {
+            LatinInflection::Case(c) => format!("{c:?}"),
+            LatinInflection::Number(n) => format!("{n:?}"),
+            LatinInflection::Gender(g) => format!("{g:?}"),
+            LatinInflection::Person(p) => format!("{p:?}"),
+            LatinInflection::Mood(m) => format!("{m:?}"),
+            LatinInflection::Voice(v) => format!("{v:?}"),
+            LatinInflection::Tense(t) => format!("{t:?}"),
+            LatinInflection::Degree(d) => format!("{d:?}"),
+        };
+        name.to_lowercase()
+    }
+
     pub fn get_code(&self) -> usize {
         match self {
             LatinInflection::Case(c) => *c as usize,
@@ -1,9 +1,26 @@
 use serde::{Deserialize, Serialize};
 
+pub use crate::query_parsing_v2::QueryScope;
+
 /// An error that occurs while executing a query.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct QueryExecError {
     pub message: String,
+    /// Why the query can't have any matches, if that's the cause of the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contradiction: Option<QueryContradiction>,
+}
+
+/// A term of a query that can never match, because some of its atoms contradict
+/// each other (like `@mood:subjunctive` and `@tense:future`).
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryContradiction {
+    /// The index of the term in the query.
+    pub term: usize,
+    /// The atoms of the term that contradict each other.
+    pub atoms: Vec<String>,
+    pub reason: String,
 }
 
 /// Extra details about a single query match.
@@ -15,6 +32,10 @@ pub struct CorpusQueryMatchMetadata<'a> {
     pub author: &'a String,
     // (Section ID, offset in section, length)
     pub leaders: Vec<(&'a String, u32, u32)>,
+    /// For queries with `@form:` atoms, the lemmata of those forms that the matched
+    /// tokens could be inflections of.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lemmata: Vec<&'a String>,
     #[serde(skip_serializing)]
     pub(crate) work_start_token: u32,
     #[serde(skip_serializing)]
@@ -30,9 +51,33 @@ pub struct CorpusQueryMatch<'a> {
     /// The boolean indicates whether the given string is part of the match
     /// (if true) or context (if false).
     pub text: Vec<(String, bool)>,
+    /// The analyses of the tokens of the match (and possibly the context), if requested
+    /// by the `analyses` option.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<MatchToken<'a>>,
+    /// The tokens that satisfied the labeled terms of the query, in the order of the
+    /// terms. Optional terms that were left out of the match are skipped.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub captures: Vec<MatchCapture<'a>>,
+}
+
+/// A token of a match that satisfied a labeled term of the query.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchCapture<'a> {
+    pub label: String,
+    /// The ID of the token in the corpus.
+    pub id: u32,
+    pub section: &'a String,
+    /// The offset (in tokens) of the token within the section.
+    pub offset: u32,
+    pub text: String,
 }
 
 /// Data to resolve a page of results.
+///
+/// For results that are sampled or aren't sorted by position, only the `result_index`
+/// (and `sample_seed`) are used, since every match needs to be found first.
 #[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct PageData {
@@ -44,12 +89,72 @@ pub struct PageData {
     pub result_id: u32,
     /// The index of the next candidate match within the candidate set.
     pub candidate_index: u32,
+    /// The seed for a random sample of the results. Requests for a sample with the
+    /// same seed always return the same matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_seed: Option<u64>,
+}
+
+/// The order in which the matches of a query are returned.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ResultSort {
+    /// In order of position in the corpus.
+    #[default]
+    Position,
+    /// By the words before the match, starting with the closest one.
+    LeftContext,
+    /// By the words of the match.
+    Match,
+    /// By the words after the match.
+    RightContext,
+    /// By author, then by work.
+    Work,
+    /// In a random order that is fixed by the seed.
+    Random(u64),
 }
 
+/// How far the context around each match extends.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ContextMode {
+    /// A fixed number of tokens on each side.
+    #[default]
+    Tokens,
+    /// To the start and end of the sentence(s) containing the match.
+    Sentence,
+    /// To the start and end of the section(s) containing the match.
+    Section,
+}
+
+/// Which tokens of each match to include analyses for.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MatchAnalyses {
+    #[default]
+    None,
+    /// Only the tokens that are part of the match.
+    Match,
+    /// The tokens of the match and of the surrounding context.
+    MatchAndContext,
+}
+
+#[derive(Default)]
 pub struct QueryOptions {
     pub page_size: usize,
     pub context_len: usize,
+    /// How far the context extends. For sentences and sections, `context_len` is the
+    /// maximum number of tokens of context on each side.
+    pub context_mode: ContextMode,
     pub strict_mode: bool,
+    /// An extra scope to restrict the query to, parsed from the same syntax as the
+    /// `[...]` prefix of a query (without the brackets). If the query also has a
+    /// scope, results must be in both.
+    pub scope: Option<QueryScope>,
+    /// The order of the results. Matches that are tied are kept in position order.
+    pub sort: ResultSort,
+    /// If set, the results are a uniform random sample of (at most) this many matches,
+    /// chosen using the `sample_seed` of the page data.
+    pub sample_size: Option<usize>,
+    /// Which tokens of each match to include the lemmata and inflections of.
+    pub analyses: MatchAnalyses,
 }
 
 /// Global information about all results of a query.
@@ -57,6 +162,160 @@ pub struct QueryOptions {
 #[serde(rename_all = "camelCase")]
 pub struct QueryGlobalInfo {
     pub estimated_results: usize,
+    /// For sampled results, the seed that was used to choose the sample.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_seed: Option<u64>,
+}
+
+/// The number of matches in a single work, along with the counts for
+/// each section of the work (omitting sections without any matches).
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkCount<'a> {
+    pub work_id: &'a String,
+    pub work_name: &'a String,
+    pub count: usize,
+    /// (Section ID, count)
+    pub sections: Vec<(&'a String, usize)>,
+}
+
+/// The number of matches for a single author, along with the counts for
+/// each of their works (omitting works without any matches).
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthorCount<'a> {
+    pub author: &'a String,
+    pub count: usize,
+    pub works: Vec<WorkCount<'a>>,
+}
+
+/// The exact number of matches for a query, grouped by where they start.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CorpusCountResult<'a> {
+    pub total: usize,
+    pub authors: Vec<AuthorCount<'a>>,
+}
+
+/// Options for finding the collocates of a query.
+pub struct CollocationOptions {
+    /// The number of tokens on each side of a match to look at.
+    pub window: usize,
+    /// The maximum number of collocates to return for each position.
+    pub max_results: usize,
+    /// Collocates that occur fewer times than this at a position are omitted.
+    pub min_count: usize,
+}
+
+/// A word (or lemma) that occurs at some position relative to the matches of a query.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Collocate<'a> {
+    pub value: &'a String,
+    /// The number of matches with the collocate at this position.
+    pub count: usize,
+    /// The number of tokens in the whole corpus with this value.
+    pub corpus_count: usize,
+    pub mutual_information: f64,
+    pub t_score: f64,
+    pub log_likelihood: f64,
+}
+
+/// The most frequent collocates at a single position relative to the matches.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionCollocates<'a> {
+    /// Negative offsets count back from the first token of a match, and positive
+    /// offsets count forward from the last token of a match.
+    pub offset: i32,
+    /// The number of matches that have a token at this position (i.e. that
+    /// are not too close to the start or end of a work).
+    pub samples: usize,
+    pub words: Vec<Collocate<'a>>,
+    pub lemmata: Vec<Collocate<'a>>,
+}
+
+/// The collocates of a query at each position around its matches.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CollocationResult<'a> {
+    pub matches: usize,
+    pub positions: Vec<PositionCollocates<'a>>,
+}
+
+/// One possible analysis of a token.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenAnalysis<'a> {
+    pub lemma: &'a String,
+    /// The inflection of the token, like `nom/abl sg`. This is empty for
+    /// words that don't inflect.
+    pub inflection: String,
+}
+
+/// One possible analysis of a token, with the values of each inflection category.
+/// Categories that don't apply are empty, and a category can have several values
+/// (like the `dat` and `abl` cases of `puellis`).
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpandedAnalysis<'a> {
+    pub lemma: &'a String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub case: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub number: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub gender: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub person: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mood: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub voice: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tense: Vec<&'static str>,
+}
+
+/// A single token of a match, with its possible analyses.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchToken<'a> {
+    /// The ID of the token in the corpus.
+    pub id: u32,
+    pub text: String,
+    /// Whether the token is part of the match (rather than the context).
+    pub is_match: bool,
+    pub analyses: Vec<ExpandedAnalysis<'a>>,
+}
+
+/// The formats that query matches can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Tsv,
+    JsonLines,
+}
+
+/// A single match of a query, as exported.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMatch<'a> {
+    pub work_id: &'a String,
+    pub author: &'a String,
+    /// The section where the match starts.
+    pub section: &'a String,
+    /// The offset (in tokens) of the start of the match within the section.
+    pub offset: u32,
+    /// The number of tokens in the match.
+    pub length: u32,
+    pub left_context: String,
+    pub text: String,
+    pub right_context: String,
+    /// The analyses of each token in the match.
+    pub analyses: Vec<Vec<TokenAnalysis<'a>>>,
+    /// The tokens that satisfied the labeled terms of the query.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub captures: Vec<MatchCapture<'a>>,
 }
 
 /// A single page of matches for a query, along with metadata.
@@ -69,3 +328,59 @@ pub struct CorpusQueryResult<'a> {
     pub next_page: Option<PageData>,
     pub timing: Vec<(String, f64)>,
 }
+
+/// How an index of candidates is stored.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum IndexRepresentation {
+    /// One bit for each token in the range.
+    BitMask,
+    /// A sorted list of token IDs.
+    Packed,
+}
+
+/// A single step in computing the candidates for a query.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexStep {
+    pub step: String,
+    pub representation: IndexRepresentation,
+    /// The number of candidates after this step.
+    pub candidates: usize,
+}
+
+/// The bounds on the number of tokens that match an atom of a query.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AtomBounds {
+    pub atom: String,
+    pub lower: usize,
+    pub upper: usize,
+}
+
+/// How the candidates for a span of contiguous terms were computed.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpanPlan {
+    pub terms: String,
+    pub steps: Vec<IndexStep>,
+}
+
+/// An explanation of how a query is executed, for finding out why a query is
+/// slow or has no matches.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryPlan {
+    /// The query after atoms are expanded and impossible constraints are pruned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pruned_query: Option<String>,
+    /// Why the query can't have any matches, if pruning found that it can't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pruning_error: Option<QueryContradiction>,
+    pub atoms: Vec<AtomBounds>,
+    pub spans: Vec<SpanPlan>,
+    /// The candidates after each span is combined with the ones after it, and after
+    /// the scope is applied.
+    pub combinations: Vec<IndexStep>,
+    pub timing: Vec<(String, f64)>,
+}
@@ -185,6 +185,14 @@ macro_rules! define_apply_op_with_bitmasks {
 define_apply_op_with_bitmasks!(apply_and_with_bitmasks, &, "&");
 define_apply_op_with_bitmasks!(apply_or_with_bitmasks, |, "|");
 
+/// Returns whether the bit at the given index is set. Bits past the end of the bitmask
+/// are not set.
+pub fn is_bit_set(bitmask: &[u64], index: usize) -> bool {
+    bitmask
+        .get(index / 64)
+        .is_some_and(|word| word & (1 << (index % 64)) != 0)
+}
+
 /// Finds the index of the next set bit (1) in the bitmask starting from the given index.
 /// Returns `None` if no such bit is found.
 pub fn next_one_bit(bitmask: &[u64], start: usize) -> Option<usize> {
@@ -212,6 +220,10 @@ pub enum Direction {
     Both,
 }
 
+/// The largest window that can be smeared in a single pass, since each shift
+/// in a pass must be less than the size of a word.
+const MAX_SMEAR_PASS: usize = 63;
+
 /// Performs a bit smear on the given bitmask with the specified window size and direction.
 ///
 /// This operates on a bit level. If the original bitmask has a bit set at position `i`,
@@ -223,34 +235,120 @@ pub enum Direction {
 /// ## Arguments
 ///
 /// * `original` - The original bitmask to smear.
-/// * `window` - The size of the window to use for smearing. The maximum value is 15.
+/// * `window` - The size of the window to use for smearing. Windows larger than 63 are
+///   smeared in several passes.
 /// * `direction` - The direction to smear the bits (SmearDirection::Left, SmearDirection::Right, or SmearDirection::Both).
 ///
 /// ## Returns
 ///
 /// The smeared bitmask.
 pub fn smear_bitmask(original: &[u64], window: usize, direction: Direction) -> Vec<u64> {
-    assert!(window > 0 && window < 16, "Window must be in (0, 16).");
+    assert!(window > 0, "Window must be positive.");
+    let mut result = original.to_vec();
+    // Smearing by `a` and then by `b` is the same as smearing by `a + b`.
+    let mut remaining = window;
+    while remaining > 0 {
+        let pass = std::cmp::min(remaining, MAX_SMEAR_PASS);
+        smear_bitmask_in_place(&mut result, pass, direction);
+        remaining -= pass;
+    }
+    result
+}
+
+/// Smears the bitmask in place, for a window of at most `MAX_SMEAR_PASS`.
+fn smear_bitmask_in_place(result: &mut [u64], window: usize, direction: Direction) {
     let sign = match direction {
         Direction::Left => -1,
         _ => 1,
     };
-    let mut result = original.to_vec();
-    bitmask_or_with_self_offset_in_place(&mut result, sign);
+    bitmask_or_with_self_offset_in_place(result, sign);
     let mut r = 1;
     while r < window {
         let offset = std::cmp::min(r, window - r);
-        bitmask_or_with_self_offset_in_place(&mut result, (offset as isize) * sign);
+        bitmask_or_with_self_offset_in_place(result, (offset as isize) * sign);
         r += offset;
     }
     // If the direction is Both, we did the smear to the right and now
     // we just need to apply a single left smear to complete the operation.
     if direction == Direction::Both {
-        bitmask_or_with_self_offset_in_place(&mut result, -(window as isize));
+        bitmask_or_with_self_offset_in_place(result, -(window as isize));
+    }
+}
+
+/// Performs a bit smear like `smear_bitmask`, except that bits are never smeared across
+/// a break. A bit set at position `i` in `breaks` separates positions `i` and `i + 1`.
+///
+/// ## Arguments
+///
+/// * `original` - The original bitmask to smear.
+/// * `window` - The size of the window to use for smearing.
+/// * `direction` - The direction to smear the bits.
+/// * `breaks` - The breaks to stop the smear at. Must have the same length as `original`.
+///
+/// ## Returns
+///
+/// The smeared bitmask.
+pub fn smear_bitmask_within_breaks(
+    original: &[u64],
+    window: usize,
+    direction: Direction,
+    breaks: &[u64],
+) -> Vec<u64> {
+    assert!(window > 0, "Window must be positive.");
+    assert_eq!(
+        original.len(),
+        breaks.len(),
+        "Bitmasks must have the same length."
+    );
+    let mut result = original.to_vec();
+    if result.is_empty() {
+        return result;
+    }
+    // Smearing left and then right covers the whole window on both sides, and no
+    // path between the two ever crosses a break.
+    if direction != Direction::Right {
+        smear_within_breaks_in_place(&mut result, window, -1, breaks);
+    }
+    if direction != Direction::Left {
+        smear_within_breaks_in_place(&mut result, window, 1, breaks);
     }
     result
 }
 
+/// Smears the bitmask in place in a single direction (negative `sign` for left and
+/// positive for right), without crossing any of the `breaks`.
+fn smear_within_breaks_in_place(result: &mut [u64], window: usize, sign: isize, breaks: &[u64]) {
+    let zeros = vec![0u64; breaks.len()];
+    let not_breaks = breaks.iter().map(|word| !word).collect::<Vec<_>>();
+    // `open[j]` has the bits that can move `2^j` positions in the smear direction
+    // without crossing a break. Going left from `i`, the break at `i - 1` is the one
+    // that blocks, so the breaks are shifted along for a right smear.
+    let mut open = vec![if sign < 0 {
+        not_breaks
+    } else {
+        apply_or_with_bitmasks(&zeros, &not_breaks, 1)
+    }];
+    let mut covered = 0;
+    while covered < window {
+        // A step of `k` on top of a smear of `covered` is only exact if `k <= covered + 1`,
+        // since otherwise bits would skip over part of the window.
+        let limit = (covered + 1).min(window - covered).min(MAX_SMEAR_PASS);
+        let power = (usize::BITS - 1 - limit.leading_zeros()) as usize;
+        while open.len() <= power {
+            let last = &open[open.len() - 1];
+            let k = 1isize << (open.len() - 1);
+            let doubled = apply_and_with_bitmasks(last, last, k * sign);
+            open.push(doubled);
+        }
+        let step = 1isize << power;
+        let moved = apply_and_with_bitmasks(&open[power], result, step * sign);
+        for (word, moved) in result.iter_mut().zip(moved) {
+            *word |= moved;
+        }
+        covered += step as usize;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -597,4 +695,75 @@ mod tests {
         verify_results_and(&a, &b, -63, 128);
         verify_results_or(&a, &b, -63, 128);
     }
+
+    #[test]
+    fn should_smear_windows_wider_than_a_word() {
+        let original = to_bitmask(&[100], 256);
+        let expected = (0..=100).collect::<Vec<_>>();
+        assert_eq!(
+            from_bitmask(&smear_bitmask(&original, 100, Direction::Left)),
+            expected
+        );
+        let expected = (30..=170).collect::<Vec<_>>();
+        assert_eq!(
+            from_bitmask(&smear_bitmask(&original, 70, Direction::Both)),
+            expected
+        );
+    }
+
+    #[test]
+    fn should_smear_within_breaks_in_each_direction() {
+        let original = to_bitmask(&[10], 64);
+        // Breaks after tokens 7 and 12.
+        let breaks = to_bitmask(&[7, 12], 64);
+        let smear = |direction| {
+            from_bitmask(&smear_bitmask_within_breaks(
+                &original, 5, direction, &breaks,
+            ))
+        };
+        assert_eq!(smear(Direction::Left), vec![8, 9, 10]);
+        assert_eq!(smear(Direction::Right), vec![10, 11, 12]);
+        assert_eq!(smear(Direction::Both), vec![8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn should_smear_within_breaks_across_words() {
+        let original = to_bitmask(&[70, 200], 256);
+        let breaks = to_bitmask(&[20, 150], 256);
+        let expected = (21..=70).chain(151..=200).collect::<Vec<_>>();
+        assert_eq!(
+            from_bitmask(&smear_bitmask_within_breaks(
+                &original,
+                100,
+                Direction::Left,
+                &breaks
+            )),
+            expected
+        );
+        let expected = (70..=150).chain(200..=255).collect::<Vec<_>>();
+        assert_eq!(
+            from_bitmask(&smear_bitmask_within_breaks(
+                &original,
+                255,
+                Direction::Right,
+                &breaks
+            )),
+            expected
+        );
+    }
+
+    #[test]
+    fn should_smear_within_breaks_like_smear_without_breaks() {
+        let original = to_bitmask(&[3, 64, 130, 190], 256);
+        let breaks = vec![0; original.len()];
+        for window in [1, 2, 3, 7, 31, 32, 33, 63, 64, 100, 255] {
+            for direction in [Direction::Left, Direction::Right, Direction::Both] {
+                assert_eq!(
+                    smear_bitmask_within_breaks(&original, window, direction, &breaks),
+                    smear_bitmask(&original, window, direction),
+                    "window {window}, direction {direction:?}"
+                );
+            }
+        }
+    }
 }
@@ -8,7 +8,7 @@ use std::process;
 use std::time::Instant;
 
 use morceus::crunch::crunch_word;
-use morceus::indices::{CrunchResult, CruncherOptions, CruncherTables};
+use morceus::indices::{CrunchResult, CruncherTables};
 use morceus::inflection_data::{
     extract_case_bits, extract_gender_bits, extract_mood, extract_number, extract_tense,
     extract_voice, iterate_cases, iterate_genders,
@@ -16,6 +16,7 @@ use morceus::inflection_data::{
 use serde::{Deserialize, Serialize};
 
 // use crate::build_corpus::corpus_serialization::write_corpus;
+use crate::build_corpus_v2::corpus_crunch_options;
 use crate::corpus_index::{CorpusStats, WorkData, WorkLookupEntry};
 
 // Constants
@@ -495,7 +496,7 @@ pub fn build_corpus(
 ) -> Result<(), Box<dyn std::error::Error>> {
     let corpus_dir = corpus_dir.unwrap_or(CORPUS_DIR);
     let tables = load_tables(tables_file);
-    let crunch_options = CruncherOptions::default();
+    let crunch_options = corpus_crunch_options();
     let get_inflections = |word: &str| crunch_word(word, &tables, &crunch_options);
 
     let start_time = Instant::now();
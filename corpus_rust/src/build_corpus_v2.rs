@@ -1,22 +1,46 @@
+mod corpus_serialization;
 mod library_utils;
+mod stored_inflections;
 mod string_processing;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
-use std::{fs, time::Instant};
+use std::{collections::HashMap, fs, time::Instant};
 
 use morceus::{
     crunch::crunch_word,
     indices::{CrunchResult, CruncherOptions, CruncherTables},
 };
+use unicode_normalization::UnicodeNormalization;
 
-use crate::build_corpus_v2::{
-    library_utils::{
-        CorpusInputWork, LIB_CORPUS_INPUT_DIR, LIB_INDEX_PATH, find_files_from_library_index,
+use crate::{
+    build_corpus_v2::{
+        corpus_serialization::write_corpus,
+        library_utils::{
+            CorpusInputWork, LIB_CORPUS_INPUT_DIR, LIB_INDEX_PATH, find_files_from_library_index,
+        },
+        stored_inflections::StoredInflections,
+        string_processing::process_tokens,
     },
-    string_processing::process_tokens,
+    corpus_index::{CorpusStats, WorkData, WorkLookupEntry, WorkRowInfo},
+    corpus_query_engine::FormAnalyzer,
 };
 
 const TABLES_FILE: &str = "build/morceus/processed/morceusTables.json";
 
+const CORPUS_DIR: &str = "build/corpus";
+const CORPUS_FILE: &str = "latin_corpus.json";
+const CORPUS_RAW_TEXT: &str = "latin_corpus_raw.txt";
+const CORPUS_BUFFERS: &str = "latin_corpus_buffers.bin";
+const CORPUS_TOKEN_STARTS: &str = "latin_corpus_token_starts.bin";
+const CORPUS_INFLECTIONS_RAW_DATA: &str = "latin_corpus_inflections_raw_data.bin";
+const CORPUS_INFLECTIONS_OFFSETS: &str = "latin_corpus_inflections_offsets.bin";
+
+/// The keys for all of the indices in the corpus, in the order they are serialized.
+const INDEX_KEYS: [&str; 10] = [
+    "word", "breaks", "lemma", "case", "number", "gender", "tense", "person", "mood", "voice",
+];
+
 fn load_tables(filename: &str) -> Result<CruncherTables, Box<dyn std::error::Error>> {
     // Read the JSON file
     let json_content = fs::read_to_string(filename).map_err(|err| {
@@ -32,28 +56,247 @@ fn load_tables(filename: &str) -> Result<CruncherTables, Box<dyn std::error::Err
     Ok(cruncher_tables)
 }
 
+/// The cruncher options used to build the corpus. These match the options in
+/// `build_corpus.ts`, so that both builders produce the same indices. Anything that
+/// analyzes words for the corpus at query time should use these too, so that its
+/// analyses agree with the stored ones.
+pub fn corpus_crunch_options() -> CruncherOptions {
+    CruncherOptions {
+        // We don't mind duplicate results because we only mark whether each
+        // token COULD BE intepreted as a particular lemma, case, etc...
+        // Consolidation only merges results, so it doesn't change the indices,
+        // and skipping it keeps the stored inflections the same as in TypeScript.
+        skip_consolidation: true,
+        ..CruncherOptions::default()
+    }
+}
+
+/// Loads an analyzer for inflected forms, using the same tables and options
+/// that are used to build the corpus.
+pub fn load_analyzer() -> Result<FormAnalyzer, Box<dyn std::error::Error>> {
+    let tables = load_tables(TABLES_FILE)?;
+    let crunch_options = corpus_crunch_options();
+    Ok(Box::new(move |word: &str| {
+        crunch_word(word, &tables, &crunch_options)
+    }))
+}
+
+/// Applies the same transformation as `cleanLemma` in the TypeScript cruncher.
+pub(crate) fn clean_lemma(lemma: &str) -> String {
+    lemma
+        .replace('^', "\u{0306}")
+        .replace('_', "\u{0304}")
+        .replace('-', "")
+}
+
+/// Removes macrons and breves from the token, leaving the result in NFD.
+fn strip_vowel_lengths(token: &str) -> String {
+    token
+        .nfd()
+        .filter(|c| *c != '\u{0304}' && *c != '\u{0306}')
+        .collect()
+}
+
+/// The state of the corpus while works are still being absorbed.
+struct InProgressLatinCorpus {
+    work_lookup: Vec<WorkLookupEntry>,
+    author_lookup: HashMap<String, (usize, usize)>,
+    stats: CorpusStats,
+    /// For each index key, the list of token IDs for each value ID.
+    indices: HashMap<&'static str, Vec<Vec<u32>>>,
+    /// For the string-keyed indices, the map from value to value ID.
+    id_table: HashMap<&'static str, HashMap<String, u32>>,
+    tokens: Vec<String>,
+    /// The `i`th break is the text between the `i`th and the `i+1`th token.
+    breaks: Vec<String>,
+}
+
+impl InProgressLatinCorpus {
+    fn new() -> Self {
+        InProgressLatinCorpus {
+            work_lookup: vec![],
+            author_lookup: HashMap::new(),
+            stats: CorpusStats {
+                total_words: 0,
+                total_works: 0,
+                unique_words: 0,
+                unique_lemmata: 0,
+            },
+            indices: INDEX_KEYS.iter().map(|key| (*key, vec![])).collect(),
+            id_table: ["word", "breaks", "lemma"]
+                .iter()
+                .map(|key| (*key, HashMap::new()))
+                .collect(),
+            tokens: vec![],
+            breaks: vec![],
+        }
+    }
+
+    /// Adds the given token to the index for `key` and `value_id`.
+    fn add(&mut self, key: &'static str, value_id: usize, token_id: u32) {
+        let index = self.indices.entry(key).or_default();
+        if index.len() <= value_id {
+            index.resize(value_id + 1, vec![]);
+        }
+        let entries = &mut index[value_id];
+        // Tokens are always added in order, so we only need to check the last one.
+        if entries.last() != Some(&token_id) {
+            entries.push(token_id);
+        }
+    }
+
+    /// Adds the given token to the index for `key` and `value`, assigning
+    /// a new ID to the value if it hasn't been seen before.
+    fn add_str_keyed(&mut self, key: &'static str, value: &str, token_id: u32) {
+        let table = self.id_table.entry(key).or_default();
+        let next_id = table.len() as u32;
+        let value_id = *table.entry(value.to_string()).or_insert(next_id);
+        self.add(key, value_id as usize, token_id);
+    }
+
+    fn id_for(&self, key: &str, value: &str) -> Option<u32> {
+        self.id_table.get(key)?.get(value).copied()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum BreakType {
+    None,
+    Soft,
+    Hard,
+}
+
+/// Returns the type of break that should separate the given row from the previous one.
+fn row_break_type(work: &CorpusInputWork, row_idx: usize, is_corpus_empty: bool) -> BreakType {
+    if row_idx == 0 || is_corpus_empty {
+        return BreakType::None;
+    }
+    let current = &work.row_ids[row_idx];
+    let previous = &work.row_ids[row_idx - 1];
+    // Break between e.g. 1.2 and 1.2.1
+    // This generates breaks between things like headers.
+    if current.len() != previous.len() {
+        return BreakType::Hard;
+    }
+    // Only consider matches on leaf siblings, e.g 1.2.1 and 1.2.2
+    let parent_len = current.len().saturating_sub(1);
+    if current[..parent_len] != previous[..parent_len] {
+        return BreakType::Hard;
+    }
+    BreakType::Soft
+}
+
 // Main function to absorb a work into the corpus
-fn absorb_work<'a>(
-    work: &'a CorpusInputWork,
-    get_inflections: impl Fn(&str) -> Vec<CrunchResult>,
-    tokens: &mut Vec<&'a str>,
-    breaks: &mut Vec<&'a str>,
+fn absorb_work(
+    work: &CorpusInputWork,
+    corpus: &mut InProgressLatinCorpus,
+    get_inflections: &impl Fn(&str) -> Vec<CrunchResult>,
+    inflections: &mut StoredInflections,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!(
         "Ingesting into corpus: {} ({}) - {}",
         work.work_name, work.author, work.id
     );
+    if work.rows.is_empty() {
+        return Err(format!("Work {} must have at least one row.", work.id).into());
+    }
+    if work.rows.len() != work.row_ids.len() {
+        return Err(format!("Work {} has mismatched rows and row IDs.", work.id).into());
+    }
+
+    let mut rows = vec![];
+    let mut words_in_work = 0;
     for (row_idx, row_text) in work.rows.iter().enumerate() {
-        let _row_ids = &work.row_ids[row_idx];
+        let break_type = row_break_type(work, row_idx, corpus.tokens.is_empty());
+        if break_type != BreakType::None
+            && let Some(last_break) = corpus.breaks.last_mut()
+        {
+            last_break.push('\n');
+            if break_type == BreakType::Hard {
+                corpus.add_str_keyed("breaks", "hard", corpus.tokens.len() as u32 - 1);
+            }
+        }
+
+        let row_start = corpus.tokens.len() as u32;
         for (substr, is_word) in process_tokens(row_text) {
             if !is_word {
-                breaks.push(substr);
+                // Any text before the first token of the corpus is dropped.
+                let Some(last_break) = corpus.breaks.last_mut() else {
+                    continue;
+                };
+                last_break.push_str(substr);
+                // This should handle abbreviations.
+                // We should either generate a list of abbreviations that we exclude,
+                // or we can simply de-rank these matches.
+                if substr.contains('.') {
+                    corpus.add_str_keyed("breaks", "hard", corpus.tokens.len() as u32 - 1);
+                }
                 continue;
             }
-            tokens.push(substr);
-            get_inflections(substr);
+            let stripped = strip_vowel_lengths(substr);
+            let normalized_word = stripped.to_lowercase();
+            let token_id = corpus.tokens.len() as u32;
+            corpus.add_str_keyed("word", &normalized_word, token_id);
+            inflections.ingest(&normalized_word, token_id, get_inflections, corpus)?;
+
+            words_in_work += 1;
+            corpus.tokens.push(stripped);
+            corpus.breaks.push(String::new());
+        }
+        let row_id = work.row_ids[row_idx].join(".");
+        rows.push(WorkRowInfo(row_id, row_start, corpus.tokens.len() as u32));
+    }
+    if let Some(last) = corpus.tokens.len().checked_sub(1) {
+        corpus.add_str_keyed("breaks", "hard", last as u32);
+    }
+
+    corpus.work_lookup.push(WorkLookupEntry {
+        work_id: work.id.clone(),
+        rows,
+        info: WorkData {
+            author: work.author.clone(),
+            name: work.work_name.clone(),
+        },
+    });
+    corpus.stats.total_words += words_in_work;
+    corpus.stats.total_works += 1;
+    Ok(())
+}
+
+/// Builds the corpus from the given works, writing all artifacts to `corpus_dir`.
+///
+/// The works must be grouped by author (by `author_code`).
+fn build_corpus_from_works(
+    works: &[CorpusInputWork],
+    get_inflections: impl Fn(&str) -> Vec<CrunchResult>,
+    corpus_dir: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start_time = Instant::now();
+    let mut corpus = InProgressLatinCorpus::new();
+    let mut inflections = StoredInflections::new();
+
+    for (i, work) in works.iter().enumerate() {
+        absorb_work(work, &mut corpus, &get_inflections, &mut inflections)?;
+        match corpus.author_lookup.get_mut(&work.author_code) {
+            None => {
+                corpus
+                    .author_lookup
+                    .insert(work.author_code.clone(), (i, i));
+            }
+            Some(range) if range.1 + 1 == i => range.1 = i,
+            Some(_) => {
+                return Err(
+                    format!("Author works are not contiguous: {}", work.author_code).into(),
+                );
+            }
         }
     }
+    println!("Corpus processing took {:?}", start_time.elapsed());
+
+    corpus.stats.unique_words = corpus.indices["word"].len() as u32;
+    corpus.stats.unique_lemmata = corpus.indices["lemma"].len() as u32;
+    write_corpus(corpus, &inflections, corpus_dir)?;
+    println!("Corpus indexing took {:?}", start_time.elapsed());
     Ok(())
 }
 
@@ -66,22 +309,161 @@ pub fn build_corpus() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let tables = load_tables(TABLES_FILE)?;
-    let crunch_options = CruncherOptions::default();
+    let crunch_options = corpus_crunch_options();
     let get_inflections = |word: &str| crunch_word(word, &tables, &crunch_options);
+    build_corpus_from_works(&works, get_inflections, CORPUS_DIR)
+}
 
-    let start_time = Instant::now();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        build_corpus_v2::test_utils::{
+            TestEngine, build_engine_from_works, make_result, make_work, query_texts, test_dir,
+        },
+        corpus_index::deserialize_corpus,
+    };
 
-    let mut tokens: Vec<&str> = Vec::new();
-    let mut breaks: Vec<&str> = Vec::new();
+    // Dative and Ablative plural.
+    const DAT_ABL_PL: u32 = (0b101000 << 16) | 2;
+    // Nominative singular.
+    const NOM_SG: u32 = (0b10 << 16) | 1;
 
-    for work in &works {
-        absorb_work(work, get_inflections, &mut tokens, &mut breaks)?;
+    fn fake_inflections(word: &str) -> Vec<CrunchResult> {
+        match word {
+            "puellis" => vec![make_result("puella", DAT_ABL_PL)],
+            "puella" => vec![make_result("puella", NOM_SG)],
+            "rosas" => vec![make_result("rosa", 0b100 << 16)],
+            "dat" => vec![make_result("do", 1 | (3 << 2))],
+            _ => vec![],
+        }
     }
 
-    println!("Corpus build took {:?}", start_time.elapsed());
-    println!("Tokens: {}", tokens.len());
-    fs::write("tokens.rs.txt", tokens.join("\n"))?;
-    println!("Breaks: {}", breaks.len());
+    fn build_test_engine(name: &str) -> TestEngine {
+        let works = vec![
+            make_work(
+                "w1",
+                "Alpha",
+                &[("1.1", "Puella rosās puellīs dat."), ("1.2", "Puella dat")],
+            ),
+            make_work(
+                "w2",
+                "Alpha",
+                &[("1", "Rosas puellis"), ("2", "dat puella")],
+            ),
+            make_work("w3", "Beta", &[("1.1", "puella, puellis dat")]),
+        ];
+        build_engine_from_works(name, &works, fake_inflections)
+    }
 
-    Ok(())
+    #[test]
+    fn strip_vowel_lengths_removes_macrons_and_breves() {
+        assert_eq!(strip_vowel_lengths("rosās"), "rosas");
+        assert_eq!(strip_vowel_lengths("pŭellā"), "puella");
+        assert_eq!(strip_vowel_lengths("Ēheu"), "Eheu");
+    }
+
+    #[test]
+    fn clean_lemma_replaces_markers() {
+        assert_eq!(clean_lemma("ro_sa"), "ro\u{0304}sa");
+        assert_eq!(clean_lemma("pu^ella"), "pu\u{0306}ella");
+        assert_eq!(clean_lemma("do#1-"), "do#1");
+    }
+
+    #[test]
+    fn corpus_crunch_options_match_typescript_builder() {
+        // `build_corpus.ts` passes `skipConsolidation: true` with otherwise default options.
+        let options = corpus_crunch_options();
+        let defaults = CruncherOptions::default();
+        assert!(options.skip_consolidation);
+        assert_eq!(options.relax_case, defaults.relax_case);
+        assert_eq!(options.handle_enclitics, defaults.handle_enclitics);
+    }
+
+    #[test]
+    fn row_break_type_handles_sections() {
+        let work = make_work(
+            "w",
+            "A",
+            &[("1.1", "a"), ("1.2", "b"), ("2.1", "c"), ("2", "d")],
+        );
+        assert_eq!(row_break_type(&work, 0, false), BreakType::None);
+        assert_eq!(row_break_type(&work, 1, true), BreakType::None);
+        assert_eq!(row_break_type(&work, 1, false), BreakType::Soft);
+        assert_eq!(row_break_type(&work, 2, false), BreakType::Hard);
+        assert_eq!(row_break_type(&work, 3, false), BreakType::Hard);
+    }
+
+    #[test]
+    fn built_corpus_has_expected_metadata() {
+        let dir = test_dir("metadata");
+        let works = vec![
+            make_work("w1", "Alpha", &[("1", "Puella dat")]),
+            make_work("w2", "Alpha", &[("1", "puellis")]),
+            make_work("w3", "Beta", &[("1", "rosas")]),
+        ];
+        let dir = dir.path().to_string_lossy().to_string();
+        build_corpus_from_works(&works, fake_inflections, &dir).unwrap();
+        let index = deserialize_corpus(&format!("{dir}/{CORPUS_FILE}")).unwrap();
+
+        assert_eq!(index.num_tokens, 4);
+        assert_eq!(index.stats.total_words, 4);
+        assert_eq!(index.stats.total_works, 3);
+        assert_eq!(index.stats.unique_words, 4);
+        assert_eq!(index.stats.unique_lemmata, 3);
+        assert_eq!(index.author_lookup["Alpha"], (0, 1));
+        assert_eq!(index.author_lookup["Beta"], (2, 2));
+        assert_eq!(index.work_lookup[1].work_id, "w2");
+        assert_eq!(index.work_lookup[1].rows[0].1, 2);
+        assert_eq!(index.work_lookup[1].rows[0].2, 3);
+        assert_eq!(index.id_table["word"]["puella"], 0);
+        assert_eq!(index.id_table["lemma"]["puella"], 0);
+    }
+
+    #[test]
+    fn build_rejects_non_contiguous_authors() {
+        let dir = test_dir("contiguous");
+        let works = vec![
+            make_work("w1", "Alpha", &[("1", "puella")]),
+            make_work("w2", "Beta", &[("1", "puella")]),
+            make_work("w3", "Alpha", &[("1", "puella")]),
+        ];
+        let path = dir.path().to_string_lossy();
+        assert!(build_corpus_from_works(&works, fake_inflections, &path).is_err());
+    }
+
+    #[test]
+    fn built_corpus_supports_word_queries() {
+        let engine = build_test_engine("word_queries");
+        assert_eq!(
+            query_texts(&engine, "puellis"),
+            vec!["puellis", "puellis", "puellis"]
+        );
+        assert_eq!(query_texts(&engine, "rosas"), vec!["rosas", "Rosas"]);
+    }
+
+    #[test]
+    fn built_corpus_supports_lemma_and_inflection_queries() {
+        let engine = build_test_engine("lemma_queries");
+        assert_eq!(query_texts(&engine, "@lemma:puella").len(), 7);
+        assert_eq!(query_texts(&engine, "@case:dat"), vec!["puellis"; 3]);
+        assert_eq!(
+            query_texts(&engine, "(@lemma:puella and @case:nom)"),
+            vec!["Puella"; 2]
+                .into_iter()
+                .chain(vec!["puella"; 2])
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn built_corpus_respects_breaks() {
+        let engine = build_test_engine("breaks");
+        // `dat. Puella` crosses a sentence break and `dat Rosas` crosses works.
+        assert!(query_texts(&engine, "dat puella").contains(&"dat puella".to_string()));
+        assert_eq!(query_texts(&engine, "dat puella").len(), 1);
+        // `1` -> `2` is a soft break, so this should match across rows.
+        assert_eq!(query_texts(&engine, "puellis dat").len(), 3);
+    }
 }
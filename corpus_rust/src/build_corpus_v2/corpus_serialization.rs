@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    build_corpus_v2::{
+        CORPUS_BUFFERS, CORPUS_FILE, CORPUS_RAW_TEXT, CORPUS_TOKEN_STARTS, INDEX_KEYS,
+        InProgressLatinCorpus, stored_inflections::StoredInflections,
+    },
+    corpus_index::{LatinCorpusIndex, StoredMapValue},
+};
+
+type BoxedResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+fn to_path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+fn u32s_to_bytes(data: &[u32]) -> Vec<u8> {
+    data.iter().flat_map(|n| n.to_le_bytes()).collect()
+}
+
+/// Writes the raw text of the corpus and the token starts.
+///
+/// The token starts file is a sequence of u32 pairs, where each pair is the
+/// byte offset of the start of a token in the raw text followed by the
+/// byte offset of the start of the break after that token.
+///
+/// Returns the paths of the raw text and token starts files.
+fn write_token_db(corpus: &InProgressLatinCorpus, dir: &Path) -> BoxedResult<(String, String)> {
+    if corpus.tokens.len() != corpus.breaks.len() {
+        return Err("Tokens and breaks must have the same length".into());
+    }
+    let mut all = String::new();
+    let mut starts = Vec::with_capacity(corpus.tokens.len() * 2);
+    for (token, token_break) in corpus.tokens.iter().zip(corpus.breaks.iter()) {
+        starts.push(u32::try_from(all.len())?);
+        all.push_str(token);
+        starts.push(u32::try_from(all.len())?);
+        all.push_str(token_break);
+    }
+
+    let raw_text_path = dir.join(CORPUS_RAW_TEXT);
+    fs::write(&raw_text_path, all)?;
+    let starts_path = dir.join(CORPUS_TOKEN_STARTS);
+    fs::write(&starts_path, u32s_to_bytes(&starts))?;
+    Ok((to_path_string(&raw_text_path), to_path_string(&starts_path)))
+}
+
+/// Converts a sorted list of token IDs to a bitmask.
+fn to_bit_mask(token_ids: &[u32], num_tokens: u32) -> Vec<u64> {
+    let mut mask = vec![0u64; num_tokens.div_ceil(64) as usize];
+    for &id in token_ids {
+        mask[(id / 64) as usize] |= 1 << (id % 64);
+    }
+    mask
+}
+
+/// Writes the index data to the buffers file and returns the metadata
+/// needed to read it back.
+struct IndexWriter {
+    writer: BufWriter<File>,
+    offset: usize,
+    num_tokens: u32,
+}
+
+impl IndexWriter {
+    fn new(path: &Path, num_tokens: u32) -> BoxedResult<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&num_tokens.to_le_bytes())?;
+        // 4 bytes because we wrote a u32 for `num_tokens` above.
+        Ok(IndexWriter {
+            writer,
+            offset: 4,
+            num_tokens,
+        })
+    }
+
+    fn write_index(
+        &mut self,
+        token_ids: &[u32],
+        force_bitmask: bool,
+    ) -> BoxedResult<StoredMapValue> {
+        let use_bitmask = force_bitmask || token_ids.len() * 32 > self.num_tokens as usize;
+        if !use_bitmask {
+            let stored = StoredMapValue::Packed {
+                offset: u32::try_from(self.offset)?,
+                len: u32::try_from(token_ids.len())?,
+            };
+            self.write_bytes(&u32s_to_bytes(token_ids))?;
+            return Ok(stored);
+        }
+
+        // Bitmasks are interpreted as a vector of 64 bit integers.
+        // To avoid having to handle misaligned data, make sure it's 64-bit aligned.
+        let padding = (8 - (self.offset % 8)) % 8;
+        self.write_bytes(&vec![0u8; padding])?;
+        let stored = StoredMapValue::BitMask {
+            offset: u32::try_from(self.offset)?,
+            num_set: u32::try_from(token_ids.len())?,
+        };
+        let bit_mask = to_bit_mask(token_ids, self.num_tokens);
+        let bytes = bit_mask
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.write_bytes(&bytes)?;
+        Ok(stored)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> BoxedResult<()> {
+        self.writer.write_all(bytes)?;
+        self.offset += bytes.len();
+        Ok(())
+    }
+}
+
+/// Writes all of the indices to the buffers file, returning the stored metadata.
+fn write_indices(
+    corpus: &InProgressLatinCorpus,
+    num_tokens: u32,
+    path: &Path,
+) -> BoxedResult<HashMap<String, Vec<StoredMapValue>>> {
+    let hard_breaks_id = corpus.id_for("breaks", "hard");
+    let mut writer = IndexWriter::new(path, num_tokens)?;
+    let mut converted = HashMap::new();
+    for key in INDEX_KEYS {
+        let mut entries = vec![];
+        for (i, token_ids) in corpus.indices[key].iter().enumerate() {
+            // The query engine requires the hard breaks to be a bitmask.
+            let force_bitmask = key == "breaks" && hard_breaks_id == Some(i as u32);
+            entries.push(writer.write_index(token_ids, force_bitmask)?);
+        }
+        converted.insert(key.to_string(), entries);
+    }
+    writer.writer.flush()?;
+    Ok(converted)
+}
+
+/// Writes the corpus and all of its supporting files to `corpus_dir`.
+pub(super) fn write_corpus(
+    corpus: InProgressLatinCorpus,
+    inflections: &StoredInflections,
+    corpus_dir: &str,
+) -> BoxedResult<()> {
+    let dir = Path::new(corpus_dir);
+    fs::create_dir_all(dir)?;
+    let num_tokens = u32::try_from(corpus.tokens.len())?;
+
+    let (raw_text_path, token_starts_path) = write_token_db(&corpus, dir)?;
+    let (inflections_raw_buffer_path, inflections_offsets_path) = inflections.save(dir)?;
+    let raw_buffer_path = dir.join(CORPUS_BUFFERS);
+    let indices = write_indices(&corpus, num_tokens, &raw_buffer_path)?;
+
+    let id_table = corpus
+        .id_table
+        .into_iter()
+        .map(|(key, table)| (key.to_string(), table))
+        .collect();
+    let index = LatinCorpusIndex {
+        work_lookup: corpus.work_lookup,
+        author_lookup: corpus.author_lookup,
+        stats: corpus.stats,
+        raw_text_path,
+        raw_buffer_path: to_path_string(&raw_buffer_path),
+        token_starts_path,
+        inflections_raw_buffer_path,
+        inflections_offsets_path,
+        indices,
+        id_table,
+        num_tokens,
+    };
+    let dest_file = dir.join(CORPUS_FILE);
+    fs::write(&dest_file, serde_json::to_string(&index)?)?;
+    println!("Corpus written to {}", dest_file.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_bit_mask_sets_expected_bits() {
+        let mask = to_bit_mask(&[0, 3, 64, 129], 130);
+        assert_eq!(mask, vec![0b1001, 1, 0b10]);
+    }
+
+    #[test]
+    fn to_bit_mask_handles_empty() {
+        assert_eq!(to_bit_mask(&[], 65), vec![0, 0]);
+        assert_eq!(to_bit_mask(&[], 0), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,258 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use morceus::{
+    indices::CrunchResult,
+    inflection_data::{WordInflectionData, expand_inflection_data},
+};
+
+use crate::build_corpus_v2::{
+    CORPUS_INFLECTIONS_OFFSETS, CORPUS_INFLECTIONS_RAW_DATA, InProgressLatinCorpus, clean_lemma,
+};
+
+// The start bits for each category in the corpus inflection mask. This
+// must match the layout expected by the query engine, which is different
+// from the `WordInflectionData` layout in `morceus`. Degree is skipped
+// because the corpus indices also don't track degree.
+const CASE_START: u32 = 0;
+const NUMBER_START: u32 = 7; // 0 + 7 cases
+const GENDER_START: u32 = 9; // 7 + 2 numbers
+const PERSON_START: u32 = 13; // 9 + 4 genders
+const MOOD_START: u32 = 16; // 13 + 3 persons
+const VOICE_START: u32 = 23; // 16 + 7 moods
+const TENSE_START: u32 = 25; // 23 + 2 voices
+
+/// The values of a single inflection category, along with the name of
+/// the index that tracks it and its start bit in the corpus mask.
+struct CategoryValues {
+    key: &'static str,
+    start_bit: u32,
+    codes: Vec<u32>,
+}
+
+fn category_values(data: WordInflectionData) -> [CategoryValues; 7] {
+    let expanded = expand_inflection_data(data);
+    let single = |code: Option<u32>| code.into_iter().collect::<Vec<_>>();
+    [
+        CategoryValues {
+            key: "case",
+            start_bit: CASE_START,
+            codes: expanded.cases.iter().map(|c| *c as u32).collect(),
+        },
+        CategoryValues {
+            key: "number",
+            start_bit: NUMBER_START,
+            codes: single(expanded.number.map(|x| x as u32)),
+        },
+        CategoryValues {
+            key: "gender",
+            start_bit: GENDER_START,
+            codes: expanded.genders.iter().map(|g| *g as u32).collect(),
+        },
+        CategoryValues {
+            key: "person",
+            start_bit: PERSON_START,
+            codes: single(expanded.person.map(|x| x as u32)),
+        },
+        CategoryValues {
+            key: "mood",
+            start_bit: MOOD_START,
+            codes: single(expanded.mood.map(|x| x as u32)),
+        },
+        CategoryValues {
+            key: "voice",
+            start_bit: VOICE_START,
+            codes: single(expanded.voice.map(|x| x as u32)),
+        },
+        CategoryValues {
+            key: "tense",
+            start_bit: TENSE_START,
+            codes: single(expanded.tense.map(|x| x as u32)),
+        },
+    ]
+}
+
+/// Packs the inflection data into the mask format used by the corpus.
+fn pack_inflection_data_for_corpus(data: WordInflectionData) -> u32 {
+    let mut mask = 0;
+    for category in category_values(data) {
+        for code in category.codes {
+            // -1 because values are 1-indexed.
+            mask |= 1 << (category.start_bit + code - 1);
+        }
+    }
+    mask
+}
+
+/// The values that should be added to the indices for every
+/// occurrence of a particular word.
+#[derive(Default)]
+struct WordIndexDimensions {
+    lemmata: Vec<String>,
+    /// Pairs of (index key, code) for the inflection indices.
+    inflections: Vec<(&'static str, u32)>,
+}
+
+impl WordIndexDimensions {
+    fn from(inflections: &[CrunchResult]) -> Self {
+        let mut dimensions = WordIndexDimensions::default();
+        for result in inflections {
+            let lemma = clean_lemma(&result.lemma);
+            if !dimensions.lemmata.contains(&lemma) {
+                dimensions.lemmata.push(lemma);
+            }
+            for category in category_values(result.context.grammatical_data) {
+                for code in category.codes {
+                    if !dimensions.inflections.contains(&(category.key, code)) {
+                        dimensions.inflections.push((category.key, code));
+                    }
+                }
+            }
+        }
+        dimensions
+    }
+}
+
+/// The packed (offset, length) of the inflection data for a token, in u32s.
+type OffsetAndLength = (u32, u32);
+
+/// The inflection data for all tokens in the corpus.
+pub(super) struct StoredInflections {
+    /// The `i`th element is the offset in `raw_data` for the `i`th token.
+    token_to_raw_data_offset: Vec<OffsetAndLength>,
+    /// Map from word to the offset in `raw_data`.
+    word_to_raw_data_offset: HashMap<String, OffsetAndLength>,
+    /// Sequence of (inflection mask, lemma ID) pairs; each pair
+    /// represents one possible inflection of a token.
+    raw_data: Vec<u32>,
+    /// Dimensions to add to the indices for each word.
+    dimensions: HashMap<String, WordIndexDimensions>,
+}
+
+impl StoredInflections {
+    pub(super) fn new() -> Self {
+        StoredInflections {
+            token_to_raw_data_offset: vec![],
+            word_to_raw_data_offset: HashMap::new(),
+            // This will represent words with no results.
+            raw_data: vec![0, 0],
+            dimensions: HashMap::new(),
+        }
+    }
+
+    /// Records the inflections of the given word (with the given token ID),
+    /// and adds the token to the lemma and inflection indices.
+    pub(super) fn ingest(
+        &mut self,
+        word: &str,
+        id: u32,
+        get_inflections: &impl Fn(&str) -> Vec<CrunchResult>,
+        corpus: &mut InProgressLatinCorpus,
+    ) -> Result<(), String> {
+        if let Some(offset) = self.word_to_raw_data_offset.get(word) {
+            // If the word has been seen before, just point to the existing data.
+            self.token_to_raw_data_offset.push(*offset);
+            self.add_to_lookups(word, id, corpus);
+            return Ok(());
+        }
+        let inflections = get_inflections(word);
+        self.dimensions
+            .insert(word.to_string(), WordIndexDimensions::from(&inflections));
+        // This must happen before encoding, since it assigns the lemma IDs.
+        self.add_to_lookups(word, id, corpus);
+
+        let mut encoded = vec![];
+        for inflection in &inflections {
+            let lemma = clean_lemma(&inflection.lemma);
+            let lemma_id = corpus
+                .id_for("lemma", &lemma)
+                .ok_or(format!("No ID for lemma {lemma}"))?;
+            encoded.push(pack_inflection_data_for_corpus(
+                inflection.context.grammatical_data,
+            ));
+            encoded.push(lemma_id);
+        }
+        // If there are no inflections, just point to the zero entry at the start of `raw_data`.
+        let offset = match encoded.is_empty() {
+            true => (0, 2),
+            false => (self.raw_data.len() as u32, encoded.len() as u32),
+        };
+        if offset.0 >= 1 << 24 || offset.1 >= 1 << 8 {
+            return Err(format!(
+                "Inflection data for {word} is too large: {offset:?}"
+            ));
+        }
+        self.token_to_raw_data_offset.push(offset);
+        self.word_to_raw_data_offset
+            .insert(word.to_string(), offset);
+        self.raw_data.append(&mut encoded);
+        Ok(())
+    }
+
+    fn add_to_lookups(&self, word: &str, id: u32, corpus: &mut InProgressLatinCorpus) {
+        let Some(dimensions) = self.dimensions.get(word) else {
+            return;
+        };
+        for lemma in &dimensions.lemmata {
+            corpus.add_str_keyed("lemma", lemma, id);
+        }
+        for (key, code) in &dimensions.inflections {
+            corpus.add(key, *code as usize, id);
+        }
+    }
+
+    /// Writes the inflection data to the given directory, returning
+    /// the paths of the raw data and the offsets files.
+    pub(super) fn save(&self, dir: &Path) -> Result<(String, String), std::io::Error> {
+        let raw_data_path = dir.join(CORPUS_INFLECTIONS_RAW_DATA);
+        let raw_data_bytes = self
+            .raw_data
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<u8>>();
+        fs::write(&raw_data_path, raw_data_bytes)?;
+
+        // Pack: the first 3 bytes are the offset, the last byte is the length.
+        let offsets_path = dir.join(CORPUS_INFLECTIONS_OFFSETS);
+        let offsets_bytes = self
+            .token_to_raw_data_offset
+            .iter()
+            .flat_map(|(offset, len)| ((offset << 8) | (len & 0xff)).to_le_bytes())
+            .collect::<Vec<u8>>();
+        fs::write(&offsets_path, offsets_bytes)?;
+
+        Ok((
+            raw_data_path.to_string_lossy().to_string(),
+            offsets_path.to_string_lossy().to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_inflection_data_for_corpus_sets_expected_bits() {
+        // Dative and Ablative (cases 3 and 5), Plural (2).
+        let data: WordInflectionData = (0b101000 << 16) | 2;
+        let packed = pack_inflection_data_for_corpus(data);
+        assert_eq!(packed, (1 << 2) | (1 << 4) | (1 << (NUMBER_START + 1)));
+    }
+
+    #[test]
+    fn pack_inflection_data_for_corpus_handles_verbal_data() {
+        // 3rd person, Active, Perfect, Indicative.
+        let data: WordInflectionData = (1 << 11) | (3 << 8) | (1 << 4) | (3 << 2);
+        let packed = pack_inflection_data_for_corpus(data);
+        let expected = (1 << MOOD_START)
+            | (1 << (TENSE_START + 2))
+            | (1 << VOICE_START)
+            | (1 << (PERSON_START + 2));
+        assert_eq!(packed, expected);
+    }
+
+    #[test]
+    fn pack_inflection_data_for_corpus_empty() {
+        assert_eq!(pack_inflection_data_for_corpus(0), 0);
+    }
+}
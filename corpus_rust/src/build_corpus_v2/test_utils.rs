@@ -0,0 +1,205 @@
+#![cfg(test)]
+
+use std::ops::Deref;
+
+use morceus::indices::{CrunchResult, InflectionContext};
+use tempfile::TempDir;
+
+use crate::{
+    api::{PageData, QueryOptions},
+    build_corpus_v2::{CORPUS_FILE, build_corpus_from_works, library_utils::CorpusInputWork},
+    corpus_index::deserialize_corpus,
+    corpus_query_engine::{CorpusQueryEngine, FormAnalyzer},
+};
+
+pub(super) fn make_work(id: &str, author_code: &str, rows: &[(&str, &str)]) -> CorpusInputWork {
+    CorpusInputWork {
+        id: id.to_string(),
+        work_name: format!("Work {id}"),
+        author: format!("Author {author_code}"),
+        author_code: author_code.to_string(),
+        rows: rows.iter().map(|(_, text)| text.to_string()).collect(),
+        row_ids: rows
+            .iter()
+            .map(|(id, _)| id.split('.').map(|s| s.to_string()).collect())
+            .collect(),
+        section_depth: 2,
+    }
+}
+
+pub(super) fn make_result(lemma: &str, grammatical_data: u32) -> CrunchResult {
+    CrunchResult {
+        lemma: lemma.to_string(),
+        form: lemma.to_string(),
+        stem: None,
+        end: None,
+        relaxed_case: false,
+        relaxed_vowel_lengths: false,
+        enclitic: None,
+        is_verb: false,
+        context: InflectionContext {
+            grammatical_data,
+            tags: None,
+            internal_tags: None,
+        },
+    }
+}
+
+/// Encodes nominal data in the `morceus` format, given the case codes and number code.
+const fn nominal(case_bits: u32, number: u32) -> u32 {
+    (case_bits << 16) | number
+}
+
+/// Encodes 3rd person singular present indicative data in the `morceus` format.
+const fn third_sg_pres_ind(voice: u32) -> u32 {
+    (1 << 11) | (1 << 8) | (voice << 4) | (3 << 2) | 1
+}
+
+const NOM: u32 = 1 << 1;
+const ACC: u32 = 1 << 2;
+const DAT: u32 = 1 << 3;
+const GEN: u32 = 1 << 4;
+const ABL: u32 = 1 << 5;
+const SG: u32 = 1;
+const PL: u32 = 2;
+
+/// A small, hard-coded analyzer for the words in the test corpus.
+pub(crate) fn fake_inflections(word: &str) -> Vec<CrunchResult> {
+    match word {
+        "puella" => vec![
+            make_result("puella", nominal(NOM, SG)),
+            make_result("puella", nominal(ABL, SG)),
+        ],
+        "puellae" => vec![
+            make_result("puella", nominal(GEN | DAT, SG)),
+            make_result("puella", nominal(NOM, PL)),
+        ],
+        "puellis" => vec![make_result("puella", nominal(DAT | ABL, PL))],
+        "rosa" => vec![
+            make_result("rosa", nominal(NOM, SG)),
+            make_result("rosa", nominal(ABL, SG)),
+        ],
+        "rosas" => vec![make_result("rosa", nominal(ACC, PL))],
+        "dat" => vec![make_result("do", third_sg_pres_ind(1))],
+        "datur" => vec![make_result("do", third_sg_pres_ind(2))],
+        // Not in the test corpus, but useful for looking up lemmata by form.
+        "dedisset" => vec![make_result("do", 0)],
+        _ => vec![],
+    }
+}
+
+/// Returns a fresh temporary directory for the given test, which is deleted when dropped.
+pub(super) fn test_dir(name: &str) -> TempDir {
+    tempfile::Builder::new()
+        .prefix(&format!("corpus_build_test_{name}_"))
+        .tempdir()
+        .unwrap()
+}
+
+/// An engine for a test corpus, along with the temporary directory that the corpus
+/// was built in. The directory is deleted when this is dropped.
+pub(crate) struct TestEngine {
+    engine: CorpusQueryEngine,
+    _dir: TempDir,
+}
+
+impl TestEngine {
+    pub(crate) fn with_analyzer(self, analyzer: FormAnalyzer) -> Self {
+        TestEngine {
+            engine: self.engine.with_analyzer(analyzer),
+            _dir: self._dir,
+        }
+    }
+}
+
+impl Deref for TestEngine {
+    type Target = CorpusQueryEngine;
+
+    fn deref(&self) -> &CorpusQueryEngine {
+        &self.engine
+    }
+}
+
+/// Builds a small test corpus (in a temporary directory unique to `name`)
+/// and returns an engine for it.
+///
+/// The corpus has the following works:
+/// - `w1` (Alpha): `1.1` "Puella rosās puellīs dat." and `1.2` "Puella dat"
+/// - `w2` (Alpha): `1` "Rosas puellis" and `2` "dat puella"
+/// - `w3` (Beta): `1.1` "puella, puellis dat" and `1.2` "rosa datur puellae"
+pub(crate) fn build_test_engine(name: &str) -> TestEngine {
+    let works = vec![
+        make_work(
+            "w1",
+            "Alpha",
+            &[("1.1", "Puella rosās puellīs dat."), ("1.2", "Puella dat")],
+        ),
+        make_work(
+            "w2",
+            "Alpha",
+            &[("1", "Rosas puellis"), ("2", "dat puella")],
+        ),
+        make_work(
+            "w3",
+            "Beta",
+            &[
+                ("1.1", "puella, puellis dat"),
+                ("1.2", "rosa datur puellae"),
+            ],
+        ),
+    ];
+    build_engine_from_works(name, &works, fake_inflections)
+}
+
+/// Builds a corpus from the given works (in a temporary directory unique to `name`),
+/// analyzing words with `get_inflections`, and returns an engine for it.
+pub(super) fn build_engine_from_works(
+    name: &str,
+    works: &[CorpusInputWork],
+    get_inflections: impl Fn(&str) -> Vec<CrunchResult>,
+) -> TestEngine {
+    let dir = test_dir(name);
+    let path = dir.path().to_string_lossy().to_string();
+    build_corpus_from_works(works, get_inflections, &path).unwrap();
+    let index = deserialize_corpus(&format!("{path}/{CORPUS_FILE}")).unwrap();
+    TestEngine {
+        engine: CorpusQueryEngine::new(index).unwrap(),
+        _dir: dir,
+    }
+}
+
+/// Runs the query against the engine, returning the matched text of each result.
+pub(crate) fn query_texts_with(
+    engine: &CorpusQueryEngine,
+    query: &str,
+    strict_mode: bool,
+) -> Vec<String> {
+    let page = PageData {
+        result_index: 0,
+        result_id: 0,
+        candidate_index: 0,
+        sample_seed: None,
+    };
+    let options = QueryOptions {
+        page_size: 100,
+        context_len: 1,
+        strict_mode,
+        ..Default::default()
+    };
+    let result = engine
+        .query_corpus(query, &page, &options)
+        .unwrap_or_else(|e| panic!("Query failed: {query}\n  {e:?}"));
+    result
+        .matches
+        .iter()
+        .map(|m| {
+            let matched = m.text.iter().filter(|(_, is_match)| *is_match);
+            matched.map(|(text, _)| text.as_str()).collect::<String>()
+        })
+        .collect()
+}
+
+/// Runs the query against the engine, returning the matched text of each result.
+pub(crate) fn query_texts(engine: &CorpusQueryEngine, query: &str) -> Vec<String> {
+    query_texts_with(engine, query, false)
+}
@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use std::cmp::Ordering::{Equal, Greater, Less};
 use std::collections::HashMap;
 use std::error::Error;
@@ -21,7 +21,7 @@ pub struct WorkData {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum StoredMapValue {
     Packed {
@@ -36,7 +36,7 @@ pub enum StoredMapValue {
 }
 
 // (ID, start, end)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WorkRowInfo(pub String, pub u32, pub u32);
 
 #[derive(Debug, Deserialize)]
@@ -58,7 +58,14 @@ impl From<(String, Vec<WorkRowInfo>, WorkData)> for WorkLookupEntry {
     }
 }
 
-#[derive(Debug, Deserialize)]
+// Serializes back to the same tuple format that we read from.
+impl Serialize for WorkLookupEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (&self.work_id, &self.rows, &self.info).serialize(serializer)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LatinCorpusIndex {
     pub work_lookup: Vec<WorkLookupEntry>,
@@ -147,6 +154,7 @@ impl LatinCorpusIndex {
             work_name: &work_data.info.name,
             author: &work_data.info.author,
             leaders: leader_info.into_iter().flatten().collect(),
+            lemmata: vec![],
             work_start_token,
             work_end_token,
         })
@@ -1,3 +1,5 @@
+mod atom_expansion;
+mod collocations;
 mod corpus_candidate_filtering;
 mod corpus_data_readers;
 mod corpus_index_calculation;
@@ -5,30 +7,53 @@ mod corpus_query_conversion;
 mod corpus_result_resolution;
 mod errors;
 mod index_data;
+mod match_captures;
+mod match_context;
+mod match_counting;
+mod match_export;
+mod query_explanation;
 mod query_pruning;
+mod query_scope;
 mod query_validation;
 mod reference_impl;
+mod result_sorting;
+mod token_analysis;
 
-use crate::api::{CorpusQueryResult, PageData, QueryExecError, QueryGlobalInfo, QueryOptions};
+use crate::api::{
+    CollocationOptions, CollocationResult, CorpusCountResult, CorpusQueryResult, PageData,
+    QueryExecError, QueryGlobalInfo, QueryOptions, QueryPlan, ResultSort,
+};
+use crate::corpus_query_engine::atom_expansion::FormLemmata;
+use crate::corpus_query_engine::collocations::MAX_COLLOCATION_WINDOW;
 use crate::corpus_query_engine::corpus_candidate_filtering::MatchIterator;
 use crate::corpus_query_engine::corpus_data_readers::{
     CorpusText, IndexBuffers, InflectionLookup, TokenStarts,
 };
-use crate::corpus_query_engine::corpus_result_resolution::get_match_page;
-use crate::corpus_query_engine::index_data::{IndexData, IndexDataRoO, IndexRange};
-use crate::corpus_query_engine::query_pruning::prune_query;
+use crate::corpus_query_engine::corpus_index_calculation::SpanResult;
+use crate::corpus_query_engine::corpus_query_conversion::InternalQueryTerm;
+use crate::corpus_query_engine::corpus_result_resolution::{
+    all_matches, get_match_page, match_range,
+};
+use crate::corpus_query_engine::index_data::{
+    IndexData, IndexDataRoO, IndexSlice, apply_and_to_indices,
+};
+use crate::corpus_query_engine::query_explanation::record_step;
+use crate::corpus_query_engine::query_pruning::{normalize_negations, prune_query};
+use crate::corpus_query_engine::query_scope::{TokenRange, bounding_range, scope_bitmask};
 use crate::corpus_query_engine::query_validation::is_query_currently_supported;
-use crate::query_parsing_v2::{Query, parse_query};
+use crate::query_parsing_v2::parse_query;
 
 use super::corpus_index::LatinCorpusIndex;
 use super::profiler::TimeProfiler;
 
+use morceus::indices::CrunchResult;
 use std::error::Error;
 
 fn empty_result() -> CorpusQueryResult<'static> {
     CorpusQueryResult {
         result_stats: QueryGlobalInfo {
             estimated_results: 0,
+            sample_seed: None,
         },
         matches: vec![],
         next_page: None,
@@ -36,6 +61,18 @@ fn empty_result() -> CorpusQueryResult<'static> {
     }
 }
 
+/// Everything needed to find the matches of a query, once its candidates are known.
+struct PreparedQuery<'a, 's> {
+    candidates: &'a IndexSlice<'a>,
+    span_candidates: &'a [SpanResult<'a>],
+    query_spans: &'a [&'a [InternalQueryTerm<'a>]],
+    scope: &'a [TokenRange],
+    form_lemmata: &'a FormLemmata<'s>,
+}
+
+/// Returns the possible analyses of an inflected form.
+pub type FormAnalyzer = Box<dyn Fn(&str) -> Vec<CrunchResult>>;
+
 /// An engine for querying a corpus.
 pub struct CorpusQueryEngine {
     corpus: LatinCorpusIndex,
@@ -43,6 +80,7 @@ pub struct CorpusQueryEngine {
     raw_buffers: IndexBuffers,
     starts: TokenStarts,
     inflections: InflectionLookup,
+    analyzer: Option<FormAnalyzer>,
 }
 
 impl CorpusQueryEngine {
@@ -55,60 +93,32 @@ impl CorpusQueryEngine {
             text: readers.1,
             raw_buffers: readers.2,
             inflections: readers.3,
+            analyzer: None,
         })
     }
 
-    fn compute_range(&self, query: &Query) -> Result<IndexRange, QueryExecError> {
-        if query.authors.len() > 1 {
-            return Err(QueryExecError::new(
-                "Multiple authors in query not currently supported",
-            ));
-        }
-        if query.authors.is_empty() {
-            let num_words = self.corpus.num_tokens.div_ceil(64);
-            let range = IndexRange {
-                start: 0,
-                end: num_words * 64,
-            };
-            return Ok(range);
-        }
-        let author = &query.authors[0];
-        let (start, end) = match self.corpus.author_lookup.get(author) {
-            Some(v) => *v,
-            None => {
-                return Err(QueryExecError::new(&format!(
-                    "Author '{author}' not found in corpus"
-                )));
-            }
-        };
-        let start = self.corpus.work_lookup[start].rows[0].1;
-        let end_work_sections = &self.corpus.work_lookup[end].rows;
-        let end = end_work_sections[end_work_sections.len() - 1].2;
-        // The range must be aligned to word boundaries.
-        Ok(IndexRange {
-            start: (start / 64) * 64,
-            end: end.div_ceil(64) * 64,
-        })
+    /// Sets the analyzer used to find the lemmata of `@form:` atoms in queries.
+    pub fn with_analyzer(mut self, analyzer: FormAnalyzer) -> Self {
+        self.analyzer = Some(analyzer);
+        self
     }
 
-    /// Queries the corpus with the given parameters.
-    /// - `query_str`: The query string to execute.
-    /// - `page_data`: Metadata required to find the correct page of results.
-    /// - `page_size`: The maximum number of results to return. If `None`, a large default is used.
-    /// - `context_len`: The number of tokens of context to include around each match. If `None`, defaults to 25.
+    /// Parses the query and computes its candidates, then passes them to `on_prepared`.
+    /// Returns `None` if the query can't have any matches.
     ///
-    /// Returns matches (and metadata) for the query.
-    pub fn query_corpus(
-        &self,
+    /// If a `plan` is given, each step of computing the candidates is recorded in it.
+    fn run_query<'s, T>(
+        &'s self,
         query_str: &str,
-        page_data: &PageData,
         options: &QueryOptions,
-    ) -> Result<CorpusQueryResult<'_>, QueryExecError> {
-        let context_len = options.context_len;
-        let mut profiler = TimeProfiler::new();
-
+        profiler: &mut TimeProfiler,
+        mut plan: Option<&mut QueryPlan>,
+        on_prepared: impl FnOnce(PreparedQuery<'_, 's>, &mut TimeProfiler) -> Result<T, QueryExecError>,
+    ) -> Result<Option<T>, QueryExecError> {
         // Parse the query
         let query = parse_query(query_str).map_err(|e| QueryExecError::new(&e.message))?;
+        let (query, form_lemmata) = self.expand_atoms(query)?;
+        let query = normalize_negations(query);
         if !is_query_currently_supported(&query) {
             return Err(QueryExecError::new(
                 "The given query contains constructs that are not yet supported",
@@ -116,14 +126,24 @@ impl CorpusQueryEngine {
         }
         let query = match prune_query(query) {
             Ok(q) => q,
-            // TODO: Pipe the error to the user.
-            Err(_) => return Ok(empty_result()),
+            // When explaining the query, the contradiction is part of the explanation.
+            Err(e) => match plan {
+                Some(plan) => {
+                    plan.pruning_error = Some(e);
+                    return Ok(None);
+                }
+                None => return Err(QueryExecError::contradiction(e)),
+            },
         };
         let terms = query
             .terms
             .iter()
             .map(|term| self.convert_query_term(term))
             .collect::<Result<Vec<_>, _>>()?;
+        if let Some(plan) = plan.as_deref_mut() {
+            plan.pruned_query = Some(terms.iter().map(ToString::to_string).collect());
+            plan.atoms = self.atom_bounds(&query);
+        }
         let query_spans = corpus_index_calculation::split_into_spans(&terms)?;
         if query_spans.len() > 10 {
             return Err(QueryExecError::new(
@@ -133,62 +153,203 @@ impl CorpusQueryEngine {
         profiler.phase("Parse query");
 
         // Find the candidates for each span individually.
-        let range = self.compute_range(&query)?;
+        let scope = self.resolve_scope_with_options(&query.scope, options)?;
+        let range = match bounding_range(&scope) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+        let span_plan = plan.as_deref_mut();
         let span_candidates =
-            match self.candidates_for_spans(&query_spans, &range, &mut profiler)? {
+            match self.candidates_for_spans(&query_spans, &range, profiler, span_plan)? {
                 Some(res) => res,
-                None => return Ok(empty_result()),
+                None => return Ok(None),
             };
 
         // Find the candidates that could match all spans.
-        let candidates = self.compute_query_candidates(&span_candidates)?;
-        let total_candidates = candidates.data.to_ref().num_elements();
-        let mut candidates = MatchIterator::new(&candidates, page_data);
+        let mut steps = plan.map(|plan| &mut plan.combinations);
+        let candidates = self.compute_query_candidates(&span_candidates, steps.as_deref_mut())?;
+        // The range might include tokens outside of the scope, so we need to exclude those.
+        let scope_mask = scope_bitmask(&scope, &range, self.corpus.num_tokens);
+        let candidates = match &scope_mask {
+            None => candidates,
+            Some(mask) => {
+                let scope_slice = IndexSlice {
+                    data: IndexDataRoO::Ref(IndexData::BitMask(mask)),
+                    range: &range,
+                    position: 0,
+                };
+                let candidates = apply_and_to_indices(&candidates, &scope_slice)?;
+                record_step(steps, || "Scope".to_string(), &candidates);
+                candidates
+            }
+        };
         profiler.phase("Combined candidates found");
 
-        // Finds a page of actual matches from the candidates.
-        let match_leaders = get_match_page(
-            &mut candidates,
-            &span_candidates,
-            &query_spans,
-            self,
-            page_data,
-            total_candidates,
+        let prepared = PreparedQuery {
+            candidates: &candidates,
+            span_candidates: &span_candidates,
+            query_spans: &query_spans,
+            scope: &scope,
+            form_lemmata: &form_lemmata,
+        };
+        on_prepared(prepared, profiler).map(Some)
+    }
+
+    /// Queries the corpus with the given parameters.
+    /// - `query_str`: The query string to execute.
+    /// - `page_data`: Metadata required to find the correct page of results.
+    /// - `page_size`: The maximum number of results to return. If `None`, a large default is used.
+    /// - `context_len`: The number of tokens of context to include around each match. If `None`, defaults to 25.
+    /// - `sort`: The order of the results. Unless this is by position, every match needs to be
+    ///   found (and sorted) for each page.
+    /// - `sample_size`: If set, the results are a random sample of the matches, chosen by the
+    ///   `sample_seed` in the page data (which is carried over to the next page).
+    ///
+    /// Returns matches (and metadata) for the query.
+    pub fn query_corpus(
+        &self,
+        query_str: &str,
+        page_data: &PageData,
+        options: &QueryOptions,
+    ) -> Result<CorpusQueryResult<'_>, QueryExecError> {
+        let context_len = options.context_len;
+        let mut profiler = TimeProfiler::new();
+        let result = self.run_query(
+            query_str,
             options,
-        )?;
-        profiler.phase("Match page computed");
+            &mut profiler,
+            None,
+            |prepared, profiler| {
+                // Finds a page of actual matches from the candidates.
+                let match_leaders = if options.sample_size.is_some() {
+                    self.sampled_match_page(&prepared, page_data, options)?
+                } else if options.sort == ResultSort::Position {
+                    let mut candidates = MatchIterator::new(prepared.candidates, page_data);
+                    get_match_page(
+                        &mut candidates,
+                        prepared.span_candidates,
+                        prepared.query_spans,
+                        prepared.scope,
+                        self,
+                        page_data,
+                        options,
+                    )?
+                } else {
+                    self.sorted_match_page(&prepared, page_data, options)?
+                };
+                profiler.phase("Match page computed");
 
-        // Turn the match IDs into actual matches (with the text and locations).
-        let matches = self.resolve_match_tokens(match_leaders.matches, context_len as u32)?;
-        profiler.phase("Matches resolved");
+                // Turn the match IDs into actual matches (with the text and locations).
+                let matches = self.resolve_match_tokens(
+                    match_leaders.matches,
+                    context_len as u32,
+                    options.context_mode,
+                    prepared.form_lemmata,
+                    options.analyses,
+                )?;
+                profiler.phase("Matches resolved");
 
-        Ok(CorpusQueryResult {
-            result_stats: match_leaders.summary_info,
-            matches,
-            next_page: match_leaders.next_page,
-            timing: profiler.get_stats().to_vec(),
+                Ok(CorpusQueryResult {
+                    result_stats: match_leaders.summary_info,
+                    matches,
+                    next_page: match_leaders.next_page,
+                    timing: vec![],
+                })
+            },
+        )?;
+        Ok(match result {
+            Some(mut result) => {
+                result.timing = profiler.get_stats().to_vec();
+                result
+            }
+            None => empty_result(),
         })
     }
+
+    /// Returns the range of tokens covered by every match of the query.
+    fn all_match_ranges(
+        &self,
+        query_str: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<TokenRange>, QueryExecError> {
+        let mut profiler = TimeProfiler::new();
+        let ranges = self.run_query(query_str, options, &mut profiler, None, |prepared, _| {
+            let mut candidates = MatchIterator::new(prepared.candidates, &PageData::default());
+            let matches = all_matches(
+                &mut candidates,
+                prepared.span_candidates,
+                prepared.query_spans,
+                prepared.scope,
+                self,
+                options,
+            )?;
+            Ok(matches.iter().map(match_range).collect())
+        })?;
+        Ok(ranges.unwrap_or_default())
+    }
+
+    /// Counts every match of the query, grouped by author, work, and section.
+    /// Unlike the estimate in `query_corpus`, the counts are exact, but every candidate
+    /// needs to be checked to compute them.
+    ///
+    /// The `page_size` and `context_len` options are ignored.
+    pub fn count_corpus(
+        &self,
+        query_str: &str,
+        options: &QueryOptions,
+    ) -> Result<CorpusCountResult<'_>, QueryExecError> {
+        let starts = self
+            .all_match_ranges(query_str, options)?
+            .iter()
+            .map(|range| range.0)
+            .collect::<Vec<_>>();
+        self.count_by_location(&starts)
+    }
+
+    /// Finds the words and lemmata that occur most often at each position around the
+    /// matches of the query (up to `window` tokens before and after each match), along
+    /// with scores for how strongly they are associated with the query.
+    ///
+    /// The `page_size` and `context_len` options are ignored.
+    pub fn collocations(
+        &self,
+        query_str: &str,
+        options: &QueryOptions,
+        collocation_options: &CollocationOptions,
+    ) -> Result<CollocationResult<'_>, QueryExecError> {
+        let window = collocation_options.window;
+        if window == 0 || window > MAX_COLLOCATION_WINDOW {
+            return Err(QueryExecError::new(&format!(
+                "The collocation window must be between 1 and {MAX_COLLOCATION_WINDOW}"
+            )));
+        }
+        let ranges = self.all_match_ranges(query_str, options)?;
+        self.collocates_of(&ranges, collocation_options)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::{Collocate, ContextMode, MatchAnalyses};
+    use crate::build_corpus_v2::test_utils;
+    use std::collections::HashMap;
 
     macro_rules! generate {
         ($query:expr) => {
-            &[
+            [
                 (
                     $query,
                     PageData {
                         result_index: 0,
                         result_id: 0,
                         candidate_index: 0,
+                        sample_seed: None,
                     },
                     QueryOptions {
                         page_size: 5,
                         context_len: 15,
-                        strict_mode: false,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -197,11 +358,12 @@ mod tests {
                         result_index: 0,
                         result_id: 0,
                         candidate_index: 0,
+                        sample_seed: None,
                     },
                     QueryOptions {
                         page_size: 25,
                         context_len: 10,
-                        strict_mode: false,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -210,11 +372,12 @@ mod tests {
                         result_index: 5,
                         result_id: 0,
                         candidate_index: 0,
+                        sample_seed: None,
                     },
                     QueryOptions {
                         page_size: 5,
                         context_len: 10,
-                        strict_mode: false,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -223,11 +386,12 @@ mod tests {
                         result_index: 50,
                         result_id: 0,
                         candidate_index: 0,
+                        sample_seed: None,
                     },
                     QueryOptions {
                         page_size: 5,
                         context_len: 10,
-                        strict_mode: false,
+                        ..Default::default()
                     },
                 ),
             ]
@@ -235,16 +399,45 @@ mod tests {
     }
 
     // (query, page_start, page_size, context_len)
-    const TEST_QUERIES: &[&[(&str, PageData, QueryOptions)]] = &[
-        generate!("@lemma:do"),
-        generate!("@case:dat"),
-        generate!("(@lemma:habeo and @voice:passive)"),
-        generate!("(@case:dat or @voice:passive)"),
-        generate!("(@case:dat or (@voice:passive and @lemma:do))"),
-        generate!("@lemma:do oscula @case:dat"),
-        generate!("[Ovid] @lemma:do oscula @case:dat"),
-        generate!("@case:dat @case:acc"),
-        generate!("@case:dat @case:nom et"),
+    fn test_queries() -> Vec<[(&'static str, PageData, QueryOptions); 4]> {
+        vec![
+            generate!("@lemma:do"),
+            generate!("@case:dat"),
+            generate!("(@lemma:habeo and @voice:passive)"),
+            generate!("(@case:dat or @voice:passive)"),
+            generate!("(@case:dat or (@voice:passive and @lemma:do))"),
+            generate!("@lemma:do oscula @case:dat"),
+            generate!("[Ovid] @lemma:do oscula @case:dat"),
+            generate!("@case:dat @case:acc"),
+            generate!("@case:dat @case:nom et"),
+            generate!("!@case:dat"),
+            generate!("@lemma:do !@case:acc"),
+            generate!("((@case:abl and @number:pl) or (@case:dat and @number:sg))"),
+            generate!("(@lemma:do and (@tense:present or (@voice:passive and @mood:subjunctive)))"),
+        ]
+    }
+
+    // Queries to compare against the reference implementation on the small test corpus.
+    const TEST_CORPUS_QUERIES: &[&str] = &[
+        "(@case:dat or (@voice:passive and @lemma:do))",
+        "((@case:abl and @number:pl) or (@case:dat and @number:sg))",
+        "((@case:nom and @number:sg) or @lemma:do)",
+        "((puellae and @number:pl) or (rosas and @case:acc))",
+        "(@case:abl and !(@lemma:rosa or @number:pl))",
+        "((@case:nom and @number:sg) or !@case:dat)",
+        "(@lemma:puella and !@case:nom)",
+        "(@lemma:puella and (@case:nom or (@case:abl and @number:sg))) @lemma:do",
+        "[Beta] @lemma:puella",
+        "[Alpha, !w1] @lemma:puella",
+        "[w1, Beta] (@case:dat or @lemma:do)",
+        "[!Alpha] @lemma:puella @lemma:do",
+        "[Beta: w3 1.2] @lemma:puella",
+        "[Alpha: w2 1] puellis dat",
+        "[Alpha: w1 1.1-2, Beta] @lemma:puella",
+        "puell* dat*",
+        "(/^ros/ or @lemma:do)",
+        "!puell*",
+        "(@lemma:/^p/ and @case:dat)",
     ];
 
     #[test]
@@ -255,9 +448,1172 @@ mod tests {
             // fails to load, because the CI doesn't handle building the index yet
             None => return,
         };
-        let test_queries = TEST_QUERIES.iter().flat_map(|s| s.iter());
+        let test_queries = test_queries().into_iter().flatten();
         for (query, page_data, options) in test_queries {
-            engine.compare_ref_impl_results(query, page_data, options);
+            engine.compare_ref_impl_results(query, &page_data, &options);
+        }
+    }
+
+    #[test]
+    fn validate_queries_on_test_corpus() {
+        let engine = test_utils::build_test_engine("validate_queries");
+        let page_data = PageData {
+            result_index: 0,
+            result_id: 0,
+            candidate_index: 0,
+            sample_seed: None,
+        };
+        // Use a page large enough for all of the results, since otherwise the
+        // result counts are only estimates (and the corpus is too small for later pages).
+        let options = QueryOptions {
+            page_size: 25,
+            context_len: 3,
+            ..Default::default()
+        };
+        for query in TEST_CORPUS_QUERIES {
+            engine.compare_ref_impl_results(query, &page_data, &options);
+        }
+    }
+
+    #[test]
+    fn author_scope_only_searches_author() {
+        let engine = test_utils::build_test_engine("author_scope");
+        assert_eq!(test_utils::query_texts(&engine, "puella").len(), 4);
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Beta] puella"),
+            vec!["puella"]
+        );
+        assert_eq!(test_utils::query_texts(&engine, "[Alpha] puella").len(), 3);
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Beta] puellis dat").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn multiple_scopes_are_combined() {
+        let engine = test_utils::build_test_engine("multiple_scopes");
+        assert_eq!(
+            test_utils::query_texts(&engine, "[w1, Beta] puella"),
+            vec!["Puella", "Puella", "puella"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Beta, Alpha] puella").len(),
+            4
+        );
+    }
+
+    #[test]
+    fn work_scope_by_name_or_id() {
+        let engine = test_utils::build_test_engine("work_scope");
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: w2] puella"),
+            vec!["puella"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: Work w2] puella"),
+            vec!["puella"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[w2] puella"),
+            vec!["puella"]
+        );
+    }
+
+    #[test]
+    fn excluded_scopes_are_skipped() {
+        let engine = test_utils::build_test_engine("excluded_scope");
+        assert_eq!(
+            test_utils::query_texts(&engine, "[!Alpha] puella"),
+            vec!["puella"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[!Alpha: w2] puella"),
+            vec!["Puella", "Puella", "puella"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha, !w1] puella"),
+            vec!["puella"]
+        );
+        assert!(test_utils::query_texts(&engine, "[Beta, !Beta] puella").is_empty());
+    }
+
+    #[test]
+    fn section_scope_only_searches_sections() {
+        let engine = test_utils::build_test_engine("section_scope");
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Beta: w3 1.2] @lemma:puella"),
+            vec!["puellae"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Beta: w3 1.1] @lemma:puella"),
+            vec!["puella", "puellis"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: Work w1 1] @lemma:do").len(),
+            2
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: w1 1.2-1.2] puella"),
+            vec!["Puella"]
+        );
+    }
+
+    #[test]
+    fn section_scope_requires_whole_match_in_range() {
+        let engine = test_utils::build_test_engine("section_scope_bounds");
+        // "puellis dat" spans the end of section 1 and the start of section 2.
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: w2] puellis dat").len(),
+            1
+        );
+        assert!(test_utils::query_texts(&engine, "[Alpha: w2 1] puellis dat").is_empty());
+        assert!(test_utils::query_texts(&engine, "[Alpha: w2 2] puellis dat").is_empty());
+        assert_eq!(
+            test_utils::query_texts(&engine, "[Alpha: w2 1-2] puellis dat").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn scope_option_restricts_query() {
+        let engine = test_utils::build_test_engine("scope_option");
+        let page = PageData {
+            result_index: 0,
+            result_id: 0,
+            candidate_index: 0,
+            sample_seed: None,
+        };
+        let count = |query: &str, scope: &str| {
+            let options = QueryOptions {
+                page_size: 10,
+                context_len: 1,
+                scope: Some(scope.parse().unwrap()),
+                ..Default::default()
+            };
+            engine
+                .query_corpus(query, &page, &options)
+                .map(|result| result.matches.len())
+                .map_err(|e| e.message)
+        };
+        assert_eq!(count("@lemma:puella", "Beta: w3 1.1"), Ok(2));
+        assert_eq!(count("[Alpha] @lemma:puella", "Alpha: w1 1.2, Beta"), Ok(1));
+        assert_eq!(count("[Alpha] @lemma:puella", "Beta"), Ok(0));
+        assert!(count("@lemma:puella", "Gamma").is_err());
+    }
+
+    #[test]
+    fn unknown_scopes_are_errors() {
+        let engine = test_utils::build_test_engine("unknown_scope");
+        let page = PageData {
+            result_index: 0,
+            result_id: 0,
+            candidate_index: 0,
+            sample_seed: None,
+        };
+        let options = QueryOptions {
+            page_size: 10,
+            context_len: 1,
+            ..Default::default()
+        };
+        for query in [
+            "[Gamma] puella",
+            "[Alpha: w3] puella",
+            "[!Gamma] puella",
+            "[Alpha: w1 3] puella",
+            "[Alpha: w1 1.2-1.1] puella",
+        ] {
+            assert!(engine.query_corpus(query, &page, &options).is_err());
         }
     }
+
+    #[test]
+    fn nested_constraints_check_each_analysis() {
+        let engine = test_utils::build_test_engine("nested_constraints");
+        assert_eq!(
+            test_utils::query_texts(
+                &engine,
+                "((@case:abl and @number:pl) or (@case:dat and @number:sg))"
+            ),
+            vec!["puellis", "puellis", "puellis", "puellae"]
+        );
+        // `puellae` could be nominative or singular, but not nominative singular.
+        assert_eq!(
+            test_utils::query_texts(&engine, "((@case:nom and @number:sg) or @lemma:do)"),
+            vec![
+                "Puella", "dat", "Puella", "dat", "dat", "puella", "puella", "dat", "rosa", "datur"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_constraints_with_words_and_negations() {
+        let engine = test_utils::build_test_engine("nested_words_and_negations");
+        assert_eq!(
+            test_utils::query_texts(
+                &engine,
+                "((puellae and @number:pl) or (rosas and @case:acc))"
+            ),
+            vec!["rosas", "Rosas", "puellae"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "(@case:abl and !(@lemma:rosa or @number:pl))"),
+            vec!["Puella", "Puella", "puella", "puella"]
+        );
+        // `puellae` has a nominative plural analysis, but it could also be dative.
+        let results =
+            test_utils::query_texts(&engine, "((@case:nom and @number:sg) or !@case:dat)");
+        assert!(results.contains(&"rosa".to_string()));
+        assert!(!results.contains(&"puellae".to_string()));
+    }
+
+    #[test]
+    fn wildcards_expand_to_matching_words() {
+        let engine = test_utils::build_test_engine("wildcards");
+        assert_eq!(test_utils::query_texts(&engine, "puell*").len(), 8);
+        assert_eq!(
+            test_utils::query_texts(&engine, "*s"),
+            vec!["rosas", "puellis", "Rosas", "puellis", "puellis"]
+        );
+        assert_eq!(test_utils::query_texts(&engine, "dat??"), vec!["datur"]);
+        assert_eq!(test_utils::query_texts(&engine, "Puell* dat").len(), 4);
+        assert_eq!(test_utils::query_texts(&engine, "!puell*").len(), 8);
+        assert!(test_utils::query_texts(&engine, "xyz*").is_empty());
+    }
+
+    #[test]
+    fn regex_patterns_expand_to_matching_words_and_lemmata() {
+        let engine = test_utils::build_test_engine("regex_patterns");
+        assert_eq!(
+            test_utils::query_texts(&engine, "/^ros/"),
+            vec!["rosas", "Rosas", "rosa"]
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "@lemma:/^(do|rosa)$/"),
+            test_utils::query_texts(&engine, "(@lemma:do or @lemma:rosa)")
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "(@lemma:/^p/ and @case:dat)"),
+            vec!["puellis", "puellis", "puellis", "puellae"]
+        );
+    }
+
+    #[test]
+    fn form_atoms_search_all_lemmata_of_form() {
+        let engine = test_utils::build_test_engine("form_atoms")
+            .with_analyzer(Box::new(test_utils::fake_inflections));
+        assert_eq!(
+            test_utils::query_texts(&engine, "@form:dedisset"),
+            test_utils::query_texts(&engine, "@lemma:do")
+        );
+        assert_eq!(
+            test_utils::query_texts(&engine, "@form:puellae @form:dedisset").len(),
+            test_utils::query_texts(&engine, "@lemma:puella @lemma:do").len()
+        );
+        assert!(test_utils::query_texts(&engine, "@form:foo").is_empty());
+    }
+
+    #[test]
+    fn form_atoms_report_matched_lemmata() {
+        let engine = test_utils::build_test_engine("form_lemmata")
+            .with_analyzer(Box::new(test_utils::fake_inflections));
+        let options = QueryOptions {
+            page_size: 10,
+            context_len: 1,
+            ..Default::default()
+        };
+        let result = engine
+            .query_corpus("[Beta] rosa @form:dedisset", &PageData::default(), &options)
+            .unwrap();
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.matches[0].metadata.lemmata, vec!["do"]);
+
+        let result = engine
+            .query_corpus("[Beta] rosa datur", &PageData::default(), &options)
+            .unwrap();
+        assert!(result.matches[0].metadata.lemmata.is_empty());
+    }
+
+    #[test]
+    fn form_atoms_require_analyzer() {
+        let engine = test_utils::build_test_engine("form_no_analyzer");
+        let options = QueryOptions {
+            page_size: 10,
+            context_len: 1,
+            ..Default::default()
+        };
+        let result = engine.query_corpus("@form:dat", &PageData::default(), &options);
+        assert!(result.is_err());
+    }
+
+    fn count_options() -> QueryOptions {
+        QueryOptions {
+            page_size: 1,
+            context_len: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn count_corpus_groups_by_author_work_and_section() {
+        let engine = test_utils::build_test_engine("count_groups");
+        let counts = engine
+            .count_corpus("@lemma:puella", &count_options())
+            .unwrap();
+        assert_eq!(counts.total, 8);
+        let summary = counts
+            .authors
+            .iter()
+            .map(|author| {
+                let works = author
+                    .works
+                    .iter()
+                    .map(|work| {
+                        let sections = work
+                            .sections
+                            .iter()
+                            .map(|(id, count)| (id.as_str(), *count))
+                            .collect::<Vec<_>>();
+                        (work.work_id.as_str(), work.count, sections)
+                    })
+                    .collect::<Vec<_>>();
+                (author.author.as_str(), author.count, works)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "Author Alpha",
+                    5,
+                    vec![
+                        ("w1", 3, vec![("1.1", 2), ("1.2", 1)]),
+                        ("w2", 2, vec![("1", 1), ("2", 1)]),
+                    ]
+                ),
+                (
+                    "Author Beta",
+                    3,
+                    vec![("w3", 3, vec![("1.1", 2), ("1.2", 1)])]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn count_corpus_matches_paged_results() {
+        let engine = test_utils::build_test_engine("count_matches_pages");
+        for query in TEST_CORPUS_QUERIES {
+            let counts = engine.count_corpus(query, &count_options()).unwrap();
+            let work_total: usize = counts.authors.iter().map(|author| author.count).sum();
+            assert_eq!(
+                counts.total,
+                test_utils::query_texts(&engine, query).len(),
+                "{query}"
+            );
+            assert_eq!(counts.total, work_total, "{query}");
+        }
+    }
+
+    #[test]
+    fn count_corpus_excludes_invalid_candidates() {
+        let engine = test_utils::build_test_engine("count_validation");
+        let counts = engine
+            .count_corpus("(@case:dat and @number:sg)", &count_options())
+            .unwrap();
+        assert_eq!(counts.total, 1);
+        assert_eq!(counts.authors.len(), 1);
+        assert_eq!(
+            counts.authors[0].works[0].sections,
+            vec![(&"1.2".to_string(), 1)]
+        );
+
+        let counts = engine.count_corpus("@lemma:foo", &count_options()).unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.authors.is_empty());
+    }
+
+    fn collocation_options(window: usize) -> CollocationOptions {
+        CollocationOptions {
+            window,
+            max_results: 10,
+            min_count: 1,
+        }
+    }
+
+    fn collocate_counts(collocates: &[Collocate]) -> Vec<(String, usize)> {
+        collocates
+            .iter()
+            .map(|c| (c.value.to_string(), c.count))
+            .collect()
+    }
+
+    #[test]
+    fn collocations_count_words_and_lemmata_at_each_position() {
+        let engine = test_utils::build_test_engine("collocations_positions");
+        let result = engine
+            .collocations("dat", &count_options(), &collocation_options(1))
+            .unwrap();
+        assert_eq!(result.matches, 4);
+        let offsets = result
+            .positions
+            .iter()
+            .map(|p| p.offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![-1, 1]);
+
+        let before = &result.positions[0];
+        assert_eq!(before.samples, 4);
+        assert_eq!(
+            collocate_counts(&before.words),
+            vec![("puellis".to_string(), 3), ("puella".to_string(), 1)]
+        );
+        assert_eq!(
+            collocate_counts(&before.lemmata),
+            vec![("puella".to_string(), 4)]
+        );
+
+        // The `dat` at the end of `w1` has nothing after it.
+        let after = &result.positions[1];
+        assert_eq!(after.samples, 3);
+        assert_eq!(
+            collocate_counts(&after.words),
+            vec![("puella".to_string(), 2), ("rosa".to_string(), 1)]
+        );
+        assert_eq!(
+            collocate_counts(&after.lemmata),
+            vec![("puella".to_string(), 2), ("rosa".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn collocations_report_corpus_counts_and_scores() {
+        let engine = test_utils::build_test_engine("collocations_scores");
+        let result = engine
+            .collocations("dat", &count_options(), &collocation_options(1))
+            .unwrap();
+        let puellis = &result.positions[0].words[0];
+        assert_eq!(puellis.corpus_count, 3);
+        // 3 of the 4 samples, when we'd expect 4 * 3 / 16 = 0.75.
+        assert!((puellis.mutual_information - 2.0).abs() < 1e-9);
+        assert!(puellis.t_score > 0.0);
+        assert!(puellis.log_likelihood > 0.0);
+    }
+
+    #[test]
+    fn collocations_are_relative_to_whole_match() {
+        let engine = test_utils::build_test_engine("collocations_whole_match");
+        let result = engine
+            .collocations("puella dat", &count_options(), &collocation_options(2))
+            .unwrap();
+        assert_eq!(result.matches, 1);
+        let summary = result
+            .positions
+            .iter()
+            .map(|p| (p.offset, p.samples, collocate_counts(&p.words)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (-2, 1, vec![("puellis".to_string(), 1)]),
+                (-1, 1, vec![("dat".to_string(), 1)]),
+                (1, 0, vec![]),
+                (2, 0, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn collocations_apply_limits() {
+        let engine = test_utils::build_test_engine("collocations_limits");
+        let options = CollocationOptions {
+            window: 1,
+            max_results: 1,
+            min_count: 2,
+        };
+        let result = engine
+            .collocations("dat", &count_options(), &options)
+            .unwrap();
+        assert_eq!(
+            collocate_counts(&result.positions[0].words),
+            vec![("puellis".to_string(), 3)]
+        );
+        assert_eq!(
+            collocate_counts(&result.positions[1].words),
+            vec![("puella".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn collocations_require_valid_window() {
+        let engine = test_utils::build_test_engine("collocations_window");
+        for window in [0, MAX_COLLOCATION_WINDOW + 1] {
+            let error = engine
+                .collocations("dat", &count_options(), &collocation_options(window))
+                .unwrap_err();
+            assert!(error.message.contains("window"), "{}", error.message);
+        }
+    }
+
+    /// Pages through every result of the query in the given order, returning
+    /// the work and matched text of each.
+    fn sorted_matches(
+        engine: &CorpusQueryEngine,
+        query: &str,
+        sort: ResultSort,
+    ) -> Vec<(String, String)> {
+        let options = QueryOptions {
+            page_size: 3,
+            context_len: 1,
+            sort,
+            ..Default::default()
+        };
+        let mut page = PageData::default();
+        let mut results = vec![];
+        loop {
+            let result = engine.query_corpus(query, &page, &options).unwrap();
+            for m in &result.matches {
+                let text = m
+                    .text
+                    .iter()
+                    .filter(|(_, is_match)| *is_match)
+                    .map(|(text, _)| text.as_str())
+                    .collect::<String>();
+                results.push((m.metadata.work_id.to_string(), text));
+            }
+            match result.next_page {
+                Some(next) => page = next,
+                None => {
+                    assert_eq!(result.result_stats.estimated_results, results.len());
+                    return results;
+                }
+            }
+        }
+    }
+
+    fn owned(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn results_sorted_by_right_context() {
+        let engine = test_utils::build_test_engine("sort_right");
+        let results = sorted_matches(&engine, "@lemma:puella", ResultSort::RightContext);
+        assert_eq!(
+            results,
+            owned(&[
+                ("w2", "puella"),
+                ("w3", "puellae"),
+                ("w1", "Puella"),
+                ("w2", "puellis"),
+                ("w1", "puellis"),
+                ("w3", "puellis"),
+                ("w3", "puella"),
+                ("w1", "Puella"),
+            ])
+        );
+    }
+
+    #[test]
+    fn results_sorted_by_left_context() {
+        let engine = test_utils::build_test_engine("sort_left");
+        let results = sorted_matches(&engine, "@lemma:puella", ResultSort::LeftContext);
+        assert_eq!(
+            results,
+            owned(&[
+                ("w1", "Puella"),
+                ("w3", "puella"),
+                ("w1", "Puella"),
+                ("w2", "puella"),
+                ("w3", "puellae"),
+                ("w3", "puellis"),
+                ("w2", "puellis"),
+                ("w1", "puellis"),
+            ])
+        );
+    }
+
+    #[test]
+    fn results_sorted_by_match_keep_ties_in_position_order() {
+        let engine = test_utils::build_test_engine("sort_match");
+        let results = sorted_matches(&engine, "@lemma:puella", ResultSort::Match);
+        assert_eq!(
+            results,
+            owned(&[
+                ("w1", "Puella"),
+                ("w1", "Puella"),
+                ("w2", "puella"),
+                ("w3", "puella"),
+                ("w3", "puellae"),
+                ("w1", "puellis"),
+                ("w2", "puellis"),
+                ("w3", "puellis"),
+            ])
+        );
+    }
+
+    #[test]
+    fn results_sorted_by_work_and_position_agree() {
+        let engine = test_utils::build_test_engine("sort_work");
+        // The test corpus is already ordered by author and work.
+        assert_eq!(
+            sorted_matches(&engine, "@lemma:puella", ResultSort::Work),
+            sorted_matches(&engine, "@lemma:puella", ResultSort::Position)
+        );
+    }
+
+    #[test]
+    fn random_sort_is_reproducible() {
+        let engine = test_utils::build_test_engine("sort_random");
+        let query = "@lemma:puella";
+        let shuffled = sorted_matches(&engine, query, ResultSort::Random(7));
+        assert_eq!(
+            shuffled,
+            sorted_matches(&engine, query, ResultSort::Random(7))
+        );
+
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        let mut expected = sorted_matches(&engine, query, ResultSort::Position);
+        expected.sort();
+        assert_eq!(sorted, expected);
+    }
+
+    /// Pages through a random sample of the matches of `@lemma:puella`, returning the
+    /// location of each match and the seed that the sample used.
+    fn sampled_locations(
+        engine: &CorpusQueryEngine,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> (Vec<String>, Option<u64>) {
+        let options = QueryOptions {
+            page_size: 2,
+            context_len: 1,
+            sample_size: Some(sample_size),
+            ..Default::default()
+        };
+        let mut page = PageData {
+            sample_seed: seed,
+            ..PageData::default()
+        };
+        let mut locations = vec![];
+        loop {
+            let result = engine
+                .query_corpus("@lemma:puella", &page, &options)
+                .unwrap();
+            assert_eq!(result.result_stats.estimated_results, 8);
+            for m in &result.matches {
+                let (section, offset, _) = m.metadata.leaders[0];
+                locations.push(format!("{} {section}:{offset}", m.metadata.work_id));
+            }
+            match result.next_page {
+                Some(next) => {
+                    assert_eq!(next.sample_seed, result.result_stats.sample_seed);
+                    page = next;
+                }
+                None => return (locations, result.result_stats.sample_seed),
+            }
+        }
+    }
+
+    #[test]
+    fn sample_is_reproducible_across_pages() {
+        let engine = test_utils::build_test_engine("sample_reproducible");
+        let (sample, seed) = sampled_locations(&engine, 3, Some(5));
+        assert_eq!(seed, Some(5));
+        assert_eq!(sample.len(), 3);
+        assert_eq!(sample, sampled_locations(&engine, 3, Some(5)).0);
+
+        let (all, _) = sampled_locations(&engine, 100, Some(5));
+        assert_eq!(all.len(), 8);
+        // Samples are in position order, like the full results.
+        let positions = sample
+            .iter()
+            .map(|location| all.iter().position(|l| l == location).unwrap())
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{positions:?}");
+    }
+
+    #[test]
+    fn sample_without_seed_reports_new_seed() {
+        let engine = test_utils::build_test_engine("sample_new_seed");
+        let (sample, seed) = sampled_locations(&engine, 3, None);
+        assert!(seed.is_some());
+        assert_eq!(sample, sampled_locations(&engine, 3, seed).0);
+    }
+
+    #[test]
+    fn sample_is_uniform_over_matches() {
+        let engine = test_utils::build_test_engine("sample_uniform");
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for seed in 0..800 {
+            let (sample, _) = sampled_locations(&engine, 2, Some(seed));
+            for location in sample {
+                *counts.entry(location).or_default() += 1;
+            }
+        }
+        // Each of the 8 matches should be chosen about 800 * 2 / 8 = 200 times.
+        assert_eq!(counts.len(), 8);
+        assert!(
+            counts.values().all(|&c| (150..250).contains(&c)),
+            "{counts:?}"
+        );
+    }
+
+    #[test]
+    fn nested_constraints_with_unknown_lemma() {
+        let engine = test_utils::build_test_engine("nested_unknown_lemma");
+        assert_eq!(
+            test_utils::query_texts(&engine, "((@lemma:foo and @case:dat) or @lemma:do)"),
+            test_utils::query_texts(&engine, "@lemma:do")
+        );
+    }
+
+    #[test]
+    fn negated_atom_excludes_possible_matches() {
+        let engine = test_utils::build_test_engine("negated_atom");
+        let results = test_utils::query_texts(&engine, "!@case:dat");
+        assert_eq!(results.len(), 12);
+        assert!(results.iter().all(|r| !r.starts_with("puelli")));
+        assert!(!results.contains(&"puellae".to_string()));
+    }
+
+    #[test]
+    fn negated_atom_in_conjunction() {
+        let engine = test_utils::build_test_engine("negated_conjunction");
+        assert_eq!(
+            test_utils::query_texts(&engine, "(@lemma:do and !@voice:passive)"),
+            vec!["dat", "dat", "dat", "dat"]
+        );
+        // `puella` could also be ablative, but `!@case:nom` excludes it because it
+        // has a nominative analysis.
+        assert_eq!(
+            test_utils::query_texts(&engine, "(@lemma:puella and !@case:nom)"),
+            vec!["puellis", "puellis", "puellis"]
+        );
+    }
+
+    #[test]
+    fn negated_composition_uses_de_morgan() {
+        let engine = test_utils::build_test_engine("negated_composition");
+        assert_eq!(
+            test_utils::query_texts(&engine, "!(@case:nom or @case:acc)"),
+            vec![
+                "puellis", "dat", "dat", "puellis", "dat", "puellis", "dat", "datur"
+            ]
+        );
+    }
+
+    #[test]
+    fn negated_unknown_lemma_excludes_nothing() {
+        let engine = test_utils::build_test_engine("negated_unknown_lemma");
+        assert_eq!(
+            test_utils::query_texts(&engine, "(@case:dat and !@lemma:foo)"),
+            test_utils::query_texts(&engine, "@case:dat")
+        );
+    }
+
+    #[test]
+    fn negated_atom_in_strict_mode() {
+        let engine = test_utils::build_test_engine("negated_strict");
+        assert_eq!(
+            test_utils::query_texts_with(&engine, "(@lemma:puella and !@case:nom)", true),
+            vec!["puellis", "puellis", "puellis"]
+        );
+    }
+
+    /// The ID, text, whether it's in the match, and lemmata of a token.
+    type AnalysedToken = (u32, String, bool, Vec<String>);
+
+    fn analysed_matches(
+        engine: &CorpusQueryEngine,
+        query: &str,
+        analyses: MatchAnalyses,
+    ) -> Vec<Vec<AnalysedToken>> {
+        let options = QueryOptions {
+            page_size: 25,
+            context_len: 1,
+            analyses,
+            ..Default::default()
+        };
+        let result = engine
+            .query_corpus(query, &PageData::default(), &options)
+            .unwrap();
+        result
+            .matches
+            .iter()
+            .map(|m| {
+                m.tokens
+                    .iter()
+                    .map(|t| {
+                        let lemmata = t.analyses.iter().map(|a| a.lemma.clone()).collect();
+                        (t.id, t.text.clone(), t.is_match, lemmata)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_includes_analyses_of_matched_tokens() {
+        let engine = test_utils::build_test_engine("match_analyses");
+        let matches = analysed_matches(&engine, "puellis dat", MatchAnalyses::Match);
+        assert_eq!(matches.len(), 3);
+        assert_eq!(
+            matches[0],
+            vec![
+                (2, "puellis".to_string(), true, vec!["puella".to_string()]),
+                (3, "dat".to_string(), true, vec!["do".to_string()]),
+            ]
+        );
+
+        let options = QueryOptions {
+            page_size: 1,
+            context_len: 1,
+            analyses: MatchAnalyses::Match,
+            ..Default::default()
+        };
+        let result = engine
+            .query_corpus("puellis dat", &PageData::default(), &options)
+            .unwrap();
+        let json = serde_json::to_value(&result.matches[0].tokens[0]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "id": 2,
+                "text": "puellis",
+                "isMatch": true,
+                "analyses": [{
+                    "lemma": "puella",
+                    "case": ["dat", "abl"],
+                    "number": ["pl"],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn query_includes_analyses_of_context_within_work() {
+        let engine = test_utils::build_test_engine("context_analyses");
+        let ids = |analyses| {
+            analysed_matches(&engine, "puellis dat", analyses)
+                .iter()
+                .map(|tokens| {
+                    tokens
+                        .iter()
+                        .map(|(id, _, is_match, _)| (*id, *is_match))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(MatchAnalyses::MatchAndContext),
+            vec![
+                vec![(1, false), (2, true), (3, true), (4, false)],
+                vec![(6, false), (7, true), (8, true), (9, false)],
+                vec![(10, false), (11, true), (12, true), (13, false)],
+            ]
+        );
+        assert!(ids(MatchAnalyses::None).iter().all(Vec::is_empty));
+    }
+
+    fn context_texts(
+        engine: &CorpusQueryEngine,
+        query: &str,
+        context_len: usize,
+        context_mode: ContextMode,
+    ) -> Vec<String> {
+        let options = QueryOptions {
+            page_size: 25,
+            context_len,
+            context_mode,
+            ..Default::default()
+        };
+        let result = engine
+            .query_corpus(query, &PageData::default(), &options)
+            .unwrap();
+        result
+            .matches
+            .iter()
+            .map(|m| {
+                m.text
+                    .iter()
+                    .map(|(text, is_core)| match is_core {
+                        true => format!("[{text}]"),
+                        false => text.clone(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sentence_context_stops_at_breaks() {
+        let engine = test_utils::build_test_engine("sentence_context");
+        assert_eq!(
+            context_texts(&engine, "dat", 5, ContextMode::Sentence),
+            vec![
+                "Puella rosas puellis [dat]",
+                "Puella [dat]",
+                "Rosas puellis\n[dat] puella",
+                "puella, puellis [dat]\nrosa datur puellae",
+            ]
+        );
+    }
+
+    #[test]
+    fn section_context_stops_at_section_boundaries() {
+        let engine = test_utils::build_test_engine("section_context");
+        assert_eq!(
+            context_texts(&engine, "dat", 5, ContextMode::Section),
+            vec![
+                "Puella rosas puellis [dat]",
+                "Puella [dat]",
+                "[dat] puella",
+                "puella, puellis [dat]",
+            ]
+        );
+    }
+
+    #[test]
+    fn bounded_context_is_capped_by_context_len() {
+        let engine = test_utils::build_test_engine("capped_context");
+        let expected = vec![
+            "rosas [puellis] dat",
+            "Rosas [puellis]",
+            "puella, [puellis] dat",
+        ];
+        assert_eq!(
+            context_texts(&engine, "puellis", 1, ContextMode::Section),
+            expected
+        );
+        assert_eq!(
+            context_texts(&engine, "puellis", 1, ContextMode::Sentence)[0],
+            expected[0]
+        );
+    }
+
+    /// Returns the (section, offset) of the leaders of each match of the query.
+    fn leader_locations(engine: &CorpusQueryEngine, query: &str) -> Vec<Vec<(String, u32)>> {
+        let result = engine
+            .query_corpus(
+                query,
+                &PageData::default(),
+                &QueryOptions {
+                    page_size: 100,
+                    ..count_options()
+                },
+            )
+            .unwrap();
+        result
+            .matches
+            .iter()
+            .map(|m| {
+                m.metadata
+                    .leaders
+                    .iter()
+                    .map(|(section, offset, _)| (section.to_string(), *offset))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sentence_proximity_does_not_cross_breaks() {
+        let engine = test_utils::build_test_engine("sentence_proximity");
+        let owned = |leaders: &[(&str, u32)]| {
+            leaders
+                .iter()
+                .map(|(s, o)| (s.to_string(), *o))
+                .collect::<Vec<_>>()
+        };
+        // In `w1`, the second `Puella` is closest to the `dat` before the period.
+        assert_eq!(
+            leader_locations(&engine, "puella 5~ dat")[1],
+            owned(&[("1.1", 3), ("1.2", 0)])
+        );
+        let within_sentence = leader_locations(&engine, "puella 5~. dat");
+        assert_eq!(within_sentence.len(), 4);
+        assert_eq!(within_sentence[1], owned(&[("1.2", 0), ("1.2", 1)]));
+        assert_eq!(leader_locations(&engine, "puella ~. dat"), within_sentence);
+    }
+
+    #[test]
+    fn proximity_allows_distances_over_fifteen() {
+        let engine = test_utils::build_test_engine("long_proximity");
+        assert_eq!(leader_locations(&engine, "rosas 20~> puella").len(), 2);
+        assert_eq!(
+            leader_locations(&engine, "rosas 20~>. puella"),
+            vec![vec![("1".to_string(), 0), ("2".to_string(), 1)]]
+        );
+    }
+
+    fn locations(matches: &[&[(&str, u32)]]) -> Vec<Vec<(String, u32)>> {
+        matches
+            .iter()
+            .map(|leaders| leaders.iter().map(|(s, o)| (s.to_string(), *o)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn gaps_bound_the_tokens_in_between() {
+        let engine = test_utils::build_test_engine("gap_relations");
+        assert_eq!(
+            leader_locations(&engine, "puella {0,2} dat"),
+            locations(&[
+                &[("1.1", 0), ("1.1", 3)],
+                &[("1.2", 0), ("1.2", 1)],
+                &[("1.1", 0), ("1.1", 2)],
+            ])
+        );
+        assert_eq!(
+            leader_locations(&engine, "puella {1,2} dat"),
+            locations(&[&[("1.1", 0), ("1.1", 3)], &[("1.1", 0), ("1.1", 2)]])
+        );
+        assert_eq!(
+            leader_locations(&engine, "puella {1} dat"),
+            locations(&[&[("1.1", 0), ("1.1", 2)]])
+        );
+    }
+
+    #[test]
+    fn optional_terms_are_included_where_possible() {
+        let engine = test_utils::build_test_engine("optional_terms");
+        assert_eq!(
+            leader_locations(&engine, "puella (puellis)? dat"),
+            locations(&[
+                &[("1.2", 0), ("1.2", 1)],
+                &[("1.1", 0), ("1.1", 1), ("1.1", 2)],
+            ])
+        );
+    }
+
+    #[test]
+    fn optional_terms_keep_adjacent_terms_in_one_sentence() {
+        let engine = test_utils::build_test_engine("optional_breaks");
+        // Like `dat puella`, this can't match `dat. Puella` across the sentence break.
+        assert_eq!(leader_locations(&engine, "dat puella").len(), 1);
+        assert_eq!(
+            leader_locations(&engine, "dat (rosas)? puella"),
+            locations(&[&[("2", 0), ("2", 1)]])
+        );
+    }
+
+    #[test]
+    fn left_out_optional_terms_keep_their_gaps() {
+        let engine = test_utils::build_test_engine("optional_gaps");
+        assert_eq!(
+            leader_locations(&engine, "rosas {0,1} (puella)? dat"),
+            locations(&[&[("1.1", 1), ("1.1", 3)], &[("1", 0), ("2", 0)]])
+        );
+    }
+
+    #[test]
+    fn agreeing_terms_share_inflections() {
+        let engine = test_utils::build_test_engine("agreement");
+        let count = |query| leader_locations(&engine, query).len();
+        assert_eq!(count("@lemma:rosa=$1:number @lemma:puella=$1:number"), 2);
+        assert_eq!(count("@lemma:rosa=$1:case @lemma:puella=$1:case"), 0);
+        assert_eq!(
+            leader_locations(&engine, "@lemma:rosa=$1:case {0,1} @lemma:puella=$1:case"),
+            locations(&[&[("1.2", 0), ("1.2", 2)]])
+        );
+    }
+
+    #[test]
+    fn agreeing_terms_need_one_analysis_for_every_category() {
+        let engine = test_utils::build_test_engine("agreement_analyses");
+        // `rosa` (nom. or abl. singular) and `puellae` (gen. or dat. singular, or nom.
+        // plural) share a case and a number, but not in any one pair of analyses.
+        let query = "@lemma:rosa=$x:case,number {0,1} @lemma:puella=$x:case,number";
+        assert!(leader_locations(&engine, query).is_empty());
+        let query = "@lemma:rosa=$x:number {0,1} @lemma:puella=$x:number";
+        assert_eq!(leader_locations(&engine, query).len(), 3);
+    }
+
+    #[test]
+    fn contradictory_queries_are_errors() {
+        let engine = test_utils::build_test_engine("contradiction");
+        let error = engine
+            .query_corpus(
+                "puella (@mood:subj and @tense:fut)",
+                &PageData::default(),
+                &count_options(),
+            )
+            .unwrap_err();
+        let contradiction = error.contradiction.unwrap();
+        assert_eq!(contradiction.term, 1);
+        assert_eq!(
+            contradiction.atoms,
+            vec!["@mood:subjunctive", "@tense:future"]
+        );
+        assert!(
+            error.message.starts_with("Term 2 can never match"),
+            "{}",
+            error.message
+        );
+        let counts = engine.count_corpus("(@case:dat and @tense:present)", &count_options());
+        assert!(counts.unwrap_err().contradiction.is_some());
+    }
+
+    fn query_captures(engine: &CorpusQueryEngine, query: &str) -> Vec<Vec<(String, String, u32)>> {
+        let options = QueryOptions {
+            page_size: 100,
+            ..count_options()
+        };
+        let result = engine
+            .query_corpus(query, &PageData::default(), &options)
+            .unwrap();
+        result
+            .matches
+            .iter()
+            .map(|m| {
+                m.captures
+                    .iter()
+                    .map(|c| (c.label.clone(), c.text.clone(), c.offset))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn labeled_terms_are_captured() {
+        let engine = test_utils::build_test_engine("captures");
+        let captures = query_captures(&engine, "noun:@case:dat {0,1} verb:@lemma:do");
+        assert_eq!(captures.len(), 3);
+        let capture =
+            |label: &str, text: &str, offset| (label.to_string(), text.to_string(), offset);
+        assert_eq!(
+            captures[0],
+            vec![capture("noun", "puellis", 2), capture("verb", "dat", 3)]
+        );
+        // Only labeled terms are captured.
+        let captures = query_captures(&engine, "rosa vb:@lemma:do");
+        assert_eq!(captures, vec![vec![capture("vb", "datur", 1)]]);
+        assert!(
+            query_captures(&engine, "puellis dat")
+                .iter()
+                .all(Vec::is_empty)
+        );
+    }
+
+    #[test]
+    fn left_out_optional_terms_are_not_captured() {
+        let engine = test_utils::build_test_engine("optional_captures");
+        let captures = query_captures(&engine, "x:rosas y:(puella)? z:dat");
+        assert!(captures.is_empty());
+        let captures = query_captures(&engine, "x:puella y:(puellis)? z:dat");
+        let labels = captures
+            .iter()
+            .map(|c| {
+                c.iter()
+                    .map(|(label, ..)| label.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![vec!["x", "z"], vec!["x", "y", "z"]]);
+    }
 }
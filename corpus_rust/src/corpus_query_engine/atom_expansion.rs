@@ -0,0 +1,214 @@
+use regex::RegexBuilder;
+
+use crate::{
+    build_corpus_v2::clean_lemma,
+    corpus_query_engine::{CorpusQueryEngine, QueryExecError},
+    query_parsing_v2::{
+        Query, QueryTerm, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation,
+    },
+};
+
+/// The IDs and names of lemmata, sorted by ID.
+pub(super) type FormLemmata<'a> = Vec<(u32, &'a String)>;
+
+/// The maximum number of words (or lemmata) that a single pattern can expand to.
+const MAX_PATTERN_EXPANSION: usize = 500;
+
+/// Returns the values that match the pattern, in sorted order. If more than `limit`
+/// values match, returns an error with the number of matches (of the given `noun`).
+fn values_matching<'a>(
+    values: impl Iterator<Item = &'a String>,
+    noun: &str,
+    pattern: &str,
+    case_insensitive: bool,
+    limit: usize,
+) -> Result<Vec<&'a str>, QueryExecError> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|e| QueryExecError::new(&format!("Invalid pattern '{pattern}': {e}")))?;
+    let mut matches = values
+        .filter(|value| regex.is_match(value))
+        .map(|value| value.as_str())
+        .collect::<Vec<_>>();
+    if matches.len() > limit {
+        return Err(QueryExecError::new(&format!(
+            "Pattern '/{pattern}/' matches {} {noun}, but at most {limit} are allowed",
+            matches.len()
+        )));
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+impl CorpusQueryEngine {
+    /// Returns the values of the given key that match the pattern, in sorted order.
+    fn keys_matching(
+        &self,
+        key: &str,
+        noun: &str,
+        pattern: &str,
+        case_insensitive: bool,
+    ) -> Result<Vec<&str>, QueryExecError> {
+        let Some(ids) = self.corpus.id_table.get(key) else {
+            return Ok(vec![]);
+        };
+        values_matching(
+            ids.keys(),
+            noun,
+            pattern,
+            case_insensitive,
+            MAX_PATTERN_EXPANSION,
+        )
+    }
+
+    /// Returns the IDs and names of the lemmata that the given form could be an inflection of.
+    fn lemmata_for_form(&self, form: &str) -> Result<FormLemmata<'_>, QueryExecError> {
+        let analyzer = self.analyzer.as_ref().ok_or(QueryExecError::new(
+            "Searching by form requires a morphological analyzer",
+        ))?;
+        let Some(ids) = self.corpus.id_table.get("lemma") else {
+            return Ok(vec![]);
+        };
+        let mut lemmata = analyzer(form)
+            .iter()
+            .filter_map(|result| ids.get_key_value(&clean_lemma(&result.lemma)))
+            .map(|(lemma, id)| (*id, lemma))
+            .collect::<Vec<_>>();
+        lemmata.sort();
+        lemmata.dedup();
+        Ok(lemmata)
+    }
+
+    fn expand_atom<'a>(
+        &'a self,
+        atom: TokenConstraintAtom,
+        form_lemmata: &mut FormLemmata<'a>,
+    ) -> Result<TokenConstraint, QueryExecError> {
+        let children = match &atom {
+            TokenConstraintAtom::WordPattern(pattern) => self
+                .keys_matching("word", "words", pattern, true)?
+                .into_iter()
+                .map(|word| TokenConstraintAtom::Word(word.to_string()))
+                .collect::<Vec<_>>(),
+            TokenConstraintAtom::LemmaPattern(pattern) => self
+                .keys_matching("lemma", "lemmata", pattern, false)?
+                .into_iter()
+                .map(|lemma| TokenConstraintAtom::Lemma(lemma.to_string()))
+                .collect(),
+            TokenConstraintAtom::Form(form) => {
+                let lemmata = self.lemmata_for_form(form)?;
+                let atoms = lemmata
+                    .iter()
+                    .map(|(_, lemma)| TokenConstraintAtom::Lemma(lemma.to_string()))
+                    .collect();
+                form_lemmata.extend(lemmata);
+                atoms
+            }
+            _ => return Ok(TokenConstraint::Atom(atom)),
+        };
+        let mut children = children
+            .into_iter()
+            .map(TokenConstraint::Atom)
+            .collect::<Vec<_>>();
+        Ok(match children.len() {
+            // An atom without any matches has no postings, just like an unknown word.
+            0 => TokenConstraint::Atom(atom),
+            1 => children.remove(0),
+            _ => TokenConstraint::Composed {
+                op: TokenConstraintOperation::Or,
+                children,
+            },
+        })
+    }
+
+    fn expand_constraint<'a>(
+        &'a self,
+        constraint: TokenConstraint,
+        form_lemmata: &mut FormLemmata<'a>,
+    ) -> Result<TokenConstraint, QueryExecError> {
+        match constraint {
+            TokenConstraint::Atom(atom) => self.expand_atom(atom, form_lemmata),
+            TokenConstraint::Composed { op, children } => Ok(TokenConstraint::Composed {
+                op,
+                children: children
+                    .into_iter()
+                    .map(|child| self.expand_constraint(child, form_lemmata))
+                    .collect::<Result<_, _>>()?,
+            }),
+            TokenConstraint::Negated(inner) => Ok(TokenConstraint::Negated(Box::new(
+                self.expand_constraint(*inner, form_lemmata)?,
+            ))),
+        }
+    }
+
+    /// Replaces any wildcard, regular expression, or form atoms in the query with
+    /// a disjunction of the words (or lemmata) in the corpus that they match.
+    ///
+    /// Also returns the lemmata that any form atoms were expanded to, sorted by ID.
+    pub(super) fn expand_atoms(
+        &self,
+        query: Query,
+    ) -> Result<(Query, FormLemmata<'_>), QueryExecError> {
+        let mut form_lemmata = vec![];
+        let terms = query
+            .terms
+            .into_iter()
+            .map(|term| {
+                Ok(QueryTerm {
+                    constraint: self.expand_constraint(term.constraint, &mut form_lemmata)?,
+                    relation: term.relation,
+                    optional: term.optional,
+                    agreement: term.agreement,
+                    label: term.label,
+                })
+            })
+            .collect::<Result<_, QueryExecError>>()?;
+        form_lemmata.sort();
+        form_lemmata.dedup();
+        let query = Query {
+            terms,
+            scope: query.scope,
+        };
+        Ok((query, form_lemmata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn values_matching_returns_sorted_matches() {
+        let all = values(&["puellis", "dat", "puella", "rosa"]);
+        assert_eq!(
+            values_matching(all.iter(), "words", "^puell", false, 10).unwrap(),
+            vec!["puella", "puellis"]
+        );
+        assert_eq!(
+            values_matching(all.iter(), "words", "^PUELLA$", true, 10).unwrap(),
+            vec!["puella"]
+        );
+        assert!(
+            values_matching(all.iter(), "words", "^PUELLA$", false, 10)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn values_matching_reports_count_over_limit() {
+        let all = values(&["puellis", "puellae", "puella"]);
+        let error = values_matching(all.iter(), "words", "^puell", false, 2).unwrap_err();
+        assert!(
+            error.message.contains("matches 3 words"),
+            "{}",
+            error.message
+        );
+        assert!(error.message.contains("at most 2"), "{}", error.message);
+    }
+}
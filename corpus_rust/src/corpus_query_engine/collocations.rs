@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use crate::{
+    api::{Collocate, CollocationOptions, CollocationResult, PositionCollocates},
+    corpus_query_engine::{
+        CorpusQueryEngine, QueryExecError,
+        query_scope::{TokenRange, work_range},
+    },
+};
+
+/// The maximum number of tokens on each side of a match that can be looked at.
+pub(super) const MAX_COLLOCATION_WINDOW: usize = 25;
+
+/// Association scores for a collocate that occurs `observed` times in `samples`
+/// positions, when it occurs `collocate_freq` times in a corpus of `corpus_size` tokens.
+///
+/// Returns the (pointwise) mutual information, the t-score, and the log-likelihood (G²).
+fn association_scores(
+    observed: usize,
+    samples: usize,
+    collocate_freq: usize,
+    corpus_size: usize,
+) -> (f64, f64, f64) {
+    let o = observed as f64;
+    let n = corpus_size as f64;
+    let expected = (samples as f64) * (collocate_freq as f64) / n;
+    let mutual_information = (o / expected).log2();
+    let t_score = (o - expected) / o.sqrt();
+
+    // The 2x2 contingency table of (at the position, elsewhere) x (collocate, other).
+    let row_sums = [samples as f64, n - samples as f64];
+    let col_sums = [collocate_freq as f64, n - collocate_freq as f64];
+    let table = [
+        [o, samples as f64 - o],
+        [
+            collocate_freq as f64 - o,
+            (n - samples as f64 - collocate_freq as f64 + o).max(0.0),
+        ],
+    ];
+    let mut log_likelihood = 0.0;
+    for (i, row) in table.iter().enumerate() {
+        for (j, cell) in row.iter().enumerate() {
+            let expected = row_sums[i] * col_sums[j] / n;
+            if *cell > 0.0 && expected > 0.0 {
+                log_likelihood += cell * (cell / expected).ln();
+            }
+        }
+    }
+    (mutual_information, t_score, 2.0 * log_likelihood)
+}
+
+/// The number of times each value (by ID) occurs at a single position.
+#[derive(Default)]
+struct PositionCounts {
+    samples: usize,
+    words: HashMap<u32, usize>,
+    lemmata: HashMap<u32, usize>,
+}
+
+impl CorpusQueryEngine {
+    /// Returns the ID of the (normalized) word for the given token, if it's known.
+    fn word_id_of(&self, token: u32) -> Result<Option<u32>, QueryExecError> {
+        let word = self.normalized_word(token)?;
+        Ok(self
+            .corpus
+            .id_table
+            .get("word")
+            .and_then(|ids| ids.get(&word))
+            .copied())
+    }
+
+    fn count_token(&self, token: u32, counts: &mut PositionCounts) -> Result<(), QueryExecError> {
+        counts.samples += 1;
+        if let Some(word) = self.word_id_of(token)? {
+            *counts.words.entry(word).or_default() += 1;
+        }
+        let mut lemmata = self
+            .inflections
+            .get_inflection_data(token)?
+            .iter()
+            .map(|data| (data >> 32) as u32)
+            .collect::<Vec<_>>();
+        // Each lemma should only be counted once, even if the token has several analyses for it.
+        lemmata.sort();
+        lemmata.dedup();
+        for lemma in lemmata {
+            *counts.lemmata.entry(lemma).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the values of the given key that were counted at any position.
+    fn names_of<'a>(
+        &'a self,
+        key: &str,
+        all_counts: &[&HashMap<u32, usize>],
+    ) -> HashMap<u32, &'a String> {
+        let Some(ids) = self.corpus.id_table.get(key) else {
+            return HashMap::new();
+        };
+        ids.iter()
+            .filter(|(_, id)| all_counts.iter().any(|counts| counts.contains_key(id)))
+            .map(|(name, id)| (*id, name))
+            .collect()
+    }
+
+    /// Returns the most frequent values of the given key from the counts, with
+    /// their association scores.
+    fn top_collocates<'a>(
+        &self,
+        key: &str,
+        names: &HashMap<u32, &'a String>,
+        counts: &HashMap<u32, usize>,
+        samples: usize,
+        options: &CollocationOptions,
+    ) -> Vec<Collocate<'a>> {
+        let mut collocates = counts
+            .iter()
+            .filter(|(_, count)| **count >= options.min_count.max(1))
+            .filter_map(|(id, count)| {
+                let value = names.get(id)?;
+                let metadata = self.corpus.indices.get(key)?.get(*id as usize)?;
+                let corpus_count = self.raw_buffers.num_elements(metadata) as usize;
+                let (mutual_information, t_score, log_likelihood) = association_scores(
+                    *count,
+                    samples,
+                    corpus_count,
+                    self.corpus.num_tokens as usize,
+                );
+                Some(Collocate {
+                    value,
+                    count: *count,
+                    corpus_count,
+                    mutual_information,
+                    t_score,
+                    log_likelihood,
+                })
+            })
+            .collect::<Vec<_>>();
+        collocates.sort_by(|a, b| b.count.cmp(&a.count).then(a.value.cmp(b.value)));
+        collocates.truncate(options.max_results);
+        collocates
+    }
+
+    /// Finds the collocates at each position around the given matches. Positions
+    /// outside of the work containing a match are not counted.
+    pub(super) fn collocates_of(
+        &self,
+        matches: &[TokenRange],
+        options: &CollocationOptions,
+    ) -> Result<CollocationResult<'_>, QueryExecError> {
+        let window = options.window;
+        // The first `window` entries are before the match, the rest are after it.
+        let mut positions = (0..(2 * window))
+            .map(|_| PositionCounts::default())
+            .collect::<Vec<_>>();
+        for &(start, end) in matches {
+            let (work_idx, _) = self.location_of(start)?;
+            let (work_start, work_end) = work_range(&self.corpus.work_lookup[work_idx])?;
+            for i in 1..=window {
+                if let Some(token) = start.checked_sub(i as u32)
+                    && token >= work_start
+                {
+                    self.count_token(token, &mut positions[window - i])?;
+                }
+                let token = end - 1 + i as u32;
+                if token < work_end {
+                    self.count_token(token, &mut positions[window + i - 1])?;
+                }
+            }
+        }
+
+        let words = positions.iter().map(|p| &p.words).collect::<Vec<_>>();
+        let word_names = self.names_of("word", &words);
+        let lemmata = positions.iter().map(|p| &p.lemmata).collect::<Vec<_>>();
+        let lemma_names = self.names_of("lemma", &lemmata);
+        let offsets = (-(window as i32)..0).chain(1..=(window as i32));
+        let positions = offsets
+            .zip(&positions)
+            .map(|(offset, counts)| PositionCollocates {
+                offset,
+                samples: counts.samples,
+                words: self.top_collocates(
+                    "word",
+                    &word_names,
+                    &counts.words,
+                    counts.samples,
+                    options,
+                ),
+                lemmata: self.top_collocates(
+                    "lemma",
+                    &lemma_names,
+                    &counts.lemmata,
+                    counts.samples,
+                    options,
+                ),
+            })
+            .collect();
+        Ok(CollocationResult {
+            matches: matches.len(),
+            positions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "Expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn association_scores_for_attracted_collocate() {
+        let (mi, t, ll) = association_scores(3, 4, 3, 16);
+        assert_close(mi, 2.0);
+        assert_close(t, 2.25 / 3f64.sqrt());
+        let expected_ll =
+            2.0 * (3.0 * 4f64.ln() + (1.0 / 3.25f64).ln() + 12.0 * (12.0 / 9.75f64).ln());
+        assert_close(ll, expected_ll);
+    }
+
+    #[test]
+    fn association_scores_without_association() {
+        let (mi, t, ll) = association_scores(2, 10, 20, 100);
+        assert_close(mi, 0.0);
+        assert_close(t, 0.0);
+        assert_close(ll, 0.0);
+    }
+}
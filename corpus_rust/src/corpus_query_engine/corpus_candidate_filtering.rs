@@ -35,6 +35,11 @@ impl<'a> MatchIterator<'a> {
             candidate_position: all_candidates.position,
         }
     }
+
+    /// The total number of candidates, including any before the current page.
+    pub(super) fn num_candidates(&self) -> usize {
+        self.candidates.num_elements()
+    }
 }
 
 impl<'a> Iterator for MatchIterator<'a> {
@@ -1,12 +1,15 @@
 use crate::{
+    api::{IndexStep, QueryPlan, SpanPlan},
     bitmask_utils::Direction,
     corpus_index::StoredMapValue,
     corpus_query_engine::{
         CorpusQueryEngine, IndexData, IndexDataRoO, QueryExecError,
         corpus_query_conversion::InternalQueryTerm,
         index_data::{
-            IndexRange, IndexSlice, apply_and_to_indices, apply_or_to_indices, find_fuzzy_matches,
+            IndexDataOwned, IndexRange, IndexSlice, apply_and_to_indices, apply_not_to_index,
+            apply_or_to_indices, find_fuzzy_matches, find_fuzzy_matches_within_breaks,
         },
+        query_explanation::record_step,
     },
     profiler::TimeProfiler,
     query_parsing_v2::{
@@ -18,18 +21,44 @@ pub(super) struct SpanResult<'a> {
     pub(super) candidates: IndexSlice<'a>,
     pub(super) length: usize,
     pub(super) relation: QueryRelation,
+    /// Whether the span can be left out of a match.
+    pub(super) optional: bool,
+    /// The labeled terms in the span.
+    pub(super) labels: Vec<SpanLabel>,
+}
+
+/// A labeled term within a span.
+pub(super) struct SpanLabel {
+    /// The offset of the term from the leader of the span.
+    pub(super) offset: u32,
+    /// The index of the term in the query.
+    pub(super) term: usize,
+    pub(super) label: String,
+}
+
+impl SpanResult<'_> {
+    /// The least and most tokens between the end of the previous span and the start of
+    /// this one, or `None` if the position isn't fixed by order (like for proximity).
+    pub(super) fn gap_bounds(&self) -> Option<(u32, u32)> {
+        match self.relation {
+            QueryRelation::After => Some((0, 0)),
+            QueryRelation::Gap { min, max } => Some((min as u32, max as u32)),
+            _ => None,
+        }
+    }
 }
 
 /// Splits a query into spans of contiguous terms. A span is a sequence of terms
 /// that need to be directly to the right of the previous term in the sequence (in
-/// particular, proximity relations break spans).
+/// particular, proximity and gap relations break spans). Optional terms are always
+/// in a span by themselves.
 pub(super) fn split_into_spans<'a>(
     query: &'a [InternalQueryTerm<'a>],
 ) -> Result<Vec<&'a [InternalQueryTerm<'a>]>, QueryExecError> {
     let mut spans = Vec::new();
     let mut span_start = 0;
     for i in 0..=query.len() {
-        if i < query.len() && query[i].is_contiguous() {
+        if i < query.len() && query[i].is_contiguous() && (i == 0 || !query[i - 1].optional) {
             continue;
         }
         let span = &query[span_start..i];
@@ -42,38 +71,54 @@ pub(super) fn split_into_spans<'a>(
     Ok(spans)
 }
 
-fn combine_span_candidates<'a>(
-    previous: &SpanResult<'a>,
-    current: &'a SpanResult<'a>,
-) -> Result<SpanResult<'a>, QueryExecError> {
-    let (distance, is_directed) = match previous.relation {
-        QueryRelation::Proximity {
-            distance,
-            is_directed,
-        } => (distance, is_directed),
-        _ => {
+/// Returns the most tokens between the leaders of the spans at `earlier` and `later`,
+/// and the direction of the later span from the earlier one. Any spans in between
+/// must be optional, and are counted as if they were present.
+fn leader_distance(
+    spans: &[SpanResult],
+    earlier: usize,
+    later: usize,
+) -> Result<(usize, Direction), QueryExecError> {
+    if let QueryRelation::Proximity {
+        distance,
+        is_directed,
+        ..
+    } = spans[later].relation
+    {
+        if later != earlier + 1 {
             return Err(QueryExecError::new(
-                "Only proximity relations are supported between spans",
+                "Optional terms can't be next to proximity relations",
             ));
         }
-    };
-    if distance == 0 || distance >= 16 {
-        return Err(QueryExecError::new(
-            "Proximity distance must be between 1 and 15",
-        ));
+        if distance == 0 {
+            return Err(QueryExecError::new("Proximity distance must be at least 1"));
+        }
+        let dir = if is_directed {
+            Direction::Left
+        } else {
+            Direction::Both
+        };
+        return Ok((distance as usize, dir));
     }
-    let dir = if is_directed {
-        Direction::Left
-    } else {
-        Direction::Both
-    };
-    let second = &previous.candidates;
-    let combined = find_fuzzy_matches(&current.candidates, second, distance as usize, dir)?;
-    Ok(SpanResult {
-        candidates: combined,
-        length: previous.length + current.length,
-        relation: current.relation.clone(),
-    })
+    let mut distance = 0;
+    for i in earlier..later {
+        let (_, max_gap) = spans[i + 1].gap_bounds().ok_or(QueryExecError::new(
+            "Only proximity, gap, and optional relations are supported between spans",
+        ))?;
+        distance += spans[i].length + max_gap as usize;
+    }
+    Ok((distance, Direction::Left))
+}
+
+/// Whether the span needs to be in the same sentence as the previous span.
+fn is_within_sentence(span: &SpanResult) -> bool {
+    matches!(
+        span.relation,
+        QueryRelation::Proximity {
+            within_sentence: true,
+            ..
+        }
+    )
 }
 
 // Basic methods for calculating indices corresponding to query terms.
@@ -97,25 +142,29 @@ impl CorpusQueryEngine {
             internal_children.reverse();
         }
 
-        let first = internal_children
-            .first()
-            .ok_or(QueryExecError::new("Empty composed query"))?;
-        let mut data = match self.compute_index_for(first.inner, range)? {
-            Some(data) => data,
-            None => return Ok(None),
-        };
-
-        for child in internal_children.iter().skip(1) {
+        if internal_children.is_empty() {
+            return Err(QueryExecError::new("Empty composed query"));
+        }
+        let mut data: Option<IndexSlice<'a>> = None;
+        for child in internal_children.iter() {
             let child_data = match self.compute_index_for(child.inner, range)? {
                 Some(data) => data,
-                None => return Ok(None),
+                // If any part of an `and` has no matches, then nothing matches.
+                None if *op == TokenConstraintOperation::And => return Ok(None),
+                // If part of an `or` has no matches, the other parts still might.
+                None => continue,
             };
-            data = match op {
-                TokenConstraintOperation::And => apply_and_to_indices(&data, &child_data),
-                TokenConstraintOperation::Or => apply_or_to_indices(&data, &child_data),
-            }?;
+            data = Some(match (data, op) {
+                (None, _) => child_data,
+                (Some(data), TokenConstraintOperation::And) => {
+                    apply_and_to_indices(&data, &child_data)?
+                }
+                (Some(data), TokenConstraintOperation::Or) => {
+                    apply_or_to_indices(&data, &child_data)?
+                }
+            });
         }
-        Ok(Some(data))
+        Ok(data)
     }
 
     /// Computes the candidate index for a particular token constraint.
@@ -129,48 +178,120 @@ impl CorpusQueryEngine {
             TokenConstraint::Composed { children, op } => {
                 self.compute_index_for_composed(children, op, range)
             }
-            TokenConstraint::Negated(_) => {
-                Err(QueryExecError::new("Negated constraints are not supported"))
+            TokenConstraint::Negated(inner) => {
+                let complement = match self.compute_index_for(inner, range)? {
+                    Some(data) => apply_not_to_index(&data, self.corpus.num_tokens)?,
+                    // If there's no index, then every token matches the negation.
+                    None => {
+                        let empty = IndexSlice {
+                            data: IndexDataRoO::Owned(IndexDataOwned::List(vec![])),
+                            range,
+                            position: 0,
+                        };
+                        apply_not_to_index(&empty, self.corpus.num_tokens)?
+                    }
+                };
+                Ok(Some(complement))
             }
         }
     }
 
+    /// Combines the candidates of the spans, recording each combination in `steps` (if any).
     pub(super) fn compute_query_candidates<'a>(
         &'a self,
         spans: &'a [SpanResult<'a>],
+        mut steps: Option<&mut Vec<IndexStep>>,
     ) -> Result<IndexSlice<'a>, QueryExecError> {
         if spans.is_empty() {
             return Err(QueryExecError::new("No spans found in query"));
         }
-        let n = spans.len();
-        if n == 1 {
-            return Ok(spans[0].candidates.to_ref());
-        }
-        let mut previous = combine_span_candidates(&spans[n - 1], &spans[n - 2])?;
-        for current in spans.iter().rev().skip(2) {
-            previous = combine_span_candidates(&previous, current)?;
+        // Optional spans might not be in the match, so only the required spans
+        // can rule out candidates.
+        let required = (0..spans.len())
+            .filter(|&i| !spans[i].optional)
+            .collect::<Vec<_>>();
+        let (&last, rest) = required
+            .split_last()
+            .ok_or(QueryExecError::new("No required spans found in query"))?;
+        let mut later = (last, spans[last].candidates.to_ref());
+        record_step(steps.as_deref_mut(), || format!("Span {last}"), &later.1);
+        for &earlier in rest.iter().rev() {
+            let (distance, dir) = leader_distance(spans, earlier, later.0)?;
+            let candidates = &spans[earlier].candidates;
+            // The smear for same-sentence relations stops at hard breaks, so that
+            // candidates in other sentences are never combined in the first place.
+            let combined = if is_within_sentence(&spans[later.0]) {
+                let hard_breaks = self.get_hard_breaks(candidates.range)?;
+                find_fuzzy_matches_within_breaks(candidates, &later.1, distance, dir, hard_breaks)?
+            } else {
+                find_fuzzy_matches(candidates, &later.1, distance, dir)?
+            };
+            let step = || {
+                let relation = if is_within_sentence(&spans[later.0]) {
+                    "in the same sentence as"
+                } else {
+                    "of"
+                };
+                format!(
+                    "Span {earlier} within {distance} {relation} span {}",
+                    later.0
+                )
+            };
+            record_step(steps.as_deref_mut(), step, &combined);
+            later = (earlier, combined);
         }
-        Ok(previous.candidates)
+        Ok(later.1)
     }
 
-    /// Computes candidates for each span.
+    /// Computes candidates for each span, recording how in the `plan` (if any).
     pub(super) fn candidates_for_spans<'a>(
         &'a self,
         spans: &'a [&[InternalQueryTerm]],
         range: &'a IndexRange,
         profiler: &mut TimeProfiler,
+        mut plan: Option<&mut QueryPlan>,
     ) -> Result<Option<Vec<SpanResult<'a>>>, QueryExecError> {
         let mut span_results = Vec::new();
+        let mut span_start = 0;
         for span in spans {
-            let candidates = match self.candidates_for_single_span(span, range, profiler)? {
+            let optional = span[0].optional;
+            let mut steps = plan.is_some().then(Vec::new);
+            let result = self.candidates_for_single_span(span, range, profiler, steps.as_mut());
+            if let (Some(plan), Some(steps)) = (plan.as_deref_mut(), steps) {
+                let terms = span.iter().map(ToString::to_string).collect::<String>();
+                plan.spans.push(SpanPlan {
+                    terms: terms.trim().to_string(),
+                    steps,
+                });
+            }
+            let candidates = match result? {
                 Some(res) => res,
+                // An optional span without candidates is just never in the match.
+                None if optional => IndexSlice {
+                    data: IndexDataRoO::Owned(IndexDataOwned::List(vec![])),
+                    range,
+                    position: 0,
+                },
                 None => return Ok(None),
             };
             span_results.push(SpanResult {
                 candidates,
                 length: span.len(),
                 relation: span[0].relation.clone(),
+                optional,
+                labels: span
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, term)| {
+                        Some(SpanLabel {
+                            offset: i as u32,
+                            term: span_start + i,
+                            label: term.label?.to_string(),
+                        })
+                    })
+                    .collect(),
             });
+            span_start += span.len();
         }
         Ok(Some(span_results))
     }
@@ -180,6 +301,7 @@ impl CorpusQueryEngine {
         query: &'a [InternalQueryTerm],
         range: &'a IndexRange,
         profiler: &mut TimeProfiler,
+        mut steps: Option<&mut Vec<IndexStep>>,
     ) -> Result<Option<IndexSlice<'a>>, QueryExecError> {
         let mut indexed_terms: Vec<(usize, &InternalQueryTerm)> =
             query.iter().enumerate().collect();
@@ -196,6 +318,13 @@ impl CorpusQueryEngine {
             position: *first_original_index as u32,
             ..data
         };
+        let step = || {
+            format!(
+                "Term {first_original_index}: {}",
+                first_term.constraint.inner
+            )
+        };
+        record_step(steps.as_deref_mut(), step, &data);
 
         profiler.phase("Initial candidates");
 
@@ -210,10 +339,13 @@ impl CorpusQueryEngine {
             };
             data = apply_and_to_indices(&data, &term_data)?;
             profiler.phase(format!("Filter from {original_index}").as_str());
+            let step = || format!("Term {original_index}: {}", term.constraint.inner);
+            record_step(steps.as_deref_mut(), step, &data);
         }
         if query.len() > 1 {
             let result = self.filter_breaks(&data, query.len(), profiler)?;
             profiler.phase("Filter breaks");
+            record_step(steps, || "Filter breaks".to_string(), &result);
             return Ok(Some(result));
         }
         Ok(Some(data))
@@ -243,6 +375,10 @@ impl CorpusQueryEngine {
             &TokenConstraintAtom::Inflection(inflection) => {
                 self.get_metadata(inflection.get_label(), inflection.get_code())
             }
+            // These are expanded before execution, so any that remain have no matches.
+            TokenConstraintAtom::WordPattern(_)
+            | TokenConstraintAtom::LemmaPattern(_)
+            | TokenConstraintAtom::Form(_) => None,
         }
     }
 
@@ -307,6 +443,9 @@ mod tests {
                 size_bounds: SizeBounds { lower: 0, upper: 0 },
             },
             relation,
+            optional: false,
+            agreement: None,
+            label: None,
         }
     }
 
@@ -328,6 +467,7 @@ mod tests {
             make_term(QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             }),
             make_term(QueryRelation::After),
         ];
@@ -344,10 +484,12 @@ mod tests {
             make_term(QueryRelation::Proximity {
                 distance: 2,
                 is_directed: false,
+                within_sentence: false,
             }),
             make_term(QueryRelation::Proximity {
                 distance: 3,
                 is_directed: true,
+                within_sentence: false,
             }),
         ];
         let spans = split_into_spans(&query).unwrap();
@@ -365,12 +507,14 @@ mod tests {
             make_term(QueryRelation::Proximity {
                 distance: 2,
                 is_directed: false,
+                within_sentence: false,
             }),
             make_term(QueryRelation::After),
             make_term(QueryRelation::After),
             make_term(QueryRelation::Proximity {
                 distance: 3,
                 is_directed: true,
+                within_sentence: false,
             }),
         ];
         let spans = split_into_spans(&query).unwrap();
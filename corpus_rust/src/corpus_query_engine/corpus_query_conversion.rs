@@ -3,7 +3,8 @@ use std::cmp::{max, min};
 use crate::{
     corpus_query_engine::{CorpusQueryEngine, QueryExecError},
     query_parsing_v2::{
-        QueryRelation, QueryTerm, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation,
+        Agreement, QueryRelation, QueryTerm, TokenConstraint, TokenConstraintAtom,
+        TokenConstraintOperation,
     },
 };
 
@@ -29,25 +30,41 @@ pub struct InternalConstraint<'a> {
 pub struct InternalQueryTerm<'a> {
     pub relation: &'a QueryRelation,
     pub constraint: InternalConstraint<'a>,
+    pub optional: bool,
+    pub agreement: Option<&'a Agreement>,
+    pub label: Option<&'a str>,
 }
 
 impl InternalQueryTerm<'_> {
-    /// Whether this term must be contiguous with the previous term.
+    /// Whether this term must be contiguous with the previous term. Optional
+    /// terms are never contiguous, since they might not be in the match at all.
     pub fn is_contiguous(&self) -> bool {
-        matches!(self.relation, QueryRelation::After | QueryRelation::First)
+        !self.optional && matches!(self.relation, QueryRelation::After | QueryRelation::First)
     }
 }
 
 impl std::fmt::Display for InternalQueryTerm<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}{}", self.relation, self.constraint.inner)
+        write!(f, "{}", self.relation)?;
+        if let Some(label) = self.label {
+            write!(f, "{label}:")?;
+        }
+        if self.optional {
+            write!(f, "({})?", self.constraint.inner)?;
+        } else {
+            write!(f, "{}", self.constraint.inner)?;
+        }
+        match self.agreement {
+            Some(agreement) => write!(f, "=${}", agreement.label),
+            None => Ok(()),
+        }
     }
 }
 
 // Methods for converting a query to an internal form.
 impl CorpusQueryEngine {
     /// Get the size bounds for a token constraint atom. This should be present in the raw data.
-    fn get_bounds_for_atom(&self, atom: &TokenConstraintAtom) -> SizeBounds {
+    pub(super) fn get_bounds_for_atom(&self, atom: &TokenConstraintAtom) -> SizeBounds {
         let metadata = match self.get_metadata_for(atom) {
             Some(m) => m,
             None => {
@@ -114,7 +131,7 @@ impl CorpusQueryEngine {
                 let upper = n - converted.size_bounds.lower;
                 let lower = n - converted.size_bounds.upper;
                 Ok(InternalConstraint {
-                    inner,
+                    inner: constraint,
                     size_bounds: SizeBounds { upper, lower },
                 })
             }
@@ -129,6 +146,9 @@ impl CorpusQueryEngine {
         Ok(InternalQueryTerm {
             relation: &term.relation,
             constraint,
+            optional: term.optional,
+            agreement: term.agreement.as_ref(),
+            label: term.label.as_deref(),
         })
     }
 }
@@ -1,28 +1,30 @@
-use std::{
-    cmp::{max, min},
-    collections::HashMap,
-};
+use std::cmp::{max, min};
 
 use crate::{
     analyzer_types::LatinInflection,
-    api::{CorpusQueryMatch, CorpusQueryMatchMetadata, PageData, QueryGlobalInfo, QueryOptions},
+    api::{
+        ContextMode, CorpusQueryMatch, CorpusQueryMatchMetadata, MatchAnalyses, PageData,
+        QueryGlobalInfo, QueryOptions,
+    },
+    bitmask_utils::is_bit_set,
     corpus_query_engine::{
-        CorpusQueryEngine, MatchIterator, QueryExecError,
-        corpus_data_readers::LemmaAndInflection,
-        corpus_index_calculation::SpanResult,
-        corpus_query_conversion::InternalQueryTerm,
-        query_validation::{atoms_in, operators_in},
+        CorpusQueryEngine, IndexData, MatchIterator, QueryExecError, atom_expansion::FormLemmata,
+        corpus_data_readers::LemmaAndInflection, corpus_index_calculation::SpanResult,
+        corpus_query_conversion::InternalQueryTerm, index_data::IndexRange,
+        query_scope::TokenRange, query_validation::operators_in, result_sorting::seeded_hash,
+    },
+    query_parsing_v2::{
+        QueryRelation, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation,
     },
-    query_parsing_v2::{QueryRelation, TokenConstraintAtom, TokenConstraintOperation},
 };
 
-const CASE_START: u32 = 0;
-const NUMBER_START: u32 = 7;
-const GENDER_START: u32 = 9;
-const PERSON_START: u32 = 13;
-const MOOD_START: u32 = 16;
-const VOICE_START: u32 = 23;
-const TENSE_START: u32 = 25;
+pub(super) const CASE_START: u32 = 0;
+pub(super) const NUMBER_START: u32 = 7;
+pub(super) const GENDER_START: u32 = 9;
+pub(super) const PERSON_START: u32 = 13;
+pub(super) const MOOD_START: u32 = 16;
+pub(super) const VOICE_START: u32 = 23;
+pub(super) const TENSE_START: u32 = 25;
 
 const CASE_MASK: u32 = 0b1111111; // << CASE_START: 7 bits starting at 0
 const NUMBER_MASK: u32 = 0b11 << NUMBER_START; // 2 bits starting at 7
@@ -32,35 +34,88 @@ const MOOD_MASK: u32 = 0b1111111 << MOOD_START; // 7 bits starting at 16
 const VOICE_MASK: u32 = 0b11 << VOICE_START; // 2 bits starting at 23
 const TENSE_MASK: u32 = 0b111111 << TENSE_START; // 6 bits starting at 25
 
-/// Finds the leader of the span with the given anchor ID.
+/// The leader of the last span found in a match, which the next span is relative to.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    /// The token ID of the leader, position-normalized (i.e., with a position of 0).
+    id: u32,
+    /// The length of the leader's span.
+    length: u32,
+    /// The least and most tokens that were skipped by optional spans left out since
+    /// the leader. The next span can have these extra tokens before it.
+    skipped: (u32, u32),
+}
+
+impl Anchor {
+    fn new(id: u32, length: u32) -> Self {
+        Anchor {
+            id,
+            length,
+            skipped: (0, 0),
+        }
+    }
+
+    /// Returns the anchor for the span after `span`, if `span` is left out of the match.
+    fn skipping(&self, span: &SpanResult) -> Result<Self, QueryExecError> {
+        let (min_gap, max_gap) = span.gap_bounds().ok_or(QueryExecError::new(
+            "Optional spans must be joined by adjacency or gaps",
+        ))?;
+        Ok(Anchor {
+            skipped: (self.skipped.0 + min_gap, self.skipped.1 + max_gap),
+            ..*self
+        })
+    }
+}
+
+/// Returns the range (with an exclusive end) of possible leaders for the span, given the
+/// anchor. The range can start before the first token, so it may be negative.
+fn leader_window(anchor: &Anchor, span: &SpanResult) -> Result<(i64, i64), QueryExecError> {
+    let id = anchor.id as i64;
+    match span.relation {
+        QueryRelation::Proximity {
+            distance,
+            is_directed,
+            ..
+        } => {
+            let start = if is_directed {
+                id
+            } else {
+                id - distance as i64
+            };
+            // +1 because the end is exclusive.
+            Ok((start, id + distance as i64 + 1))
+        }
+        QueryRelation::After | QueryRelation::Gap { .. } => {
+            let (min_gap, max_gap) = span.gap_bounds().unwrap_or_default();
+            let (min_skipped, max_skipped) = anchor.skipped;
+            let end = id + anchor.length as i64;
+            Ok((
+                end + (min_gap + min_skipped) as i64,
+                end + (max_gap + max_skipped) as i64 + 1,
+            ))
+        }
+        QueryRelation::First => Err(QueryExecError::new("`First` found for non-initial span")),
+    }
+}
+
+/// Finds the leader of the span with the given anchor.
 ///
 /// # Arguments
-/// * `anchor_id` - The token ID of the anchor. This is position-normalized (i.e., has a position of 0).
+/// * `anchor` - The leader of the last span found in the match.
 /// * `span` - The span to find the leader for.
 ///
 /// Returns the possible token IDs of the span leader, position-normalized.
-fn find_span_leader(anchor_id: u32, span: &SpanResult) -> Result<Vec<u32>, QueryExecError> {
+fn find_span_leader(anchor: &Anchor, span: &SpanResult) -> Result<Vec<u32>, QueryExecError> {
     if span.length == 0 {
         return Err(QueryExecError::new("Empty query span found"));
     }
-    let (proximity_len, is_directed) = match span.relation {
-        QueryRelation::Proximity {
-            distance,
-            is_directed,
-        } => (distance as u32, is_directed),
-        _ => {
-            return Err(QueryExecError::new("`First` found for non-initial span"));
-        }
-    };
-
-    let subtractand = if is_directed { 0 } else { proximity_len };
-    let anchor_start = anchor_id.saturating_sub(subtractand);
-    // +1 because the end is exclusive.
-    let anchor_end = anchor_id + proximity_len + 1;
+    let (window_start, window_end) = leader_window(anchor, span)?;
+    let anchor_start = window_start.max(0) as u32;
+    let anchor_end = window_end.max(0) as u32;
     let (span_start, span_end) = span.candidates.normalized_range();
     if anchor_end <= span_start || span_end <= anchor_start {
         // There's no overlap in the ranges.
-        return Err(QueryExecError::new("No span leader found within proximity"));
+        return Ok(vec![]);
     }
     let start = max(anchor_start, span_start) + span.candidates.position;
     let end = min(anchor_end, span_end) + span.candidates.position;
@@ -72,9 +127,7 @@ fn find_span_leader(anchor_id: u32, span: &SpanResult) -> Result<Vec<u32>, Query
             if end_bit <= span.candidates.position {
                 // We won't have any candidates possible, because any candidate bit would
                 // be negative after applying the position offset.
-                return Err(QueryExecError::new(
-                    "No span leader found within proximity [for bitmask].",
-                ));
+                return Ok(vec![]);
             }
             // Skip bits before the position offset.
             let start_bit = max(start_bit, span.candidates.position);
@@ -91,9 +144,7 @@ fn find_span_leader(anchor_id: u32, span: &SpanResult) -> Result<Vec<u32>, Query
             let i = list.partition_point(|x| *x < start);
             let j = list.partition_point(|x| *x < end);
             if i >= j {
-                return Err(QueryExecError::new(
-                    "No span leader found within proximity [for list].",
-                ));
+                return Ok(vec![]);
             }
 
             Ok(list[i..j]
@@ -112,15 +163,17 @@ struct SpanLeaderTree<'a> {
     root: SpanLeaderNode,
 }
 
-/// The sorted, and the original order of the spans.
-type SortedStartsAndSpans<'a> = (Vec<StartAndSpan<'a>>, Vec<StartAndSpan<'a>>);
+/// The sorted spans, and the original order of the spans (with `None` for
+/// optional spans that were left out).
+type SortedStartsAndSpans<'a> = (Vec<StartAndSpan<'a>>, Vec<Option<StartAndSpan<'a>>>);
 
 impl<'a> SpanLeaderTree<'a> {
     fn new(
         anchor_id: u32,
         all_span_candidates: &'a [SpanResult<'a>],
+        hard_breaks: &[u64],
     ) -> Result<Self, QueryExecError> {
-        let root = span_tree_rooted_at(anchor_id, all_span_candidates)?;
+        let root = span_tree_rooted_at(anchor_id, all_span_candidates, hard_breaks)?;
         Ok(SpanLeaderTree {
             span_candidates: all_span_candidates,
             root,
@@ -141,17 +194,20 @@ impl<'a> SpanLeaderTree<'a> {
     fn collect_paths(
         &self,
         node: &SpanLeaderNode,
-        current_path: &mut Vec<StartAndSpan<'a>>,
+        current_path: &mut Vec<Option<StartAndSpan<'a>>>,
         result: &mut Vec<SortedStartsAndSpans<'a>>,
     ) {
         let depth = current_path.len();
 
         // Add current node to path
-        current_path.push((node.leader_id, &self.span_candidates[depth]));
+        current_path.push(
+            node.leader_id
+                .map(|leader_id| (leader_id, &self.span_candidates[depth])),
+        );
 
         // If we've reached the target depth, save the path
         if current_path.len() == self.span_candidates.len() {
-            let mut sorted_path = current_path.clone();
+            let mut sorted_path = current_path.iter().flatten().copied().collect::<Vec<_>>();
             sorted_path.sort_by_key(|(start, _)| *start);
             // TODO: We should move overlap checks when we're constructing the tree.
             // This way, we can prune entire branches if the first few spans overlap.
@@ -172,31 +228,96 @@ impl<'a> SpanLeaderTree<'a> {
 
 #[derive(Default)]
 struct SpanLeaderNode {
-    leader_id: u32,
+    /// The leader of the span, or `None` if it's an optional span that was left out.
+    leader_id: Option<u32>,
     children: Vec<SpanLeaderNode>,
 }
 
+/// Whether there's a hard break after any of the tokens from `start` up to
+/// (but not including) `end`.
+fn has_break_between(hard_breaks: &[u64], start: u32, end: u32) -> bool {
+    (start..end).any(|token| is_bit_set(hard_breaks, token as usize))
+}
+
+/// Whether the span leader is allowed by the relation to the leader of the previous span.
+/// Spans that need to be in the same sentence can't have a hard break between their leaders,
+/// and adjacent spans (which are split up around optional terms) can't have one between them.
+///
+/// Candidates are already restricted to the same sentence when spans are combined, but that
+/// only means *some* later leader is in the same sentence, so each pair is still checked here.
+fn is_leader_allowed(
+    anchor: &Anchor,
+    leader_id: u32,
+    span: &SpanResult,
+    hard_breaks: &[u64],
+) -> bool {
+    match span.relation {
+        QueryRelation::Proximity {
+            within_sentence: true,
+            ..
+        } => {
+            let (start, end) = (min(anchor.id, leader_id), max(anchor.id, leader_id));
+            !has_break_between(hard_breaks, start, end)
+        }
+        QueryRelation::After if leader_id == anchor.id + anchor.length => {
+            !is_bit_set(hard_breaks, leader_id as usize - 1)
+        }
+        _ => true,
+    }
+}
+
+/// Whether the span needs the hard breaks to check its relation with the previous span.
+fn needs_hard_breaks(span: &SpanResult) -> bool {
+    matches!(
+        span.relation,
+        QueryRelation::After
+            | QueryRelation::Proximity {
+                within_sentence: true,
+                ..
+            }
+    )
+}
+
+/// Builds the tree of possible span leaders starting at `token_id`. The `hard_breaks` are
+/// only used for spans that need them to check their relation (see `needs_hard_breaks`).
+///
+/// Optional spans have a child for each possible leader, followed by a child for leaving
+/// the span out, so that paths that include optional spans come first.
 fn span_tree_rooted_at(
     token_id: u32,
     all_span_candidates: &[SpanResult],
+    hard_breaks: &[u64],
 ) -> Result<SpanLeaderNode, QueryExecError> {
-    if all_span_candidates.is_empty() {
-        return Err(QueryExecError::new("No query spans provided"));
-    }
-    // A list of (leader ID, index of child nodes in `all_nodes`)
-    let mut raw_nodes: Vec<(u32, Vec<usize>)> = vec![(token_id, vec![])];
+    let first = all_span_candidates
+        .first()
+        .ok_or(QueryExecError::new("No query spans provided"))?;
+    // A list of (leader ID, the anchor for the next span, index of child nodes in `all_nodes`)
+    type RawNode = (Option<u32>, Anchor, Vec<usize>);
+    let root_anchor = Anchor::new(token_id, first.length as u32);
+    let mut raw_nodes: Vec<RawNode> = vec![(Some(token_id), root_anchor, vec![])];
     let mut queue = vec![0];
 
     for span in all_span_candidates.iter().skip(1) {
         let mut new_queue = vec![];
         for i in queue.drain(..) {
-            let leader_id = raw_nodes[i].0;
+            let anchor = raw_nodes[i].1;
             // Find the possible leaders for this span that are close
             // to the last span's leader.
-            for span_leader in find_span_leader(leader_id, span)? {
+            let mut children = vec![];
+            for span_leader in find_span_leader(&anchor, span)? {
+                if !is_leader_allowed(&anchor, span_leader, span, hard_breaks) {
+                    continue;
+                }
+                let next_anchor = Anchor::new(span_leader, span.length as u32);
+                children.push((Some(span_leader), next_anchor, vec![]));
+            }
+            if span.optional {
+                children.push((None, anchor.skipping(span)?, vec![]));
+            }
+            for child in children {
                 let id = raw_nodes.len();
-                raw_nodes.push((span_leader, vec![]));
-                raw_nodes[i].1.push(id);
+                raw_nodes.push(child);
+                raw_nodes[i].2.push(id);
                 new_queue.push(id);
             }
         }
@@ -205,7 +326,7 @@ fn span_tree_rooted_at(
 
     // Now, reconstruct the tree from the raw nodes.
     let mut nodes = Vec::<SpanLeaderNode>::with_capacity(raw_nodes.len());
-    for (leader_id, children_indices) in raw_nodes.iter().rev() {
+    for (leader_id, _, children_indices) in raw_nodes.iter().rev() {
         let mut node = SpanLeaderNode {
             leader_id: *leader_id,
             children: Vec::with_capacity(children_indices.len()),
@@ -227,9 +348,11 @@ fn span_tree_rooted_at(
     Ok(root)
 }
 
+/// Computes the token offsets of the chunks of a match, given the number of tokens
+/// of context to include before and after it.
 fn compute_offsets<'a>(
     leaders: &[StartAndSpan<'a>],
-    context_len: u32,
+    (left_context, right_context): (u32, u32),
     num_tokens: u32,
 ) -> Result<Vec<(u32, bool)>, QueryExecError> {
     if leaders.is_empty() {
@@ -237,34 +360,35 @@ fn compute_offsets<'a>(
     }
     let mut offsets = Vec::with_capacity(leaders.len() + 2);
     // The left context start token. Make sure it doesn't go below 0.
-    offsets.push((max(0, leaders[0].0.saturating_sub(context_len)), false));
+    offsets.push((max(0, leaders[0].0.saturating_sub(left_context)), false));
     for &(start, span) in leaders.iter() {
         let len = span.length as u32;
-        // Make sure this is updated if we don't add an offset for the left context above;
-        // otherwise, this would panic.
-        if start != 0 && start <= offsets[offsets.len() - 1].0 {
-            return Err(QueryExecError::new("Overlapping spans found"));
-        }
-        if start == 0 {
-            if offsets.len() > 1 {
-                return Err(QueryExecError::new("Multiple spans starting at 0"));
-            }
+        if offsets.len() == 1 && start == offsets[0].0 {
+            // There's no left context (for example, at the start of the corpus),
+            // so the first chunk is the match itself.
             offsets[0].1 = true;
-            offsets.push((len - 1, false));
+            offsets.push((start + len - 1, false));
             continue;
         }
+        if start <= offsets[offsets.len() - 1].0 {
+            return Err(QueryExecError::new("Overlapping spans found"));
+        }
         offsets.push((start, true));
         offsets.push((start + len - 1, false));
     }
     // The right context end token. Make sure it doesn't go beyond the number of tokens.
-    offsets.push((
-        min(
-            num_tokens - 1,
-            leaders[leaders.len() - 1].0 + leaders[leaders.len() - 1].1.length as u32 + context_len
-                - 1,
-        ),
-        false,
-    ));
+    if right_context > 0 {
+        offsets.push((
+            min(
+                num_tokens - 1,
+                leaders[leaders.len() - 1].0
+                    + leaders[leaders.len() - 1].1.length as u32
+                    + right_context
+                    - 1,
+            ),
+            false,
+        ));
+    }
     Ok(offsets)
 }
 
@@ -279,7 +403,7 @@ fn do_spans_overlap(spans: &[StartAndSpan]) -> bool {
         return false;
     }
     let mut spans = spans.to_vec();
-    spans.sort_by(|a, b| a.0.cmp(&b.0));
+    spans.sort_by_key(|a| a.0);
     for i in 0..spans.len() - 1 {
         let (start_i, span) = spans[i];
         let end_i = start_i + span.length as u32;
@@ -298,8 +422,9 @@ fn do_spans_overlap(spans: &[StartAndSpan]) -> bool {
 fn find_leaders_for<'a>(
     token_id: u32,
     all_span_candidates: &'a [SpanResult],
+    hard_breaks: &[u64],
 ) -> Result<Vec<SortedStartsAndSpans<'a>>, QueryExecError> {
-    let tree = SpanLeaderTree::new(token_id, all_span_candidates)?;
+    let tree = SpanLeaderTree::new(token_id, all_span_candidates, hard_breaks)?;
     Ok(tree.find_leaders())
 }
 
@@ -314,6 +439,7 @@ fn get_result_stats(
             return QueryGlobalInfo {
                 // Since there's no next page, this is exact.
                 estimated_results: current_page.result_index as usize + results_in_page,
+                sample_seed: None,
             };
         }
         Some(v) => v,
@@ -324,26 +450,168 @@ fn get_result_stats(
         (remaining_candidates as f64 * hit_rate + 0.99).floor() as usize;
     QueryGlobalInfo {
         estimated_results: next_page.result_index as usize + estimated_remaining_results,
+        sample_seed: None,
     }
 }
 
 type InflectionMask = u32;
-type StrictModeMask = u32;
-struct TokenValidationInfo {
+
+const CATEGORY_MASKS: [InflectionMask; 7] = [
+    CASE_MASK,
+    NUMBER_MASK,
+    GENDER_MASK,
+    PERSON_MASK,
+    MOOD_MASK,
+    VOICE_MASK,
+    TENSE_MASK,
+];
+
+/// A token constraint, resolved so that it can be checked directly against
+/// the inflection data of a single analysis.
+#[derive(Debug)]
+enum AnalysisConstraint<'a> {
+    /// The bit for a single inflection value in the inflection mask.
+    Inflection(InflectionMask),
+    /// The ID of a lemma, or `None` if the lemma is not in the corpus.
+    Lemma(Option<u32>),
+    /// The index for a word, or `None` if the word is not in the corpus. Unlike
+    /// the other atoms, this applies to the token rather than to an analysis.
+    Word(Option<IndexData<'a>>),
+    /// Negations apply to the whole token: they hold only if no analysis of the token
+    /// matches the inner constraint (in any reading).
+    Not(Box<AnalysisConstraint<'a>>),
+    And(Vec<AnalysisConstraint<'a>>),
+    Or(Vec<AnalysisConstraint<'a>>),
+}
+
+/// A single analysis of a particular token.
+struct ObservedAnalysis<'a> {
+    token: u32,
+    inflection: InflectionMask,
+    lemma: u32,
+    /// Every analysis of the token, including this one.
+    all_analyses: &'a [LemmaAndInflection],
+}
+
+impl<'a> ObservedAnalysis<'a> {
+    fn new(
+        token: u32,
+        analysis: LemmaAndInflection,
+        all_analyses: &'a [LemmaAndInflection],
+    ) -> Self {
+        ObservedAnalysis {
+            token,
+            inflection: analysis as u32,
+            lemma: (analysis >> 32) as u32,
+            all_analyses,
+        }
+    }
+}
+
+impl<'a> AnalysisConstraint<'a> {
+    fn from(constraint: &TokenConstraint, corpus: &'a CorpusQueryEngine) -> Result<Self, String> {
+        match constraint {
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma(lemma)) => Ok(Self::Lemma(
+                corpus
+                    .corpus
+                    .id_table
+                    .get("lemma")
+                    .and_then(|m| m.get(lemma))
+                    .copied(),
+            )),
+            TokenConstraint::Atom(
+                atom @ (TokenConstraintAtom::Word(_)
+                | TokenConstraintAtom::WordPattern(_)
+                | TokenConstraintAtom::LemmaPattern(_)
+                | TokenConstraintAtom::Form(_)),
+            ) => {
+                let index = match corpus.get_metadata_for(atom) {
+                    Some(metadata) => Some(
+                        corpus
+                            .raw_buffers
+                            .resolve_index(metadata, corpus.corpus.num_tokens)?,
+                    ),
+                    None => None,
+                };
+                Ok(Self::Word(index))
+            }
+            TokenConstraint::Atom(TokenConstraintAtom::Inflection(inflection)) => {
+                let start = match inflection {
+                    LatinInflection::Case(_) => CASE_START,
+                    LatinInflection::Number(_) => NUMBER_START,
+                    LatinInflection::Gender(_) => GENDER_START,
+                    LatinInflection::Person(_) => PERSON_START,
+                    LatinInflection::Mood(_) => MOOD_START,
+                    LatinInflection::Voice(_) => VOICE_START,
+                    LatinInflection::Tense(_) => TENSE_START,
+                    LatinInflection::Degree(_) => {
+                        return Err("Degree inflection not supported for validation".to_string());
+                    }
+                };
+                // -1 because it is 1-based in the LatinInflection enum.
+                Ok(Self::Inflection(
+                    1 << (start + inflection.get_code() as u32 - 1),
+                ))
+            }
+            TokenConstraint::Negated(inner) => Ok(Self::Not(Box::new(Self::from(inner, corpus)?))),
+            TokenConstraint::Composed { op, children } => {
+                let children = children
+                    .iter()
+                    .map(|child| Self::from(child, corpus))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(match op {
+                    TokenConstraintOperation::And => Self::And(children),
+                    TokenConstraintOperation::Or => Self::Or(children),
+                })
+            }
+        }
+    }
+
+    /// Whether the constraint holds for a single reading of an analysis.
+    ///
+    /// Negations are checked against every analysis of the token rather than the reading,
+    /// so that (for example) `(@lemma:puella and !@case:nom)` excludes `puella` even though
+    /// it could also be ablative. This matches the indices, where `!@case:nom` is every
+    /// token without a nominative analysis.
+    fn holds_for(&self, reading: InflectionMask, observed: &ObservedAnalysis) -> bool {
+        match self {
+            Self::Inflection(bit) => reading & bit != 0,
+            Self::Lemma(id) => *id == Some(observed.lemma),
+            Self::Word(index) => index
+                .as_ref()
+                .is_some_and(|index| index_contains(index, observed.token)),
+            Self::Not(inner) => !observed.all_analyses.iter().any(|analysis| {
+                let other = ObservedAnalysis::new(observed.token, *analysis, observed.all_analyses);
+                readings_of(other.inflection).any(|reading| inner.holds_for(reading, &other))
+            }),
+            Self::And(children) => children.iter().all(|c| c.holds_for(reading, observed)),
+            Self::Or(children) => children.iter().any(|c| c.holds_for(reading, observed)),
+        }
+    }
+}
+
+fn index_contains(index: &IndexData<'_>, token: u32) -> bool {
+    match index {
+        IndexData::List(list) => list.binary_search(&token).is_ok(),
+        IndexData::BitMask(bitmask) => bitmask
+            .get((token / 64) as usize)
+            .is_some_and(|word| word & (1 << (token % 64)) != 0),
+    }
+}
+
+struct TokenValidationInfo<'a> {
     span_idx: usize,
     term_idx: usize,
-    inflection: InflectionMask,
-    strict_mask: Option<StrictModeMask>,
-    lemmata: Vec<u32>,
-    is_conjunction: bool,
+    constraint: AnalysisConstraint<'a>,
+    strict: bool,
 }
 
 /// Within the spans, find the positions that need inflection validation.
 fn positions_needing_validation<'a>(
-    spans: &'a [&[InternalQueryTerm]],
-    id_table: &'a HashMap<String, HashMap<String, u32>>,
+    spans: &[&[InternalQueryTerm]],
+    corpus: &'a CorpusQueryEngine,
     strict: bool,
-) -> Result<Vec<TokenValidationInfo>, String> {
+) -> Result<Vec<TokenValidationInfo<'a>>, String> {
     let mut positions = vec![];
     for (span_idx, span) in spans.iter().enumerate() {
         for (term_idx, term) in span.iter().enumerate() {
@@ -352,133 +620,184 @@ fn positions_needing_validation<'a>(
             if !strict && !has_and {
                 // if we're not in strict mode, `or` doesn't need validation because the
                 // indices encode whether any of the possible options meets the given inflection
-                // category.
+                // category. Similarly, negations of atoms are computed exactly by the indices.
                 continue;
             }
-            let atoms = atoms_in(term.constraint.inner);
-            let mut inflection_mask = 0;
-            let mut strict_mask = if strict { Some(0) } else { None };
-            let mut inflection_constraints = 0;
-            let mut lemmata = vec![];
-            for atom in atoms {
-                match atom {
-                    TokenConstraintAtom::Lemma(lemma) => {
-                        let id = id_table
-                            .get("lemma")
-                            .and_then(|m| m.get(lemma))
-                            .ok_or("Could not find lemma")?;
-                        lemmata.push(*id);
-                    }
-                    TokenConstraintAtom::Inflection(inflection) => {
-                        inflection_constraints += 1;
-                        let (start, category_mask) = match inflection {
-                            LatinInflection::Case(_) => (CASE_START, CASE_MASK),
-                            LatinInflection::Number(_) => (NUMBER_START, NUMBER_MASK),
-                            LatinInflection::Gender(_) => (GENDER_START, GENDER_MASK),
-                            LatinInflection::Person(_) => (PERSON_START, PERSON_MASK),
-                            LatinInflection::Mood(_) => (MOOD_START, MOOD_MASK),
-                            LatinInflection::Voice(_) => (VOICE_START, VOICE_MASK),
-                            LatinInflection::Tense(_) => (TENSE_START, TENSE_MASK),
-                            LatinInflection::Degree(_) => {
-                                return Err(
-                                    "Degree inflection not supported for validation".to_string()
-                                );
-                            }
-                        };
-                        // -1 because it is 1-based in the LatinInflection enum.
-                        let bit = start + inflection.get_code() as u32 - 1;
-                        inflection_mask |= 1 << bit;
-                        // Set the appropriate category mask in strict_mask
-                        if let Some(ref mut mask) = strict_mask {
-                            *mask |= category_mask;
-                        }
-                    }
-                    _ => {}
-                }
+            positions.push(TokenValidationInfo {
+                span_idx,
+                term_idx,
+                constraint: AnalysisConstraint::from(term.constraint.inner, corpus)?,
+                strict,
+            });
+        }
+    }
+    Ok(positions)
+}
+
+/// Terms that need to agree with each other, along with the inflection categories
+/// (as a mask of every value in them) that they need to agree in.
+struct AgreementGroup<'a> {
+    members: Vec<TokenValidationInfo<'a>>,
+    categories: InflectionMask,
+}
+
+/// Within the spans, find the groups of terms that need to agree.
+fn agreement_groups<'a>(
+    spans: &[&[InternalQueryTerm]],
+    corpus: &'a CorpusQueryEngine,
+) -> Result<Vec<AgreementGroup<'a>>, String> {
+    let mut groups: Vec<(String, AgreementGroup<'a>)> = vec![];
+    for (span_idx, span) in spans.iter().enumerate() {
+        for (term_idx, term) in span.iter().enumerate() {
+            let Some(agreement) = term.agreement else {
+                continue;
+            };
+            let member = TokenValidationInfo {
+                span_idx,
+                term_idx,
+                constraint: AnalysisConstraint::from(term.constraint.inner, corpus)?,
+                strict: false,
+            };
+            if let Some((_, group)) = groups.iter_mut().find(|(l, _)| *l == agreement.label) {
+                group.members.push(member);
+                continue;
             }
-            let threshold = if strict { 0 } else { 1 };
-            if (lemmata.len() + inflection_constraints) > threshold {
-                positions.push(TokenValidationInfo {
-                    span_idx,
-                    term_idx,
-                    inflection: inflection_mask,
-                    lemmata,
-                    // If there's no operator, then we only have one term and it wouldn't
-                    // matter if we treat it as an `and` or an `or`.
-                    is_conjunction: has_and,
-                    strict_mask,
-                });
+            let categories = agreement.categories.iter().fold(0, |mask, category| {
+                mask | CATEGORY_MASKS[*category as usize]
+            });
+            let group = AgreementGroup {
+                members: vec![member],
+                categories,
+            };
+            groups.push((agreement.label.clone(), group));
+        }
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// Whether the inflections have at least one value in each of the `categories`.
+fn has_value_in_each(inflections: InflectionMask, categories: InflectionMask) -> bool {
+    CATEGORY_MASKS
+        .iter()
+        .all(|mask| mask & categories == 0 || inflections & mask != 0)
+}
+
+/// Checks whether the tokens in each agreement group agree. For this, each token needs
+/// an analysis that matches its own term, such that those analyses all share a value in
+/// each of the categories of the group. Optional terms that were left out are ignored.
+fn has_agreeing_inflections(
+    unsorted_leaders: &[Option<(u32, &SpanResult)>],
+    agreements: &[AgreementGroup],
+    corpus: &CorpusQueryEngine,
+) -> Result<bool, String> {
+    for group in agreements {
+        // The values that the tokens so far could all share.
+        let mut shared: Option<Vec<InflectionMask>> = None;
+        for member in &group.members {
+            let Some((leader, _)) = unsorted_leaders[member.span_idx] else {
+                continue;
+            };
+            let token = leader + member.term_idx as u32;
+            let all_analyses = corpus.inflections.get_inflection_data(token)?;
+            let analyses = all_analyses
+                .iter()
+                .filter(|data| does_inflection_match(token, **data, all_analyses, member))
+                .map(|data| *data as InflectionMask & group.categories)
+                .collect::<Vec<_>>();
+            let mut options = match shared {
+                None => analyses,
+                Some(shared) => shared
+                    .iter()
+                    .flat_map(|values| analyses.iter().map(move |analysis| values & analysis))
+                    .collect(),
+            };
+            options.retain(|values| has_value_in_each(*values, group.categories));
+            if options.is_empty() {
+                return Ok(false);
             }
+            options.sort();
+            options.dedup();
+            shared = Some(options);
         }
     }
-    Ok(positions)
+    Ok(true)
 }
 
-/// Checks if bitset A is a subset of bitset B (i.e., all bits set in A are also set in B).
-///
-/// # Arguments
-/// * `maybe_subset` - The potential subset
-/// * `maybe_superset` - The potential superset
+/// Returns the lowest set bit of the mask, or 0 if there is none.
+fn lowest_bit(mask: InflectionMask) -> InflectionMask {
+    mask & mask.wrapping_neg()
+}
+
+/// The possible readings of an analysis, each of which has at most one value for each
+/// inflection category. For example, an analysis that could be either dative or ablative
+/// plural has two readings: dative plural and ablative plural.
 ///
-/// Returns true if A is a subset of B, false otherwise.
-#[inline]
-fn is_bitset_subset(maybe_subset: u32, maybe_superset: u32) -> bool {
-    (maybe_subset & maybe_superset) == maybe_subset
+/// This is checked for every analysis of every token that needs validation, so the
+/// readings are generated lazily (like an odometer, with later categories changing first)
+/// instead of collecting their cross product.
+struct Readings {
+    analysis: InflectionMask,
+    next: Option<InflectionMask>,
+}
+
+impl Iterator for Readings {
+    type Item = InflectionMask;
+
+    fn next(&mut self) -> Option<InflectionMask> {
+        let current = self.next.take()?;
+        // The categories after the one that changes go back to their first value.
+        let mut reset_mask = 0;
+        let mut reset_values = 0;
+        for category_mask in CATEGORY_MASKS.iter().rev() {
+            let values = self.analysis & category_mask;
+            let chosen = current & category_mask;
+            let higher = values & !(chosen | chosen.wrapping_sub(1));
+            if higher != 0 {
+                let kept = current & !category_mask & !reset_mask;
+                self.next = Some(kept | lowest_bit(higher) | reset_values);
+                break;
+            }
+            reset_mask |= category_mask;
+            reset_values |= lowest_bit(values);
+        }
+        Some(current)
+    }
 }
 
-/// Checks whether the given inflection restrictions all match the given inflection data.
+/// Returns the readings of the analysis (see `Readings`).
+fn readings_of(analysis: InflectionMask) -> Readings {
+    let first = CATEGORY_MASKS
+        .iter()
+        .fold(0, |reading, mask| reading | lowest_bit(analysis & mask));
+    Readings {
+        analysis,
+        next: Some(first),
+    }
+}
+
+/// Checks whether the given inflection analysis matches the constraints.
+///
+/// In strict mode, every reading of the analysis must match, so that (for example)
+/// an analysis that could be either nominative or accusative does not match `@case:acc`.
+/// Otherwise, it's enough for any one reading to match.
 fn does_inflection_match(
+    token: u32,
     lemma_and_inflection: LemmaAndInflection,
+    all_analyses: &[LemmaAndInflection],
     constraints: &TokenValidationInfo,
 ) -> bool {
-    let observed_bits = lemma_and_inflection as u32;
-    if constraints.is_conjunction {
-        if !is_bitset_subset(constraints.inflection, observed_bits) {
-            // This checks that `constraints.inflection` is a subset of `observed_bits`.
-            // If the constraint is an `and`, we expect the observed bits to have all of the
-            // constraint bits set.
-            return false;
-        }
-        if let Some(strict_mask) = constraints.strict_mask
-            && (observed_bits & strict_mask) != constraints.inflection
-        {
-            // This checks that, for the categories that are constrained, that the `observed_bits`
-            // exactly match the constraint bits.
-            return false;
-        }
-        let observed_lemma = (lemma_and_inflection >> 32) as u32;
-        // Less than one because it's not possible for one analysis to have
-        // multiple associated lemmata.
-        constraints.lemmata.len() <= 1
-            && constraints
-                .lemmata
-                .iter()
-                // An empty iterator returns true
-                .all(|&expected_lemma| observed_lemma == expected_lemma)
+    let observed = ObservedAnalysis::new(token, lemma_and_inflection, all_analyses);
+    let mut results = readings_of(observed.inflection)
+        .map(|reading| constraints.constraint.holds_for(reading, &observed));
+    if constraints.strict {
+        results.all(|matches| matches)
     } else {
-        let something_matches = observed_bits & constraints.inflection != 0;
-        let strict_mask = constraints.strict_mask;
-        if something_matches && strict_mask.is_none() {
-            // If we're not in strict mode and something matches, then we're done.
-            return true;
-        }
-        if something_matches && let Some(mask) = strict_mask {
-            // The parts of the observed bits that are relevant to the constraints.
-            let observed_subset = observed_bits & mask;
-            if is_bitset_subset(observed_subset, constraints.inflection) {
-                // In strict mode, we need to know that the observed subset couldn't have some
-                // other possible interpretation. For example, if something observed could be either
-                // nominative or accusative, but we only want accusative, then we can't accept it.
-                return true;
-            }
-        }
-        let observed_lemma = (lemma_and_inflection >> 32) as u32;
-        constraints.lemmata.contains(&observed_lemma)
+        results.any(|matches| matches)
     }
 }
 
 type StartAndSpan<'a> = (u32, &'a SpanResult<'a>);
-type SpanLeaders<'a> = Vec<StartAndSpan<'a>>;
+pub(super) type SpanLeaders<'a> = Vec<StartAndSpan<'a>>;
 pub(super) struct MatchPageResult<'a> {
     pub matches: Vec<SpanLeaders<'a>>,
     pub next_page: Option<PageData>,
@@ -503,9 +822,26 @@ fn within_work_bounds(sorted_span_leaders: &[(u32, &SpanResult<'_>)], work_bound
     work_bounds[start_work] < end && end < *work_bounds.get(start_work + 1).unwrap_or(&u32::MAX)
 }
 
+/// Whether the whole match is within one of the ranges in the query scope. The
+/// candidates are already restricted to the scope, so this only matters when a
+/// match could run past the end of a scope range (e.g. the end of a section).
+#[inline]
+fn within_scope(sorted_span_leaders: &[(u32, &SpanResult<'_>)], scope: &[TokenRange]) -> bool {
+    let (Some(first), Some(last)) = (sorted_span_leaders.first(), sorted_span_leaders.last())
+    else {
+        return true;
+    };
+    let start = first.0;
+    let end = last.0 + last.1.length as u32;
+    let i = scope.partition_point(|range| range.1 <= start);
+    scope
+        .get(i)
+        .is_some_and(|range| range.0 <= start && end <= range.1)
+}
+
 #[inline]
 fn has_matching_inflections(
-    unsorted_leaders: &[(u32, &SpanResult)],
+    unsorted_leaders: &[Option<(u32, &SpanResult)>],
     needs_validation: &[TokenValidationInfo],
     corpus: &CorpusQueryEngine,
 ) -> Result<bool, String> {
@@ -514,21 +850,25 @@ fn has_matching_inflections(
     // - For `and` constraints, all of the constraints need to be met
     // - For `or` constraints, at least one of the constraints needs to be met
     for token_data in needs_validation {
-        let token_to_check = unsorted_leaders[token_data.span_idx].0 + (token_data.term_idx as u32);
+        // Optional spans that were left out don't have anything to check.
+        let Some((leader, _)) = unsorted_leaders[token_data.span_idx] else {
+            continue;
+        };
+        let token_to_check = leader + (token_data.term_idx as u32);
         let inflection_options = corpus.inflections.get_inflection_data(token_to_check)?;
-        let is_strict = token_data.strict_mask.is_some();
+        let is_strict = token_data.strict;
         if is_strict
-            && !inflection_options
-                .iter()
-                .all(|data| does_inflection_match(*data, token_data))
+            && !inflection_options.iter().all(|data| {
+                does_inflection_match(token_to_check, *data, inflection_options, token_data)
+            })
         {
             // In strict mode, all of the options need to match the constraints.
             return Ok(false);
         }
         if !is_strict
-            && !inflection_options
-                .iter()
-                .any(|data| does_inflection_match(*data, token_data))
+            && !inflection_options.iter().any(|data| {
+                does_inflection_match(token_to_check, *data, inflection_options, token_data)
+            })
         {
             // In non-strict mode, one of the options needs to match the constraints.
             return Ok(false);
@@ -538,25 +878,26 @@ fn has_matching_inflections(
 }
 
 #[inline]
+#[allow(clippy::too_many_arguments)]
 fn leaders_for_candidate<'a>(
     token_id: u32,
     all_span_candidates: &'a [SpanResult],
     work_bounds: &[u32],
+    hard_breaks: &[u64],
+    scope: &[TokenRange],
     needs_validation: &[TokenValidationInfo],
+    agreements: &[AgreementGroup],
     corpus: &CorpusQueryEngine,
     last_match: Option<&Vec<StartAndSpan<'a>>>,
 ) -> Result<Option<Vec<StartAndSpan<'a>>>, QueryExecError> {
-    for (sorted, unsorted) in find_leaders_for(token_id, all_span_candidates)? {
+    for (sorted, unsorted) in find_leaders_for(token_id, all_span_candidates, hard_breaks)? {
         if let Some(last_match) = last_match {
-            if sorted.len() != last_match.len() {
-                return Err(QueryExecError::new(
-                    "Inconsistent number of spans in match results.",
-                ));
-            }
-            let is_duplicate = last_match
-                .iter()
-                .zip(sorted.iter())
-                .all(|(a, b)| a.0 == b.0);
+            // Matches can have different numbers of spans if some are optional.
+            let is_duplicate = sorted.len() == last_match.len()
+                && last_match
+                    .iter()
+                    .zip(sorted.iter())
+                    .all(|(a, b)| a.0 == b.0);
             if is_duplicate {
                 continue;
             }
@@ -565,33 +906,180 @@ fn leaders_for_candidate<'a>(
             // We have spans that cross work boundaries.
             continue;
         }
+        if !within_scope(&sorted, scope) {
+            // The match starts in the scope but runs past the end of it.
+            continue;
+        }
         if !has_matching_inflections(&unsorted, needs_validation, corpus)? {
             // We have inflections constraints that don't match one singular analysis.
             continue;
         }
+        if !has_agreeing_inflections(&unsorted, agreements, corpus)? {
+            // Some tokens that need to agree don't have analyses in common.
+            continue;
+        }
         return Ok(Some(sorted));
     }
     Ok(None)
 }
 
+/// The range of every token in the corpus, for reading the hard breaks.
+fn whole_corpus(corpus: &CorpusQueryEngine) -> IndexRange {
+    IndexRange {
+        start: 0,
+        end: corpus.corpus.num_tokens.div_ceil(64) * 64,
+    }
+}
+
+/// Returns the hard breaks in the `range`, if any of the spans need them to check
+/// their relation with the previous span (and an empty bitmask otherwise).
+fn sentence_breaks<'a>(
+    corpus: &'a CorpusQueryEngine,
+    all_span_candidates: &[SpanResult],
+    range: &'a IndexRange,
+) -> Result<&'a [u64], QueryExecError> {
+    if all_span_candidates.iter().any(needs_hard_breaks) {
+        corpus.get_hard_breaks(range)
+    } else {
+        Ok(&[])
+    }
+}
+
+/// Returns the first token of each work in the corpus.
+fn work_starts(corpus: &CorpusQueryEngine) -> Vec<u32> {
+    corpus
+        .corpus
+        .work_lookup
+        .iter()
+        .map(|w| w.rows[0].1)
+        .collect()
+}
+
+/// Returns the range of tokens covered by the match, from the first token up to
+/// (but not including) the token after the last.
+pub(super) fn match_range(leaders: &SpanLeaders) -> TokenRange {
+    match (leaders.first(), leaders.last()) {
+        (Some(first), Some(last)) => (first.0, last.0 + last.1.length as u32),
+        _ => (0, 0),
+    }
+}
+
+/// Calls `on_match` with every match from the remaining candidates, in order.
+pub(super) fn for_each_match<'a>(
+    candidates: &mut MatchIterator<'_>,
+    all_span_candidates: &'a [SpanResult],
+    query_spans: &[&[InternalQueryTerm]],
+    scope: &[TokenRange],
+    corpus: &CorpusQueryEngine,
+    options: &QueryOptions,
+    mut on_match: impl FnMut(&SpanLeaders<'a>) -> Result<(), QueryExecError>,
+) -> Result<(), QueryExecError> {
+    let work_bounds = work_starts(corpus);
+    let range = whole_corpus(corpus);
+    let hard_breaks = sentence_breaks(corpus, all_span_candidates, &range)?;
+    let needs_validation = positions_needing_validation(query_spans, corpus, options.strict_mode)?;
+    let agreements = agreement_groups(query_spans, corpus)?;
+    let mut last_match: Option<SpanLeaders<'a>> = None;
+    for token_id in candidates {
+        let leaders = leaders_for_candidate(
+            token_id?,
+            all_span_candidates,
+            &work_bounds,
+            hard_breaks,
+            scope,
+            &needs_validation,
+            &agreements,
+            corpus,
+            last_match.as_ref(),
+        )?;
+        if let Some(leaders) = leaders {
+            on_match(&leaders)?;
+            last_match = Some(leaders);
+        }
+    }
+    Ok(())
+}
+
+/// Returns every match from the remaining candidates.
+pub(super) fn all_matches<'a>(
+    candidates: &mut MatchIterator<'_>,
+    all_span_candidates: &'a [SpanResult],
+    query_spans: &[&[InternalQueryTerm]],
+    scope: &[TokenRange],
+    corpus: &CorpusQueryEngine,
+    options: &QueryOptions,
+) -> Result<Vec<SpanLeaders<'a>>, QueryExecError> {
+    let mut matches = vec![];
+    for_each_match(
+        candidates,
+        all_span_candidates,
+        query_spans,
+        scope,
+        corpus,
+        options,
+        |leaders| {
+            matches.push(leaders.clone());
+            Ok(())
+        },
+    )?;
+    Ok(matches)
+}
+
+/// Returns a uniform random sample of (at most) `sample_size` matches from the remaining
+/// candidates, in no particular order, along with the total number of matches.
+/// The sample only depends on the seed and the matches.
+pub(super) fn sampled_matches<'a>(
+    candidates: &mut MatchIterator<'_>,
+    all_span_candidates: &'a [SpanResult],
+    query_spans: &[&[InternalQueryTerm]],
+    scope: &[TokenRange],
+    corpus: &CorpusQueryEngine,
+    options: &QueryOptions,
+    seed: u64,
+) -> Result<(Vec<SpanLeaders<'a>>, usize), QueryExecError> {
+    let sample_size = options.sample_size.unwrap_or(0);
+    let mut sample: Vec<SpanLeaders<'a>> = Vec::with_capacity(sample_size);
+    let mut total = 0;
+    for_each_match(
+        candidates,
+        all_span_candidates,
+        query_spans,
+        scope,
+        corpus,
+        options,
+        |leaders| {
+            // Reservoir sampling: the `i`th match replaces a random element of the
+            // sample with probability `sample_size / (i + 1)`.
+            if total < sample_size {
+                sample.push(leaders.clone());
+            } else {
+                let i = seeded_hash(seed, total as u64) % (total as u64 + 1);
+                if let Some(replaced) = sample.get_mut(i as usize) {
+                    *replaced = leaders.clone();
+                }
+            }
+            total += 1;
+            Ok(())
+        },
+    )?;
+    Ok((sample, total))
+}
+
 pub(super) fn get_match_page<'a>(
     candidates: &mut MatchIterator<'_>,
     all_span_candidates: &'a [SpanResult],
     query_spans: &[&[InternalQueryTerm]],
+    scope: &[TokenRange],
     corpus: &CorpusQueryEngine,
     current_page: &PageData,
-    total_candidates: usize,
     options: &QueryOptions,
 ) -> Result<MatchPageResult<'a>, QueryExecError> {
     let page_size = options.page_size;
-    let work_bounds = corpus
-        .corpus
-        .work_lookup
-        .iter()
-        .map(|w| w.rows[0].1)
-        .collect::<Vec<u32>>();
-    let needs_validation =
-        positions_needing_validation(query_spans, &corpus.corpus.id_table, options.strict_mode)?;
+    let work_bounds = work_starts(corpus);
+    let range = whole_corpus(corpus);
+    let hard_breaks = sentence_breaks(corpus, all_span_candidates, &range)?;
+    let needs_validation = positions_needing_validation(query_spans, corpus, options.strict_mode)?;
+    let agreements = agreement_groups(query_spans, corpus)?;
     let mut matches = vec![];
     let mut skipped_candidates = 0;
 
@@ -604,7 +1092,10 @@ pub(super) fn get_match_page<'a>(
             token_id,
             all_span_candidates,
             &work_bounds,
+            hard_breaks,
+            scope,
             &needs_validation,
+            &agreements,
             corpus,
             matches.last(),
         )?;
@@ -624,7 +1115,10 @@ pub(super) fn get_match_page<'a>(
             token_id,
             all_span_candidates,
             &work_bounds,
+            hard_breaks,
+            scope,
             &needs_validation,
+            &agreements,
             corpus,
             matches.last(),
         )?;
@@ -638,10 +1132,12 @@ pub(super) fn get_match_page<'a>(
                 + page_size as u32
                 + skipped_candidates as u32,
             result_id: token_id,
+            sample_seed: None,
         });
         break;
     }
 
+    let total_candidates = candidates.num_candidates();
     let summary_info = get_result_stats(total_candidates, matches.len(), current_page, &next_page);
     Ok(MatchPageResult {
         matches,
@@ -651,6 +1147,39 @@ pub(super) fn get_match_page<'a>(
 }
 
 impl CorpusQueryEngine {
+    /// Returns the text of the token, normalized in the same way as the `word` index.
+    pub(super) fn normalized_word(&self, token: u32) -> Result<String, QueryExecError> {
+        let start = self.starts.token_start(token)?;
+        let end = self.starts.break_start(token)?;
+        Ok(self.text.slice(start, end).to_lowercase())
+    }
+
+    /// Returns the lemmata from `form_lemmata` that the tokens in the match could have.
+    fn form_lemmata_in_match<'a>(
+        &self,
+        match_leaders: &SpanLeaders,
+        form_lemmata: &FormLemmata<'a>,
+    ) -> Result<Vec<&'a String>, QueryExecError> {
+        let mut lemmata: Vec<&'a String> = vec![];
+        if form_lemmata.is_empty() {
+            return Ok(lemmata);
+        }
+        for (start, span) in match_leaders {
+            for token in *start..(*start + span.length as u32) {
+                for data in self.inflections.get_inflection_data(token)? {
+                    let lemma_id = (data >> 32) as u32;
+                    let Ok(i) = form_lemmata.binary_search_by_key(&lemma_id, |(id, _)| *id) else {
+                        continue;
+                    };
+                    if !lemmata.contains(&form_lemmata[i].1) {
+                        lemmata.push(form_lemmata[i].1);
+                    }
+                }
+            }
+        }
+        Ok(lemmata)
+    }
+
     #[inline]
     fn resolve_text_ranges(
         &self,
@@ -697,18 +1226,22 @@ impl CorpusQueryEngine {
         Ok(text_chunks)
     }
 
-    pub(super) fn resolve_match_tokens(
-        &self,
+    pub(super) fn resolve_match_tokens<'a>(
+        &'a self,
         matches: Vec<SpanLeaders>,
         context_len: u32,
-    ) -> Result<Vec<CorpusQueryMatch<'_>>, QueryExecError> {
+        context_mode: ContextMode,
+        form_lemmata: &FormLemmata<'a>,
+        analyses: MatchAnalyses,
+    ) -> Result<Vec<CorpusQueryMatch<'a>>, QueryExecError> {
         if matches.is_empty() {
             return Ok(vec![]);
         }
 
+        let contexts = self.context_sizes(&matches, context_len, context_mode)?;
         let mut starts: Vec<(Vec<usize>, Vec<bool>)> = Vec::with_capacity(matches.len());
-        for match_leaders in &matches {
-            let span_ranges = compute_offsets(match_leaders, context_len, self.corpus.num_tokens)?;
+        for (match_leaders, context) in matches.iter().zip(&contexts) {
+            let span_ranges = compute_offsets(match_leaders, *context, self.corpus.num_tokens)?;
             let mut offsets: Vec<usize> = Vec::with_capacity(span_ranges.len());
             let mut is_core_match: Vec<bool> = Vec::with_capacity(span_ranges.len());
             for (i, (token_offset, is_core)) in span_ranges.iter().enumerate() {
@@ -734,23 +1267,43 @@ impl CorpusQueryEngine {
         // Compute the metadata while the OS is (hopefully) loading the pages into memory.
         let mut all_metadata: Vec<CorpusQueryMatchMetadata> = Vec::with_capacity(matches.len());
         for match_leaders in &matches {
-            all_metadata.push(
-                self.corpus.resolve_match_token(
-                    match_leaders
-                        .iter()
-                        .map(|(id, span)| (*id, span.length))
-                        .collect(),
-                )?,
-            );
+            let mut metadata = self.corpus.resolve_match_token(
+                match_leaders
+                    .iter()
+                    .map(|(id, span)| (*id, span.length))
+                    .collect(),
+            )?;
+            metadata.lemmata = self.form_lemmata_in_match(match_leaders, form_lemmata)?;
+            all_metadata.push(metadata);
         }
 
+        let lemma_names = match analyses {
+            MatchAnalyses::None => vec![],
+            _ => self.lemma_names(),
+        };
         let matches = starts
             .into_iter()
             .zip(all_metadata)
-            .map(|((byte_offsets, is_core), metadata)| {
-                let text = self.resolve_text_ranges(byte_offsets, is_core, &metadata)?;
-                Ok(CorpusQueryMatch { metadata, text })
-            })
+            .zip(matches.iter().zip(contexts))
+            .map(
+                |(((byte_offsets, is_core), metadata), (match_leaders, context))| {
+                    let text = self.resolve_text_ranges(byte_offsets, is_core, &metadata)?;
+                    let tokens = self.match_tokens(
+                        match_leaders,
+                        &metadata,
+                        context,
+                        analyses,
+                        &lemma_names,
+                    )?;
+                    let captures = self.match_captures(match_leaders)?;
+                    Ok(CorpusQueryMatch {
+                        metadata,
+                        text,
+                        tokens,
+                        captures,
+                    })
+                },
+            )
             .collect::<Result<Vec<_>, QueryExecError>>()?;
 
         Ok(matches)
@@ -764,7 +1317,7 @@ mod tests {
         corpus_query_engine::{
             IndexDataRoO,
             corpus_index_calculation::SpanResult,
-            corpus_result_resolution::find_span_leader,
+            corpus_result_resolution::{Anchor, find_span_leader},
             index_data::{IndexDataOwned, IndexRange, IndexSlice},
         },
         query_parsing_v2::QueryRelation,
@@ -786,10 +1339,13 @@ mod tests {
             relation: QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             },
+            optional: false,
+            labels: vec![],
         };
 
-        let leader = find_span_leader(27, &span_result);
+        let leader = find_span_leader(&Anchor::new(27, 1), &span_result);
 
         assert_eq!(leader.unwrap(), vec![23]);
     }
@@ -812,10 +1368,13 @@ mod tests {
             relation: QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             },
+            optional: false,
+            labels: vec![],
         };
 
-        let leader = find_span_leader(27, &span_result);
+        let leader = find_span_leader(&Anchor::new(27, 1), &span_result);
 
         assert_eq!(leader.unwrap(), vec![23]);
     }
@@ -838,10 +1397,13 @@ mod tests {
             relation: QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             },
+            optional: false,
+            labels: vec![],
         };
 
-        let leader = find_span_leader(27, &span_result);
+        let leader = find_span_leader(&Anchor::new(27, 1), &span_result);
 
         assert_eq!(leader.unwrap(), vec![23, 31]);
     }
@@ -862,10 +1424,13 @@ mod tests {
             relation: QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             },
+            optional: false,
+            labels: vec![],
         };
 
-        let leader = find_span_leader(25, &span_result);
+        let leader = find_span_leader(&Anchor::new(25, 1), &span_result);
 
         assert_eq!(leader.unwrap(), vec![21]);
     }
@@ -886,11 +1451,139 @@ mod tests {
             relation: QueryRelation::Proximity {
                 distance: 5,
                 is_directed: false,
+                within_sentence: false,
             },
+            optional: false,
+            labels: vec![],
         };
 
-        let leader = find_span_leader(25, &span_result);
+        let leader = find_span_leader(&Anchor::new(25, 1), &span_result);
 
         assert_eq!(leader.unwrap(), vec![21, 29]);
     }
+
+    use super::{AnalysisConstraint, TokenValidationInfo, does_inflection_match, readings_of};
+
+    const NOM: u32 = 1;
+    const ACC: u32 = 1 << 1;
+    const DAT: u32 = 1 << 2;
+    const SG: u32 = 1 << 7;
+    const PL: u32 = 1 << 8;
+
+    fn validation_info(
+        constraint: AnalysisConstraint<'_>,
+        strict: bool,
+    ) -> TokenValidationInfo<'_> {
+        TokenValidationInfo {
+            span_idx: 0,
+            term_idx: 0,
+            constraint,
+            strict,
+        }
+    }
+
+    fn analysis(lemma: u32, inflection: u32) -> u64 {
+        ((lemma as u64) << 32) | inflection as u64
+    }
+
+    /// Whether the analysis matches, for a token that has no other analyses.
+    fn does_match(analysis: u64, info: &TokenValidationInfo) -> bool {
+        does_inflection_match(0, analysis, &[analysis], info)
+    }
+
+    #[test]
+    fn test_readings_of_expands_categories() {
+        let readings = |analysis| readings_of(analysis).collect::<Vec<_>>();
+        assert_eq!(readings(0), vec![0]);
+        assert_eq!(readings(NOM | PL), vec![NOM | PL]);
+        assert_eq!(
+            readings(NOM | ACC | SG | PL),
+            vec![NOM | SG, NOM | PL, ACC | SG, ACC | PL]
+        );
+        assert_eq!(
+            readings(NOM | ACC | DAT | PL),
+            vec![NOM | PL, ACC | PL, DAT | PL]
+        );
+    }
+
+    #[test]
+    fn test_does_inflection_match_conjunction_with_negation() {
+        let info = validation_info(
+            AnalysisConstraint::And(vec![
+                AnalysisConstraint::Inflection(NOM),
+                AnalysisConstraint::Not(Box::new(AnalysisConstraint::Inflection(ACC))),
+                AnalysisConstraint::Not(Box::new(AnalysisConstraint::Lemma(Some(7)))),
+            ]),
+            false,
+        );
+
+        assert!(does_match(analysis(1, NOM), &info));
+        assert!(!does_match(analysis(1, NOM | ACC), &info));
+        assert!(!does_match(analysis(7, NOM), &info));
+    }
+
+    #[test]
+    fn test_does_inflection_match_disjunction_with_negation() {
+        let info = validation_info(
+            AnalysisConstraint::Or(vec![
+                AnalysisConstraint::Inflection(NOM),
+                AnalysisConstraint::Not(Box::new(AnalysisConstraint::Inflection(ACC))),
+            ]),
+            false,
+        );
+
+        assert!(does_match(analysis(1, NOM | ACC), &info));
+        assert!(does_match(analysis(1, 0), &info));
+        assert!(!does_match(analysis(1, ACC), &info));
+    }
+
+    #[test]
+    fn test_does_inflection_match_negation_applies_to_token() {
+        // (@lemma:1 and !@case:nom)
+        let info = validation_info(
+            AnalysisConstraint::And(vec![
+                AnalysisConstraint::Lemma(Some(1)),
+                AnalysisConstraint::Not(Box::new(AnalysisConstraint::Inflection(NOM))),
+            ]),
+            false,
+        );
+        let analyses = [analysis(1, NOM | SG), analysis(1, DAT | SG)];
+        // The dative analysis isn't nominative, but the token has a nominative analysis.
+        for analysis in analyses {
+            assert!(!does_inflection_match(0, analysis, &analyses, &info));
+        }
+        let analyses = [analysis(1, ACC | SG), analysis(1, DAT | SG)];
+        for analysis in analyses {
+            assert!(does_inflection_match(0, analysis, &analyses, &info));
+        }
+    }
+
+    #[test]
+    fn test_does_inflection_match_nested() {
+        // ((nom and sg) or (dat and pl))
+        let info = |strict| {
+            validation_info(
+                AnalysisConstraint::Or(vec![
+                    AnalysisConstraint::And(vec![
+                        AnalysisConstraint::Inflection(NOM),
+                        AnalysisConstraint::Inflection(SG),
+                    ]),
+                    AnalysisConstraint::And(vec![
+                        AnalysisConstraint::Inflection(DAT),
+                        AnalysisConstraint::Inflection(PL),
+                    ]),
+                ]),
+                strict,
+            )
+        };
+
+        assert!(does_match(analysis(1, NOM | SG), &info(false)));
+        assert!(does_match(analysis(1, DAT | PL), &info(false)));
+        assert!(!does_match(analysis(1, NOM | PL), &info(false)));
+        assert!(!does_match(analysis(1, DAT | SG), &info(false)));
+        // Some, but not all, readings match.
+        assert!(does_match(analysis(1, NOM | DAT | SG), &info(false)));
+        assert!(!does_match(analysis(1, NOM | DAT | SG), &info(true)));
+        assert!(does_match(analysis(1, NOM | SG), &info(true)));
+    }
 }
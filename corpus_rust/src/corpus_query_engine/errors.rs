@@ -1,9 +1,24 @@
-use crate::corpus_query_engine::QueryExecError;
+use crate::{api::QueryContradiction, corpus_query_engine::QueryExecError};
 
 impl QueryExecError {
     pub(super) fn new(message: &str) -> Self {
         QueryExecError {
             message: message.to_string(),
+            contradiction: None,
+        }
+    }
+
+    /// An error for a query that can't have any matches.
+    pub(super) fn contradiction(contradiction: QueryContradiction) -> Self {
+        let message = format!(
+            "Term {} can never match ({}): {}",
+            contradiction.term + 1,
+            contradiction.atoms.join(", "),
+            contradiction.reason
+        );
+        QueryExecError {
+            message,
+            contradiction: Some(contradiction),
         }
     }
 }
@@ -333,6 +333,52 @@ pub fn apply_or_to_indices<'a, 'b>(
     })
 }
 
+/// Computes the complement of the given index within its range.
+///
+/// The result is always a bitmask, and never includes tokens at or after `num_tokens`.
+pub fn apply_not_to_index<'a>(
+    index: &IndexSlice<'a>,
+    num_tokens: u32,
+) -> Result<IndexSlice<'a>, String> {
+    let range = index.range;
+    if !range.has_word_bounds() {
+        return Err("Range must be aligned to 64-bit word boundaries".to_string());
+    }
+    let num_words = ((range.end - range.start) / 64) as usize;
+    let mut result = match index.data.to_ref() {
+        IndexData::BitMask(bitmask) => {
+            let mut result = bitmask.iter().map(|word| !word).collect::<Vec<_>>();
+            result.resize(num_words, u64::MAX);
+            result
+        }
+        IndexData::List(list) => {
+            let mut result = vec![u64::MAX; num_words];
+            for &id in list {
+                if id < range.start || id >= range.end {
+                    continue;
+                }
+                let bitmask_id = id - range.start;
+                result[(bitmask_id / 64) as usize] &= !(1 << (bitmask_id % 64));
+            }
+            result
+        }
+    };
+    // Clear out any bits for tokens that don't exist.
+    for (i, word) in result.iter_mut().enumerate() {
+        let word_start = range.start + (i as u32) * 64;
+        if word_start >= num_tokens {
+            *word = 0;
+        } else if num_tokens - word_start < 64 {
+            *word &= (1 << (num_tokens - word_start)) - 1;
+        }
+    }
+    Ok(IndexSlice {
+        data: IndexDataRoO::Owned(IndexDataOwned::BitMask(result)),
+        range,
+        position: index.position,
+    })
+}
+
 /// Returns the matches that are within a maximum fuzz distance applied.
 ///
 /// The results are always returned relative to the `first` index and its positioning.
@@ -342,8 +388,8 @@ pub fn find_fuzzy_matches<'a>(
     max_dist: usize,
     dir: Direction,
 ) -> Result<IndexSlice<'a>, String> {
-    if max_dist == 0 || max_dist >= 16 {
-        return Err("max_distance must be between 1 and 15".to_string());
+    if max_dist == 0 {
+        return Err("max_distance must be at least 1".to_string());
     }
     let offset = first.position as i32 - second.position as i32;
 
@@ -387,6 +433,93 @@ pub fn find_fuzzy_matches<'a>(
     })
 }
 
+/// Finds fuzzy matches like `find_fuzzy_matches`, except that a match can't have a hard
+/// break between the leader of `first` and the leader of `second`.
+///
+/// ## Arguments
+///
+/// * `hard_breaks` - The hard breaks over the same range as the indices, where a bit set
+///   at `i` means that there's a break after token `i`.
+pub fn find_fuzzy_matches_within_breaks<'a>(
+    first: &IndexSlice<'a>,
+    second: &IndexSlice<'a>,
+    max_dist: usize,
+    dir: Direction,
+    hard_breaks: &[u64],
+) -> Result<IndexSlice<'a>, String> {
+    if max_dist == 0 {
+        return Err("max_distance must be at least 1".to_string());
+    }
+    if first.range != second.range {
+        return Err("Cannot find matches between indices with different ranges".to_string());
+    }
+    if second.position >= 64 {
+        return Err("Index position must be less than 64".to_string());
+    }
+    let start = first.range.start;
+    // The smear has to stop at breaks, so the second index is always smeared as a bitmask.
+    let second_bitmask = match second.data.to_ref() {
+        IndexData::BitMask(bm) => bm.to_vec(),
+        IndexData::List(arr) => {
+            let mut bitmask = vec![0u64; hard_breaks.len()];
+            for &id in arr {
+                let bit = (id - start) as usize;
+                if bit / 64 < bitmask.len() {
+                    bitmask[bit / 64] |= 1 << (bit % 64);
+                }
+            }
+            bitmask
+        }
+    };
+    if second_bitmask.len() != hard_breaks.len() {
+        return Err("Hard breaks must cover the same range as the index".to_string());
+    }
+    if second_bitmask.is_empty() {
+        return Ok(IndexSlice {
+            data: IndexDataRoO::Owned(IndexDataOwned::List(vec![])),
+            range: first.range,
+            position: first.position,
+        });
+    }
+    // The breaks are between leaders, so they need to be moved to where the second index is.
+    let breaks = match second.position {
+        0 => hard_breaks.to_vec(),
+        position => {
+            let zeros = vec![0u64; hard_breaks.len()];
+            bitmask_utils::apply_or_with_bitmasks(&zeros, hard_breaks, position as isize)
+        }
+    };
+    let smeared =
+        bitmask_utils::smear_bitmask_within_breaks(&second_bitmask, max_dist, dir, &breaks);
+    let offset = first.position as i64 - second.position as i64;
+
+    let index = match first.data.to_ref() {
+        IndexData::BitMask(bm) => {
+            if bm.len() != smeared.len() {
+                return Err("Hard breaks must cover the same range as the index".to_string());
+            }
+            let result = bitmask_utils::apply_and_with_bitmasks(bm, &smeared, offset as isize);
+            IndexDataOwned::BitMask(result)
+        }
+        IndexData::List(arr) => {
+            let overlaps = arr
+                .iter()
+                .copied()
+                .filter(|&id| {
+                    let bit = id as i64 - offset - start as i64;
+                    bit >= 0 && bitmask_utils::is_bit_set(&smeared, bit as usize)
+                })
+                .collect();
+            IndexDataOwned::List(overlaps)
+        }
+    };
+    Ok(IndexSlice {
+        data: IndexDataRoO::Owned(index),
+        range: first.range,
+        position: first.position,
+    })
+}
+
 /// Returns the numbers that are present in both input arrays, applying an offset to the second array.
 /// and which has a maximum fuzz distance applied.
 ///
@@ -1377,6 +1510,47 @@ mod tests {
         assert_eq!(result, to_slice_list(&[10, 30], 5));
     }
 
+    #[test]
+    fn find_fuzzy_matches_within_breaks_stops_at_breaks() {
+        // Breaks after tokens 15 and 25.
+        let breaks = to_bitmask(&[15, 25], 192);
+        let first_bm = to_bitmask(&[10, 20, 30], 192);
+        let second_bm = to_bitmask(&[12, 28], 192);
+        let firsts = [
+            to_slice(IndexData::List(&[10, 20, 30]), 0),
+            to_slice(IndexData::BitMask(&first_bm), 0),
+        ];
+        let seconds = [
+            to_slice(IndexData::List(&[12, 28]), 0),
+            to_slice(IndexData::BitMask(&second_bm), 0),
+        ];
+        for first in &firsts {
+            for second in &seconds {
+                let result =
+                    find_fuzzy_matches_within_breaks(first, second, 10, Both, &breaks).unwrap();
+                let ids = match result.data.to_ref() {
+                    IndexData::BitMask(bm) => from_bitmask(bm),
+                    IndexData::List(arr) => arr.to_vec(),
+                };
+                assert_eq!(ids, vec![10, 30]);
+            }
+        }
+    }
+
+    #[test]
+    fn find_fuzzy_matches_within_breaks_uses_leader_positions() {
+        // The leader of the first index is at 20, and the leaders of the second are
+        // at 12 and 26. Only the break after 17 is between any of the leaders.
+        let breaks = to_bitmask(&[17, 27], 192);
+        let first = to_slice(IndexData::List(&[22]), 2);
+        let second = to_slice(IndexData::List(&[15, 29]), 3);
+
+        let result = find_fuzzy_matches_within_breaks(&first, &second, 10, Left, &breaks).unwrap();
+        assert_eq!(result, to_slice_list(&[22], 2));
+        let result = find_fuzzy_matches_within_breaks(&first, &second, 10, Right, &breaks).unwrap();
+        assert_eq!(result, to_slice_list(&[], 2));
+    }
+
     #[test]
     fn find_fuzzy_matches_with_array_and_bitmask_bitmask_start_nonzero() {
         // bitmask relative [1,6] with start 64 => absolute [65,70]
@@ -1398,4 +1572,61 @@ mod tests {
         let expected = to_bitmask(&[1, 6], 128);
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn apply_not_to_index_list() {
+        let index = to_slice_list(&[0, 5, 64, 150], 2);
+        let result = apply_not_to_index(&index, 192).unwrap();
+
+        let expected = (0..192)
+            .filter(|x| ![0, 5, 64, 150].contains(x))
+            .collect::<Vec<_>>();
+        assert_eq!(result, to_slice_bitmask(&expected, 192, 2));
+    }
+
+    #[test]
+    fn apply_not_to_index_bitmask() {
+        let index = to_slice_bitmask(&[1, 63, 100], 192, 0);
+        let result = apply_not_to_index(&index, 192).unwrap();
+
+        let expected = (0..192)
+            .filter(|x| ![1, 63, 100].contains(x))
+            .collect::<Vec<_>>();
+        assert_eq!(result, to_slice_bitmask(&expected, 192, 0));
+    }
+
+    #[test]
+    fn apply_not_to_index_ignores_tokens_past_end() {
+        let index = to_slice_list(&[3], 0);
+        let result = apply_not_to_index(&index, 70).unwrap();
+
+        let expected = (0..70).filter(|x| *x != 3).collect::<Vec<_>>();
+        assert_eq!(result, to_slice_bitmask(&expected, 192, 0));
+    }
+
+    #[test]
+    fn apply_not_to_index_empty_list() {
+        let index = to_slice_list(&[], 0);
+        let result = apply_not_to_index(&index, 130).unwrap();
+
+        let expected = (0..130).collect::<Vec<_>>();
+        assert_eq!(result, to_slice_bitmask(&expected, 192, 0));
+    }
+
+    #[test]
+    fn apply_not_to_index_with_nonzero_range_start() {
+        let range = IndexRange {
+            start: 64,
+            end: 128,
+        };
+        let list = [65, 127];
+        let index = IndexSlice::from(&IndexData::List(&list), &range, 0).unwrap();
+        let result = apply_not_to_index(&index, 200).unwrap();
+
+        let expected = (0..64).filter(|x| *x != 1 && *x != 63).collect::<Vec<_>>();
+        assert_eq!(
+            result.data,
+            IndexDataRoO::Owned(IndexDataOwned::BitMask(to_bitmask(&expected, 64)))
+        );
+    }
 }
@@ -0,0 +1,109 @@
+use crate::{
+    api::MatchCapture,
+    corpus_query_engine::{
+        CorpusQueryEngine, QueryExecError, corpus_index_calculation::SpanLabel,
+        corpus_result_resolution::SpanLeaders,
+    },
+};
+
+impl CorpusQueryEngine {
+    /// Returns the tokens of the match that satisfied labeled terms, in the order of the
+    /// terms in the query.
+    pub(super) fn match_captures(
+        &self,
+        leaders: &SpanLeaders,
+    ) -> Result<Vec<MatchCapture<'_>>, QueryExecError> {
+        let mut labeled = leaders
+            .iter()
+            .flat_map(|(leader, span)| span.labels.iter().map(move |label| (leader, label)))
+            .collect::<Vec<_>>();
+        labeled.sort_by_key(|(_, label)| label.term);
+        labeled
+            .into_iter()
+            .map(|(leader, SpanLabel { offset, label, .. })| {
+                let id = leader + offset;
+                let (work, row) = self.location_of(id)?;
+                let section = &self.corpus.work_lookup[work].rows[row];
+                let text = self
+                    .text
+                    .slice(self.starts.token_start(id)?, self.starts.break_start(id)?);
+                Ok(MatchCapture {
+                    label: label.clone(),
+                    id,
+                    section: &section.0,
+                    offset: id - section.1,
+                    text,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        build_corpus_v2::test_utils,
+        corpus_query_engine::{
+            corpus_index_calculation::{SpanLabel, SpanResult},
+            index_data::{IndexDataOwned, IndexDataRoO, IndexRange, IndexSlice},
+        },
+        query_parsing_v2::QueryRelation,
+    };
+
+    fn span<'a>(range: &'a IndexRange, leader: u32, labels: Vec<SpanLabel>) -> SpanResult<'a> {
+        SpanResult {
+            candidates: IndexSlice {
+                data: IndexDataRoO::Owned(IndexDataOwned::List(vec![leader])),
+                range,
+                position: 0,
+            },
+            length: 2,
+            relation: QueryRelation::First,
+            optional: false,
+            labels,
+        }
+    }
+
+    fn label(offset: u32, term: usize, label: &str) -> SpanLabel {
+        SpanLabel {
+            offset,
+            term,
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn match_captures_are_in_term_order() {
+        let engine = test_utils::build_test_engine("match_captures");
+        let range = IndexRange { start: 0, end: 64 };
+        let first = span(&range, 10, vec![label(1, 2, "case")]);
+        let second = span(&range, 13, vec![label(0, 0, "noun"), label(1, 1, "verb")]);
+        let captures = engine
+            .match_captures(&vec![(10, &first), (13, &second)])
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.label, c.id, c.section.as_str(), c.offset, c.text))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            captures,
+            vec![
+                ("noun".to_string(), 13, "1.2", 0, "rosa".to_string()),
+                ("verb".to_string(), 14, "1.2", 1, "datur".to_string()),
+                ("case".to_string(), 11, "1.1", 1, "puellis".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn match_captures_without_labels() {
+        let engine = test_utils::build_test_engine("match_captures_unlabeled");
+        let range = IndexRange { start: 0, end: 64 };
+        let unlabeled = span(&range, 0, vec![]);
+        assert!(
+            engine
+                .match_captures(&vec![(0, &unlabeled)])
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use crate::{
+    api::ContextMode,
+    bitmask_utils::is_bit_set,
+    corpus_query_engine::{
+        CorpusQueryEngine, QueryExecError, corpus_result_resolution::SpanLeaders,
+        index_data::IndexRange,
+    },
+};
+
+impl FromStr for ContextMode {
+    type Err = QueryExecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(ContextMode::Tokens),
+            "sentence" => Ok(ContextMode::Sentence),
+            "section" => Ok(ContextMode::Section),
+            _ => Err(QueryExecError::new(&format!(
+                "Unknown context mode '{s}' (expected tokens, sentence, or section)"
+            ))),
+        }
+    }
+}
+
+impl CorpusQueryEngine {
+    /// Returns the number of tokens of context to show before and after each match.
+    /// For sentences and sections, the context stops at the boundary or after
+    /// `context_len` tokens, whichever comes first.
+    pub(super) fn context_sizes(
+        &self,
+        matches: &[SpanLeaders],
+        context_len: u32,
+        mode: ContextMode,
+    ) -> Result<Vec<(u32, u32)>, QueryExecError> {
+        let range = IndexRange {
+            start: 0,
+            end: self.corpus.num_tokens.div_ceil(64) * 64,
+        };
+        let hard_breaks = match mode {
+            ContextMode::Sentence => self.get_hard_breaks(&range)?,
+            _ => &[],
+        };
+        matches
+            .iter()
+            .map(|leaders| {
+                let (Some(first), Some(last)) = (leaders.first(), leaders.last()) else {
+                    return Err(QueryExecError::new("No leaders provided"));
+                };
+                let start = first.0;
+                let end = last.0 + last.1.length as u32;
+                match mode {
+                    ContextMode::Tokens => Ok((context_len, context_len)),
+                    ContextMode::Sentence => {
+                        Ok(self.sentence_context(hard_breaks, (start, end), context_len))
+                    }
+                    ContextMode::Section => self.section_context((start, end), context_len),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the context sizes that extend to the sentence boundaries around the
+    /// tokens in `[start, end)`.
+    fn sentence_context(
+        &self,
+        hard_breaks: &[u64],
+        (start, end): (u32, u32),
+        context_len: u32,
+    ) -> (u32, u32) {
+        let mut left = 0;
+        while left < context_len
+            && left < start
+            && !is_bit_set(hard_breaks, (start - left - 1) as usize)
+        {
+            left += 1;
+        }
+        let mut right = 0;
+        while right < context_len
+            && end + right < self.corpus.num_tokens
+            && !is_bit_set(hard_breaks, (end + right - 1) as usize)
+        {
+            right += 1;
+        }
+        (left, right)
+    }
+
+    /// Returns the context sizes that extend to the boundaries of the sections
+    /// containing the tokens in `[start, end)`.
+    fn section_context(
+        &self,
+        (start, end): (u32, u32),
+        context_len: u32,
+    ) -> Result<(u32, u32), QueryExecError> {
+        let section_of = |token| {
+            let (work, row) = self.location_of(token)?;
+            Ok::<_, QueryExecError>(&self.corpus.work_lookup[work].rows[row])
+        };
+        let first_section = section_of(start)?;
+        let last_section = section_of(end - 1)?;
+        Ok((
+            (start - first_section.1).min(context_len),
+            (last_section.2 - end).min(context_len),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        bitmask_utils::to_bitmask,
+        build_corpus_v2::test_utils,
+        corpus_query_engine::{
+            corpus_index_calculation::SpanResult,
+            index_data::{IndexDataOwned, IndexDataRoO, IndexSlice},
+        },
+        query_parsing_v2::QueryRelation,
+    };
+
+    #[test]
+    fn sentence_context_stops_at_hard_breaks() {
+        let engine = test_utils::build_test_engine("sentence_context");
+        let hard_breaks = to_bitmask(&[3, 9], 64);
+        assert_eq!(engine.sentence_context(&hard_breaks, (6, 7), 5), (2, 3));
+        assert_eq!(engine.sentence_context(&hard_breaks, (6, 7), 1), (1, 1));
+    }
+
+    #[test]
+    fn sentence_context_stops_at_corpus_bounds() {
+        let engine = test_utils::build_test_engine("sentence_context_bounds");
+        assert_eq!(engine.sentence_context(&[], (0, 1), 3), (0, 3));
+        assert_eq!(engine.sentence_context(&[], (15, 16), 2), (2, 0));
+    }
+
+    #[test]
+    fn section_context_stops_at_section_bounds() {
+        let engine = test_utils::build_test_engine("section_context");
+        assert_eq!(engine.section_context((11, 12), 5).unwrap(), (1, 1));
+        assert_eq!(engine.section_context((4, 5), 5).unwrap(), (0, 1));
+        assert_eq!(engine.section_context((15, 16), 1).unwrap(), (1, 0));
+        // The match spans two sections, so the context extends into both.
+        assert_eq!(engine.section_context((7, 9), 5).unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn context_sizes_for_each_mode() {
+        let engine = test_utils::build_test_engine("context_sizes");
+        let range = IndexRange { start: 0, end: 64 };
+        let span = SpanResult {
+            candidates: IndexSlice {
+                data: IndexDataRoO::Owned(IndexDataOwned::List(vec![6])),
+                range: &range,
+                position: 0,
+            },
+            length: 2,
+            relation: QueryRelation::First,
+            optional: false,
+            labels: vec![],
+        };
+        // The match is all of section `1` of `w2`, which continues the sentence into section `2`.
+        let matches = vec![vec![(6, &span)]];
+        let sizes = |mode| engine.context_sizes(&matches, 5, mode).unwrap();
+        assert_eq!(sizes(ContextMode::Tokens), vec![(5, 5)]);
+        assert_eq!(sizes(ContextMode::Sentence), vec![(0, 2)]);
+        assert_eq!(sizes(ContextMode::Section), vec![(0, 0)]);
+        assert!(
+            engine
+                .context_sizes(&[vec![]], 5, ContextMode::Tokens)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn context_mode_from_str() {
+        assert_eq!(
+            "section".parse::<ContextMode>().unwrap(),
+            ContextMode::Section
+        );
+        assert!("paragraph".parse::<ContextMode>().is_err());
+    }
+}
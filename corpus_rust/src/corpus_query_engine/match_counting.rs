@@ -0,0 +1,135 @@
+use crate::{
+    api::{AuthorCount, CorpusCountResult, WorkCount},
+    corpus_query_engine::{CorpusQueryEngine, QueryExecError},
+};
+
+impl CorpusQueryEngine {
+    /// Returns the indices of the work and the section (within the work) that contain the token.
+    pub(super) fn location_of(&self, token: u32) -> Result<(usize, usize), QueryExecError> {
+        let works = &self.corpus.work_lookup;
+        let work_idx = works.partition_point(|work| work.rows.last().is_some_and(|r| r.2 <= token));
+        let rows = &works
+            .get(work_idx)
+            .ok_or(QueryExecError::new("Match is not in any work"))?
+            .rows;
+        let row_idx = rows.partition_point(|row| row.2 <= token);
+        if row_idx >= rows.len() {
+            return Err(QueryExecError::new("Match is not in any section"));
+        }
+        Ok((work_idx, row_idx))
+    }
+
+    /// Groups the matches (given by their first tokens) by author, work, and section.
+    pub(super) fn count_by_location(
+        &self,
+        match_starts: &[u32],
+    ) -> Result<CorpusCountResult<'_>, QueryExecError> {
+        let mut locations = match_starts
+            .iter()
+            .map(|&token| self.location_of(token))
+            .collect::<Result<Vec<_>, _>>()?;
+        locations.sort();
+
+        let mut authors: Vec<AuthorCount> = vec![];
+        for (work_idx, row_idx) in locations {
+            let work = &self.corpus.work_lookup[work_idx];
+            let section = &work.rows[row_idx].0;
+            // The works for each author are contiguous, so we only need to check the last one.
+            let author = match authors.last_mut() {
+                Some(last) if last.author == &work.info.author => last,
+                _ => {
+                    authors.push(AuthorCount {
+                        author: &work.info.author,
+                        count: 0,
+                        works: vec![],
+                    });
+                    authors
+                        .last_mut()
+                        .ok_or(QueryExecError::new("Missing author"))?
+                }
+            };
+            author.count += 1;
+            let work_count = match author.works.last_mut() {
+                Some(last) if last.work_id == &work.work_id => last,
+                _ => {
+                    author.works.push(WorkCount {
+                        work_id: &work.work_id,
+                        work_name: &work.info.name,
+                        count: 0,
+                        sections: vec![],
+                    });
+                    author
+                        .works
+                        .last_mut()
+                        .ok_or(QueryExecError::new("Missing work"))?
+                }
+            };
+            work_count.count += 1;
+            match work_count.sections.last_mut() {
+                Some((id, count)) if *id == section => *count += 1,
+                _ => work_count.sections.push((section, 1)),
+            }
+        }
+        Ok(CorpusCountResult {
+            total: match_starts.len(),
+            authors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_corpus_v2::test_utils;
+
+    #[test]
+    fn location_of_finds_work_and_section() {
+        let engine = test_utils::build_test_engine("location_of");
+        assert_eq!(engine.location_of(0).unwrap(), (0, 0));
+        assert_eq!(engine.location_of(3).unwrap(), (0, 0));
+        assert_eq!(engine.location_of(4).unwrap(), (0, 1));
+        assert_eq!(engine.location_of(6).unwrap(), (1, 0));
+        assert_eq!(engine.location_of(15).unwrap(), (2, 1));
+        assert!(engine.location_of(16).is_err());
+    }
+
+    #[test]
+    fn count_by_location_groups_unsorted_matches() {
+        let engine = test_utils::build_test_engine("count_by_location");
+        let counts = engine.count_by_location(&[13, 5, 0, 10, 4]).unwrap();
+        assert_eq!(counts.total, 5);
+        let summary = counts
+            .authors
+            .iter()
+            .map(|author| {
+                let works = author.works.iter().map(|work| {
+                    let sections = work.sections.iter().map(|(id, n)| (id.as_str(), *n));
+                    (work.work_id.as_str(), work.count, sections.collect())
+                });
+                (author.author.as_str(), author.count, works.collect())
+            })
+            .collect::<Vec<(_, _, Vec<(_, _, Vec<_>)>)>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "Author Alpha",
+                    3,
+                    vec![("w1", 3, vec![("1.1", 1), ("1.2", 2)])]
+                ),
+                (
+                    "Author Beta",
+                    2,
+                    vec![("w3", 2, vec![("1.1", 1), ("1.2", 1)])]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn count_by_location_without_matches() {
+        let engine = test_utils::build_test_engine("count_by_location_empty");
+        let counts = engine.count_by_location(&[]).unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.authors.is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+use std::{borrow::Cow, io::Write, str::FromStr};
+
+use crate::{
+    api::{
+        CorpusQueryMatch, ExportFormat, ExportedMatch, MatchAnalyses, PageData, QueryOptions,
+        TokenAnalysis,
+    },
+    corpus_query_engine::{
+        CorpusQueryEngine, MatchIterator, QueryExecError,
+        corpus_result_resolution::{SpanLeaders, for_each_match, match_range},
+    },
+    profiler::TimeProfiler,
+    query_parsing_v2::parse_query,
+};
+
+/// The number of matches that are resolved (and written) at a time.
+const EXPORT_BATCH_SIZE: usize = 256;
+
+const EXPORT_COLUMNS: [&str; 9] = [
+    "work_id",
+    "author",
+    "section",
+    "offset",
+    "length",
+    "left_context",
+    "match",
+    "right_context",
+    "analyses",
+];
+
+impl FromStr for ExportFormat {
+    type Err = QueryExecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "tsv" => Ok(ExportFormat::Tsv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            _ => Err(QueryExecError::new(&format!(
+                "Unknown export format '{s}' (expected csv, tsv, or jsonl)"
+            ))),
+        }
+    }
+}
+
+fn write_error(e: std::io::Error) -> QueryExecError {
+    QueryExecError::new(&format!("Failed to write export: {e}"))
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn tsv_field(value: &str) -> Cow<'_, str> {
+    if value.contains(['\t', '\n', '\r']) {
+        Cow::Owned(value.replace(['\t', '\n', '\r'], " "))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Formats the analyses of each token for a single column, like
+/// `puella (nom sg)|puella (abl sg); do (sg 3rd ind act pres)`.
+fn format_analyses(analyses: &[Vec<TokenAnalysis>]) -> String {
+    analyses
+        .iter()
+        .map(|token| {
+            token
+                .iter()
+                .map(|analysis| match analysis.inflection.as_str() {
+                    "" => analysis.lemma.to_string(),
+                    inflection => format!("{} ({inflection})", analysis.lemma),
+                })
+                .collect::<Vec<_>>()
+                .join("|")
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn write_row(format: ExportFormat, fields: &[&str], out: &mut dyn Write) -> std::io::Result<()> {
+    let (separator, escape): (&str, fn(&str) -> Cow<'_, str>) = match format {
+        ExportFormat::Csv => (",", csv_field),
+        _ => ("\t", tsv_field),
+    };
+    let row = fields
+        .iter()
+        .map(|field| escape(field))
+        .collect::<Vec<_>>()
+        .join(separator);
+    writeln!(out, "{row}")
+}
+
+/// Returns the labels of the terms in the query, which each get their own column.
+fn query_labels(query_str: &str) -> Result<Vec<String>, QueryExecError> {
+    let query = parse_query(query_str).map_err(|e| QueryExecError::new(&e.message))?;
+    Ok(query
+        .terms
+        .into_iter()
+        .filter_map(|term| term.label)
+        .collect())
+}
+
+fn write_match(
+    format: ExportFormat,
+    exported: &ExportedMatch,
+    labels: &[String],
+    out: &mut dyn Write,
+) -> Result<(), QueryExecError> {
+    if format == ExportFormat::JsonLines {
+        serde_json::to_writer(&mut *out, exported)
+            .map_err(|e| QueryExecError::new(&format!("Failed to serialize match: {e}")))?;
+        return writeln!(out).map_err(write_error);
+    }
+    let offset = exported.offset.to_string();
+    let length = exported.length.to_string();
+    let analyses = format_analyses(&exported.analyses);
+    let mut fields = vec![
+        exported.work_id.as_str(),
+        exported.author.as_str(),
+        exported.section.as_str(),
+        &offset,
+        &length,
+        &exported.left_context,
+        &exported.text,
+        &exported.right_context,
+        &analyses,
+    ];
+    // Labeled terms that were left out of the match have empty columns.
+    for label in labels {
+        let capture = exported.captures.iter().find(|c| &c.label == label);
+        fields.push(capture.map_or("", |c| c.text.as_str()));
+    }
+    write_row(format, &fields, out).map_err(write_error)
+}
+
+/// Splits the text of a match into the left context, the match itself, and the right context.
+fn split_text(text: &[(String, bool)]) -> (String, String, String) {
+    let concat = |parts: &[(String, bool)]| parts.iter().map(|(s, _)| s.as_str()).collect();
+    let first = text.iter().position(|(_, is_core)| *is_core);
+    let last = text.iter().rposition(|(_, is_core)| *is_core);
+    match (first, last) {
+        (Some(first), Some(last)) => (
+            concat(&text[..first]),
+            concat(&text[first..=last]),
+            concat(&text[(last + 1)..]),
+        ),
+        _ => (concat(text), String::new(), String::new()),
+    }
+}
+
+fn exported_match<'a>(
+    resolved: CorpusQueryMatch<'a>,
+    length: u32,
+    analyses: Vec<Vec<TokenAnalysis<'a>>>,
+) -> Result<ExportedMatch<'a>, QueryExecError> {
+    let metadata = resolved.metadata;
+    let (section, offset, _) = *metadata
+        .leaders
+        .first()
+        .ok_or(QueryExecError::new("Match has no leaders"))?;
+    let (left_context, text, right_context) = split_text(&resolved.text);
+    Ok(ExportedMatch {
+        captures: resolved.captures,
+        work_id: metadata.work_id,
+        author: metadata.author,
+        section,
+        offset,
+        length,
+        left_context,
+        text,
+        right_context,
+        analyses,
+    })
+}
+
+impl CorpusQueryEngine {
+    /// Resolves and writes a batch of matches, returning the number written.
+    fn write_batch<'a>(
+        &'a self,
+        batch: Vec<SpanLeaders>,
+        options: &QueryOptions,
+        lemma_names: &[Option<&'a String>],
+        (format, labels): (ExportFormat, &[String]),
+        out: &mut dyn Write,
+    ) -> Result<usize, QueryExecError> {
+        let mut details = Vec::with_capacity(batch.len());
+        for leaders in &batch {
+            let (start, end) = match_range(leaders);
+            let analyses = (start..end)
+                .map(|token| self.analyses_of(token, lemma_names))
+                .collect::<Result<Vec<_>, _>>()?;
+            details.push((end - start, analyses));
+        }
+        let resolved = self.resolve_match_tokens(
+            batch,
+            options.context_len as u32,
+            options.context_mode,
+            &vec![],
+            MatchAnalyses::None,
+        )?;
+        let count = resolved.len();
+        for (resolved, (length, analyses)) in resolved.into_iter().zip(details) {
+            write_match(
+                format,
+                &exported_match(resolved, length, analyses)?,
+                labels,
+                out,
+            )?;
+        }
+        Ok(count)
+    }
+
+    /// Writes every match of the query to `out` in the given format, in position order,
+    /// and returns the number of matches. Matches are resolved in small batches, so the
+    /// full set of results is never held in memory.
+    ///
+    /// The `page_size`, `sort`, `sample_size`, and `analyses` options are ignored, since
+    /// the analyses of the matched tokens are always exported. Each labeled term of the
+    /// query gets an extra column (or field, for JSON Lines) with the text of its token.
+    pub fn export_corpus(
+        &self,
+        query_str: &str,
+        options: &QueryOptions,
+        format: ExportFormat,
+        out: &mut dyn Write,
+    ) -> Result<usize, QueryExecError> {
+        let labels = query_labels(query_str)?;
+        if format != ExportFormat::JsonLines {
+            let mut columns = EXPORT_COLUMNS.to_vec();
+            columns.extend(labels.iter().map(String::as_str));
+            write_row(format, &columns, out).map_err(write_error)?;
+        }
+        let mut profiler = TimeProfiler::new();
+        let total = self.run_query(query_str, options, &mut profiler, None, |prepared, _| {
+            let lemma_names = self.lemma_names();
+            let mut candidates = MatchIterator::new(prepared.candidates, &PageData::default());
+            let mut batch = Vec::with_capacity(EXPORT_BATCH_SIZE);
+            let mut total = 0;
+            for_each_match(
+                &mut candidates,
+                prepared.span_candidates,
+                prepared.query_spans,
+                prepared.scope,
+                self,
+                options,
+                |leaders| {
+                    batch.push(leaders.clone());
+                    if batch.len() >= EXPORT_BATCH_SIZE {
+                        let full_batch = std::mem::take(&mut batch);
+                        let output = (format, labels.as_slice());
+                        total +=
+                            self.write_batch(full_batch, options, &lemma_names, output, out)?;
+                    }
+                    Ok(())
+                },
+            )?;
+            let output = (format, labels.as_slice());
+            total += self.write_batch(batch, options, &lemma_names, output, out)?;
+            Ok(total)
+        })?;
+        out.flush().map_err(write_error)?;
+        Ok(total.unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_corpus_v2::test_utils;
+
+    fn export(engine: &CorpusQueryEngine, query: &str, format: ExportFormat) -> (usize, String) {
+        let options = QueryOptions {
+            page_size: 1,
+            context_len: 1,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let total = engine
+            .export_corpus(query, &options, format, &mut out)
+            .unwrap();
+        (total, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn exports_matches_as_json_lines() {
+        let engine = test_utils::build_test_engine("export_jsonl");
+        let (total, output) = export(&engine, "puellis dat", ExportFormat::JsonLines);
+        assert_eq!(total, 3);
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "workId": "w1",
+                "author": "Author Alpha",
+                "section": "1.1",
+                "offset": 2,
+                "length": 2,
+                "leftContext": "rosas ",
+                "text": "puellis dat",
+                "rightContext": ".\nPuella",
+                "analyses": [
+                    [{"lemma": "puella", "inflection": "dat/abl pl"}],
+                    [{"lemma": "do", "inflection": "sg 3rd ind act pres"}],
+                ],
+            })
+        );
+        let works = lines
+            .iter()
+            .map(|l| l["workId"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(works, vec!["w1", "w2", "w3"]);
+    }
+
+    #[test]
+    fn exports_matches_as_csv_and_tsv() {
+        let engine = test_utils::build_test_engine("export_csv");
+        let (_, csv) = export(&engine, "puellis dat", ExportFormat::Csv);
+        // The newline in the right context is quoted, so this is still a single row.
+        let first_row = format!(
+            "{}\nw1,Author Alpha,1.1,2,2,rosas ,puellis dat,\".\nPuella\",\
+             puella (dat/abl pl); do (sg 3rd ind act pres)\n",
+            EXPORT_COLUMNS.join(",")
+        );
+        assert!(csv.starts_with(&first_row), "{csv}");
+
+        let (total, tsv) = export(&engine, "puellis dat", ExportFormat::Tsv);
+        let rows = tsv.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), total + 1);
+        assert_eq!(
+            rows[3],
+            "w3\tAuthor Beta\t1.1\t1\t2\tpuella, \tpuellis dat\t rosa\t\
+             puella (dat/abl pl); do (sg 3rd ind act pres)"
+        );
+    }
+
+    #[test]
+    fn exports_a_column_for_each_label() {
+        let engine = test_utils::build_test_engine("export_labels");
+        let (_, csv) = export(&engine, "noun:puellis verb:dat", ExportFormat::Csv);
+        let rows = csv.lines().collect::<Vec<_>>();
+        assert_eq!(rows[0], format!("{},noun,verb", EXPORT_COLUMNS.join(",")));
+        assert!(
+            csv.contains("; do (sg 3rd ind act pres),puellis,dat\n"),
+            "{csv}"
+        );
+
+        let (_, jsonl) = export(&engine, "noun:puellis dat", ExportFormat::JsonLines);
+        let first = jsonl.lines().next().unwrap();
+        let first = serde_json::from_str::<serde_json::Value>(first).unwrap();
+        assert_eq!(
+            first["captures"],
+            serde_json::json!([
+                {"label": "noun", "id": 2, "section": "1.1", "offset": 2, "text": "puellis"},
+            ])
+        );
+    }
+
+    #[test]
+    fn exports_nothing_without_matches() {
+        let engine = test_utils::build_test_engine("export_empty");
+        let (total, output) = export(&engine, "@lemma:nonexistent", ExportFormat::Csv);
+        assert_eq!(total, 0);
+        assert_eq!(output.lines().count(), 1);
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("puella"), "puella");
+        assert_eq!(csv_field("puella, rosa"), "\"puella, rosa\"");
+        assert_eq!(csv_field("a \"b\"\nc"), "\"a \"\"b\"\"\nc\"");
+    }
+
+    #[test]
+    fn tsv_fields_have_no_separators() {
+        assert_eq!(tsv_field("puella\trosa\ndat"), "puella rosa dat");
+    }
+
+    #[test]
+    fn split_text_separates_context() {
+        let text = vec![
+            ("Puella ".to_string(), false),
+            ("rosas".to_string(), true),
+            (" ".to_string(), false),
+            ("puellis".to_string(), true),
+            (" dat".to_string(), false),
+        ];
+        assert_eq!(
+            split_text(&text),
+            (
+                "Puella ".to_string(),
+                "rosas puellis".to_string(),
+                " dat".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    api::{AtomBounds, IndexRepresentation, IndexStep, QueryOptions, QueryPlan},
+    corpus_query_engine::{CorpusQueryEngine, IndexData, QueryExecError, index_data::IndexSlice},
+    profiler::TimeProfiler,
+    query_parsing_v2::{Query, TokenConstraint},
+};
+
+/// Adds a step (with the representation and size of `slice`) to `steps`, if given.
+pub(super) fn record_step(
+    steps: Option<&mut Vec<IndexStep>>,
+    step: impl FnOnce() -> String,
+    slice: &IndexSlice,
+) {
+    let Some(steps) = steps else {
+        return;
+    };
+    let data = slice.data.to_ref();
+    let representation = match data {
+        IndexData::BitMask(_) => IndexRepresentation::BitMask,
+        IndexData::List(_) => IndexRepresentation::Packed,
+    };
+    steps.push(IndexStep {
+        step: step(),
+        representation,
+        candidates: data.num_elements(),
+    });
+}
+
+impl CorpusQueryEngine {
+    /// Explains how the query would be executed: the query after pruning, the bounds
+    /// on each of its atoms, how the candidates for each span are computed and then
+    /// combined, and how long each phase took.
+    ///
+    /// Only the candidates are computed, so the `page_size`, `context_len`, `sort`,
+    /// `sample_size`, and `analyses` options are ignored.
+    pub fn explain_query(
+        &self,
+        query_str: &str,
+        options: &QueryOptions,
+    ) -> Result<QueryPlan, QueryExecError> {
+        let mut plan = QueryPlan::default();
+        let mut profiler = TimeProfiler::new();
+        self.run_query(
+            query_str,
+            options,
+            &mut profiler,
+            Some(&mut plan),
+            |_, _| Ok(()),
+        )?;
+        plan.timing = profiler.get_stats().to_vec();
+        Ok(plan)
+    }
+
+    /// Returns the size bounds of every atom in the query, in order.
+    pub(super) fn atom_bounds(&self, query: &Query) -> Vec<AtomBounds> {
+        let mut bounds = Vec::new();
+        let mut pending = query
+            .terms
+            .iter()
+            .rev()
+            .map(|term| &term.constraint)
+            .collect::<Vec<_>>();
+        while let Some(constraint) = pending.pop() {
+            match constraint {
+                TokenConstraint::Atom(atom) => {
+                    let size_bounds = self.get_bounds_for_atom(atom);
+                    bounds.push(AtomBounds {
+                        atom: constraint.to_string(),
+                        lower: size_bounds.lower,
+                        upper: size_bounds.upper,
+                    });
+                }
+                TokenConstraint::Composed { children, .. } => {
+                    pending.extend(children.iter().rev());
+                }
+                TokenConstraint::Negated(inner) => pending.push(inner),
+            }
+        }
+        bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_corpus_v2::test_utils,
+        corpus_query_engine::index_data::{IndexDataOwned, IndexDataRoO, IndexRange},
+        query_parsing_v2::parse_query,
+    };
+
+    fn explain(engine: &CorpusQueryEngine, query: &str) -> QueryPlan {
+        engine
+            .explain_query(query, &QueryOptions::default())
+            .unwrap()
+    }
+
+    fn step_counts(steps: &[IndexStep]) -> Vec<(&str, usize)> {
+        steps
+            .iter()
+            .map(|step| (step.step.as_str(), step.candidates))
+            .collect()
+    }
+
+    #[test]
+    fn explains_each_step_of_a_span() {
+        let engine = test_utils::build_test_engine("explain_span");
+        let plan = explain(&engine, "puellis dat");
+        assert_eq!(plan.pruned_query.as_deref(), Some("puellis dat"));
+        assert_eq!(plan.pruning_error, None);
+        let atoms = plan
+            .atoms
+            .iter()
+            .map(|a| (a.atom.as_str(), a.lower, a.upper))
+            .collect::<Vec<_>>();
+        assert_eq!(atoms, vec![("puellis", 3, 3), ("dat", 4, 4)]);
+        assert_eq!(plan.spans.len(), 1);
+        assert_eq!(plan.spans[0].terms, "puellis dat");
+        assert_eq!(
+            step_counts(&plan.spans[0].steps),
+            vec![
+                ("Term 0: puellis", 3),
+                ("Term 1: dat", 3),
+                ("Filter breaks", 3)
+            ]
+        );
+        assert_eq!(step_counts(&plan.combinations), vec![("Span 0", 3)]);
+        assert!(!plan.timing.is_empty());
+    }
+
+    #[test]
+    fn explains_how_spans_are_combined() {
+        let engine = test_utils::build_test_engine("explain_combined");
+        let plan = explain(&engine, "rosa 2~ @lemma:puella");
+        let terms = plan
+            .spans
+            .iter()
+            .map(|s| s.terms.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(terms, vec!["rosa", "2~ @lemma:puella"]);
+        assert_eq!(
+            step_counts(&plan.combinations),
+            vec![("Span 1", 8), ("Span 0 within 2 of span 1", 1)]
+        );
+        let representations = plan
+            .spans
+            .iter()
+            .flat_map(|s| &s.steps)
+            .map(|s| s.representation)
+            .collect::<Vec<_>>();
+        assert_eq!(representations.len(), 2);
+    }
+
+    #[test]
+    fn explains_pruned_queries() {
+        let engine = test_utils::build_test_engine("explain_pruned");
+        let plan = explain(&engine, "(@case:dat and @tense:present)");
+        assert!(plan.pruning_error.is_some());
+        assert_eq!(plan.pruned_query, None);
+        assert!(plan.spans.is_empty());
+        assert!(plan.combinations.is_empty());
+    }
+
+    #[test]
+    fn combines_same_sentence_spans_within_sentences() {
+        let engine = test_utils::build_test_engine("explain_sentence");
+        // The `dat` at the end of `w1` 1.1 is only near a `puella` in the next sentence.
+        let plan = explain(&engine, "dat 2~ puella");
+        assert_eq!(
+            step_counts(&plan.combinations),
+            vec![("Span 1", 4), ("Span 0 within 2 of span 1", 4)]
+        );
+        let plan = explain(&engine, "dat 2~. puella");
+        assert_eq!(
+            step_counts(&plan.combinations),
+            vec![
+                ("Span 1", 4),
+                ("Span 0 within 2 in the same sentence as span 1", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn record_step_records_representation_and_size() {
+        let range = IndexRange { start: 0, end: 64 };
+        let slice = |data| IndexSlice {
+            data: IndexDataRoO::Owned(data),
+            range: &range,
+            position: 0,
+        };
+        let mut steps = vec![];
+        record_step(
+            Some(&mut steps),
+            || "list".to_string(),
+            &slice(IndexDataOwned::List(vec![1, 5, 9])),
+        );
+        record_step(
+            Some(&mut steps),
+            || "mask".to_string(),
+            &slice(IndexDataOwned::BitMask(vec![0b1011])),
+        );
+        record_step(
+            None,
+            || unreachable!(),
+            &slice(IndexDataOwned::List(vec![])),
+        );
+        let summary = steps
+            .iter()
+            .map(|s| (s.step.as_str(), s.representation, s.candidates))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("list", IndexRepresentation::Packed, 3),
+                ("mask", IndexRepresentation::BitMask, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn atom_bounds_lists_nested_atoms_in_order() {
+        let engine = test_utils::build_test_engine("atom_bounds");
+        let query = parse_query("(@case:dat or !(rosas and @number:pl)) dat").unwrap();
+        let bounds = engine
+            .atom_bounds(&query)
+            .into_iter()
+            .map(|b| (b.atom, b.lower, b.upper))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bounds,
+            vec![
+                ("@case:3".to_string(), 4, 4),
+                ("rosas".to_string(), 2, 2),
+                ("@number:2".to_string(), 6, 6),
+                ("dat".to_string(), 4, 4),
+            ]
+        );
+    }
+}
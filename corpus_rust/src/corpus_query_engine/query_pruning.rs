@@ -3,9 +3,12 @@ use morceus::inflection_data::{
 };
 
 use crate::{
-    analyzer_types::LatinInflection::{self, Case, Gender, Mood, Number, Person, Tense, Voice},
-    corpus_query_engine::query_validation::atoms_in,
-    query_parsing_v2::{Query, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation},
+    analyzer_types::LatinInflection::{self, Case, Gender, Mood, Number, Person, Tense},
+    api::QueryContradiction,
+    corpus_query_engine::query_validation::signed_atoms_in,
+    query_parsing_v2::{
+        Query, QueryTerm, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation,
+    },
 };
 
 /// Whether the inflection only applies to nominal forms.
@@ -34,33 +37,40 @@ fn is_verbal_only(infl: &LatinInflection) -> bool {
     }
 }
 
-/// Whether the given mood is incompatible with the other specified inflection values.
-fn is_mood_incompatible_with(
+/// Returns the inflection that the given mood is incompatible with (out of the
+/// other specified inflection values) and why, if there is one.
+fn mood_incompatibility(
     mood: LatinMood,
     tense: Option<LatinTense>,
     case: Option<LatinCase>,
     gender: Option<LatinGender>,
     person: Option<LatinPerson>,
     number: Option<LatinNumber>,
-) -> bool {
+) -> Option<(LatinInflection, &'static str)> {
     if mood == LatinMood::Subjunctive {
-        return matches!(
-            tense,
+        return match tense {
             // Subjunctives do not have future tense.
-            Some(LatinTense::Future) | Some(LatinTense::FuturePerfect)
-        );
+            Some(t @ (LatinTense::Future | LatinTense::FuturePerfect)) => {
+                Some((Tense(t), "subjunctives have no future tenses"))
+            }
+            _ => None,
+        };
     }
     if mood == LatinMood::Supine {
         // Morpheus considers all supines to be neuter, so expect either that or unspecified.
-        let gender_matches = matches!(gender, None | Some(LatinGender::Neuter));
-        let case_matches = matches!(
-            case,
-            // Mopheus considers supines to be either Nominative (-um) or Accusative (-u).
-            // Unspecified doesn't impose any restriction and is allowed as well.
-            None | Some(LatinCase::Nominative) | Some(LatinCase::Dative)
-        );
-        // The result is incompatible if either mismatches.
-        return !gender_matches || !case_matches;
+        if let Some(g) = gender
+            && g != LatinGender::Neuter
+        {
+            return Some((Gender(g), "supines are always neuter"));
+        }
+        // Mopheus considers supines to be either Nominative (-um) or Accusative (-u).
+        // Unspecified doesn't impose any restriction and is allowed as well.
+        return match case {
+            Some(c) if !matches!(c, LatinCase::Nominative | LatinCase::Dative) => {
+                Some((Case(c), "supines are only nominative or dative"))
+            }
+            _ => None,
+        };
     }
     if mood == LatinMood::Participle {
         // Participles can have case / gender / number because they are nouns.
@@ -68,111 +78,177 @@ fn is_mood_incompatible_with(
         // But they don't have person.
         // TODO: We could add checks on the tense and voice here as well, because
         // participles don't have all combinations of tense and voice.
-        return person.is_some();
+        return person.map(|p| (Person(p), "participles have no person"));
     }
     if mood == LatinMood::Infinitive {
-        if person.is_some() || number.is_some() {
-            // Infinitives are not inflected for person or number.
-            // Case and gender are handled outside of this because we mark
-            // Infinitive as verbal only in `is_verbal_only`.
-            return true;
+        // Infinitives are not inflected for person or number.
+        // Case and gender are handled outside of this because we mark
+        // Infinitive as verbal only in `is_verbal_only`.
+        if let Some(p) = person {
+            return Some((Person(p), "infinitives have no person"));
+        }
+        if let Some(n) = number {
+            return Some((Number(n), "infinitives have no number"));
         }
         // Otherwise, check that the tense is one of the incompatible ones.
-        return matches!(
-            tense,
+        return match tense {
             // Future infinitives exist but are periphrastic, which the analyzer does not handle yet.
-            Some(LatinTense::Future)
-                | Some(LatinTense::FuturePerfect)
-                | Some(LatinTense::Imperfect)
-                | Some(LatinTense::Pluperfect)
-        );
+            Some(
+                t @ (LatinTense::Future
+                | LatinTense::FuturePerfect
+                | LatinTense::Imperfect
+                | LatinTense::Pluperfect),
+            ) => Some((
+                Tense(t),
+                "infinitives are only present or perfect (future infinitives are periphrastic)",
+            )),
+            _ => None,
+        };
     }
     if mood == LatinMood::Imperative {
-        let tense_matches = matches!(
-            tense,
-            // Imperatives only exist in present and future tenses.
-            None | Some(LatinTense::Present) | Some(LatinTense::Future)
-        );
-        return !tense_matches;
+        // Imperatives only exist in present and future tenses.
+        return match tense {
+            Some(t) if !matches!(t, LatinTense::Present | LatinTense::Future) => {
+                Some((Tense(t), "imperatives are only present or future"))
+            }
+            _ => None,
+        };
     }
 
-    false // No incompatibility found
+    None // No incompatibility found
 }
 
-fn is_conjunction_impossible(terms: &[LatinInflection]) -> bool {
-    if terms.iter().any(is_nominal_only) && terms.iter().any(is_verbal_only) {
+/// Returns the inflections that can't all apply to the same token and why, if
+/// there are any.
+fn conjunction_conflict(terms: &[LatinInflection]) -> Option<(Vec<LatinInflection>, String)> {
+    let nominal = terms.iter().find(|infl| is_nominal_only(infl));
+    let verbal = terms.iter().find(|infl| is_verbal_only(infl));
+    if let (Some(nominal), Some(verbal)) = (nominal, verbal) {
         // Some condition applies only to nominals, and some other one applies only to verbals.
         // No one token can be both.
-        return true;
-    }
-
-    let mut case = None;
-    let mut number = None;
-    let mut gender = None;
-    let mut person = None;
-    let mut mood = None;
-    let mut voice = None;
-    let mut tense = None;
-
-    macro_rules! set_or_check_conflict {
-        ($field:ident, $value:expr) => {
-            if let Some(existing) = $field {
-                if $value != existing {
-                    // This means that two terms are looking for the
-                    // same category but different values, e.g. Nominative
-                    // and Accusative on the same token.
-                    return true;
-                }
-            } else {
-                $field = Some($value);
-            }
-        };
+        let reason = "one only applies to nominal forms and the other only to verbal forms";
+        return Some((vec![*nominal, *verbal], reason.to_string()));
     }
 
-    for term in terms {
-        match term {
-            Case(c) => set_or_check_conflict!(case, *c),
-            Number(n) => set_or_check_conflict!(number, *n),
-            Gender(g) => set_or_check_conflict!(gender, *g),
-            Person(p) => set_or_check_conflict!(person, *p),
-            Mood(m) => set_or_check_conflict!(mood, *m),
-            Voice(v) => set_or_check_conflict!(voice, *v),
-            Tense(t) => set_or_check_conflict!(tense, *t),
-            LatinInflection::Degree(_) => {}
+    for (i, term) in terms.iter().enumerate() {
+        if matches!(term, LatinInflection::Degree(_)) {
+            continue;
+        }
+        // This means that two terms are looking for the same category but different
+        // values, e.g. Nominative and Accusative on the same token.
+        let label = term.get_label();
+        if let Some(other) = terms[..i]
+            .iter()
+            .find(|other| other.get_label() == label && *other != term)
+        {
+            let reason = format!("a word can't have more than one {label}");
+            return Some((vec![*other, *term], reason));
+        }
+    }
+
+    macro_rules! find_value {
+        ($variant:ident) => {
+            terms.iter().find_map(|term| match term {
+                $variant(value) => Some(*value),
+                _ => None,
+            })
         };
     }
 
-    let mood = match mood {
-        // All of the remaining ways to be impossible require a mood.
-        None => return false,
-        Some(m) => m,
-    };
+    // All of the remaining ways to be impossible require a mood.
+    let mood = find_value!(Mood)?;
+    let (other, reason) = mood_incompatibility(
+        mood,
+        find_value!(Tense),
+        find_value!(Case),
+        find_value!(Gender),
+        find_value!(Person),
+        find_value!(Number),
+    )?;
+    Some((vec![Mood(mood), other], reason.to_string()))
+}
 
-    is_mood_incompatible_with(mood, tense, case, gender, person, number)
+/// The atoms of a constraint that contradict each other, and why.
+#[derive(Debug)]
+struct Contradiction {
+    atoms: Vec<String>,
+    reason: String,
 }
 
-fn is_constraint_impossible(term: &TokenConstraint) -> bool {
-    let is_and = match term {
-        TokenConstraint::Composed { op, .. } => *op == TokenConstraintOperation::And,
-        _ => false,
+/// Returns how the atom would be written in a query, with the full names of inflections.
+fn atom_name(atom: &TokenConstraintAtom, negated: bool) -> String {
+    let name = match atom {
+        TokenConstraintAtom::Inflection(infl) => {
+            format!("@{}:{}", infl.get_label(), infl.get_value_name())
+        }
+        _ => TokenConstraint::Atom(atom.clone()).to_string(),
     };
-    if !is_and {
-        // Only conjunctions can be impossible.
-        return false;
+    if negated { format!("!{name}") } else { name }
+}
+
+/// Returns why the constraint can never be satisfied, if it can't.
+fn find_contradiction(term: &TokenConstraint) -> Option<Contradiction> {
+    let children = match term {
+        TokenConstraint::Composed {
+            op: TokenConstraintOperation::Or,
+            children,
+        } => {
+            // A disjunction is only impossible if every option is impossible.
+            let contradictions = children
+                .iter()
+                .map(find_contradiction)
+                .collect::<Option<Vec<_>>>()?;
+            let reasons = contradictions
+                .iter()
+                .map(|c| c.reason.as_str())
+                .collect::<Vec<_>>();
+            return Some(Contradiction {
+                reason: format!("every alternative is impossible ({})", reasons.join("; ")),
+                atoms: contradictions.into_iter().flat_map(|c| c.atoms).collect(),
+            });
+        }
+        TokenConstraint::Composed { children, .. } => children,
+        // Atoms (and negations, which only apply to atoms) are always possible.
+        _ => return None,
+    };
+    if let Some(contradiction) = children.iter().find_map(find_contradiction) {
+        return Some(contradiction);
     }
-    // In the validation step, we check that every term is either an
-    // atom or an AND/OR of atoms, so we can just extract the atoms here.
-    let atoms = atoms_in(term);
+    // Otherwise, check whether the atoms directly in the conjunction conflict. Any
+    // nested disjunctions are ignored here, so this may miss some impossible cases.
+    let signed_atoms = children
+        .iter()
+        .filter(|child| !matches!(child, TokenConstraint::Composed { .. }))
+        .flat_map(signed_atoms_in)
+        .collect::<Vec<_>>();
+    let negated_atom = signed_atoms
+        .iter()
+        .find(|(atom, negated)| *negated && signed_atoms.contains(&(*atom, false)));
+    if let Some((atom, _)) = negated_atom {
+        // Something like `(@case:dat and !@case:dat)` can never match.
+        return Some(Contradiction {
+            atoms: vec![atom_name(atom, false), atom_name(atom, true)],
+            reason: "an atom can't be both required and excluded".to_string(),
+        });
+    }
+    // Negated atoms only exclude values, so they can't conflict with each other
+    // or with the other atoms in the way that positive atoms can.
+    let atoms = signed_atoms
+        .into_iter()
+        .filter(|(_, negated)| !negated)
+        .map(|(atom, _)| atom)
+        .collect::<Vec<_>>();
     let lemmata = atoms
         .iter()
-        .filter_map(|atom| match &atom {
-            TokenConstraintAtom::Lemma(_) => Some(()),
-            _ => None,
-        })
-        .count();
-    if lemmata > 1 {
+        .filter(|atom| matches!(atom, TokenConstraintAtom::Lemma(_)))
+        .map(|atom| atom_name(atom, false))
+        .collect::<Vec<_>>();
+    if lemmata.len() > 1 {
         // More than one lemma in conjunction is impossible.
-        return true;
+        return Some(Contradiction {
+            atoms: lemmata,
+            reason: "a word can't have more than one lemma".to_string(),
+        });
     }
 
     let inflections = atoms
@@ -182,13 +258,78 @@ fn is_constraint_impossible(term: &TokenConstraint) -> bool {
             _ => None,
         })
         .collect::<Vec<_>>();
-    is_conjunction_impossible(&inflections)
+    let (conflicting, reason) = conjunction_conflict(&inflections)?;
+    Some(Contradiction {
+        atoms: conflicting
+            .into_iter()
+            .map(|infl| atom_name(&TokenConstraintAtom::Inflection(infl), false))
+            .collect(),
+        reason,
+    })
+}
+
+fn flip_operation(op: TokenConstraintOperation) -> TokenConstraintOperation {
+    match op {
+        TokenConstraintOperation::And => TokenConstraintOperation::Or,
+        TokenConstraintOperation::Or => TokenConstraintOperation::And,
+    }
 }
 
-pub(super) fn prune_query(query: Query) -> Result<Query, String> {
-    for term in &query.terms {
-        if is_constraint_impossible(&term.constraint) {
-            return Err("Query contains impossible constraints".to_string());
+/// Pushes negations down to the atoms (using De Morgan's laws) and flattens
+/// nested compositions that use the same operation as their parent.
+fn normalize_constraint(constraint: TokenConstraint, negate: bool) -> TokenConstraint {
+    match constraint {
+        TokenConstraint::Atom(_) if negate => TokenConstraint::Negated(Box::new(constraint)),
+        TokenConstraint::Atom(_) => constraint,
+        TokenConstraint::Negated(inner) => normalize_constraint(*inner, !negate),
+        TokenConstraint::Composed { op, children } => {
+            let op = if negate { flip_operation(op) } else { op };
+            let mut flattened = vec![];
+            for child in children {
+                match normalize_constraint(child, negate) {
+                    TokenConstraint::Composed {
+                        op: child_op,
+                        children: grandchildren,
+                    } if child_op == op => flattened.extend(grandchildren),
+                    other => flattened.push(other),
+                }
+            }
+            TokenConstraint::Composed {
+                op,
+                children: flattened,
+            }
+        }
+    }
+}
+
+/// Rewrites the query so that negations only ever apply directly to atoms.
+/// For example, `!(@case:dat or @case:abl)` becomes `(!@case:dat and !@case:abl)`.
+pub(super) fn normalize_negations(query: Query) -> Query {
+    let terms = query
+        .terms
+        .into_iter()
+        .map(|term| QueryTerm {
+            constraint: normalize_constraint(term.constraint, false),
+            ..term
+        })
+        .collect();
+    Query { terms, ..query }
+}
+
+/// Checks that every required term of the query could match something, returning
+/// the first contradiction otherwise.
+pub(super) fn prune_query(query: Query) -> Result<Query, QueryContradiction> {
+    // An impossible optional term is just never part of a match.
+    for (i, term) in query.terms.iter().enumerate() {
+        if term.optional {
+            continue;
+        }
+        if let Some(Contradiction { atoms, reason }) = find_contradiction(&term.constraint) {
+            return Err(QueryContradiction {
+                term: i,
+                atoms,
+                reason,
+            });
         }
     }
     Ok(query)
@@ -198,6 +339,14 @@ pub(super) fn prune_query(query: Query) -> Result<Query, String> {
 mod tests {
     use super::*;
 
+    fn is_conjunction_impossible(terms: &[LatinInflection]) -> bool {
+        conjunction_conflict(terms).is_some()
+    }
+
+    fn is_constraint_impossible(term: &TokenConstraint) -> bool {
+        find_contradiction(term).is_some()
+    }
+
     #[test]
     fn test_nominal_and_verbal_conflict() {
         // Case (nominal) + Tense (verbal) should be impossible
@@ -450,4 +599,124 @@ mod tests {
             Number(LatinNumber::Singular)
         ]));
     }
+
+    fn constraint_of(query: &str) -> TokenConstraint {
+        let query = crate::query_parsing_v2::parse_query(query).unwrap();
+        normalize_negations(query).terms.remove(0).constraint
+    }
+
+    #[test]
+    fn test_normalize_negations_applies_de_morgan() {
+        assert_eq!(
+            constraint_of("!(@case:dat or @case:abl)"),
+            constraint_of("(!@case:dat and !@case:abl)")
+        );
+        assert_eq!(
+            constraint_of("!(@case:dat and !@lemma:do)"),
+            constraint_of("(!@case:dat or @lemma:do)")
+        );
+    }
+
+    #[test]
+    fn test_normalize_negations_removes_double_negation() {
+        assert_eq!(constraint_of("!(!@case:dat)"), constraint_of("@case:dat"));
+    }
+
+    #[test]
+    fn test_normalize_negations_flattens_same_operation() {
+        assert_eq!(
+            constraint_of("!(@case:dat or (@case:abl or @case:gen))"),
+            constraint_of("(!@case:dat and !@case:abl and !@case:gen)")
+        );
+    }
+
+    #[test]
+    fn test_negated_atoms_do_not_conflict() {
+        assert!(!is_constraint_impossible(&constraint_of(
+            "(@case:nom and !@case:acc)"
+        )));
+        assert!(!is_constraint_impossible(&constraint_of(
+            "(@lemma:do and !@lemma:habeo)"
+        )));
+        assert!(!is_constraint_impossible(&constraint_of(
+            "(@case:nom and !@tense:present)"
+        )));
+    }
+
+    #[test]
+    fn test_atom_and_its_negation_is_impossible() {
+        assert!(is_constraint_impossible(&constraint_of(
+            "(@case:dat and !@case:dat)"
+        )));
+    }
+
+    #[test]
+    fn test_nested_disjunction_is_not_part_of_conjunction() {
+        assert!(!is_constraint_impossible(&constraint_of(
+            "(@case:dat and (@number:pl or @case:abl))"
+        )));
+        assert!(!is_constraint_impossible(&constraint_of(
+            "((@case:abl and @number:pl) or (@case:dat and @number:sg))"
+        )));
+    }
+
+    #[test]
+    fn test_nested_impossible_constraints() {
+        assert!(is_constraint_impossible(&constraint_of(
+            "(@lemma:do and (@case:dat and @tense:present))"
+        )));
+        assert!(is_constraint_impossible(&constraint_of(
+            "((@case:abl and @case:dat) or (@number:sg and @number:pl))"
+        )));
+        assert!(!is_constraint_impossible(&constraint_of(
+            "((@case:abl and @case:dat) or @number:sg)"
+        )));
+    }
+
+    fn contradiction_in(query: &str) -> QueryContradiction {
+        let query = crate::query_parsing_v2::parse_query(query).unwrap();
+        prune_query(normalize_negations(query)).unwrap_err()
+    }
+
+    #[test]
+    fn test_contradictions_name_the_conflicting_atoms() {
+        assert_eq!(
+            contradiction_in("@lemma:do (@mood:subj and @number:pl and @tense:fut)"),
+            QueryContradiction {
+                term: 1,
+                atoms: vec!["@mood:subjunctive".to_string(), "@tense:future".to_string()],
+                reason: "subjunctives have no future tenses".to_string(),
+            }
+        );
+        let contradiction = contradiction_in("(@case:dat and @tense:present)");
+        assert_eq!(contradiction.atoms, vec!["@case:dative", "@tense:present"]);
+        let contradiction = contradiction_in("(@mood:inf and @person:1)");
+        assert_eq!(
+            contradiction.atoms,
+            vec!["@mood:infinitive", "@person:first"]
+        );
+        assert_eq!(contradiction.reason, "infinitives have no person");
+    }
+
+    #[test]
+    fn test_contradictions_for_lemmata_and_negations() {
+        let contradiction = contradiction_in("(@lemma:do and @lemma:habeo)");
+        assert_eq!(contradiction.atoms, vec!["@lemma:do", "@lemma:habeo"]);
+        let contradiction = contradiction_in("(@case:dat and !@case:dat)");
+        assert_eq!(contradiction.atoms, vec!["@case:dative", "!@case:dative"]);
+        let contradiction =
+            contradiction_in("((@case:abl and @case:dat) or (@number:sg and @number:pl))");
+        assert_eq!(contradiction.atoms.len(), 4);
+        assert!(
+            contradiction
+                .reason
+                .starts_with("every alternative is impossible")
+        );
+    }
+
+    #[test]
+    fn test_impossible_optional_terms_are_not_contradictions() {
+        let query = crate::query_parsing_v2::parse_query("@lemma:do (@case:dat and @tense:pres)?");
+        assert!(prune_query(normalize_negations(query.unwrap())).is_ok());
+    }
 }
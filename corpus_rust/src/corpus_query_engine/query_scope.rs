@@ -0,0 +1,366 @@
+use std::str::FromStr;
+
+use crate::{
+    api::QueryOptions,
+    corpus_index::WorkLookupEntry,
+    corpus_query_engine::{CorpusQueryEngine, QueryExecError, index_data::IndexRange},
+    query_parsing_v2::{QueryScope, ScopeItem, SectionRange, parse_scope},
+};
+
+impl FromStr for QueryScope {
+    type Err = QueryExecError;
+
+    /// Parses a scope in the syntax of the `[...]` prefix of a query, without the brackets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_scope(s).map_err(|e| QueryExecError::new(&e.message))
+    }
+}
+
+/// A range of token IDs, where the start is inclusive and the end is exclusive.
+pub(super) type TokenRange = (u32, u32);
+
+pub(super) fn work_range(work: &WorkLookupEntry) -> Result<TokenRange, QueryExecError> {
+    let first = work
+        .rows
+        .first()
+        .ok_or(QueryExecError::new("Missing first section!"))?;
+    let last = work
+        .rows
+        .last()
+        .ok_or(QueryExecError::new("Missing last section!"))?;
+    Ok((first.1, last.2))
+}
+
+/// Whether the section ID is, or is within, the section with the given prefix.
+fn is_in_section(id: &str, prefix: &str) -> bool {
+    id.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn section_range(
+    work: &WorkLookupEntry,
+    sections: &SectionRange,
+) -> Result<TokenRange, QueryExecError> {
+    let not_found = |id: &str| {
+        QueryExecError::new(&format!("Section '{id}' not found in '{}'", work.info.name))
+    };
+    let first = work
+        .rows
+        .iter()
+        .position(|row| is_in_section(&row.0, &sections.start))
+        .ok_or_else(|| not_found(&sections.start))?;
+    let last = work
+        .rows
+        .iter()
+        .rposition(|row| is_in_section(&row.0, &sections.end))
+        .ok_or_else(|| not_found(&sections.end))?;
+    if last < first {
+        return Err(QueryExecError::new(&format!(
+            "Section '{}' comes before '{}'",
+            sections.end, sections.start
+        )));
+    }
+    Ok((work.rows[first].1, work.rows[last].2))
+}
+
+/// Sorts the given ranges and merges any that overlap or touch.
+fn merge_ranges(mut ranges: Vec<TokenRange>) -> Vec<TokenRange> {
+    ranges.sort();
+    let mut merged: Vec<TokenRange> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Removes the `excluded` ranges from the `included` ranges. Both
+/// inputs must be sorted and disjoint, and the output will be as well.
+fn subtract_ranges(included: &[TokenRange], excluded: &[TokenRange]) -> Vec<TokenRange> {
+    let mut result = vec![];
+    for &(start, end) in included {
+        let mut current = start;
+        for &(ex_start, ex_end) in excluded {
+            if ex_end <= current || ex_start >= end {
+                continue;
+            }
+            if ex_start > current {
+                result.push((current, ex_start));
+            }
+            current = current.max(ex_end);
+        }
+        if current < end {
+            result.push((current, end));
+        }
+    }
+    result
+}
+
+/// Returns the parts of the ranges that are in both `first` and `second`. Both
+/// inputs must be sorted and disjoint, and the output will be as well.
+fn intersect_ranges(first: &[TokenRange], second: &[TokenRange]) -> Vec<TokenRange> {
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < first.len() && j < second.len() {
+        let start = first[i].0.max(second[j].0);
+        let end = first[i].1.min(second[j].1);
+        if start < end {
+            result.push((start, end));
+        }
+        if first[i].1 < second[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+impl CorpusQueryEngine {
+    /// Returns the indices of the works by the given author.
+    fn works_for_author(&self, author: &str) -> Option<std::ops::RangeInclusive<usize>> {
+        let (start, end) = self.corpus.author_lookup.get(author)?;
+        Some(*start..=*end)
+    }
+
+    fn ranges_for_scope_item(&self, item: &ScopeItem) -> Result<Vec<TokenRange>, QueryExecError> {
+        match item {
+            ScopeItem::Name(name) => {
+                if let Some(works) = self.works_for_author(name) {
+                    return self.corpus.work_lookup[works]
+                        .iter()
+                        .map(work_range)
+                        .collect();
+                }
+                let work = self
+                    .corpus
+                    .work_lookup
+                    .iter()
+                    .find(|work| &work.work_id == name)
+                    .ok_or(QueryExecError::new(&format!(
+                        "Author or work '{name}' not found in corpus"
+                    )))?;
+                Ok(vec![work_range(work)?])
+            }
+            ScopeItem::Work {
+                author,
+                work,
+                sections,
+            } => {
+                let works = self
+                    .works_for_author(author)
+                    .ok_or(QueryExecError::new(&format!(
+                        "Author '{author}' not found in corpus"
+                    )))?;
+                let entry = self.corpus.work_lookup[works]
+                    .iter()
+                    .find(|entry| {
+                        &entry.work_id == work || entry.info.name.eq_ignore_ascii_case(work)
+                    })
+                    .ok_or(QueryExecError::new(&format!(
+                        "Work '{work}' by '{author}' not found in corpus"
+                    )))?;
+                match sections {
+                    Some(sections) => Ok(vec![section_range(entry, sections)?]),
+                    None => Ok(vec![work_range(entry)?]),
+                }
+            }
+        }
+    }
+
+    fn ranges_for_scope_items(
+        &self,
+        items: &[ScopeItem],
+    ) -> Result<Vec<TokenRange>, QueryExecError> {
+        let mut ranges = vec![];
+        for item in items {
+            ranges.extend(self.ranges_for_scope_item(item)?);
+        }
+        Ok(merge_ranges(ranges))
+    }
+
+    /// Resolves the scope of a query into a sorted list of disjoint token ranges.
+    pub(super) fn resolve_scope(
+        &self,
+        scope: &QueryScope,
+    ) -> Result<Vec<TokenRange>, QueryExecError> {
+        let included = match scope.included.is_empty() {
+            true => vec![(0, self.corpus.num_tokens)],
+            false => self.ranges_for_scope_items(&scope.included)?,
+        };
+        let excluded = self.ranges_for_scope_items(&scope.excluded)?;
+        Ok(subtract_ranges(&included, &excluded))
+    }
+
+    /// Resolves the scope of a query, restricted further by the scope in the
+    /// query options (if any).
+    pub(super) fn resolve_scope_with_options(
+        &self,
+        scope: &QueryScope,
+        options: &QueryOptions,
+    ) -> Result<Vec<TokenRange>, QueryExecError> {
+        let ranges = self.resolve_scope(scope)?;
+        let Some(extra) = &options.scope else {
+            return Ok(ranges);
+        };
+        Ok(intersect_ranges(&ranges, &self.resolve_scope(extra)?))
+    }
+}
+
+/// Returns the smallest word-aligned range that covers all of the given token ranges.
+pub(super) fn bounding_range(ranges: &[TokenRange]) -> Option<IndexRange> {
+    let start = ranges.first()?.0;
+    let end = ranges.last()?.1;
+    Some(IndexRange {
+        start: (start / 64) * 64,
+        end: end.div_ceil(64) * 64,
+    })
+}
+
+/// Returns a bitmask (relative to the start of `range`) with a bit set for every
+/// token in the given ranges, or `None` if every token in `range` is covered.
+pub(super) fn scope_bitmask(
+    ranges: &[TokenRange],
+    range: &IndexRange,
+    num_tokens: u32,
+) -> Option<Vec<u64>> {
+    let covers_all =
+        ranges.len() == 1 && ranges[0].0 <= range.start && ranges[0].1 >= range.end.min(num_tokens);
+    if covers_all {
+        return None;
+    }
+    let mut bitmask = vec![0u64; ((range.end - range.start) / 64) as usize];
+    for &(start, end) in ranges {
+        if start >= range.end || end <= range.start || start >= end {
+            continue;
+        }
+        let start = (start.max(range.start) - range.start) as usize;
+        let end = (end.min(range.end) - range.start) as usize;
+        let (first, last) = (start / 64, (end - 1) / 64);
+        // Only the words at the edges of the range are partially covered.
+        let first_mask = !0u64 << (start % 64);
+        let last_mask = !0u64 >> (63 - (end - 1) % 64);
+        if first == last {
+            bitmask[first] |= first_mask & last_mask;
+            continue;
+        }
+        bitmask[first] |= first_mask;
+        bitmask[first + 1..last].fill(!0);
+        bitmask[last] |= last_mask;
+    }
+    Some(bitmask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_parse_from_strings() {
+        let scope = "Alpha: w1 1.2, !Beta".parse::<QueryScope>().unwrap();
+        assert_eq!(scope.included.len(), 1);
+        assert_eq!(scope.excluded, vec![ScopeItem::Name("Beta".to_string())]);
+        assert!("".parse::<QueryScope>().is_err());
+        assert!("Alpha:".parse::<QueryScope>().is_err());
+    }
+
+    #[test]
+    fn merge_ranges_combines_overlapping_and_adjacent() {
+        assert_eq!(
+            merge_ranges(vec![(10, 20), (0, 5), (5, 8), (15, 25), (30, 40)]),
+            vec![(0, 8), (10, 25), (30, 40)]
+        );
+        assert_eq!(merge_ranges(vec![]), vec![]);
+    }
+
+    #[test]
+    fn subtract_ranges_removes_excluded_parts() {
+        assert_eq!(
+            subtract_ranges(&[(0, 100)], &[(10, 20), (50, 60)]),
+            vec![(0, 10), (20, 50), (60, 100)]
+        );
+        assert_eq!(
+            subtract_ranges(&[(0, 10), (20, 30)], &[(5, 25)]),
+            vec![(0, 5), (25, 30)]
+        );
+        assert_eq!(subtract_ranges(&[(0, 10)], &[(0, 10)]), vec![]);
+        assert_eq!(subtract_ranges(&[(0, 10)], &[]), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn intersect_ranges_keeps_shared_parts() {
+        assert_eq!(
+            intersect_ranges(&[(0, 10), (20, 30)], &[(5, 25), (28, 40)]),
+            vec![(5, 10), (20, 25), (28, 30)]
+        );
+        assert_eq!(intersect_ranges(&[(0, 10)], &[(10, 20)]), vec![]);
+        assert_eq!(intersect_ranges(&[(0, 10)], &[]), vec![]);
+    }
+
+    #[test]
+    fn bounding_range_is_word_aligned() {
+        assert_eq!(
+            bounding_range(&[(70, 80), (100, 130)]),
+            Some(IndexRange {
+                start: 64,
+                end: 192
+            })
+        );
+        assert_eq!(bounding_range(&[]), None);
+    }
+
+    #[test]
+    fn scope_bitmask_sets_tokens_in_ranges() {
+        let range = IndexRange {
+            start: 64,
+            end: 192,
+        };
+        let bitmask = scope_bitmask(&[(66, 68), (128, 129)], &range, 192).unwrap();
+        assert_eq!(bitmask, vec![0b1100, 1]);
+    }
+
+    #[test]
+    fn scope_bitmask_fills_whole_words() {
+        let range = IndexRange { start: 0, end: 256 };
+        let bitmask = scope_bitmask(&[(60, 200), (250, 300)], &range, 300).unwrap();
+        assert_eq!(
+            bitmask,
+            vec![0b1111 << 60, !0, !0, ((1 << 8) - 1) | (0b111111 << 58)]
+        );
+        let bitmask = scope_bitmask(&[(0, 64), (128, 192)], &range, 300).unwrap();
+        assert_eq!(bitmask, vec![!0, 0, !0, 0]);
+    }
+
+    #[test]
+    fn scope_bitmask_matches_setting_each_bit() {
+        let range = IndexRange {
+            start: 64,
+            end: 384,
+        };
+        let ranges = [
+            (0, 65),
+            (70, 71),
+            (100, 200),
+            (255, 256),
+            (256, 320),
+            (383, 500),
+        ];
+        let mut expected = vec![0u64; 5];
+        for &(start, end) in &ranges {
+            for token in start.max(range.start)..end.min(range.end) {
+                let relative = token - range.start;
+                expected[(relative / 64) as usize] |= 1 << (relative % 64);
+            }
+        }
+        assert_eq!(scope_bitmask(&ranges, &range, 500), Some(expected));
+    }
+
+    #[test]
+    fn scope_bitmask_is_none_when_everything_is_covered() {
+        let range = IndexRange { start: 0, end: 128 };
+        assert_eq!(scope_bitmask(&[(0, 100)], &range, 100), None);
+        assert!(scope_bitmask(&[(0, 99)], &range, 100).is_some());
+    }
+}
@@ -2,11 +2,12 @@ use crate::query_parsing_v2::{
     Query, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation,
 };
 
-fn has_negations(term: &TokenConstraint) -> bool {
+/// Whether the term has any negations that apply to something other than an atom.
+fn has_non_atomic_negations(term: &TokenConstraint) -> bool {
     match term {
-        TokenConstraint::Negated(_) => true,
+        TokenConstraint::Negated(child) => !matches!(**child, TokenConstraint::Atom(_)),
         TokenConstraint::Atom(_) => false,
-        TokenConstraint::Composed { children, .. } => children.iter().any(has_negations),
+        TokenConstraint::Composed { children, .. } => children.iter().any(has_non_atomic_negations),
     }
 }
 
@@ -23,51 +24,33 @@ pub(super) fn operators_in(term: &TokenConstraint) -> Vec<TokenConstraintOperati
     }
 }
 
-pub(super) fn atoms_in(term: &TokenConstraint) -> Vec<&TokenConstraintAtom> {
-    match term {
-        TokenConstraint::Atom(s) => vec![s],
-        TokenConstraint::Negated(child) => atoms_in(child),
-        TokenConstraint::Composed { children, .. } => {
-            let mut atoms = vec![];
-            for child in children {
-                atoms.extend(atoms_in(child));
+/// Returns the atoms in the term, along with whether each one is negated.
+pub(super) fn signed_atoms_in(term: &TokenConstraint) -> Vec<(&TokenConstraintAtom, bool)> {
+    fn collect<'a>(
+        term: &'a TokenConstraint,
+        negated: bool,
+        atoms: &mut Vec<(&'a TokenConstraintAtom, bool)>,
+    ) {
+        match term {
+            TokenConstraint::Atom(s) => atoms.push((s, negated)),
+            TokenConstraint::Negated(child) => collect(child, !negated, atoms),
+            TokenConstraint::Composed { children, .. } => {
+                for child in children {
+                    collect(child, negated, atoms);
+                }
             }
-            atoms
-        }
-    }
-}
-
-fn max_level_of_contstraint(term: &TokenConstraint) -> usize {
-    match term {
-        TokenConstraint::Atom(_) => 1,
-        TokenConstraint::Negated(child) => max_level_of_contstraint(child),
-        TokenConstraint::Composed { children, .. } => {
-            children
-                .iter()
-                .map(max_level_of_contstraint)
-                .max()
-                .unwrap_or(1)
-                + 1
         }
     }
+    let mut atoms = vec![];
+    collect(term, false, &mut atoms);
+    atoms
 }
 
 fn is_term_currently_supported(term: &TokenConstraint) -> bool {
-    if has_negations(term) {
-        return false;
-    }
-    // Currently, we only support atoms or an AND or OR of atoms.
-    if max_level_of_contstraint(term) > 2 {
-        return false;
-    }
-    let ops = operators_in(term);
-    let has_or = ops.contains(&TokenConstraintOperation::Or);
-    let has_and = ops.contains(&TokenConstraintOperation::And);
-    if has_or && has_and {
-        // Currently we can't support both because it complicates the compatibility checking.
-        return false;
-    }
-    true
+    // Negations are pushed down to the atoms before this check, so
+    // anything else is unexpected. Otherwise, any combination of `and`
+    // and `or` is supported.
+    !has_non_atomic_negations(term)
 }
 
 pub(super) fn is_query_currently_supported(query: &Query) -> bool {
@@ -76,3 +59,48 @@ pub(super) fn is_query_currently_supported(query: &Query) -> bool {
         .iter()
         .all(|term| is_term_currently_supported(&term.constraint))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_parsing_v2::parse_query;
+
+    fn is_supported(query: &str) -> bool {
+        is_query_currently_supported(&parse_query(query).unwrap())
+    }
+
+    #[test]
+    fn supports_negated_atoms() {
+        assert!(is_supported("!@case:dat"));
+        assert!(is_supported("(@lemma:do and !@voice:passive)"));
+        assert!(is_supported("(!@case:dat or !@case:abl)"));
+    }
+
+    #[test]
+    fn rejects_negated_compositions() {
+        assert!(!is_supported("!(@case:dat or @case:abl)"));
+    }
+
+    #[test]
+    fn supports_nested_compositions() {
+        assert!(is_supported(
+            "(@case:dat or (@voice:passive and @lemma:do))"
+        ));
+        assert!(is_supported(
+            "((@case:abl and @number:pl) or (@case:dat and @number:sg))"
+        ));
+        assert!(is_supported(
+            "(@lemma:puella and (@case:nom or (@case:abl and !@number:pl)))"
+        ));
+    }
+
+    #[test]
+    fn signed_atoms_in_tracks_negations() {
+        let query = parse_query("(@lemma:do and !@voice:passive)").unwrap();
+        let signed = signed_atoms_in(&query.terms[0].constraint)
+            .into_iter()
+            .map(|(_, negated)| negated)
+            .collect::<Vec<_>>();
+        assert_eq!(signed, vec![false, true]);
+    }
+}
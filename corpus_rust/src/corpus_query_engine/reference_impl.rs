@@ -1,6 +1,6 @@
 #![cfg(test)]
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::env::set_current_dir;
 
 use crate::api::{PageData, QueryGlobalInfo, QueryOptions};
@@ -12,12 +12,26 @@ use crate::{
     corpus_query_engine::{
         CorpusQueryEngine, CorpusQueryResult, IndexData, QueryExecError,
         index_data::{apply_and_with_arrays, apply_or_with_arrays},
+        query_validation::operators_in,
+    },
+    query_parsing_v2::{
+        QueryTerm, TokenConstraint, TokenConstraintAtom, TokenConstraintOperation, parse_query,
     },
-    query_parsing_v2::{QueryTerm, TokenConstraint, parse_query},
 };
 
 const CORPUS_ROOT: &str = "build/corpus/latin_corpus.json";
 
+/// The (label, start bit, number of bits) for each category in the inflection data.
+const CATEGORY_BITS: [(&str, u32, u32); 7] = [
+    ("case", 0, 7),
+    ("number", 7, 2),
+    ("gender", 9, 4),
+    ("person", 13, 3),
+    ("mood", 16, 7),
+    ("voice", 23, 2),
+    ("tense", 25, 6),
+];
+
 pub(super) fn get_engine_unsafe() -> Option<CorpusQueryEngine> {
     set_current_dir("..").unwrap();
     let index = match deserialize_corpus(CORPUS_ROOT) {
@@ -108,7 +122,9 @@ impl CorpusQueryEngine {
     ) -> Result<Vec<u32>, QueryExecError> {
         match constraint {
             TokenConstraint::Atom(atom) => {
-                let metadata = self.get_metadata_for(atom).unwrap();
+                let Some(metadata) = self.get_metadata_for(atom) else {
+                    return Ok(vec![]);
+                };
                 let index = self
                     .raw_buffers
                     .resolve_index(metadata, self.corpus.num_tokens)
@@ -136,14 +152,88 @@ impl CorpusQueryEngine {
                 }
                 Ok(results)
             }
-            _ => Err(QueryExecError {
-                message: "Unimplemented".to_string(),
-            }),
+            TokenConstraint::Negated(inner) => {
+                let excluded = self.index_for_constraint(inner)?;
+                Ok((0..self.corpus.num_tokens)
+                    .filter(|id| excluded.binary_search(id).is_err())
+                    .collect())
+            }
         }
     }
 
+    /// Whether a single reading (with at most one value per category) matches the constraint.
+    fn reading_matches_ref_impl(
+        &self,
+        constraint: &TokenConstraint,
+        token: u32,
+        reading: u32,
+        analysis: (u32, u32),
+    ) -> bool {
+        match constraint {
+            TokenConstraint::Atom(TokenConstraintAtom::Inflection(inflection)) => {
+                let start = CATEGORY_BITS
+                    .iter()
+                    .find(|(label, _, _)| *label == inflection.get_label())
+                    .unwrap()
+                    .1;
+                reading & (1 << (start + inflection.get_code() as u32 - 1)) != 0
+            }
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma(lemma)) => {
+                self.corpus.id_table["lemma"].get(lemma) == Some(&analysis.1)
+            }
+            TokenConstraint::Atom(_) => self
+                .index_for_constraint(constraint)
+                .unwrap()
+                .contains(&token),
+            // Negations exclude the token if any reading of any of its analyses matches.
+            TokenConstraint::Negated(inner) => !self.token_matches_ref_impl(inner, token),
+            TokenConstraint::Composed { op, children } => {
+                let mut results = children
+                    .iter()
+                    .map(|c| self.reading_matches_ref_impl(c, token, reading, analysis));
+                match op {
+                    TokenConstraintOperation::And => results.all(|x| x),
+                    TokenConstraintOperation::Or => results.any(|x| x),
+                }
+            }
+        }
+    }
+
+    /// Whether some reading of some analysis of the token matches the constraint.
+    fn token_matches_ref_impl(&self, constraint: &TokenConstraint, token: u32) -> bool {
+        let analyses = self.inflections.get_inflection_data(token).unwrap();
+        analyses.iter().any(|data| {
+            let analysis = (*data as u32, (*data >> 32) as u32);
+            // Build up all of the readings, one category at a time.
+            let mut readings = vec![0u32];
+            for (_, start, size) in CATEGORY_BITS {
+                let options = (start..start + size)
+                    .filter(|bit| analysis.0 & (1 << bit) != 0)
+                    .collect::<Vec<_>>();
+                if options.is_empty() {
+                    continue;
+                }
+                readings = readings
+                    .iter()
+                    .flat_map(|r| options.iter().map(move |bit| r | (1 << bit)))
+                    .collect();
+            }
+            readings
+                .into_iter()
+                .any(|r| self.reading_matches_ref_impl(constraint, token, r, analysis))
+        })
+    }
+
     fn index_for_term(&self, term: &QueryTerm) -> Result<Vec<u32>, QueryExecError> {
-        self.index_for_constraint(&term.constraint)
+        let ids = self.index_for_constraint(&term.constraint)?;
+        if !operators_in(&term.constraint).contains(&TokenConstraintOperation::And) {
+            // Without an `and`, the indices are already exact.
+            return Ok(ids);
+        }
+        Ok(ids
+            .into_iter()
+            .filter(|id| self.token_matches_ref_impl(&term.constraint, *id))
+            .collect())
     }
 
     fn resolve_match_ref_impl(
@@ -156,29 +246,45 @@ impl CorpusQueryEngine {
             v.sort_by_key(|k| k.0);
             v
         };
+        let metadata = self.corpus.resolve_match_token(
+            sorted_ranges
+                .iter()
+                .map(|&(id, end)| (id, (end - id + 1) as usize))
+                .collect(),
+        )?;
+        // The context never extends past the bounds of the work.
+        let first_context_id = max(
+            sorted_ranges[0].0.saturating_sub(context_len as u32),
+            metadata.work_start_token,
+        );
+        let last_token_id = metadata.work_end_token - 1;
         // (ID, should_use_token, is_match_text_start)
         let mut id_chunks = vec![];
-        id_chunks.push((
-            sorted_ranges[0].0.saturating_sub(context_len as u32),
-            true,
-            false,
-        ));
+        // If there's no room for context (e.g. at the start of a work), skip it.
+        if first_context_id < sorted_ranges[0].0 {
+            id_chunks.push((first_context_id, true, false));
+        }
         for &(start, end) in &sorted_ranges {
-            let last = id_chunks.last().unwrap();
-            assert!(start >= last.0);
+            if let Some(last) = id_chunks.last() {
+                assert!(start >= last.0);
+            }
             // Start at the start of the token
             id_chunks.push((start, true, true));
             // End at the start of the break.
             id_chunks.push((end, false, false));
         }
-        id_chunks.push((
-            min(
-                id_chunks.last().unwrap().0 + context_len as u32,
-                self.corpus.num_tokens - 1,
-            ),
-            false,
-            false,
-        ));
+        let last_match_id = id_chunks.last().unwrap().0;
+        let last_context_id = min(
+            last_match_id + context_len as u32,
+            self.corpus.num_tokens - 1,
+        );
+        // Context that extends past the end of the work is truncated to the work,
+        // and dropped entirely if there's nothing left.
+        if last_context_id <= last_token_id {
+            id_chunks.push((last_context_id, false, false));
+        } else if last_match_id < last_token_id {
+            id_chunks.push((last_token_id, false, false));
+        }
 
         let mut text = vec![];
         for i in 0..id_chunks.len() - 1 {
@@ -198,24 +304,13 @@ impl CorpusQueryEngine {
         }
 
         Ok(CorpusQueryMatch {
-            metadata: self.corpus.resolve_match_token(
-                sorted_ranges
-                    .iter()
-                    .map(|&(id, end)| (id, (end - id) as usize))
-                    .collect(),
-            )?,
+            metadata,
             text,
+            tokens: vec![],
+            captures: vec![],
         })
     }
 
-    fn resolve_author_data(&self, name: Option<&String>) -> Option<(u32, u32)> {
-        let name = name?;
-        let (start, end) = self.corpus.author_lookup.get(name)?;
-        let start_work = self.corpus.work_lookup[*start].rows.first()?.1;
-        let end_work = self.corpus.work_lookup[*end - 1].rows.last()?.1;
-        Some((start_work, end_work))
-    }
-
     fn query_corpus_ref_impl(
         &self,
         query_str: &str,
@@ -226,6 +321,7 @@ impl CorpusQueryEngine {
     ) -> Result<CorpusQueryResult<'_>, QueryExecError> {
         let query =
             parse_query(query_str).map_err(|_| QueryExecError::new("Failed to parse query"))?;
+        let (query, _) = self.expand_atoms(query)?;
         let first = &query.terms[0];
 
         struct SpanCandidate {
@@ -266,23 +362,25 @@ impl CorpusQueryEngine {
         }
 
         let hard_breaks = self.hard_breaks_ref_impl()?;
-        // Just for now.
-        assert!(query.authors.len() <= 1);
-        let author_data = self.resolve_author_data(query.authors.first());
+        let scope = self.resolve_scope(&query.scope)?;
         let match_ids: Vec<SpanCandidate> = candidates
             .iter()
             .map(|SpanCandidate { ids, span_length }| {
                 let ids = ids
                     .iter()
                     // -2 because we only care about breaks between tokens, not after the last token.
-                    .filter(|x| !arr_has_any_in_range(&hard_breaks, &(**x, **x + span_length - 2)));
-                let ids: Vec<u32> = if let Some(author_data) = author_data {
-                    ids.filter(|x| **x >= author_data.0 && **x + span_length - 1 <= author_data.1)
-                        .copied()
-                        .collect()
-                } else {
-                    ids.copied().collect()
-                };
+                    .filter(|x| {
+                        *span_length < 2
+                            || !arr_has_any_in_range(&hard_breaks, &(**x, **x + span_length - 2))
+                    });
+                let ids: Vec<u32> = ids
+                    .filter(|x| {
+                        scope
+                            .iter()
+                            .any(|(start, end)| **x >= *start && **x + span_length <= *end)
+                    })
+                    .copied()
+                    .collect();
                 SpanCandidate {
                     ids,
                     span_length: *span_length,
@@ -321,10 +419,12 @@ impl CorpusQueryEngine {
             result_index: *i as u32 + page_data.result_index,
             result_id: ranges[0].0,
             candidate_index: *i as u32 + page_data.result_index,
+            sample_seed: None,
         });
         let result = CorpusQueryResult {
             result_stats: QueryGlobalInfo {
                 estimated_results: match_ids.len(),
+                sample_seed: None,
             },
             next_page,
             timing: vec![],
@@ -0,0 +1,301 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    api::{PageData, QueryGlobalInfo, QueryOptions, ResultSort},
+    corpus_query_engine::{
+        CorpusQueryEngine, MatchIterator, PreparedQuery, QueryExecError,
+        corpus_result_resolution::{
+            MatchPageResult, SpanLeaders, for_each_match, match_range, sampled_matches,
+        },
+        query_scope::work_range,
+    },
+};
+
+/// The number of words of context that are compared when sorting by context.
+const SORT_CONTEXT_WORDS: u32 = 3;
+
+impl FromStr for ResultSort {
+    type Err = QueryExecError;
+
+    /// Parses a sort order, which is one of `position`, `left`, `match`, `right`,
+    /// `work`, or `random:<seed>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "position" => Ok(ResultSort::Position),
+            "left" => Ok(ResultSort::LeftContext),
+            "match" => Ok(ResultSort::Match),
+            "right" => Ok(ResultSort::RightContext),
+            "work" => Ok(ResultSort::Work),
+            _ => s
+                .strip_prefix("random:")
+                .and_then(|seed| seed.parse::<u64>().ok())
+                .map(ResultSort::Random)
+                .ok_or(QueryExecError::new(&format!("Unknown sort order '{s}'"))),
+        }
+    }
+}
+
+/// Mixes the seed and value into a pseudo-random number (using the SplitMix64 finalizer),
+/// which is stable across platforms and versions.
+pub(super) fn seeded_hash(seed: u64, value: u64) -> u64 {
+    let mut z = seed ^ value.wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The value that matches are sorted by (before their position).
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey<'a> {
+    Words(Vec<String>),
+    Work(&'a String, &'a String),
+    Random(u64),
+}
+
+/// A match along with what it's sorted by. Matches are ordered by their key,
+/// with ties broken by position.
+struct KeyedMatch<'k, 'a> {
+    key: SortKey<'k>,
+    position: u32,
+    leaders: SpanLeaders<'a>,
+}
+
+impl PartialEq for KeyedMatch<'_, '_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KeyedMatch<'_, '_> {}
+
+impl PartialOrd for KeyedMatch<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KeyedMatch<'_, '_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key, self.position).cmp(&(&other.key, other.position))
+    }
+}
+
+impl CorpusQueryEngine {
+    fn words_in(&self, tokens: impl Iterator<Item = u32>) -> Result<SortKey<'_>, QueryExecError> {
+        let words = tokens
+            .map(|token| self.normalized_word(token))
+            .collect::<Result<_, _>>()?;
+        Ok(SortKey::Words(words))
+    }
+
+    fn sort_key(
+        &self,
+        leaders: &SpanLeaders,
+        sort: ResultSort,
+    ) -> Result<SortKey<'_>, QueryExecError> {
+        let (start, end) = match_range(leaders);
+        let (work_idx, _) = self.location_of(start)?;
+        let work = &self.corpus.work_lookup[work_idx];
+        let (work_start, work_end) = work_range(work)?;
+        match sort {
+            ResultSort::LeftContext => {
+                let first = start.saturating_sub(SORT_CONTEXT_WORDS).max(work_start);
+                self.words_in((first..start).rev())
+            }
+            ResultSort::Match => self.words_in(start..end),
+            ResultSort::RightContext => {
+                self.words_in(end..(end + SORT_CONTEXT_WORDS).min(work_end))
+            }
+            ResultSort::Work => Ok(SortKey::Work(&work.info.author, &work.info.name)),
+            ResultSort::Random(seed) => Ok(SortKey::Random(seeded_hash(seed, start as u64))),
+            // Every match has the same key, so they stay in position order.
+            ResultSort::Position => Ok(SortKey::Random(0)),
+        }
+    }
+
+    fn keyed_match<'a>(
+        &self,
+        leaders: &SpanLeaders<'a>,
+        sort: ResultSort,
+    ) -> Result<KeyedMatch<'_, 'a>, QueryExecError> {
+        Ok(KeyedMatch {
+            key: self.sort_key(leaders, sort)?,
+            position: match_range(leaders).0,
+            leaders: leaders.clone(),
+        })
+    }
+
+    /// Sorts the matches in the given order, breaking ties by position.
+    fn sort_matches<'a>(
+        &self,
+        matches: Vec<SpanLeaders<'a>>,
+        sort: ResultSort,
+    ) -> Result<Vec<SpanLeaders<'a>>, QueryExecError> {
+        let mut keyed = matches
+            .iter()
+            .map(|leaders| self.keyed_match(leaders, sort))
+            .collect::<Result<Vec<_>, QueryExecError>>()?;
+        keyed.sort();
+        Ok(keyed.into_iter().map(|keyed| keyed.leaders).collect())
+    }
+
+    /// Finds every match of the prepared query and returns the requested page of them,
+    /// in the order given by the options. Only the matches up to the end of the page
+    /// are kept (in a bounded heap), so memory use doesn't grow with the number of matches.
+    pub(super) fn sorted_match_page<'a>(
+        &self,
+        prepared: &PreparedQuery<'a, '_>,
+        page_data: &PageData,
+        options: &QueryOptions,
+    ) -> Result<MatchPageResult<'a>, QueryExecError> {
+        let limit = (page_data.result_index as usize).saturating_add(options.page_size);
+        let mut candidates = MatchIterator::new(prepared.candidates, &PageData::default());
+        // A max-heap, so the last match (in sorted order) is the one to drop.
+        let mut kept: BinaryHeap<KeyedMatch> = BinaryHeap::with_capacity(limit.min(1024) + 1);
+        let mut total = 0;
+        for_each_match(
+            &mut candidates,
+            prepared.span_candidates,
+            prepared.query_spans,
+            prepared.scope,
+            self,
+            options,
+            |leaders| {
+                total += 1;
+                if limit == 0 {
+                    return Ok(());
+                }
+                let keyed = self.keyed_match(leaders, options.sort)?;
+                if kept.len() < limit {
+                    kept.push(keyed);
+                } else if kept.peek().is_some_and(|last| keyed < *last) {
+                    kept.pop();
+                    kept.push(keyed);
+                }
+                Ok(())
+            },
+        )?;
+        let matches = kept
+            .into_sorted_vec()
+            .into_iter()
+            .map(|keyed| keyed.leaders)
+            .collect();
+        Ok(page_of_matches(
+            matches, total, total, page_data, options, None,
+        ))
+    }
+
+    /// Draws a random sample of the matches of the prepared query (using the seed from
+    /// the page data, or a new one if there is none) and returns the requested page of
+    /// the sample, in the order given by the options.
+    pub(super) fn sampled_match_page<'a>(
+        &self,
+        prepared: &PreparedQuery<'a, '_>,
+        page_data: &PageData,
+        options: &QueryOptions,
+    ) -> Result<MatchPageResult<'a>, QueryExecError> {
+        let seed = page_data.sample_seed.unwrap_or_else(new_seed);
+        let mut candidates = MatchIterator::new(prepared.candidates, &PageData::default());
+        let (sample, total) = sampled_matches(
+            &mut candidates,
+            prepared.span_candidates,
+            prepared.query_spans,
+            prepared.scope,
+            self,
+            options,
+            seed,
+        )?;
+        let sample = self.sort_matches(sample, options.sort)?;
+        let sample_len = sample.len();
+        Ok(page_of_matches(
+            sample,
+            sample_len,
+            total,
+            page_data,
+            options,
+            Some(seed),
+        ))
+    }
+}
+
+/// The largest seed for a new random sample. Seeds are passed back and forth with
+/// Javascript as numbers, so they need to be exactly representable as doubles.
+const MAX_NEW_SEED: u64 = (1 << 53) - 1;
+
+/// Returns a seed for a new random sample.
+fn new_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| seeded_hash(elapsed.as_secs(), elapsed.subsec_nanos() as u64) & MAX_NEW_SEED)
+        .unwrap_or_default()
+}
+
+/// Returns the requested page of the (already ordered) matches, which are the first of
+/// `available` matches in that order. The `total` is the number of matches overall, which
+/// is more than the number available for a sample.
+fn page_of_matches<'a>(
+    mut matches: Vec<SpanLeaders<'a>>,
+    available: usize,
+    total: usize,
+    page_data: &PageData,
+    options: &QueryOptions,
+    sample_seed: Option<u64>,
+) -> MatchPageResult<'a> {
+    let start = (page_data.result_index as usize).min(matches.len());
+    let end = (start + options.page_size).min(matches.len());
+    let next_page = (end < available).then_some(PageData {
+        result_index: end as u32,
+        result_id: 0,
+        candidate_index: 0,
+        sample_seed,
+    });
+    MatchPageResult {
+        matches: matches.drain(start..end).collect(),
+        next_page,
+        summary_info: QueryGlobalInfo {
+            estimated_results: total,
+            sample_seed,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sort_orders() {
+        assert_eq!(
+            "left".parse::<ResultSort>().unwrap(),
+            ResultSort::LeftContext
+        );
+        assert_eq!("work".parse::<ResultSort>().unwrap(), ResultSort::Work);
+        assert_eq!(
+            "random:42".parse::<ResultSort>().unwrap(),
+            ResultSort::Random(42)
+        );
+        assert!("random:abc".parse::<ResultSort>().is_err());
+        assert!("middle".parse::<ResultSort>().is_err());
+    }
+
+    #[test]
+    fn seeded_hash_depends_on_seed_and_value() {
+        assert_eq!(seeded_hash(1, 2), seeded_hash(1, 2));
+        assert_ne!(seeded_hash(1, 2), seeded_hash(2, 2));
+        assert_ne!(seeded_hash(1, 2), seeded_hash(1, 3));
+    }
+
+    #[test]
+    fn new_seeds_are_exact_in_javascript() {
+        let seed = new_seed();
+        assert!(seed <= MAX_NEW_SEED);
+        assert_eq!(seed as f64 as u64, seed);
+        assert_eq!(
+            format!("random:{seed}").parse::<ResultSort>().unwrap(),
+            ResultSort::Random(seed)
+        );
+    }
+}
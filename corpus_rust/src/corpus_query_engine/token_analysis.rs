@@ -0,0 +1,208 @@
+use std::{
+    cmp::{max, min},
+    str::FromStr,
+};
+
+use crate::{
+    api::{CorpusQueryMatchMetadata, ExpandedAnalysis, MatchAnalyses, MatchToken, TokenAnalysis},
+    corpus_query_engine::{
+        CorpusQueryEngine, QueryExecError,
+        corpus_result_resolution::{
+            CASE_START, GENDER_START, MOOD_START, NUMBER_START, PERSON_START, SpanLeaders,
+            TENSE_START, VOICE_START,
+        },
+    },
+};
+
+/// The start of each inflection category in the inflection mask, along with
+/// the labels of its values (in order of their codes).
+const CATEGORY_LABELS: [(u32, &[&str]); 7] = [
+    (
+        CASE_START,
+        &["nom", "acc", "dat", "gen", "abl", "voc", "loc"],
+    ),
+    (NUMBER_START, &["sg", "pl"]),
+    (GENDER_START, &["masc", "fem", "neut", "adverbial"]),
+    (PERSON_START, &["1st", "2nd", "3rd"]),
+    (
+        MOOD_START,
+        &["ind", "imp", "subj", "part", "gerundive", "inf", "supine"],
+    ),
+    (VOICE_START, &["act", "pass"]),
+    (
+        TENSE_START,
+        &["pres", "impf", "perf", "futperf", "fut", "plupf"],
+    ),
+];
+
+impl FromStr for MatchAnalyses {
+    type Err = QueryExecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MatchAnalyses::None),
+            "match" => Ok(MatchAnalyses::Match),
+            "context" => Ok(MatchAnalyses::MatchAndContext),
+            _ => Err(QueryExecError::new(&format!(
+                "Unknown analyses option '{s}' (expected none, match, or context)"
+            ))),
+        }
+    }
+}
+
+/// Returns the labels of the values in the inflection mask for each category,
+/// in the order of `CATEGORY_LABELS`.
+fn category_values(mask: u32) -> [Vec<&'static str>; 7] {
+    CATEGORY_LABELS.map(|(start, labels)| {
+        labels
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << (start + *i as u32)) != 0)
+            .map(|(_, label)| *label)
+            .collect()
+    })
+}
+
+/// Formats the inflection mask of an analysis, like `dat/abl pl`.
+pub(super) fn format_inflection(mask: u32) -> String {
+    category_values(mask)
+        .iter()
+        .filter(|values| !values.is_empty())
+        .map(|values| values.join("/"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Expands the inflection mask of an analysis of the given lemma.
+pub(super) fn expand_inflection(lemma: &String, mask: u32) -> ExpandedAnalysis<'_> {
+    let [case, number, gender, person, mood, voice, tense] = category_values(mask);
+    ExpandedAnalysis {
+        lemma,
+        case,
+        number,
+        gender,
+        person,
+        mood,
+        voice,
+        tense,
+    }
+}
+
+impl CorpusQueryEngine {
+    /// Returns the name of each lemma, indexed by ID.
+    pub(super) fn lemma_names(&self) -> Vec<Option<&String>> {
+        let Some(ids) = self.corpus.id_table.get("lemma") else {
+            return vec![];
+        };
+        let mut names = vec![None; ids.len()];
+        for (name, id) in ids {
+            if let Some(slot) = names.get_mut(*id as usize) {
+                *slot = Some(name);
+            }
+        }
+        names
+    }
+
+    /// Returns the possible analyses of the token.
+    pub(super) fn analyses_of<'a>(
+        &self,
+        token: u32,
+        lemma_names: &[Option<&'a String>],
+    ) -> Result<Vec<TokenAnalysis<'a>>, QueryExecError> {
+        let analyses = self.inflections.get_inflection_data(token)?;
+        Ok(analyses
+            .iter()
+            .filter_map(|data| {
+                let lemma = (*lemma_names.get((data >> 32) as usize)?)?;
+                Some(TokenAnalysis {
+                    lemma,
+                    inflection: format_inflection(*data as u32),
+                })
+            })
+            .collect())
+    }
+
+    /// Returns the tokens of the match (and of the context, if requested) with their
+    /// expanded analyses. The context is the given number of tokens before and after
+    /// the match, limited to the work of the match.
+    pub(super) fn match_tokens<'a>(
+        &self,
+        leaders: &SpanLeaders,
+        metadata: &CorpusQueryMatchMetadata,
+        (left_context, right_context): (u32, u32),
+        mode: MatchAnalyses,
+        lemma_names: &[Option<&'a String>],
+    ) -> Result<Vec<MatchToken<'a>>, QueryExecError> {
+        let (Some(first), Some(last)) = (leaders.first(), leaders.last()) else {
+            return Ok(vec![]);
+        };
+        let match_end = last.0 + last.1.length as u32;
+        let (start, end) = match mode {
+            MatchAnalyses::None => return Ok(vec![]),
+            MatchAnalyses::Match => (first.0, match_end),
+            MatchAnalyses::MatchAndContext => (
+                max(
+                    first.0.saturating_sub(left_context),
+                    metadata.work_start_token,
+                ),
+                min(match_end + right_context, metadata.work_end_token),
+            ),
+        };
+        let mut tokens = Vec::with_capacity((end - start) as usize);
+        for id in start..end {
+            let is_match = leaders
+                .iter()
+                .any(|(leader, span)| (*leader..(*leader + span.length as u32)).contains(&id));
+            if !is_match && mode == MatchAnalyses::Match {
+                continue;
+            }
+            let text = self
+                .text
+                .slice(self.starts.token_start(id)?, self.starts.break_start(id)?);
+            let analyses = self
+                .inflections
+                .get_inflection_data(id)?
+                .iter()
+                .filter_map(|data| {
+                    let lemma = (*lemma_names.get((data >> 32) as usize)?)?;
+                    Some(expand_inflection(lemma, *data as u32))
+                })
+                .collect();
+            tokens.push(MatchToken {
+                id,
+                text,
+                is_match,
+                analyses,
+            });
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_inflection_lists_values_by_category() {
+        let dat_abl_pl =
+            (1 << (CASE_START + 2)) | (1 << (CASE_START + 4)) | (1 << (NUMBER_START + 1));
+        assert_eq!(format_inflection(dat_abl_pl), "dat/abl pl");
+        let pres_ind_act_3rd =
+            (1 << (PERSON_START + 2)) | (1 << MOOD_START) | (1 << VOICE_START) | (1 << TENSE_START);
+        assert_eq!(format_inflection(pres_ind_act_3rd), "3rd ind act pres");
+        assert_eq!(format_inflection(0), "");
+    }
+
+    #[test]
+    fn expand_inflection_splits_categories() {
+        let lemma = "puella".to_string();
+        let dat_abl_pl =
+            (1 << (CASE_START + 2)) | (1 << (CASE_START + 4)) | (1 << (NUMBER_START + 1));
+        let expanded = expand_inflection(&lemma, dat_abl_pl);
+        assert_eq!(expanded.case, vec!["dat", "abl"]);
+        assert_eq!(expanded.number, vec!["pl"]);
+        assert!(expanded.gender.is_empty());
+        assert!(expanded.tense.is_empty());
+    }
+}
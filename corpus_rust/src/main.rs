@@ -2,8 +2,12 @@ use std::env;
 use std::time::Instant;
 
 use corpus::{
-    api::{CorpusQueryResult, PageData, QueryExecError, QueryOptions},
-    build_corpus_v2::build_corpus,
+    api::{
+        Collocate, CollocationOptions, ContextMode, CorpusQueryResult, ExpandedAnalysis,
+        ExportFormat, IndexRepresentation, IndexStep, MatchAnalyses, PageData, QueryExecError,
+        QueryOptions, QueryScope, ResultSort,
+    },
+    build_corpus_v2::{build_corpus, load_analyzer},
     corpus_index,
     corpus_query_engine::{self, CorpusQueryEngine},
 };
@@ -11,6 +15,10 @@ use corpus::{
 const ARG_QUIET: &str = "--quiet";
 const ARG_NO_STATS: &str = "--no-stats";
 const ARG_STRICT: &str = "--strict";
+const ARG_COUNT: &str = "--count";
+const ARG_COLLOCATIONS: &str = "--collocations";
+const ARG_EXPORT: &str = "--export";
+const ARG_EXPLAIN: &str = "--explain";
 const CORPUS_ROOT: &str = "build/corpus/latin_corpus.json";
 
 fn load_corpus_with_timing(path: &str) -> corpus_index::LatinCorpusIndex {
@@ -30,18 +38,26 @@ fn load_corpus_with_timing(path: &str) -> corpus_index::LatinCorpusIndex {
     corpus
 }
 
+fn query_options() -> QueryOptions {
+    QueryOptions {
+        page_size: get_limit_arg(),
+        context_len: get_context_arg(),
+        context_mode: get_parsed_arg("--context-mode", ContextMode::Tokens),
+        strict_mode: has_arg(ARG_STRICT),
+        scope: has_arg("--scope").then(|| get_parsed_arg("--scope", QueryScope::default())),
+        sort: get_parsed_arg("--sort", ResultSort::Position),
+        sample_size: has_arg("--sample").then(|| get_arg_or_default("sample", 100)),
+        analyses: get_parsed_arg("--analyses", MatchAnalyses::None),
+    }
+}
+
 fn query_with_timing<'a>(
     engine: &'a CorpusQueryEngine,
     query: &str,
     page_data: &PageData,
 ) -> Result<CorpusQueryResult<'a>, QueryExecError> {
-    let options = QueryOptions {
-        page_size: get_limit_arg(),
-        context_len: get_context_arg(),
-        strict_mode: has_arg(ARG_STRICT),
-    };
     let start = Instant::now();
-    let results = engine.query_corpus(query, page_data, &options)?;
+    let results = engine.query_corpus(query, page_data, &query_options())?;
     let duration = start.elapsed();
     if !has_arg(ARG_NO_STATS) {
         println!("Query executed in {duration:.2?}");
@@ -68,12 +84,28 @@ fn get_query_arg_or_exit() -> String {
         return q.clone();
     }
     eprintln!(
-        "Usage: {} --query <QUERY> [--limit <N>] [--context <N>] [--quiet]",
+        "Usage: {} --query <QUERY> [--scope <SCOPE>] [--sort <ORDER>] [--sample <N> [--seed <SEED>]] [--analyses <match|context>] [--limit <N>] [--context <N> [--context-mode <tokens|sentence|section>]] [--count] [--collocations <WINDOW>] [--export <csv|tsv|jsonl> --output <PATH>] [--explain] [--quiet]",
         args.first().unwrap_or(&"program".to_string())
     );
     std::process::exit(1);
 }
 
+/// Parses the value of an option like `--sort`, exiting if it is invalid.
+fn get_parsed_arg<T: std::str::FromStr<Err = QueryExecError>>(tag: &str, fallback: T) -> T {
+    let args: Vec<String> = env::args().collect();
+    let Some(value) = args
+        .iter()
+        .position(|a| a == tag)
+        .and_then(|pos| args.get(pos + 1))
+    else {
+        return fallback;
+    };
+    value.parse().unwrap_or_else(|e: QueryExecError| {
+        eprintln!("{}", e.message);
+        std::process::exit(1);
+    })
+}
+
 fn get_arg_or_default<T: std::str::FromStr>(name: &str, fallback: T) -> T {
     let args: Vec<String> = env::args().collect();
     if let Some(pos) = args.iter().position(|a| *a == format!("--{name}"))
@@ -112,6 +144,25 @@ fn get_results<'a>(
     result.unwrap()
 }
 
+fn format_analysis(analysis: &ExpandedAnalysis) -> String {
+    let categories = [
+        &analysis.case,
+        &analysis.number,
+        &analysis.gender,
+        &analysis.person,
+        &analysis.mood,
+        &analysis.voice,
+        &analysis.tense,
+    ];
+    let inflection = categories
+        .iter()
+        .filter(|values| !values.is_empty())
+        .map(|values| values.join("/"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{} ({inflection})", analysis.lemma)
+}
+
 fn print_query_results(
     engine: &CorpusQueryEngine,
     query_str: &str,
@@ -143,10 +194,180 @@ fn print_query_results(
         }
         chunks.push("\n".to_string());
         print!("{}", chunks.join(""));
+        for token in &match_data.tokens {
+            let color = if token.is_match { "[31m" } else { "[90m" };
+            let analyses = token
+                .analyses
+                .iter()
+                .map(format_analysis)
+                .collect::<Vec<_>>()
+                .join(" | ");
+            println!("      \x1b{color}{}\x1b[0m: {analyses}", token.text);
+        }
+        for capture in &match_data.captures {
+            println!(
+                "      \x1b[33m{}\x1b[0m: {} ({} +{})",
+                capture.label, capture.text, capture.section, capture.offset
+            );
+        }
     }
     results.next_page
 }
 
+fn print_query_counts(engine: &CorpusQueryEngine, query_str: &str) {
+    let start = Instant::now();
+    let counts = engine
+        .count_corpus(query_str, &query_options())
+        .unwrap_or_else(|e| {
+            eprintln!("Error executing query: {}", e.message);
+            std::process::exit(1);
+        });
+    if !has_arg(ARG_NO_STATS) {
+        println!("Query counted in {:.2?}", start.elapsed());
+    }
+    println!("\n\x1b[4m{} matches:\x1b[0m", counts.total);
+    for author in &counts.authors {
+        println!("  \x1b[34m{}\x1b[0m: {}", author.author, author.count);
+        for work in &author.works {
+            println!("    \x1b[32m{}\x1b[0m: {}", work.work_name, work.count);
+            if has_arg(ARG_QUIET) {
+                continue;
+            }
+            for (section, count) in &work.sections {
+                println!("      {section}: {count}");
+            }
+        }
+    }
+}
+
+fn print_collocates(label: &str, collocates: &[Collocate]) {
+    if collocates.is_empty() {
+        return;
+    }
+    println!("    {label}:");
+    for c in collocates {
+        println!(
+            "      {:<20} {:>6} (corpus: {}, MI: {:.2}, t: {:.2}, LL: {:.2})",
+            c.value, c.count, c.corpus_count, c.mutual_information, c.t_score, c.log_likelihood
+        );
+    }
+}
+
+fn print_query_collocations(engine: &CorpusQueryEngine, query_str: &str) {
+    let start = Instant::now();
+    let options = CollocationOptions {
+        window: get_arg_or_default("collocations", 5),
+        max_results: get_limit_arg(),
+        min_count: get_arg_or_default("min-count", 2),
+    };
+    let result = engine
+        .collocations(query_str, &query_options(), &options)
+        .unwrap_or_else(|e| {
+            eprintln!("Error executing query: {}", e.message);
+            std::process::exit(1);
+        });
+    if !has_arg(ARG_NO_STATS) {
+        println!("Collocations computed in {:.2?}", start.elapsed());
+    }
+    println!("\n\x1b[4mCollocates of {} matches:\x1b[0m", result.matches);
+    for position in &result.positions {
+        println!(
+            "  \x1b[34m{:+}\x1b[0m ({} samples)",
+            position.offset, position.samples
+        );
+        print_collocates("Words", &position.words);
+        if !has_arg(ARG_QUIET) {
+            print_collocates("Lemmata", &position.lemmata);
+        }
+    }
+}
+
+fn export_query_matches(engine: &CorpusQueryEngine, query_str: &str) {
+    let exit_with = |message: String| -> ! {
+        eprintln!("{message}");
+        std::process::exit(1);
+    };
+    let args: Vec<String> = env::args().collect();
+    let arg_after = |name: &str| {
+        let pos = args.iter().position(|a| a == name)?;
+        args.get(pos + 1)
+    };
+    let Some(format) = arg_after(ARG_EXPORT) else {
+        exit_with("Missing export format".to_string());
+    };
+    let format = format
+        .parse::<ExportFormat>()
+        .unwrap_or_else(|e| exit_with(e.message));
+    let Some(path) = arg_after("--output") else {
+        exit_with("Exporting requires an --output path".to_string());
+    };
+    let file = std::fs::File::create(path)
+        .unwrap_or_else(|e| exit_with(format!("Failed to create {path}: {e}")));
+    let mut out = std::io::BufWriter::new(file);
+
+    let start = Instant::now();
+    let total = engine
+        .export_corpus(query_str, &query_options(), format, &mut out)
+        .unwrap_or_else(|e| exit_with(format!("Error executing query: {}", e.message)));
+    println!(
+        "Exported {total} matches to {path} in {:.2?}",
+        start.elapsed()
+    );
+}
+
+fn print_index_steps(steps: &[IndexStep], indent: &str) {
+    for step in steps {
+        let representation = match step.representation {
+            IndexRepresentation::BitMask => "bitmask",
+            IndexRepresentation::Packed => "packed",
+        };
+        println!(
+            "{indent}{}: {} candidates ({representation})",
+            step.step, step.candidates
+        );
+    }
+}
+
+fn print_query_plan(engine: &CorpusQueryEngine, query_str: &str) {
+    let plan = engine
+        .explain_query(query_str, &query_options())
+        .unwrap_or_else(|e| {
+            eprintln!("Error executing query: {}", e.message);
+            std::process::exit(1);
+        });
+    if let Some(error) = &plan.pruning_error {
+        println!(
+            "\n\x1b[4mTerm {} can never match:\x1b[0m {} ({})",
+            error.term + 1,
+            error.reason,
+            error.atoms.join(", ")
+        );
+    }
+    if let Some(query) = &plan.pruned_query {
+        println!("\n\x1b[4mPruned query:\x1b[0m {query}");
+    }
+    if !plan.atoms.is_empty() {
+        println!("\n\x1b[4mAtoms:\x1b[0m");
+    }
+    for atom in &plan.atoms {
+        println!("  {}: {} to {} tokens", atom.atom, atom.lower, atom.upper);
+    }
+    for (i, span) in plan.spans.iter().enumerate() {
+        println!("\n\x1b[4mSpan {i}:\x1b[0m \x1b[34m{}\x1b[0m", span.terms);
+        print_index_steps(&span.steps, "  ");
+    }
+    if !plan.combinations.is_empty() {
+        println!("\n\x1b[4mCombined candidates:\x1b[0m");
+        print_index_steps(&plan.combinations, "  ");
+    }
+    if !has_arg(ARG_NO_STATS) && !plan.timing.is_empty() {
+        println!("\nQuery timing breakdown:");
+        for (k, v) in &plan.timing {
+            println!("  {}: {:.3} ms", k, *v);
+        }
+    }
+}
+
 fn print_top_snapshot_for(pid: u32, show_header: bool) {
     let pid_arg = pid.to_string();
     let output = std::process::Command::new("top")
@@ -197,15 +418,32 @@ fn build_if_needed() -> Result<(), Box<dyn std::error::Error>> {
 fn main() {
     build_if_needed().expect("Failed to build corpus");
     let corpus = load_corpus_with_timing(CORPUS_ROOT);
-    let engine =
+    let mut engine =
         corpus_query_engine::CorpusQueryEngine::new(corpus).expect("Failed to create query engine");
+    let query_str = get_query_arg_or_exit();
+    // The tables are slow to load, so only do so if the query needs them.
+    if query_str.contains("@form:") || query_str.contains("@f:") {
+        engine = engine.with_analyzer(load_analyzer().expect("Failed to load analyzer"));
+    }
     if has_arg("--mem") {
         print_mem_summary("Before query execution".to_string(), 1);
     }
-    let query_str = get_query_arg_or_exit();
-    let mut page_data = PageData::default();
-    for _ in 0..get_pages_arg() {
-        page_data = print_query_results(&engine, &query_str, &page_data).unwrap_or_default();
+    if has_arg(ARG_COUNT) {
+        print_query_counts(&engine, &query_str);
+    } else if has_arg(ARG_COLLOCATIONS) {
+        print_query_collocations(&engine, &query_str);
+    } else if has_arg(ARG_EXPORT) {
+        export_query_matches(&engine, &query_str);
+    } else if has_arg(ARG_EXPLAIN) {
+        print_query_plan(&engine, &query_str);
+    } else {
+        let mut page_data = PageData {
+            sample_seed: has_arg("--seed").then(|| get_arg_or_default("seed", 0)),
+            ..PageData::default()
+        };
+        for _ in 0..get_pages_arg() {
+            page_data = print_query_results(&engine, &query_str, &page_data).unwrap_or_default();
+        }
     }
     if has_arg("--mem") {
         print_mem_summary("After query execution".to_string(), 1);
@@ -3,13 +3,51 @@ use std::str::FromStr;
 use super::analyzer_types::LatinInflection;
 
 const DEFAULT_PROXIMITY: u8 = 5;
-const SIMPLE_PREFIXES: [&str; 4] = ["@lemma:", "@word:", "@l:", "@w:"];
+/// The distance for a same-sentence relation (like `~.`) without an explicit distance.
+const SENTENCE_PROXIMITY: u8 = u8::MAX;
+const SIMPLE_PREFIXES: [&str; 6] = ["@lemma:", "@word:", "@form:", "@l:", "@w:", "@f:"];
+/// Names that can't be labels, since they're more likely a constraint missing an `@`.
+const RESERVED_LABELS: [&str; 20] = [
+    "lemma", "word", "form", "l", "w", "f", "case", "c", "number", "n", "gender", "g", "person",
+    "p", "mood", "m", "voice", "v", "tense", "t",
+];
 
 /// A query on the corpus.
 #[derive(Debug, Clone)]
 pub struct Query {
     pub terms: Vec<QueryTerm>,
-    pub authors: Vec<String>,
+    pub scope: QueryScope,
+}
+
+/// An inclusive range of sections within a work. Each end is a section ID, or a
+/// prefix of one (so `4` would include sections `4.1`, `4.2`, and so on).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionRange {
+    pub start: String,
+    pub end: String,
+}
+
+/// One entry in the scope of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScopeItem {
+    /// An author, or the ID of a single work.
+    Name(String),
+    /// A single work by an author, identified by either its name or its ID.
+    /// If `sections` is present, only those sections of the work are included.
+    Work {
+        author: String,
+        work: String,
+        sections: Option<SectionRange>,
+    },
+}
+
+/// The parts of the corpus that a query should search.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryScope {
+    /// The parts of the corpus to search. If empty, the whole corpus is searched.
+    pub included: Vec<ScopeItem>,
+    /// The parts of the corpus to skip, even if they are included above.
+    pub excluded: Vec<ScopeItem>,
 }
 
 /// An error that occurs while parsing a query.
@@ -33,6 +71,60 @@ pub struct QueryTerm {
     pub constraint: TokenConstraint,
     /// The relationship this token has with the last token.
     pub relation: QueryRelation,
+    /// Whether the token can be left out of a match. If it is, the next token has
+    /// the same relation with the token before this one as it would with this one.
+    pub optional: bool,
+    /// The agreement group of this token, if any.
+    pub agreement: Option<Agreement>,
+    /// The label of this term, if any. Matches report which token satisfied each label.
+    pub label: Option<String>,
+}
+
+/// An inflection category that tokens can agree in. These are in the same order
+/// as the categories in the inflection data of the corpus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgreementCategory {
+    Case,
+    Number,
+    Gender,
+    Person,
+    Mood,
+    Voice,
+    Tense,
+}
+
+impl FromStr for AgreementCategory {
+    type Err = QueryParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" | "case" => Ok(AgreementCategory::Case),
+            "n" | "number" => Ok(AgreementCategory::Number),
+            "g" | "gender" => Ok(AgreementCategory::Gender),
+            "p" | "person" => Ok(AgreementCategory::Person),
+            "m" | "mood" => Ok(AgreementCategory::Mood),
+            "v" | "voice" => Ok(AgreementCategory::Voice),
+            "t" | "tense" => Ok(AgreementCategory::Tense),
+            _ => Err(QueryParseError::new(&format!(
+                "Unknown agreement category: {s}"
+            ))),
+        }
+    }
+}
+
+/// The categories that tokens agree in if none are given.
+const DEFAULT_AGREEMENT: [AgreementCategory; 3] = [
+    AgreementCategory::Case,
+    AgreementCategory::Number,
+    AgreementCategory::Gender,
+];
+
+/// A requirement that every token with the same `label` has an analysis that shares
+/// a value with the others in each of the `categories`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Agreement {
+    pub label: String,
+    pub categories: Vec<AgreementCategory>,
 }
 
 /// Represents a relationship between a current token and a previous token.
@@ -40,8 +132,16 @@ pub struct QueryTerm {
 pub enum QueryRelation {
     /// A token is exactly after the previous token.
     After,
-    /// A token is within N tokens of the previous token.
-    Proximity { distance: u8, is_directed: bool },
+    /// A token is within N tokens of the previous token. If `within_sentence` is set,
+    /// there can't be a hard break (like the end of a sentence) between them.
+    Proximity {
+        distance: u8,
+        is_directed: bool,
+        within_sentence: bool,
+    },
+    /// A token comes after the previous token, with between `min` and `max` (inclusive)
+    /// other tokens in between.
+    Gap { min: u8, max: u8 },
     /// This is the first term in the query. There is no relation
     /// with a previous token because there is no previous token.
     First,
@@ -52,10 +152,18 @@ impl std::fmt::Display for QueryRelation {
         match self {
             QueryRelation::After => write!(f, " "),
             QueryRelation::First => write!(f, ""),
+            QueryRelation::Gap { min, max } => write!(f, " {{{min},{max}}} "),
             QueryRelation::Proximity {
                 distance,
                 is_directed,
-            } => write!(f, " {}~{} ", distance, if *is_directed { ">" } else { "" }),
+                within_sentence,
+            } => write!(
+                f,
+                " {}~{}{} ",
+                distance,
+                if *is_directed { ">" } else { "" },
+                if *within_sentence { "." } else { "" }
+            ),
         }
     }
 }
@@ -65,6 +173,13 @@ pub enum TokenConstraintAtom {
     Word(String),
     Lemma(String),
     Inflection(LatinInflection),
+    /// A regular expression (wildcards are converted to one) that matches words.
+    WordPattern(String),
+    /// A regular expression (wildcards are converted to one) that matches lemmata.
+    LemmaPattern(String),
+    /// An inflected form, which matches any token that has one of the lemmata the form could
+    /// be an inflection of.
+    Form(String),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -93,6 +208,9 @@ impl std::fmt::Display for TokenConstraint {
                 TokenConstraintAtom::Inflection(inf) => {
                     write!(f, "@{}:{}", inf.get_label(), inf.get_code())
                 }
+                TokenConstraintAtom::WordPattern(p) => write!(f, "/{p}/"),
+                TokenConstraintAtom::LemmaPattern(p) => write!(f, "@lemma:/{p}/"),
+                TokenConstraintAtom::Form(form) => write!(f, "@form:{form}"),
             },
             TokenConstraint::Composed { op, children } => {
                 let op_str = match op {
@@ -143,6 +261,44 @@ fn is_valid_lemma(s: &str) -> bool {
     chars.all(|c| c.is_ascii_alphabetic())
 }
 
+/// Parses a wildcard (like `amav*` or `am?`) or regular expression (like `/^in.*ibus$/`)
+/// into the source of a regular expression. Returns `None` if the input is neither.
+fn parse_pattern(input: &str) -> Result<Option<String>, QueryParseError> {
+    if let Some(rest) = input.strip_prefix('/') {
+        let pattern = rest
+            .strip_suffix('/')
+            .ok_or(QueryParseError::new("Missing closing '/' in pattern"))?;
+        if pattern.is_empty() {
+            return Err(QueryParseError::new("Empty pattern not allowed"));
+        }
+        if let Err(e) = regex::Regex::new(pattern) {
+            return Err(QueryParseError::new(&format!(
+                "Invalid regular expression '{pattern}': {e}"
+            )));
+        }
+        return Ok(Some(pattern.to_string()));
+    }
+    if !input.contains(['*', '?']) {
+        return Ok(None);
+    }
+    if !input.chars().any(|c| c.is_ascii_alphabetic()) {
+        return Err(QueryParseError::new(
+            "Wildcards must include at least one letter",
+        ));
+    }
+    let mut pattern = "^".to_string();
+    for c in input.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c if c.is_ascii_alphabetic() => pattern.push(c),
+            _ => return Err(QueryParseError::new("Wildcards must be alphabetic")),
+        }
+    }
+    pattern.push('$');
+    Ok(Some(pattern))
+}
+
 /// Helper to parse a token atom from a string
 fn parse_token_atom(input: &str) -> Result<TokenConstraintAtom, QueryParseError> {
     for simple_prefix in SIMPLE_PREFIXES.iter() {
@@ -156,13 +312,21 @@ fn parse_token_atom(input: &str) -> Result<TokenConstraintAtom, QueryParseError>
         if content.is_empty() {
             return Err(QueryParseError::new("Empty token atom not allowed"));
         }
+        if let Some(pattern) = parse_pattern(&content)? {
+            match *simple_prefix {
+                "@l:" | "@lemma:" => return Ok(TokenConstraintAtom::LemmaPattern(pattern)),
+                "@w:" | "@word:" => return Ok(TokenConstraintAtom::WordPattern(pattern)),
+                _ => return Err(QueryParseError::new("Forms cannot contain patterns")),
+            }
+        }
         if !is_valid_lemma(&content) {
             return Err(QueryParseError::new("Token atom must be alphabetic"));
         }
         match *simple_prefix {
             "@l:" | "@lemma:" => return Ok(TokenConstraintAtom::Lemma(content.to_string())),
             "@w:" | "@word:" => return Ok(TokenConstraintAtom::Word(content.to_string())),
-            _ => unreachable!(), // We only have two prefixes defined
+            "@f:" | "@form:" => return Ok(TokenConstraintAtom::Form(content.to_string())),
+            _ => unreachable!(), // We only have three prefixes defined
         }
     }
 
@@ -175,6 +339,10 @@ fn parse_token_atom(input: &str) -> Result<TokenConstraintAtom, QueryParseError>
         }
     }
 
+    if let Some(pattern) = parse_pattern(input)? {
+        return Ok(TokenConstraintAtom::WordPattern(pattern));
+    }
+
     // Default to word for plain text that matches Latin alphabet
     if input.chars().all(|c| c.is_ascii_alphabetic()) && !input.is_empty() {
         return Ok(TokenConstraintAtom::Word(input.to_string()));
@@ -185,18 +353,59 @@ fn parse_token_atom(input: &str) -> Result<TokenConstraintAtom, QueryParseError>
     )))
 }
 
+/// Parses the bounds of a gap, like `{0,3}` or `{2}`, without the braces.
+fn parse_gap(input: &str) -> Result<QueryRelation, QueryParseError> {
+    let parse_bound = |bound: &str| {
+        bound
+            .trim()
+            .parse::<u8>()
+            .map_err(|_| QueryParseError::new("Gap bounds must be numbers from 0 to 255"))
+    };
+    let (min, max) = match input.split_once(',') {
+        Some((min, max)) => (parse_bound(min)?, parse_bound(max)?),
+        None => {
+            let n = parse_bound(input)?;
+            (n, n)
+        }
+    };
+    if min > max {
+        return Err(QueryParseError::new(
+            "The start of a gap can't be larger than the end",
+        ));
+    }
+    Ok(QueryRelation::Gap { min, max })
+}
+
 fn parse_relation(raw_input: &str) -> Result<QueryRelation, QueryParseError> {
-    let input = raw_input.trim();
+    let trimmed = raw_input.trim();
+    if trimmed.is_empty() {
+        return Ok(QueryRelation::After);
+    }
+    if let Some(rest) = trimmed.strip_prefix('{') {
+        let bounds = rest
+            .strip_suffix('}')
+            .ok_or(QueryParseError::new("Missing closing '}' in gap"))?;
+        return parse_gap(bounds);
+    }
+    let (input, within_sentence) = match trimmed.strip_suffix('.') {
+        Some(rest) => (rest, true),
+        None => (trimmed, false),
+    };
     let n = input.chars().count();
     if n == 0 {
-        return Ok(QueryRelation::After);
+        return Err(QueryParseError::new("Missing `~` in relation"));
     }
 
     if n == 1 {
         check_equal!(input.chars().next(), Some('~'), "");
         return Ok(QueryRelation::Proximity {
-            distance: 5,
+            distance: if within_sentence {
+                SENTENCE_PROXIMITY
+            } else {
+                DEFAULT_PROXIMITY
+            },
             is_directed: false,
+            within_sentence,
         });
     }
 
@@ -205,10 +414,27 @@ fn parse_relation(raw_input: &str) -> Result<QueryRelation, QueryParseError> {
     let is_directed = ult == Some('>');
     check_equal!(if is_directed { penult } else { ult }, Some('~'), "");
     let leading = &input[..n - (if is_directed { 2 } else { 1 })];
-    let distance = leading.parse::<u8>().unwrap_or(DEFAULT_PROXIMITY);
+    let distance = match leading {
+        "" if within_sentence => SENTENCE_PROXIMITY,
+        "" => DEFAULT_PROXIMITY,
+        _ => match leading.parse::<u8>() {
+            Ok(0) => {
+                return Err(QueryParseError::new(
+                    "Proximity distance must be at least 1",
+                ));
+            }
+            Ok(distance) => distance,
+            Err(_) => {
+                return Err(QueryParseError::new(
+                    "Proximity distances must be numbers from 1 to 255",
+                ));
+            }
+        },
+    };
     Ok(QueryRelation::Proximity {
         distance,
         is_directed,
+        within_sentence,
     })
 }
 
@@ -370,15 +596,46 @@ fn find_word_end(input: &str, start_idx: usize) -> usize {
     n - 1
 }
 
+/// Returns the number of characters in the label (including the `:`) at the start
+/// of the input, like `verb:` in `verb:@mood:subj`, if there is one.
+fn label_prefix_len(input: &str) -> Option<usize> {
+    let mut chars = input.chars();
+    if !chars.next()?.is_ascii_alphabetic() {
+        return None;
+    }
+    for (i, c) in chars.enumerate() {
+        match c {
+            ':' => return Some(i + 2),
+            c if c.is_ascii_alphanumeric() || c == '_' => continue,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Splits a label (like `verb:` in `verb:@mood:subj`) off of a term.
+fn split_label(term: &str) -> Result<(Option<String>, &str), QueryParseError> {
+    let Some(len) = label_prefix_len(term) else {
+        return Ok((None, term));
+    };
+    let label = &term[..len - 1];
+    if RESERVED_LABELS.contains(&label) {
+        return Err(QueryParseError::new(&format!(
+            "`{label}` can't be used as a label (did you mean `@{label}:`?)"
+        )));
+    }
+    Ok((Some(label.to_string()), &term[len..]))
+}
+
 /// Finds the start of the next constraint after `start_idx`.
-/// This is defined as the first `!`, `(`, `@`, or alphabet letter.
+/// This is defined as the first `!`, `(`, `@`, alphabet letter, or start of a pattern.
 fn find_next_constraint(input: &str, start_idx: isize) -> Option<usize> {
     if start_idx < -1 {
         return None;
     }
     for (i, c) in input.char_indices().skip((start_idx + 1) as usize) {
         match c {
-            '!' | '@' | '(' | 'a'..='z' | 'A'..='Z' => return Some(i),
+            '!' | '@' | '(' | '*' | '?' | '/' | 'a'..='z' | 'A'..='Z' => return Some(i),
             _ => {}
         }
     }
@@ -406,6 +663,9 @@ fn split_query(raw_input: &str) -> Result<(Vec<String>, Vec<String>), QueryParse
             continue;
         }
         let start = i;
+        // If the term has a label, skip it.
+        let rest = input.chars().skip(i).collect::<String>();
+        i += label_prefix_len(&rest).unwrap_or(0);
         // If we have negation, skip that and try the next character.
         if input.chars().nth(i) == Some('!') {
             i += 1;
@@ -414,11 +674,19 @@ fn split_query(raw_input: &str) -> Result<(Vec<String>, Vec<String>), QueryParse
         match c {
             Some('(') => {
                 i = find_close_paren(input, i)?;
+                // A `?` after the parentheses marks the term as optional.
+                if input.chars().nth(i + 1) == Some('?') {
+                    i += 1;
+                }
+                // An agreement label can come after that.
+                if input.chars().nth(i + 1) == Some('=') {
+                    i = find_word_end(input, i + 1);
+                }
             }
             Some('@') => {
                 i = find_word_end(input, i);
             }
-            Some(c) if c.is_alphabetic() => {
+            Some(c) if c.is_alphabetic() || matches!(c, '*' | '?' | '/') => {
                 i = find_word_end(input, i);
             }
             _ => {
@@ -450,12 +718,24 @@ fn split_query(raw_input: &str) -> Result<(Vec<String>, Vec<String>), QueryParse
 ///   composed of characters in the Latin alphabet (a-z, A-Z).
 /// - `@case:<case>`, `@tense:<tense>` etc... for each inflection
 ///   category in `LatinInflection`.
+/// - `@form:<form>`, which matches every token with a lemma that `<form>` could
+///   be an inflection of. For example, `@form:dedisset` matches any form of `do`.
+///   This requires the query engine to have a morphological analyzer.
+/// - A wildcard in place of a `<word>` or `<lemma>`, where `*` matches any number
+///   of letters and `?` matches exactly one.
+/// - A regular expression between slashes in place of a `<word>` or `<lemma>`. The
+///   expression cannot contain spaces.
+///
+/// Wildcards and regular expressions match any of the words (or lemmata) in the corpus
+/// that they match, up to a limit on the number of matching words.
 ///
 /// ### Examples
 ///
 /// - `@lemma:amor`
 /// - `@word:amoris`, (or the equivalent but briefer `amoris`)
 /// - `@case:genitive`.
+/// - `amav*`, `*que`, `/^in.*ibus$/`, `@lemma:am*`
+/// - `@form:dedisset`
 ///
 /// ## Token Constraint
 ///
@@ -470,6 +750,11 @@ fn split_query(raw_input: &str) -> Result<(Vec<String>, Vec<String>), QueryParse
 /// - `(<token-atom> or <token-atom> ... or <token-atom>)` to represent the
 ///   disjunction of several constraints.
 ///
+/// A negation applies to the whole token rather than to a single analysis of it, so
+/// `!<token-constraint>` matches tokens where no analysis matches the constraint. For
+/// example, `(@lemma:puella and !@case:nom)` doesn't match `puella`, even though it
+/// could also be ablative.
+///
 /// ### Examples
 ///
 /// - `@word:amoris` / `amoris` / `(amoris)`
@@ -492,35 +777,253 @@ fn split_query(raw_input: &str) -> Result<(Vec<String>, Vec<String>), QueryParse
 /// - `<token-constraint-a`<optional:number K>~> <token-constraint-b>` means that matches
 ///   will contain only those results where a token that matches A is within K tokens before a
 ///   token that matches B.
+/// - Either of the proximity relations above followed by `.` (like `3~.` or `3~>.`) means
+///   the same, except that there can't be a sentence break between A and B. Without a
+///   number, `~.` matches A and B anywhere in the same sentence (up to 255 tokens apart).
+///
+/// Proximity distances must be from 1 to 255 tokens.
+///
+/// - `<token-constraint-a> {M,N} <token-constraint-b>` means the matches will contain only
+///   those results where a token that matches A is followed by a token that matches B,
+///   with between M and N (inclusive, up to 255) other tokens in between. `{N}` means
+///   exactly N tokens in between.
+///
+/// A parenthesized token constraint or `@` atom followed by `?` (like `(@lemma:sum)?` or
+/// `@lemma:sum?`) is optional, so `@case:nom @lemma:sum? @case:acc` matches a nominative
+/// followed by an accusative, with or without a form of `sum` in between. Matches include
+/// the optional term where possible. After a plain word (like `am?`), the `?` is still a
+/// wildcard, and a wildcard at the end of an `@` atom (like `@lemma:am*?`) is rejected as
+/// ambiguous. Optional terms can't be first in the query or next to a proximity relation.
+///
+/// A term followed by `=$<label>` (like `@lemma:magnus=$1 @lemma:vir=$1`) has to agree
+/// with every other term with the same label: each token needs an analysis matching its
+/// term, and those analyses need to share a case, number, and gender. Other categories can
+/// be listed after the label (like `=$1:case,number`), and the label comes after any `?`.
+///
+/// A term can be given a name by starting it with `<name>:` (like `verb:@mood:subj`), so that
+/// each match reports the token that satisfied it. Names must start with a letter, can only
+/// contain letters, numbers, and `_`, and must be unique within the query.
 ///
 /// There is no bound in the length of the allowed query.
 ///
-/// A query may be preceded by a list of authors in square brackets.
+/// A query may be preceded by a scope in square brackets, which is a comma separated
+/// list of entries. Each entry is one of the following:
+/// - `<author>` or `<work-id>` to search an author or a single work.
+/// - `<author>: <work>` to search one work by an author, where `<work>` is either
+///   the name or the ID of the work.
+/// - `<author>: <work> <sections>` to search only some sections of a work, where
+///   `<sections>` is a section ID (like `4` or `1.1`) or a range of them (like
+///   `1.1-1.10`, or equivalently `1.1-10`).
+/// - Any of the above prefixed with `!` to exclude it from the search.
+///
 /// For example, `[Cicero, Caesar] @lemma:amor` will restrict the search to works
-/// by Cicero or Caesar.
+/// by Cicero or Caesar, `[Ovid: Metamorphoses] @lemma:amor` to just the Metamorphoses,
+/// `[Vergil: Aeneid 4] @lemma:amor` to just book 4 of the Aeneid, and `[!Cicero] @lemma:amor`
+/// to everything except Cicero.
 pub fn parse_query(input: &str) -> Result<Query, QueryParseError> {
-    let (query_body, authors) = parse_author_prefix(input)?;
+    let (query_body, scope) = parse_scope_prefix(input)?;
     let (constraints, relations) = split_query(query_body)?;
     let n = constraints.len();
     check_equal!(n, relations.len() + 1, "Unexpected query split");
     let mut terms: Vec<QueryTerm> = vec![];
-    terms.push(QueryTerm {
-        constraint: parse_token_constraint(&constraints[0])?,
-        relation: QueryRelation::First,
-    });
-    for i in 1..n {
+    for i in 0..n {
+        let (label, constraint) = split_label(&constraints[i])?;
+        let (constraint, agreement) = split_agreement(constraint)?;
+        let (constraint, optional) = split_optional(constraint)?;
+        let relation = match i {
+            0 => QueryRelation::First,
+            _ => parse_relation(&relations[i - 1])?,
+        };
         terms.push(QueryTerm {
-            constraint: parse_token_constraint(&constraints[i])?,
-            relation: parse_relation(&relations[i - 1])?,
+            constraint: parse_token_constraint(&constraint)?,
+            relation,
+            optional,
+            agreement,
+            label,
         });
     }
-    Ok(Query { terms, authors })
+    check_optional_terms(&terms)?;
+    check_agreements(&terms)?;
+    check_labels(&terms)?;
+    Ok(Query { terms, scope })
+}
+
+/// Splits the `?` that marks an optional term (like `(est)?` or `@lemma:sum?`) off of a term.
+fn split_optional(term: &str) -> Result<(String, bool), QueryParseError> {
+    if let Some(inner) = term.strip_suffix(")?") {
+        return Ok((format!("{inner})"), true));
+    }
+    let atom = term.strip_prefix('!').unwrap_or(term);
+    let Some(inner) = term.strip_suffix('?').filter(|_| atom.starts_with('@')) else {
+        // Without a prefix, a trailing `?` is a wildcard (like `am?`).
+        return Ok((term.to_string(), false));
+    };
+    let content = inner.split_once(':').map_or(inner, |(_, content)| content);
+    if !content.starts_with('/') && content.contains(['*', '?']) {
+        return Err(QueryParseError::new(&format!(
+            "Ambiguous '?' at the end of '{term}'. Use '({inner})?' for an optional \
+             term or a regular expression for a wildcard."
+        )));
+    }
+    Ok((inner.to_string(), true))
+}
+
+/// Splits an agreement suffix (like `=$1` or `=$1:case,number`) off of a term.
+fn split_agreement(term: &str) -> Result<(&str, Option<Agreement>), QueryParseError> {
+    let Some((constraint, suffix)) = term.rsplit_once("=$") else {
+        return Ok((term, None));
+    };
+    if suffix.contains('/') {
+        // This is part of a regular expression, like `/^a=$/`.
+        return Ok((term, None));
+    }
+    let (label, categories) = match suffix.split_once(':') {
+        Some((label, categories)) => (
+            label,
+            categories
+                .split(',')
+                .map(AgreementCategory::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => (suffix, DEFAULT_AGREEMENT.to_vec()),
+    };
+    if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(QueryParseError::new(
+            "Agreement labels must be letters or numbers",
+        ));
+    }
+    let agreement = Agreement {
+        label: label.to_string(),
+        categories,
+    };
+    Ok((constraint, Some(agreement)))
+}
+
+/// Checks that no two terms have the same label.
+fn check_labels(terms: &[QueryTerm]) -> Result<(), QueryParseError> {
+    let labels = terms
+        .iter()
+        .filter_map(|term| term.label.as_ref())
+        .collect::<Vec<_>>();
+    for (i, label) in labels.iter().enumerate() {
+        if labels[..i].contains(label) {
+            return Err(QueryParseError::new(&format!(
+                "The label `{label}` is used more than once"
+            )));
+        }
+    }
+    Ok(())
 }
 
-fn parse_author_prefix(input: &str) -> Result<(&str, Vec<String>), QueryParseError> {
+/// Checks that every agreement label is used by at least two terms, and that the
+/// terms with the same label agree on the categories.
+fn check_agreements(terms: &[QueryTerm]) -> Result<(), QueryParseError> {
+    let agreements = terms
+        .iter()
+        .filter_map(|term| term.agreement.as_ref())
+        .collect::<Vec<_>>();
+    for agreement in &agreements {
+        let group = agreements
+            .iter()
+            .filter(|other| other.label == agreement.label)
+            .collect::<Vec<_>>();
+        if group.len() < 2 {
+            return Err(QueryParseError::new(&format!(
+                "Agreement label ${} must be used by at least two terms",
+                agreement.label
+            )));
+        }
+        if group
+            .iter()
+            .any(|other| other.categories != agreement.categories)
+        {
+            return Err(QueryParseError::new(&format!(
+                "Terms with agreement label ${} have different categories",
+                agreement.label
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that optional terms are only used where the rest of the query still makes
+/// sense without them: not at the start, and not next to a proximity relation.
+fn check_optional_terms(terms: &[QueryTerm]) -> Result<(), QueryParseError> {
+    if terms.first().is_some_and(|term| term.optional) {
+        return Err(QueryParseError::new(
+            "The first term of a query can't be optional",
+        ));
+    }
+    let is_proximity = |term: &QueryTerm| matches!(term.relation, QueryRelation::Proximity { .. });
+    for (i, term) in terms.iter().enumerate() {
+        if !term.optional {
+            continue;
+        }
+        if is_proximity(term) || terms.get(i + 1).is_some_and(is_proximity) {
+            return Err(QueryParseError::new(
+                "Optional terms can't be next to proximity relations",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the input looks like a section ID, e.g. `4` or `1.10`. Each part
+/// must start with a digit, to avoid confusion with the names of works.
+fn is_section_id(input: &str) -> bool {
+    input.split('.').all(|part| {
+        part.starts_with(|c: char| c.is_ascii_digit())
+            && part.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+/// Parses a section or range of sections, like `4`, `1.1-1.10`, or `1.1-10`.
+fn parse_section_range(input: &str) -> Option<SectionRange> {
+    let (start, end) = input.split_once('-').unwrap_or((input, input));
+    if !is_section_id(start) || !is_section_id(end) {
+        return None;
+    }
+    // An abbreviated end like `1.1-10` replaces only the last parts of the start.
+    let start_parts = start.split('.').collect::<Vec<_>>();
+    let end_parts = end.split('.').collect::<Vec<_>>();
+    let prefix_len = start_parts.len().saturating_sub(end_parts.len());
+    let end = [&start_parts[..prefix_len], &end_parts[..]]
+        .concat()
+        .join(".");
+    Some(SectionRange {
+        start: start.to_string(),
+        end,
+    })
+}
+
+fn parse_scope_item(input: &str) -> Result<ScopeItem, QueryParseError> {
+    let Some((author, work)) = input.split_once(':') else {
+        return Ok(ScopeItem::Name(input.to_string()));
+    };
+    let (author, work) = (author.trim(), work.trim());
+    if author.is_empty() || work.is_empty() {
+        return Err(QueryParseError::new(&format!(
+            "Invalid work in query scope: '{input}'"
+        )));
+    }
+    let (work, sections) = match work.rsplit_once(char::is_whitespace) {
+        Some((name, last)) => match parse_section_range(last) {
+            Some(sections) => (name.trim(), Some(sections)),
+            None => (work, None),
+        },
+        None => (work, None),
+    };
+    Ok(ScopeItem::Work {
+        author: author.to_string(),
+        work: work.to_string(),
+        sections,
+    })
+}
+
+fn parse_scope_prefix(input: &str) -> Result<(&str, QueryScope), QueryParseError> {
     let trimmed = input.trim_start();
     if !trimmed.starts_with('[') {
-        return Ok((trimmed, Vec::new()));
+        return Ok((trimmed, QueryScope::default()));
     }
 
     let close_idx = trimmed[1..]
@@ -528,21 +1031,30 @@ fn parse_author_prefix(input: &str) -> Result<(&str, Vec<String>), QueryParseErr
         .ok_or_else(|| QueryParseError::new("Missing closing ']' in author list"))?
         + 1;
 
-    let authors_segment = trimmed[1..close_idx].trim();
-    let authors: Vec<String> = authors_segment
-        .split(',')
-        .map(|author| author.trim())
-        .filter(|author| !author.is_empty())
-        .map(|author| author.to_string())
-        .collect();
+    let scope = parse_scope(&trimmed[1..close_idx])?;
+    let remainder = trimmed[close_idx + 1..].trim_start();
 
-    if authors.is_empty() {
-        return Err(QueryParseError::new("Author list cannot be empty"));
-    }
+    Ok((remainder, scope))
+}
 
-    let remainder = trimmed[close_idx + 1..].trim_start();
+/// Parses a scope, in the same syntax as the `[...]` prefix of a query but
+/// without the brackets. For example: `Vergil: Aeneid 4, !Cicero`.
+pub fn parse_scope(input: &str) -> Result<QueryScope, QueryParseError> {
+    let mut scope = QueryScope::default();
+    for entry in input.split(',').map(|entry| entry.trim()) {
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.strip_prefix('!') {
+            Some(excluded) => scope.excluded.push(parse_scope_item(excluded.trim())?),
+            None => scope.included.push(parse_scope_item(entry)?),
+        }
+    }
 
-    Ok((remainder, authors))
+    if scope.included.is_empty() && scope.excluded.is_empty() {
+        return Err(QueryParseError::new("Author list cannot be empty"));
+    }
+    Ok(scope)
 }
 
 #[cfg(test)]
@@ -567,14 +1079,16 @@ mod tests {
             parse_relation("~").unwrap(),
             QueryRelation::Proximity {
                 distance: 5,
-                is_directed: false
+                is_directed: false,
+                within_sentence: false,
             }
         );
         assert_eq!(
             parse_relation("  ~  ").unwrap(),
             QueryRelation::Proximity {
                 distance: 5,
-                is_directed: false
+                is_directed: false,
+                within_sentence: false,
             }
         );
     }
@@ -585,14 +1099,16 @@ mod tests {
             parse_relation("~>").unwrap(),
             QueryRelation::Proximity {
                 distance: 5,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
             }
         );
         assert_eq!(
             parse_relation("  ~>  ").unwrap(),
             QueryRelation::Proximity {
                 distance: 5,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
             }
         );
     }
@@ -603,14 +1119,16 @@ mod tests {
             parse_relation("10~").unwrap(),
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: false
+                is_directed: false,
+                within_sentence: false,
             }
         );
         assert_eq!(
             parse_relation("  10~  ").unwrap(),
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: false
+                is_directed: false,
+                within_sentence: false,
             }
         );
     }
@@ -621,16 +1139,91 @@ mod tests {
             parse_relation("10~>").unwrap(),
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
             }
         );
         assert_eq!(
             parse_relation("  10~>  ").unwrap(),
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_relation_within_sentence() {
+        assert_eq!(
+            parse_relation("~.").unwrap(),
+            QueryRelation::Proximity {
+                distance: SENTENCE_PROXIMITY,
+                is_directed: false,
+                within_sentence: true,
             }
         );
+        assert_eq!(
+            parse_relation(" 3~>. ").unwrap(),
+            QueryRelation::Proximity {
+                distance: 3,
+                is_directed: true,
+                within_sentence: true,
+            }
+        );
+        assert_eq!(
+            parse_relation("40~").unwrap(),
+            QueryRelation::Proximity {
+                distance: 40,
+                is_directed: false,
+                within_sentence: false,
+            }
+        );
+        assert!(parse_relation(".").is_err());
+        assert!(parse_relation("~.>").is_err());
+    }
+
+    #[test]
+    fn parse_relation_rejects_out_of_range_distances() {
+        for relation in ["300~", "256~>", "-1~", "x~", "3x~.", "0~", "0~>."] {
+            assert!(parse_relation(relation).is_err(), "{relation}");
+        }
+        assert_eq!(
+            parse_relation("0~").unwrap_err().message,
+            "Proximity distance must be at least 1"
+        );
+        assert_eq!(
+            parse_relation("300~").unwrap_err().message,
+            "Proximity distances must be numbers from 1 to 255"
+        );
+        assert_eq!(
+            parse_relation("255~").unwrap(),
+            QueryRelation::Proximity {
+                distance: 255,
+                is_directed: false,
+                within_sentence: false,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_relation_gap() {
+        assert_eq!(
+            parse_relation("{0,3}").unwrap(),
+            QueryRelation::Gap { min: 0, max: 3 }
+        );
+        assert_eq!(
+            parse_relation(" { 1 , 20 } ").unwrap(),
+            QueryRelation::Gap { min: 1, max: 20 }
+        );
+        assert_eq!(
+            parse_relation("{2}").unwrap(),
+            QueryRelation::Gap { min: 2, max: 2 }
+        );
+        assert!(parse_relation("{3,1}").is_err());
+        assert!(parse_relation("{0,300}").is_err());
+        assert!(parse_relation("{0,3").is_err());
+        assert!(parse_relation("{}").is_err());
     }
 
     #[test]
@@ -683,6 +1276,66 @@ mod tests {
         assert!(parse_token_atom("amo1").is_err());
     }
 
+    #[test]
+    fn parse_token_atom_form_prefix() {
+        assert_eq!(
+            parse_token_atom("@form:dedisset").unwrap(),
+            TokenConstraintAtom::Form("dedisset".to_string())
+        );
+        assert_eq!(
+            parse_token_atom("@f:amat").unwrap(),
+            TokenConstraintAtom::Form("amat".to_string())
+        );
+        assert!(parse_token_atom("@form:am*").is_err());
+    }
+
+    #[test]
+    fn parse_token_atom_wildcards() {
+        assert_eq!(
+            parse_token_atom("amav*").unwrap(),
+            TokenConstraintAtom::WordPattern("^amav.*$".to_string())
+        );
+        assert_eq!(
+            parse_token_atom("*que").unwrap(),
+            TokenConstraintAtom::WordPattern("^.*que$".to_string())
+        );
+        assert_eq!(
+            parse_token_atom("@lemma:am?").unwrap(),
+            TokenConstraintAtom::LemmaPattern("^am.$".to_string())
+        );
+        assert!(parse_token_atom("**").is_err());
+        assert!(parse_token_atom("am1*").is_err());
+    }
+
+    #[test]
+    fn parse_token_atom_regex() {
+        assert_eq!(
+            parse_token_atom("/^in.*ibus$/").unwrap(),
+            TokenConstraintAtom::WordPattern("^in.*ibus$".to_string())
+        );
+        assert_eq!(
+            parse_token_atom("@word:/^(in|ex)/").unwrap(),
+            TokenConstraintAtom::WordPattern("^(in|ex)".to_string())
+        );
+        assert!(parse_token_atom("/^in").is_err());
+        assert!(parse_token_atom("//").is_err());
+        assert!(parse_token_atom("/in[/").is_err());
+    }
+
+    #[test]
+    fn parse_query_with_patterns() {
+        let query = parse_query("*que (/^(in|ex)$/ or @case:abl) amav*").unwrap();
+        assert_eq!(query.terms.len(), 3);
+        assert_eq!(
+            query.terms[0].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::WordPattern("^.*que$".to_string()))
+        );
+        assert_eq!(
+            query.terms[1].constraint.to_string(),
+            "(/^(in|ex)$/ or @case:5)"
+        );
+    }
+
     #[test]
     fn parse_token_atom_invalid_inflection() {
         // Unknown inflection label should produce an error
@@ -893,11 +1546,152 @@ mod tests {
             query.terms[1].relation,
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
             }
         );
     }
 
+    #[test]
+    fn parse_query_gap() {
+        let query = parse_query("amor {0,3} est").unwrap();
+        assert_eq!(query.terms.len(), 2);
+        assert_eq!(
+            query.terms[1].relation,
+            QueryRelation::Gap { min: 0, max: 3 }
+        );
+    }
+
+    #[test]
+    fn parse_query_optional_terms() {
+        let query = parse_query("@case:nom (@lemma:sum)? {0,2} !(est)? amor").unwrap();
+        assert_eq!(query.terms.len(), 4);
+        let optional = query.terms.iter().map(|t| t.optional).collect::<Vec<_>>();
+        assert_eq!(optional, vec![false, true, true, false]);
+        assert_eq!(
+            query.terms[1].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma("sum".to_string()))
+        );
+        assert_eq!(
+            query.terms[2].constraint,
+            TokenConstraint::Negated(Box::new(TokenConstraint::Atom(TokenConstraintAtom::Word(
+                "est".to_string()
+            ))))
+        );
+        assert_eq!(
+            query.terms[2].relation,
+            QueryRelation::Gap { min: 0, max: 2 }
+        );
+        let query = parse_query("amor @lemma:sum? !@case:nom? @l:/^am.$/? est").unwrap();
+        let optional = query.terms.iter().map(|t| t.optional).collect::<Vec<_>>();
+        assert_eq!(optional, vec![false, true, true, true, false]);
+        assert_eq!(
+            query.terms[1].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma("sum".to_string()))
+        );
+        assert_eq!(
+            query.terms[2].constraint,
+            TokenConstraint::Negated(Box::new(TokenConstraint::Atom(
+                TokenConstraintAtom::Inflection(LatinInflection::Case(LatinCase::Nominative))
+            )))
+        );
+        assert_eq!(
+            query.terms[3].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::LemmaPattern("^am.$".to_string()))
+        );
+        let query = parse_query("amor verb:@lemma:sum?=$1 @case:nom=$1").unwrap();
+        assert!(query.terms[1].optional);
+        assert!(query.terms[1].agreement.is_some());
+        // Without a prefix, the `?` is still a wildcard.
+        let query = parse_query("amor am?").unwrap();
+        assert!(!query.terms[1].optional);
+        assert_eq!(
+            query.terms[1].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::WordPattern("^am.$".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_query_invalid_optional_terms() {
+        assert!(parse_query("(amor)? est").is_err());
+        assert!(parse_query("amor ~ (est)?").is_err());
+        assert!(parse_query("amor (est)? 3~> puella").is_err());
+        assert!(parse_query("@lemma:sum? est").is_err());
+        let error = parse_query("amor @lemma:am*?").unwrap_err();
+        assert!(error.message.contains("Ambiguous"), "{}", error.message);
+        assert!(parse_query("amor @lemma:a??").is_err());
+        assert!(parse_query("amor (@lemma:am*)?").unwrap().terms[1].optional);
+    }
+
+    #[test]
+    fn parse_query_agreement() {
+        let query =
+            parse_query("@lemma:magnus=$1 (@lemma:vir)?=$2:case,n est=$2:case,n @lemma:bonus=$1")
+                .unwrap();
+        assert_eq!(
+            query.terms[0].agreement,
+            Some(Agreement {
+                label: "1".to_string(),
+                categories: DEFAULT_AGREEMENT.to_vec(),
+            })
+        );
+        assert_eq!(
+            query.terms[0].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma("magnus".to_string()))
+        );
+        assert!(query.terms[1].optional);
+        assert_eq!(
+            query.terms[1].agreement,
+            Some(Agreement {
+                label: "2".to_string(),
+                categories: vec![AgreementCategory::Case, AgreementCategory::Number],
+            })
+        );
+        assert_eq!(query.terms[2].agreement, query.terms[1].agreement);
+        assert_eq!(query.terms[3].agreement, query.terms[0].agreement);
+        let query = parse_query("@lemma:magnus est").unwrap();
+        assert_eq!(query.terms[0].agreement, None);
+        // Inflections can still use `=` for their values.
+        let query = parse_query("@case=nom").unwrap();
+        assert_eq!(query.terms[0].agreement, None);
+    }
+
+    #[test]
+    fn parse_query_labels() {
+        let query = parse_query("noun:@case:dat adj:(magnus)? verb:@lemma:do est").unwrap();
+        let labels = query
+            .terms
+            .iter()
+            .map(|t| t.label.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![Some("noun"), Some("adj"), Some("verb"), None]);
+        assert!(query.terms[1].optional);
+        assert_eq!(
+            query.terms[2].constraint,
+            TokenConstraint::Atom(TokenConstraintAtom::Lemma("do".to_string()))
+        );
+        let query = parse_query("sum neg:!(est)?=$1 x:!est=$1").unwrap();
+        assert_eq!(query.terms[1].label.as_deref(), Some("neg"));
+        assert!(query.terms[1].optional);
+        assert!(query.terms[2].agreement.is_some());
+    }
+
+    #[test]
+    fn parse_query_invalid_labels() {
+        assert!(parse_query("x:est x:sum").is_err());
+        let error = parse_query("lemma:amo").unwrap_err();
+        assert!(error.message.contains("@lemma:"), "{}", error.message);
+        assert!(parse_query("case:dat").is_err());
+    }
+
+    #[test]
+    fn parse_query_invalid_agreement() {
+        assert!(parse_query("@lemma:magnus=$1 @lemma:vir").is_err());
+        assert!(parse_query("@lemma:magnus=$1:case @lemma:vir=$1").is_err());
+        assert!(parse_query("@lemma:magnus=$1:degree @lemma:vir=$1:degree").is_err());
+        assert!(parse_query("@lemma:magnus=$ @lemma:vir=$").is_err());
+    }
+
     #[test]
     fn parse_query_complex() {
         let query = parse_query(
@@ -940,7 +1734,8 @@ mod tests {
             query.terms[1].relation,
             QueryRelation::Proximity {
                 distance: 10,
-                is_directed: false
+                is_directed: false,
+                within_sentence: false,
             }
         );
 
@@ -953,7 +1748,8 @@ mod tests {
             query.terms[2].relation,
             QueryRelation::Proximity {
                 distance: 5,
-                is_directed: true
+                is_directed: true,
+                within_sentence: false,
             }
         );
     }
@@ -1029,12 +1825,113 @@ mod tests {
     fn parse_query_with_authors_prefix() {
         let query = parse_query("[Cicero, Caesar] @lemma:amor").unwrap();
         assert_eq!(
-            query.authors,
-            vec!["Cicero".to_string(), "Caesar".to_string()]
+            query.scope.included,
+            vec![
+                ScopeItem::Name("Cicero".to_string()),
+                ScopeItem::Name("Caesar".to_string())
+            ]
         );
+        assert!(query.scope.excluded.is_empty());
         assert_eq!(query.terms.len(), 1);
     }
 
+    #[test]
+    fn parse_query_with_work_scope() {
+        let query = parse_query("[Ovid: Metamorphoses, Vergil] @lemma:amor").unwrap();
+        assert_eq!(
+            query.scope.included,
+            vec![
+                ScopeItem::Work {
+                    author: "Ovid".to_string(),
+                    work: "Metamorphoses".to_string(),
+                    sections: None,
+                },
+                ScopeItem::Name("Vergil".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_with_excluded_scope() {
+        let query = parse_query("[!Cicero, ! Ovid: Amores] @lemma:amor").unwrap();
+        assert!(query.scope.included.is_empty());
+        assert_eq!(
+            query.scope.excluded,
+            vec![
+                ScopeItem::Name("Cicero".to_string()),
+                ScopeItem::Work {
+                    author: "Ovid".to_string(),
+                    work: "Amores".to_string(),
+                    sections: None,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_with_section_scope() {
+        let query = parse_query("[Vergil: Aeneid 4] @lemma:amor").unwrap();
+        assert_eq!(
+            query.scope.included,
+            vec![ScopeItem::Work {
+                author: "Vergil".to_string(),
+                work: "Aeneid".to_string(),
+                sections: Some(SectionRange {
+                    start: "4".to_string(),
+                    end: "4".to_string()
+                }),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_query_with_section_range_scope() {
+        let query = parse_query("[Cicero: In Catilinam 1.1-1.10] @lemma:amor").unwrap();
+        let expected = ScopeItem::Work {
+            author: "Cicero".to_string(),
+            work: "In Catilinam".to_string(),
+            sections: Some(SectionRange {
+                start: "1.1".to_string(),
+                end: "1.10".to_string(),
+            }),
+        };
+        assert_eq!(query.scope.included, vec![expected.clone()]);
+
+        let abbreviated = parse_query("[Cicero: In Catilinam 1.1-10] @lemma:amor").unwrap();
+        assert_eq!(abbreviated.scope.included, vec![expected]);
+    }
+
+    #[test]
+    fn parse_query_work_name_is_not_a_section() {
+        let query = parse_query("[Ovid: Book w2, Cicero: phi0474.phi013] @lemma:amor").unwrap();
+        assert!(
+            query
+                .scope
+                .included
+                .iter()
+                .all(|item| matches!(item, ScopeItem::Work { sections: None, .. }))
+        );
+    }
+
+    #[test]
+    fn parse_scope_without_brackets() {
+        let scope = parse_scope("Vergil: Aeneid 4, !Cicero").unwrap();
+        assert_eq!(scope.included.len(), 1);
+        assert_eq!(scope.excluded, vec![ScopeItem::Name("Cicero".to_string())]);
+        assert!(parse_scope(" , ").is_err());
+    }
+
+    #[test]
+    fn parse_query_invalid_work_scope() {
+        assert!(parse_query("[Ovid:] @lemma:amor").is_err());
+        assert!(parse_query("[: Amores] @lemma:amor").is_err());
+    }
+
+    #[test]
+    fn parse_query_empty_scope() {
+        assert!(parse_query("[ , ] @lemma:amor").is_err());
+    }
+
     #[test]
     fn parse_query_invalid_author_not_prefix() {
         assert!(parse_query("@lemma:amor [Cicero]").is_err());
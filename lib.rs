@@ -4,13 +4,18 @@
 )]
 
 use corpus::{
-    api::{PageData, QueryOptions},
+    api::{
+        CollocationOptions, ContextMode, MatchAnalyses, PageData, QueryExecError, QueryOptions,
+        QueryScope, ResultSort,
+    },
+    build_corpus_v2::corpus_crunch_options,
     corpus_index::deserialize_corpus,
     corpus_query_engine::CorpusQueryEngine,
 };
 use morceus::{
     crunch::crunch_word,
     indices::{CruncherOptions, CruncherTables},
+    paradigms::{ParadigmFilter, generate_filtered_paradigms},
 };
 use std::{fs, process};
 
@@ -18,10 +23,21 @@ use node_bindgen::derive::node_bindgen;
 
 const CORPUS_FILE: &str = "latin_corpus.json";
 
-fn create_engine(corpus_dir: String) -> Result<CorpusQueryEngine, String> {
+fn create_engine(
+    corpus_dir: String,
+    tables_path: Option<String>,
+) -> Result<CorpusQueryEngine, String> {
     let corpus_path = &format!("{corpus_dir}/{CORPUS_FILE}");
     let corpus = deserialize_corpus(corpus_path).map_err(|e| e.to_string())?;
-    CorpusQueryEngine::new(corpus).map_err(|e| e.to_string())
+    let engine = CorpusQueryEngine::new(corpus).map_err(|e| e.to_string())?;
+    let Some(tables_path) = tables_path else {
+        return Ok(engine);
+    };
+    let tables = load_tables(&tables_path);
+    let options = corpus_crunch_options();
+    Ok(engine.with_analyzer(Box::new(move |word: &str| {
+        crunch_word(word, &tables, &options)
+    })))
 }
 
 struct QueryEngineWrapper {
@@ -31,16 +47,18 @@ struct QueryEngineWrapper {
 #[node_bindgen]
 impl QueryEngineWrapper {
     #[node_bindgen(constructor)]
-    fn new(corpus_dir: String) -> Self {
+    fn new(corpus_dir: String, tables_path: Option<String>) -> Self {
         // `node_bindgen` does not seem to support returning a `Result` from a constructor.
         // An error here will cause the Node process to crash, but it's not that bad since this
         // will only be called once.
         #[allow(clippy::expect_used)]
-        let engine = create_engine(corpus_dir.clone()).expect("Failed to create query engine");
+        let engine =
+            create_engine(corpus_dir.clone(), tables_path).expect("Failed to create query engine");
         Self { engine }
     }
 
     #[node_bindgen]
+    #[allow(clippy::too_many_arguments)]
     fn query(
         &self,
         query_str: String,
@@ -48,7 +66,25 @@ impl QueryEngineWrapper {
         page_size: u32,
         context_len: u32,
         strict_mode: bool,
+        scope: Option<String>,
+        sort: Option<String>,
+        sample_size: Option<u32>,
+        analyses: Option<String>,
+        context_mode: Option<String>,
     ) -> Result<String, String> {
+        let sort = sort
+            .map(|s| s.parse::<ResultSort>().map_err(query_error))
+            .transpose()?
+            .unwrap_or_default();
+        let analyses = analyses
+            .map(|a| a.parse::<MatchAnalyses>().map_err(query_error))
+            .transpose()?
+            .unwrap_or_default();
+        let context_mode = context_mode
+            .map(|m| m.parse::<ContextMode>().map_err(query_error))
+            .transpose()?
+            .unwrap_or_default();
+        let scope = parse_scope_option(scope)?;
         let page_data = page_data
             .map(|pd_str| {
                 serde_json::from_str::<corpus::api::PageData>(&pd_str)
@@ -59,31 +95,118 @@ impl QueryEngineWrapper {
                 result_index: 0,
                 result_id: 0,
                 candidate_index: 0,
+                sample_seed: None,
             });
         let options = QueryOptions {
             page_size: page_size as usize,
             context_len: context_len as usize,
+            context_mode,
+            strict_mode,
+            scope,
+            sort,
+            sample_size: sample_size.map(|size| size as usize),
+            analyses,
+        };
+        let result =
+            catch_query_panics(|| self.engine.query_corpus(&query_str, &page_data, &options))?
+                .map_err(query_error)?;
+        serde_json::to_string(&result).map_err(|_| "Failed to serialize result".to_string())
+    }
+
+    #[node_bindgen]
+    fn count(
+        &self,
+        query_str: String,
+        strict_mode: bool,
+        scope: Option<String>,
+    ) -> Result<String, String> {
+        let scope = parse_scope_option(scope)?;
+        let options = QueryOptions {
+            strict_mode,
+            scope,
+            ..Default::default()
+        };
+        let result = catch_query_panics(|| self.engine.count_corpus(&query_str, &options))?
+            .map_err(query_error)?;
+        serde_json::to_string(&result).map_err(|_| "Failed to serialize result".to_string())
+    }
+
+    #[node_bindgen]
+    fn explain(
+        &self,
+        query_str: String,
+        strict_mode: bool,
+        scope: Option<String>,
+    ) -> Result<String, String> {
+        let scope = parse_scope_option(scope)?;
+        let options = QueryOptions {
             strict_mode,
+            scope,
+            ..Default::default()
         };
-        // We use `AssertUnwindSafe` because the `engine` struct itself is read only. The
-        // only mutable data is returned as outputs, which we lose in the panic anyways.
-        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            self.engine.query_corpus(&query_str, &page_data, &options)
-        }))
-        .map_err(|panic_payload| {
-            if let Some(s) = panic_payload.downcast_ref::<&str>() {
-                format!("Query panicked: {}", s)
-            } else if let Some(s) = panic_payload.downcast_ref::<String>() {
-                format!("Query panicked: {}", s)
-            } else {
-                "Query panicked with non-string payload".to_string()
-            }
+        let result = catch_query_panics(|| self.engine.explain_query(&query_str, &options))?
+            .map_err(query_error)?;
+        serde_json::to_string(&result).map_err(|_| "Failed to serialize result".to_string())
+    }
+
+    #[node_bindgen]
+    fn collocations(
+        &self,
+        query_str: String,
+        strict_mode: bool,
+        scope: Option<String>,
+        window: u32,
+        max_results: u32,
+        min_count: u32,
+    ) -> Result<String, String> {
+        let scope = parse_scope_option(scope)?;
+        let options = QueryOptions {
+            strict_mode,
+            scope,
+            ..Default::default()
+        };
+        let collocation_options = CollocationOptions {
+            window: window as usize,
+            max_results: max_results as usize,
+            min_count: min_count as usize,
+        };
+        let result = catch_query_panics(|| {
+            self.engine
+                .collocations(&query_str, &options, &collocation_options)
         })?
-        .map_err(|e| e.message)?;
+        .map_err(query_error)?;
         serde_json::to_string(&result).map_err(|_| "Failed to serialize result".to_string())
     }
 }
 
+/// Converts a query error to the JSON returned to Node, so that callers can show
+/// which term and atoms conflict for queries that can never match.
+fn query_error(e: QueryExecError) -> String {
+    serde_json::to_string(&e).unwrap_or(e.message)
+}
+
+/// Parses the extra scope for a query, if there is one.
+fn parse_scope_option(scope: Option<String>) -> Result<Option<QueryScope>, String> {
+    scope
+        .map(|s| s.parse::<QueryScope>().map_err(query_error))
+        .transpose()
+}
+
+/// Runs the query, converting any panic into an error.
+fn catch_query_panics<T>(run_query: impl FnOnce() -> T) -> Result<T, String> {
+    // We use `AssertUnwindSafe` because the `engine` struct itself is read only. The
+    // only mutable data is returned as outputs, which we lose in the panic anyways.
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(run_query)).map_err(|panic_payload| {
+        if let Some(s) = panic_payload.downcast_ref::<&str>() {
+            format!("Query panicked: {}", s)
+        } else if let Some(s) = panic_payload.downcast_ref::<String>() {
+            format!("Query panicked: {}", s)
+        } else {
+            "Query panicked with non-string payload".to_string()
+        }
+    })
+}
+
 fn load_tables(filename: &str) -> CruncherTables {
     // Read the JSON file
     let json_content = fs::read_to_string(filename).unwrap_or_else(|err| {
@@ -109,6 +232,8 @@ struct Cruncher {
 impl Cruncher {
     #[node_bindgen(constructor)]
     fn new(table_path: String) -> Self {
+        // This stands in for the TypeScript cruncher, which consolidates its results.
+        // Only the corpus skips consolidation (see `corpus_crunch_options`).
         let default_options = CruncherOptions::default();
         Self {
             tables: load_tables(&table_path),
@@ -121,4 +246,21 @@ impl Cruncher {
         let results = crunch_word(&word, &self.tables, &self.default_options);
         serde_json::to_string(&results).map_err(|_| "Failed to serialize result".to_string())
     }
+
+    #[node_bindgen]
+    fn paradigm(&self, lemma: String, filter: Option<String>) -> Result<String, String> {
+        let filter: ParadigmFilter = filter.as_deref().unwrap_or("").parse()?;
+        let paradigms = generate_filtered_paradigms(&lemma, &self.tables, &filter);
+        serde_json::to_string(&paradigms).map_err(|_| "Failed to serialize result".to_string())
+    }
+
+    #[node_bindgen]
+    fn paradigm_tables(&self, lemma: String, filter: Option<String>) -> Result<String, String> {
+        let filter: ParadigmFilter = filter.as_deref().unwrap_or("").parse()?;
+        let tables = generate_filtered_paradigms(&lemma, &self.tables, &filter)
+            .iter()
+            .map(|paradigm| paradigm.to_tables())
+            .collect::<Vec<_>>();
+        serde_json::to_string(&tables).map_err(|_| "Failed to serialize result".to_string())
+    }
 }
@@ -26,10 +26,9 @@ impl Autocompleter<'_> {
     /// Creates an autocompleter with the given options.
     ///
     /// # Arguments
-    /// * `tables` - The underlying tables used for completions. Currently these aren't generated in Rust code.
-    ///   Instead, they are generated in the Javascript code using Node.js (or Bun).
-    ///   To create tables, from the `morcus-net` repo root, run:
-    ///   `./morcus.sh build --morceus_tables`
+    /// * `tables` - The underlying tables used for completions. These can be built from `morceus-data`
+    ///   with `table_builder::build_tables`, or generated in the Javascript code by running
+    ///   `./morcus.sh build --morceus_tables` from the `morcus-net` repo root.
     /// * `default_options` - Default options for the autocompleter.
     pub fn new<'t>(
         tables: Cow<'t, CruncherTables>,
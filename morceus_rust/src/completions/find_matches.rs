@@ -355,7 +355,7 @@ mod tests {
     fn test_empty_ranges() {
         let pairs: Vec<(usize, usize, &[u16])> = vec![];
         let result = range_pairs_to_iter(pairs).copied().collect::<Vec<_>>();
-        assert_eq!(result, vec![]);
+        assert_eq!(result, Vec::<u16>::new());
     }
 
     #[test]
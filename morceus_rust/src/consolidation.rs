@@ -0,0 +1,300 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{
+    indices::CrunchResult,
+    inflection_data::{
+        LatinCase, LatinGender, WordInflectionData, extract_case_bits, extract_degree,
+        extract_gender_bits, extract_mood, extract_number, extract_person, extract_tense,
+        extract_voice,
+    },
+};
+
+const CASE_SHIFT: u32 = 16;
+const GENDER_SHIFT: u32 = 24;
+const BYTE_MASK: u32 = 0xff;
+
+/// Returns the values of each category of the data, as bitsets. Categories that
+/// can only have one value have either no bits set or a single bit.
+fn value_sets(data: WordInflectionData) -> [u32; 8] {
+    let single = |value: u32| if value == 0 { 0 } else { 1 << value };
+    [
+        extract_case_bits(data),
+        single(extract_degree(data)),
+        extract_gender_bits(data),
+        single(extract_mood(data)),
+        single(extract_number(data)),
+        single(extract_person(data)),
+        single(extract_tense(data)),
+        single(extract_voice(data)),
+    ]
+}
+
+/// Compares two sets of values: `Less` if the first is a strict subset of the
+/// second, `Greater` if it's a strict superset, and `None` if neither contains the other.
+fn compare_field(first: u32, second: u32) -> Option<Ordering> {
+    let is_subset = first & !second == 0;
+    let is_superset = second & !first == 0;
+    match (is_subset, is_superset) {
+        (true, true) => Some(Ordering::Equal),
+        (true, false) => Some(Ordering::Less),
+        (false, true) => Some(Ordering::Greater),
+        (false, false) => None,
+    }
+}
+
+/// Compares the two grammatical data: `Equal` if they're exactly equal, `Less` if
+/// every category of the first is a subset of the second (and one is a strict subset),
+/// `Greater` for the reverse, and `None` if none of the above are true.
+fn compare_grammatical_data(
+    first: WordInflectionData,
+    second: WordInflectionData,
+) -> Option<Ordering> {
+    let mut result = Ordering::Equal;
+    for (a, b) in value_sets(first).into_iter().zip(value_sets(second)) {
+        match (result, compare_field(a, b)?) {
+            (_, Ordering::Equal) => {}
+            (Ordering::Equal, ordering) => result = ordering,
+            (current, ordering) if current != ordering => return None,
+            _ => {}
+        }
+    }
+    Some(result)
+}
+
+/// Keeps the strictest provenance of the two results, since a merged result was
+/// only found by relaxing the input if every result it came from was.
+fn merge_provenance(target: &mut CrunchResult, other: &CrunchResult) {
+    target.relaxed_case &= other.relaxed_case;
+    target.relaxed_vowel_lengths &= other.relaxed_vowel_lengths;
+}
+
+/// Merges results that have the same values as another result in the given category
+/// (stored at `shift`) except for values in `consolidable`, unioning the values.
+fn consolidate_by_category(
+    cluster: Vec<CrunchResult>,
+    shift: u32,
+    consolidable: u32,
+) -> Vec<CrunchResult> {
+    let category_mask = BYTE_MASK << shift;
+    let mut unmerged = Vec::new();
+    let mut merge_groups: Vec<CrunchResult> = Vec::new();
+    for item in cluster {
+        let data = item.context.grammatical_data;
+        // Only consider those that have a subset.
+        if (data >> shift) & BYTE_MASK & !consolidable != 0 {
+            unmerged.push(item);
+            continue;
+        }
+        let leader = merge_groups.iter_mut().find(|leader| {
+            leader.context.grammatical_data & !category_mask == data & !category_mask
+        });
+        match leader {
+            Some(leader) => {
+                leader.context.grammatical_data |= data;
+                merge_provenance(leader, &item);
+            }
+            None => merge_groups.push(item),
+        }
+    }
+    unmerged.extend(merge_groups);
+    unmerged
+}
+
+/// Consolidates results (with the same lemma and form) to reduce duplication.
+fn consolidate_result_cluster(cluster: Vec<CrunchResult>) -> Vec<CrunchResult> {
+    let mut original = cluster;
+    // Merge results that are strict subsets of other results.
+    loop {
+        let original_len = original.len();
+        let mut consolidated: Vec<CrunchResult> = Vec::new();
+        for result in original {
+            let mut consumed = false;
+            for existing in consolidated.iter_mut() {
+                let data = result.context.grammatical_data;
+                let Some(ordering) =
+                    compare_grammatical_data(data, existing.context.grammatical_data)
+                else {
+                    continue;
+                };
+                consumed = true;
+                if ordering == Ordering::Greater {
+                    let mut replacement = result.clone();
+                    merge_provenance(&mut replacement, existing);
+                    *existing = replacement;
+                } else {
+                    merge_provenance(existing, &result);
+                }
+            }
+            if !consumed {
+                consolidated.push(result);
+            }
+        }
+        let did_consolidate = original_len != consolidated.len();
+        original = consolidated;
+        if !did_consolidate {
+            break;
+        }
+    }
+    let cases = (1 << LatinCase::Ablative as u32) | (1 << LatinCase::Dative as u32);
+    let original = consolidate_by_category(original, CASE_SHIFT, cases);
+    let genders = (1 << LatinGender::Feminine as u32)
+        | (1 << LatinGender::Masculine as u32)
+        | (1 << LatinGender::Neuter as u32);
+    consolidate_by_category(original, GENDER_SHIFT, genders)
+}
+
+/// Consolidates the raw results of crunching a word, which have many duplicates (for
+/// example, from each i/j and u/v alternate). Results are only merged with others
+/// that have the same lemma, form, enclitic, and tags; clusters are kept in the order
+/// of their first result.
+pub(crate) fn consolidate_crunch_results(raw_results: Vec<CrunchResult>) -> Vec<CrunchResult> {
+    let mut cluster_ids: HashMap<(String, String, Option<String>, String), usize> = HashMap::new();
+    let mut clusters: Vec<Vec<CrunchResult>> = Vec::new();
+    for result in raw_results {
+        let mut tags = result
+            .context
+            .tags
+            .iter()
+            .flatten()
+            .map(|tag| tag.trim().to_lowercase())
+            .collect::<Vec<_>>();
+        tags.sort();
+        let key = (
+            result.lemma.clone(),
+            result.form.clone(),
+            result.enclitic.clone(),
+            tags.join("@"),
+        );
+        let id = *cluster_ids.entry(key).or_insert_with(|| {
+            clusters.push(Vec::new());
+            clusters.len() - 1
+        });
+        clusters[id].push(result);
+    }
+    clusters
+        .into_iter()
+        .flat_map(consolidate_result_cluster)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indices::InflectionContext;
+
+    const NOM: u32 = 1 << (LatinCase::Nominative as u32 + CASE_SHIFT);
+    const DAT: u32 = 1 << (LatinCase::Dative as u32 + CASE_SHIFT);
+    const ABL: u32 = 1 << (LatinCase::Ablative as u32 + CASE_SHIFT);
+    const MASC: u32 = 1 << (LatinGender::Masculine as u32 + GENDER_SHIFT);
+    const FEM: u32 = 1 << (LatinGender::Feminine as u32 + GENDER_SHIFT);
+    const SG: u32 = 1;
+    const PL: u32 = 2;
+
+    fn result(lemma: &str, form: &str, grammatical_data: u32) -> CrunchResult {
+        CrunchResult {
+            lemma: lemma.to_string(),
+            form: form.to_string(),
+            stem: None,
+            end: None,
+            relaxed_case: false,
+            relaxed_vowel_lengths: false,
+            enclitic: None,
+            is_verb: false,
+            context: InflectionContext {
+                grammatical_data,
+                tags: None,
+                internal_tags: None,
+            },
+        }
+    }
+
+    fn data_of(results: &[CrunchResult]) -> Vec<u32> {
+        results.iter().map(|r| r.context.grammatical_data).collect()
+    }
+
+    #[test]
+    fn duplicates_keep_the_strictest_provenance() {
+        let relaxed = CrunchResult {
+            relaxed_case: true,
+            relaxed_vowel_lengths: true,
+            ..result("puella", "puella", NOM | SG)
+        };
+        let exact = result("puella", "puella", NOM | SG);
+        let results = consolidate_crunch_results(vec![relaxed.clone(), exact, relaxed]);
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].relaxed_case);
+        assert!(!results[0].relaxed_vowel_lengths);
+
+        let relaxed = CrunchResult {
+            relaxed_case: true,
+            ..result("puella", "Puella", NOM | SG)
+        };
+        let results = consolidate_crunch_results(vec![relaxed.clone(), relaxed]);
+        assert!(results[0].relaxed_case);
+    }
+
+    #[test]
+    fn subsets_are_merged_into_supersets() {
+        let results = consolidate_crunch_results(vec![
+            result("puella", "puella", SG),
+            result("puella", "puella", NOM | SG),
+            result("puella", "puella", ABL | SG),
+        ]);
+        assert_eq!(data_of(&results), vec![NOM | SG, ABL | SG]);
+    }
+
+    #[test]
+    fn dative_and_ablative_are_merged() {
+        let results = consolidate_crunch_results(vec![
+            result("puella", "puellis", DAT | PL),
+            result("puella", "puellis", NOM | PL),
+            result("puella", "puellis", ABL | PL),
+            result("puella", "puellis", ABL | SG),
+        ]);
+        assert_eq!(data_of(&results), vec![NOM | PL, DAT | ABL | PL, ABL | SG]);
+    }
+
+    #[test]
+    fn genders_are_merged() {
+        let results = consolidate_crunch_results(vec![
+            result("bonus", "bonis", DAT | MASC | PL),
+            result("bonus", "bonis", DAT | FEM | PL),
+            result("bonus", "bonis", ABL | MASC | PL),
+            result("bonus", "bonis", ABL | FEM | PL),
+        ]);
+        assert_eq!(data_of(&results), vec![DAT | ABL | MASC | FEM | PL]);
+    }
+
+    #[test]
+    fn different_lemmata_forms_and_enclitics_are_kept_apart() {
+        let with_enclitic = CrunchResult {
+            enclitic: Some("que".to_string()),
+            ..result("puella", "puella", NOM | SG)
+        };
+        let results = consolidate_crunch_results(vec![
+            result("puella", "puella", NOM | SG),
+            result("puellus", "puella", NOM | SG),
+            with_enclitic,
+            result("puella", "puella", SG),
+        ]);
+        let keys = results
+            .iter()
+            .map(|r| (r.lemma.as_str(), r.enclitic.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![("puella", None), ("puellus", None), ("puella", Some("que"))]
+        );
+    }
+
+    #[test]
+    fn incomparable_data_is_not_merged() {
+        assert_eq!(compare_grammatical_data(NOM | SG, DAT | SG), None);
+        assert_eq!(compare_grammatical_data(NOM, SG), None);
+        assert_eq!(compare_grammatical_data(SG, NOM | SG), Some(Ordering::Less));
+        assert_eq!(
+            compare_grammatical_data(NOM | DAT, DAT),
+            Some(Ordering::Greater)
+        );
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
     ambiguous_uv_ij::alternates_with_i_or_u,
-    indices::{CrunchResult, CruncherOptions, CruncherTables, StemMapValue},
+    consolidation::consolidate_crunch_results,
+    indices::{CrunchResult, CruncherOptions, CruncherTables, StemMapValue, VowelLength},
     stem_merging::merge_stem_and_ending,
+    vowel_lengths::{NormalizedWord, has_compatible_lengths, normalize_word},
 };
 
 const ENCLITICS: [&str; 3] = ["que", "ne", "ve"];
@@ -209,14 +211,12 @@ pub fn crunch_word(
     tables: &CruncherTables,
     options: &CruncherOptions,
 ) -> Vec<CrunchResult> {
-    // Note: In the full implementation we would need to handle combining characters
-    // (macron/breve markers) here, but for simplicity we'll assume the word is already
-    // normalized and has no combining characters
-    for c in word.chars() {
-        if !c.is_ascii_alphabetic() {
-            return vec![];
-        }
-    }
+    // Remove macrons, breves, and other diacritics, since the tables are keyed on
+    // unmarked text. The marked lengths are checked against the results afterwards.
+    let Some(NormalizedWord { text, quantities }) = normalize_word(word) else {
+        return vec![];
+    };
+    let word = text.as_str();
 
     // First analyze the word as-is
     let mut results = vec![crunch_and_maybe_relax_case(word, tables, options)];
@@ -228,10 +228,27 @@ pub fn crunch_word(
             results.push(crunch_and_maybe_relax_case(&alternate, tables, options));
         }
     }
-    // Flatten all results into a single vector
-    let flattened: Vec<CrunchResult> = results.into_iter().flatten().collect();
+    // Flatten all results into a single vector, checking that the vowel lengths
+    // of each result are compatible with any that were marked in the input. Note that
+    // the alternates and case relaxations don't change the positions of any characters.
+    let flattened: Vec<CrunchResult> = results
+        .into_iter()
+        .flatten()
+        .filter_map(|mut result| {
+            if has_compatible_lengths(&quantities, &result.form) {
+                return Some(result);
+            }
+            match options.vowel_length {
+                VowelLength::Strict => None,
+                VowelLength::Relaxed => {
+                    result.relaxed_vowel_lengths = true;
+                    Some(result)
+                }
+            }
+        })
+        .collect();
     if options.skip_consolidation {
         return flattened;
     }
-    unimplemented!()
+    consolidate_crunch_results(flattened)
 }
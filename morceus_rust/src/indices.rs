@@ -3,8 +3,8 @@ use std::collections::HashMap;
 
 use crate::inflection_data::WordInflectionData;
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(from = "(u32, String, bool, bool)")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "(u32, String, bool, bool)", into = "(u32, String, bool, bool)")]
 pub struct StemMapValue {
     pub index: u32,
     pub lemma: String,
@@ -24,6 +24,12 @@ impl From<(u32, String, bool, bool)> for StemMapValue {
     }
 }
 
+impl From<StemMapValue> for (u32, String, bool, bool) {
+    fn from(value: StemMapValue) -> Self {
+        (value.index, value.lemma, value.is_verb, value.is_stem)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum StemOrForm {
@@ -100,7 +106,7 @@ pub struct Stem {
     pub context: InflectionContext,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 /// Represents an irregular form that does not follow standard inflection patterns.
 /// Unlike a `Stem`, an `IrregularForm` represents a complete word and doesn't
@@ -121,18 +127,20 @@ pub struct InflectionEnding {
     pub context: InflectionContext,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Lemma {
     pub lemma: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stems: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub irregular_forms: Option<Vec<u32>>,
     // The default is false if not present.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub is_verb: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InflectionTable {
     pub name: String,
@@ -146,7 +154,7 @@ pub type GroupedInflectionTable = HashMap<String, Vec<InflectionEnding>>;
 pub type InflectionTableKey = u16;
 
 // Data structures required for computing inflection analyses.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CruncherTables {
     /// Maps endings to the possible inflection paradigms associated with that ending.
@@ -214,7 +222,7 @@ impl Default for CruncherOptions {
             relax_u_and_v: true,
             relax_i_and_j: true,
             handle_enclitics: true,
-            skip_consolidation: true,
+            skip_consolidation: false,
         }
     }
 }
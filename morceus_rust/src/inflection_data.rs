@@ -1,4 +1,7 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinCase {
     Nominative = 1,
     Accusative = 2,
@@ -9,13 +12,15 @@ pub enum LatinCase {
     Locative = 7,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinNumber {
     Singular = 1,
     Plural = 2,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinGender {
     Masculine = 1,
     Feminine = 2,
@@ -23,14 +28,16 @@ pub enum LatinGender {
     Adverbial = 4,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinPerson {
     First = 1,
     Second = 2,
     Third = 3,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinMood {
     Indicative = 1,
     Imperative = 2,
@@ -41,13 +48,15 @@ pub enum LatinMood {
     Supine = 7,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinVoice {
     Active = 1,
     Passive = 2,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinTense {
     Present = 1,
     Imperfect = 2,
@@ -57,7 +66,8 @@ pub enum LatinTense {
     Pluperfect = 6,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum LatinDegree {
     Positive = 1,
     Comparative = 2,
@@ -93,13 +103,13 @@ pub type WordInflectionData = u32;
 const NUMBER_SHIFT: u32 = 0;
 const PERSON_SHIFT: u32 = 2;
 const VOICE_SHIFT: u32 = 4;
-const DEGREE_SHIFT: u32 = 6;
+pub(crate) const DEGREE_SHIFT: u32 = 6;
 const TENSE_SHIFT: u32 = 8;
 const MOOD_SHIFT: u32 = 11;
 const CASE_SHIFT: u32 = 16;
 const GENDER_SHIFT: u32 = 24;
 
-const TWO_BITS: u32 = 0b11;
+pub(crate) const TWO_BITS: u32 = 0b11;
 const THREE_BITS: u32 = 0b111;
 const BYTE_MASK: u32 = 0xff;
 
@@ -148,15 +158,23 @@ pub fn extract_gender_bits(data: WordInflectionData) -> u32 {
     extract_field(data, GENDER_SHIFT, BYTE_MASK)
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct ExpandedInflectionData {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub number: Option<LatinNumber>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub person: Option<LatinPerson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub voice: Option<LatinVoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub degree: Option<LatinDegree>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tense: Option<LatinTense>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mood: Option<LatinMood>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub cases: Vec<LatinCase>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub genders: Vec<LatinGender>,
 }
 
@@ -217,6 +235,25 @@ pub fn expand_inflection_data(data: WordInflectionData) -> ExpandedInflectionDat
     }
 }
 
+/// Packs the expanded inflection data into its compact representation. This is the
+/// inverse of `expand_inflection_data`.
+pub fn pack_inflection_data(data: &ExpandedInflectionData) -> WordInflectionData {
+    let mut packed: WordInflectionData = 0;
+    packed |= data.number.map_or(0, |x| x as u32) << NUMBER_SHIFT;
+    packed |= data.person.map_or(0, |x| x as u32) << PERSON_SHIFT;
+    packed |= data.voice.map_or(0, |x| x as u32) << VOICE_SHIFT;
+    packed |= data.degree.map_or(0, |x| x as u32) << DEGREE_SHIFT;
+    packed |= data.tense.map_or(0, |x| x as u32) << TENSE_SHIFT;
+    packed |= data.mood.map_or(0, |x| x as u32) << MOOD_SHIFT;
+    for case in &data.cases {
+        packed |= 1 << (CASE_SHIFT + *case as u32);
+    }
+    for gender in &data.genders {
+        packed |= 1 << (GENDER_SHIFT + *gender as u32);
+    }
+    packed
+}
+
 #[inline]
 fn merge_single_field(
     template: WordInflectionData,
@@ -2,7 +2,13 @@ mod ambiguous_uv_ij;
 #[cfg(feature = "complete")]
 pub mod completions;
 #[cfg(feature = "crunch")]
+mod consolidation;
+#[cfg(feature = "crunch")]
 pub mod crunch;
 pub mod indices;
 pub mod inflection_data;
+pub mod paradigms;
 mod stem_merging;
+pub mod table_builder;
+#[cfg(feature = "crunch")]
+mod vowel_lengths;
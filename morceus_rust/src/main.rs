@@ -1,6 +1,7 @@
 use morceus::indices::CruncherTables;
 use std::env;
 use std::fs;
+use std::path::Path;
 use std::process;
 
 const TABLES_FILE: &str = "build/morceus/processed/morceusTables.json";
@@ -54,7 +55,8 @@ fn load_tables(filename: &str) -> CruncherTables {
     let json_content = fs::read_to_string(filename).unwrap_or_else(|err| {
         eprintln!("Error reading file '{filename}': {err}");
         eprintln!(
-            "To generate the tables, run (from the repo root):\n./morcus.sh build --morceus_tables",
+            "To generate the tables, run (from the repo root):\n./morcus.sh build --morceus_tables\n\
+             or build them in Rust with the `build-tables` command.",
         );
         process::exit(1);
     });
@@ -172,12 +174,86 @@ fn handle_crunch(args: &[String], tables: &CruncherTables) {
     }
 }
 
+fn handle_paradigm(args: &[String], tables: &CruncherTables) -> Result<(), String> {
+    use morceus::paradigms::{ParadigmFilter, generate_filtered_paradigms};
+
+    assert!(&args[2].starts_with("paradigm"));
+    let as_forms = &args[2] == "paradigm-forms";
+    let lemma = &args[3];
+    let filter: ParadigmFilter = args[4..].join(" ").parse()?;
+
+    let paradigms = timed!(
+        "Generated paradigms",
+        generate_filtered_paradigms(lemma, tables, &filter)
+    );
+    if paradigms.is_empty() {
+        println!("No lemma found for '{lemma}'");
+        return Ok(());
+    }
+    for paradigm in paradigms {
+        if !as_forms {
+            println!("{}", paradigm.to_tables());
+            continue;
+        }
+        println!("- Lemma: {} [{}]", paradigm.lemma, paradigm.forms.len());
+        for form in paradigm.forms {
+            println!(
+                "  - {} | Inflection Code {}",
+                form.form, form.context.grammatical_data
+            );
+        }
+    }
+    Ok(())
+}
+
+fn handle_build_tables(args: &[String]) -> Result<(), String> {
+    use morceus::table_builder::{build_tables, diff_tables};
+
+    assert!(&args[2].ends_with("-tables"));
+    let data_root = Path::new(&args[3]);
+    let tables = timed!("Built tables", build_tables(data_root)?);
+    if !validate_tables(&tables) {
+        return Err("The built tables are invalid".to_string());
+    }
+
+    if &args[2] == "diff-tables" {
+        let expected_path = args.get(4).map_or(TABLES_FILE, String::as_str);
+        let expected = load_tables(expected_path);
+        let diffs = timed!("Compared tables", diff_tables(&expected, &tables)?);
+        if diffs.is_empty() {
+            println!("The tables match {expected_path}");
+            return Ok(());
+        }
+        for diff in &diffs {
+            println!("{diff}");
+        }
+        return Err(format!("Found differences from {expected_path}"));
+    }
+
+    let output_path = args.get(4).map_or(TABLES_FILE, String::as_str);
+    if let Some(parent) = Path::new(output_path).parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_string(&tables).map_err(|e| e.to_string())?;
+    fs::write(output_path, json).map_err(|e| e.to_string())?;
+    println!("Saved tables to {output_path}");
+    Ok(())
+}
+
 fn print_usage(args: &[String]) {
     eprintln!("Usage:");
     eprintln!("  Analyses for a given word:");
     eprintln!("    {} crunch <word>", args[0]);
     eprintln!("  Possible completions for a given prefix:");
     eprintln!("    {} complete <prefix>", args[0]);
+    eprintln!("  All forms of a lemma, optionally filtered (e.g. `abl pl`):");
+    eprintln!("    {} paradigm <lemma> [inflections...]", args[0]);
+    eprintln!("  The same forms as a list, without the tables:");
+    eprintln!("    {} paradigm-forms <lemma> [inflections...]", args[0]);
+    eprintln!("  Build the tables from morceus-data (by default, to {TABLES_FILE}):");
+    eprintln!("    {} build-tables <morceus-data root> [output]", args[0]);
+    eprintln!("  Compare the built tables with existing ones (by default, {TABLES_FILE}):");
+    eprintln!("    {} diff-tables <morceus-data root> [tables]", args[0]);
 }
 
 fn main() {
@@ -187,17 +263,26 @@ fn main() {
         process::exit(1);
     }
 
+    let command = &args[2];
+    if command == "build-tables" || command == "diff-tables" {
+        if let Err(err) = handle_build_tables(&args) {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        return;
+    }
+
     let start = std::time::Instant::now();
     let tables = load_tables(TABLES_FILE);
     let duration = start.elapsed();
     println!("Parsed tables in {duration:.2?}");
 
-    let command = &args[2];
     match command.as_str() {
         #[cfg(feature = "crunch")]
         "crunch" => handle_crunch(&args, &tables),
         #[cfg(feature = "complete")]
         "complete" | "complete-exact" => handle_complete(&args, &tables).unwrap(),
+        "paradigm" | "paradigm-forms" => handle_paradigm(&args, &tables).unwrap(),
         _ => {
             eprintln!("Unknown command: {command}");
             process::exit(1);
@@ -208,4 +293,7 @@ fn main() {
 /* Run with:
 cargo run --package morceus --release cli crunch <word>
 cargo run --package morceus --release --no-default-features --features complete cli complete <prefix>
+cargo run --package morceus --release cli paradigm <lemma> [inflections...]
+cargo run --package morceus --release --features full cli build-tables morceus-data
+cargo run --package morceus --release --features full cli diff-tables morceus-data
 */
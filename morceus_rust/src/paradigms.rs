@@ -0,0 +1,434 @@
+mod tables;
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::{
+    indices::{CruncherTables, InflectionContext, Lemma},
+    inflection_data::{
+        ExpandedInflectionData, LatinCase, LatinDegree, LatinGender, LatinMood, LatinNumber,
+        LatinPerson, LatinTense, LatinVoice, expand_inflection_data,
+    },
+    stem_merging::merge_stem_and_ending,
+};
+
+pub use tables::{
+    CaseRow, ConjugationTable, DeclensionTable, NonFiniteGroup, NumberCells, ParadigmTables,
+    PersonRow, TableForm,
+};
+
+pub type ParadigmError = String;
+
+/// A single inflected form of a lemma.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParadigmForm {
+    /// The form, with vowel lengths marked as in the tables (`_` for long and `^` for short).
+    pub form: String,
+    /// The stem used to generate this form, or `None` for irregular forms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stem: Option<String>,
+    #[serde(flatten)]
+    pub context: InflectionContext,
+}
+
+/// All the forms of a single lemma.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Paradigm {
+    pub lemma: String,
+    pub is_verb: bool,
+    pub forms: Vec<ParadigmForm>,
+}
+
+/// Restricts a paradigm to the forms with the given inflections. A form matches if it
+/// has every value set on the filter; categories that aren't set on the filter match anything.
+///
+/// Filters can be parsed from a list of inflections separated by spaces or commas, for
+/// example `abl pl` or `3rd sg perfect subjunctive passive`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ParadigmFilter {
+    data: ExpandedInflectionData,
+}
+
+fn set_value<T: PartialEq>(
+    slot: &mut Option<T>,
+    value: T,
+    token: &str,
+) -> Result<(), ParadigmError> {
+    match slot {
+        Some(existing) if *existing != value => Err(format!("Conflicting inflection: {token}")),
+        _ => {
+            *slot = Some(value);
+            Ok(())
+        }
+    }
+}
+
+fn matches_value<T: PartialEq>(filter: &Option<T>, value: &Option<T>) -> bool {
+    filter.is_none() || filter == value
+}
+
+impl ParadigmFilter {
+    fn add(&mut self, token: &str) -> Result<(), ParadigmError> {
+        let data = &mut self.data;
+        let case = match token {
+            "nom" | "nominative" => LatinCase::Nominative,
+            "acc" | "accusative" => LatinCase::Accusative,
+            "dat" | "dative" => LatinCase::Dative,
+            "gen" | "genitive" => LatinCase::Genitive,
+            "abl" | "ablative" => LatinCase::Ablative,
+            "voc" | "vocative" => LatinCase::Vocative,
+            "loc" | "locative" => LatinCase::Locative,
+            _ => return self.add_non_case(token),
+        };
+        if !data.cases.contains(&case) {
+            data.cases.push(case);
+        }
+        Ok(())
+    }
+
+    fn add_non_case(&mut self, token: &str) -> Result<(), ParadigmError> {
+        let data = &mut self.data;
+        let gender = match token {
+            "masc" | "masculine" => LatinGender::Masculine,
+            "fem" | "feminine" => LatinGender::Feminine,
+            "neut" | "neuter" => LatinGender::Neuter,
+            "sg" | "singular" => return set_value(&mut data.number, LatinNumber::Singular, token),
+            "pl" | "plural" => return set_value(&mut data.number, LatinNumber::Plural, token),
+            "1st" | "first" => return set_value(&mut data.person, LatinPerson::First, token),
+            "2nd" | "second" => return set_value(&mut data.person, LatinPerson::Second, token),
+            "3rd" | "third" => return set_value(&mut data.person, LatinPerson::Third, token),
+            "act" | "active" => return set_value(&mut data.voice, LatinVoice::Active, token),
+            "pass" | "passive" => return set_value(&mut data.voice, LatinVoice::Passive, token),
+            "pos" | "positive" => return set_value(&mut data.degree, LatinDegree::Positive, token),
+            "comp" | "comparative" => {
+                return set_value(&mut data.degree, LatinDegree::Comparative, token);
+            }
+            "superl" | "superlative" => {
+                return set_value(&mut data.degree, LatinDegree::Superlative, token);
+            }
+            "pres" | "present" => return set_value(&mut data.tense, LatinTense::Present, token),
+            "impf" | "imperfect" => {
+                return set_value(&mut data.tense, LatinTense::Imperfect, token);
+            }
+            "perf" | "perfect" => return set_value(&mut data.tense, LatinTense::Perfect, token),
+            "futperf" | "future-perfect" => {
+                return set_value(&mut data.tense, LatinTense::FuturePerfect, token);
+            }
+            "fut" | "future" => return set_value(&mut data.tense, LatinTense::Future, token),
+            "plupf" | "pluperfect" => {
+                return set_value(&mut data.tense, LatinTense::Pluperfect, token);
+            }
+            "ind" | "indicative" => return set_value(&mut data.mood, LatinMood::Indicative, token),
+            "imperat" | "imperative" => {
+                return set_value(&mut data.mood, LatinMood::Imperative, token);
+            }
+            "subj" | "subjunctive" => {
+                return set_value(&mut data.mood, LatinMood::Subjunctive, token);
+            }
+            "part" | "participle" => {
+                return set_value(&mut data.mood, LatinMood::Participle, token);
+            }
+            "gerundive" => return set_value(&mut data.mood, LatinMood::Gerundive, token),
+            "inf" | "infinitive" => return set_value(&mut data.mood, LatinMood::Infinitive, token),
+            "supine" => return set_value(&mut data.mood, LatinMood::Supine, token),
+            _ => return Err(format!("Unknown inflection: {token}")),
+        };
+        if !data.genders.contains(&gender) {
+            data.genders.push(gender);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given inflection context satisfies the filter.
+    pub fn matches(&self, context: &InflectionContext) -> bool {
+        let filter = &self.data;
+        let data = expand_inflection_data(context.grammatical_data);
+        // The positive degree is implied if it is not marked.
+        let degree = data.degree.or(Some(LatinDegree::Positive));
+        matches_value(&filter.number, &data.number)
+            && matches_value(&filter.person, &data.person)
+            && matches_value(&filter.voice, &data.voice)
+            && matches_value(&filter.degree, &degree)
+            && matches_value(&filter.tense, &data.tense)
+            && matches_value(&filter.mood, &data.mood)
+            && filter.cases.iter().all(|c| data.cases.contains(c))
+            && filter.genders.iter().all(|g| data.genders.contains(g))
+    }
+}
+
+impl FromStr for ParadigmFilter {
+    type Err = ParadigmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = ParadigmFilter::default();
+        for token in s.split([' ', ',']).filter(|t| !t.is_empty()) {
+            filter.add(&token.to_lowercase())?;
+        }
+        Ok(filter)
+    }
+}
+
+/// Returns whether the given lemma has the requested name. Vowel length markers are
+/// ignored, and a name without a disambiguating number (like `occido`) will match all
+/// of the lemmata with that name (like `occido#1` and `occido#2`).
+fn has_name(lemma: &Lemma, name: &str) -> bool {
+    let stored = lemma.lemma.replace(['_', '^', '-', '+'], "");
+    stored == name
+        || stored
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with('#'))
+}
+
+fn paradigm_for(lemma: &Lemma, tables: &CruncherTables, filter: &ParadigmFilter) -> Paradigm {
+    let mut forms = Vec::new();
+    for &index in lemma.irregular_forms.iter().flatten() {
+        let Some(irreg) = tables.all_irregs.get(index as usize) else {
+            continue;
+        };
+        if filter.matches(&irreg.context) {
+            forms.push(ParadigmForm {
+                form: irreg.form.clone(),
+                stem: None,
+                context: irreg.context.clone(),
+            });
+        }
+    }
+    for &index in lemma.stems.iter().flatten() {
+        let Some(stem) = tables.all_stems.get(index as usize) else {
+            continue;
+        };
+        let Some(table) = tables.inflection_lookup.get(stem.inflection as usize) else {
+            continue;
+        };
+        let mut stem_forms = Vec::new();
+        for end in table.values().flatten() {
+            let Some(context) = merge_stem_and_ending(stem, end) else {
+                continue;
+            };
+            if !filter.matches(&context) {
+                continue;
+            }
+            // * is the placeholder for an empty ending.
+            let ending = if end.ending == "*" { "" } else { &end.ending };
+            stem_forms.push(ParadigmForm {
+                form: format!("{}{}", stem.stem, ending),
+                stem: Some(stem.stem.clone()),
+                context,
+            });
+        }
+        // The endings are grouped in a map, so sort them for a stable order.
+        stem_forms.sort_by(|a, b| {
+            (a.context.grammatical_data, &a.form).cmp(&(b.context.grammatical_data, &b.form))
+        });
+        forms.extend(stem_forms);
+    }
+    Paradigm {
+        lemma: lemma.lemma.clone(),
+        is_verb: lemma.is_verb,
+        forms,
+    }
+}
+
+/// Generates all of the inflected forms of a lemma.
+///
+/// # Arguments
+/// * `lemma` - The name of the lemma, without vowel lengths (for example, `rex`). If there
+///   are multiple lemmata with this name (like `occido#1` and `occido#2`), all are returned.
+/// * `tables` - Morphological database tables for lookups.
+///
+/// # Returns
+/// The paradigm for each lemma with the given name, in the order of the tables.
+pub fn generate_paradigms(lemma: &str, tables: &CruncherTables) -> Vec<Paradigm> {
+    generate_filtered_paradigms(lemma, tables, &ParadigmFilter::default())
+}
+
+/// Generates the inflected forms of a lemma that match the given filter, for example
+/// only the ablative plural.
+pub fn generate_filtered_paradigms(
+    lemma: &str,
+    tables: &CruncherTables,
+    filter: &ParadigmFilter,
+) -> Vec<Paradigm> {
+    tables
+        .raw_lemmata
+        .iter()
+        .filter(|l| has_name(l, lemma))
+        .map(|l| paradigm_for(l, tables, filter))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::indices::{InflectionEnding, IrregularForm, Stem, StemCode};
+
+    const NOM: u32 = 1 << 17;
+    const ACC: u32 = 1 << 18;
+    const DAT: u32 = 1 << 19;
+    const GEN: u32 = 1 << 20;
+    const ABL: u32 = 1 << 21;
+    const FEM: u32 = 1 << 26;
+    const SG: u32 = 1;
+    const PL: u32 = 2;
+
+    fn context(grammatical_data: u32) -> InflectionContext {
+        InflectionContext {
+            grammatical_data,
+            tags: None,
+            internal_tags: None,
+        }
+    }
+
+    fn ending(ending: &str, data: u32) -> InflectionEnding {
+        InflectionEnding {
+            ending: ending.to_string(),
+            context: context(data),
+        }
+    }
+
+    fn lemma(name: &str, stems: Option<Vec<u32>>, irregs: Option<Vec<u32>>) -> Lemma {
+        Lemma {
+            lemma: name.to_string(),
+            stems,
+            irregular_forms: irregs,
+            is_verb: false,
+        }
+    }
+
+    fn irreg(form: &str, data: u32) -> IrregularForm {
+        IrregularForm {
+            code: StemCode::None,
+            form: form.to_string(),
+            context: context(data),
+        }
+    }
+
+    fn test_tables() -> CruncherTables {
+        let table = HashMap::from([
+            (
+                "a".to_string(),
+                vec![ending("a", NOM | SG), ending("a_", ABL | SG)],
+            ),
+            (
+                "ae".to_string(),
+                vec![ending("ae", GEN | DAT | SG), ending("ae", NOM | PL)],
+            ),
+            ("am".to_string(), vec![ending("am", ACC | SG)]),
+            ("is".to_string(), vec![ending("i_s", DAT | ABL | PL)]),
+        ]);
+        CruncherTables {
+            #[cfg(feature = "crunch")]
+            ends_map: HashMap::new(),
+            #[cfg(feature = "crunch")]
+            stem_map: HashMap::new(),
+            inflection_lookup: vec![table],
+            #[cfg(feature = "extra")]
+            numerals: vec![],
+            #[cfg(feature = "extra")]
+            raw_tables: HashMap::new(),
+            raw_lemmata: vec![
+                lemma("rosa", Some(vec![0]), None),
+                lemma("vis", None, Some(vec![0, 1])),
+                lemma("occido#1", None, Some(vec![0])),
+                lemma("occido#2", None, Some(vec![1])),
+            ],
+            all_stems: vec![Stem {
+                code: StemCode::No,
+                stem: "ros".to_string(),
+                inflection: 0,
+                context: context(FEM),
+            }],
+            all_irregs: vec![irreg("vi_s", NOM | SG | FEM), irreg("vi_", ABL | SG | FEM)],
+        }
+    }
+
+    fn forms(paradigms: &[Paradigm]) -> Vec<Vec<&str>> {
+        paradigms
+            .iter()
+            .map(|p| p.forms.iter().map(|f| f.form.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn generates_all_forms_of_a_stem() {
+        let tables = test_tables();
+        let paradigms = generate_paradigms("rosa", &tables);
+        assert_eq!(paradigms.len(), 1);
+        assert_eq!(paradigms[0].lemma, "rosa");
+        let mut all = forms(&paradigms)[0].clone();
+        all.sort();
+        assert_eq!(
+            all,
+            vec!["rosa", "rosa_", "rosae", "rosae", "rosam", "rosi_s"]
+        );
+        let stems = paradigms[0].forms.iter().map(|f| f.stem.as_deref());
+        assert!(stems.into_iter().all(|s| s == Some("ros")));
+    }
+
+    #[test]
+    fn generates_irregular_forms() {
+        let tables = test_tables();
+        let paradigms = generate_paradigms("vis", &tables);
+        assert_eq!(forms(&paradigms), vec![vec!["vi_s", "vi_"]]);
+        assert!(paradigms[0].forms.iter().all(|f| f.stem.is_none()));
+    }
+
+    #[test]
+    fn generates_all_homographs() {
+        let tables = test_tables();
+        let paradigms = generate_paradigms("occido", &tables);
+        let lemmata = paradigms
+            .iter()
+            .map(|p| p.lemma.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(lemmata, vec!["occido#1", "occido#2"]);
+
+        let paradigms = generate_paradigms("occido#2", &tables);
+        assert_eq!(forms(&paradigms), vec![vec!["vi_"]]);
+        assert!(generate_paradigms("occ", &tables).is_empty());
+    }
+
+    #[test]
+    fn filters_forms() {
+        let tables = test_tables();
+        let filter = "abl pl".parse::<ParadigmFilter>().unwrap();
+        let paradigms = generate_filtered_paradigms("rosa", &tables, &filter);
+        assert_eq!(forms(&paradigms), vec![vec!["rosi_s"]]);
+
+        let filter = "Nominative, fem".parse::<ParadigmFilter>().unwrap();
+        let paradigms = generate_filtered_paradigms("rosa", &tables, &filter);
+        assert_eq!(forms(&paradigms), vec![vec!["rosa", "rosae"]]);
+
+        let filter = "gen dat sg".parse::<ParadigmFilter>().unwrap();
+        let paradigms = generate_filtered_paradigms("rosa", &tables, &filter);
+        assert_eq!(forms(&paradigms), vec![vec!["rosae"]]);
+    }
+
+    #[test]
+    fn parses_verb_filters() {
+        let filter = "3rd sg perfect subjunctive passive"
+            .parse::<ParadigmFilter>()
+            .unwrap();
+        let expected = ExpandedInflectionData {
+            person: Some(LatinPerson::Third),
+            number: Some(LatinNumber::Singular),
+            tense: Some(LatinTense::Perfect),
+            mood: Some(LatinMood::Subjunctive),
+            voice: Some(LatinVoice::Passive),
+            ..Default::default()
+        };
+        assert_eq!(filter, ParadigmFilter { data: expected });
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!("abl foo".parse::<ParadigmFilter>().is_err());
+        assert!("sg pl".parse::<ParadigmFilter>().is_err());
+        assert!("sg singular".parse::<ParadigmFilter>().is_ok());
+    }
+}
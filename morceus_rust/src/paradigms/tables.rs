@@ -0,0 +1,626 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    inflection_data::{
+        ExpandedInflectionData, LatinCase, LatinDegree, LatinGender, LatinMood, LatinNumber,
+        LatinPerson, LatinTense, LatinVoice, expand_inflection_data,
+    },
+    paradigms::Paradigm,
+};
+
+/// The traditional order of the cases in a declension table.
+const CASE_ORDER: [LatinCase; 7] = [
+    LatinCase::Nominative,
+    LatinCase::Genitive,
+    LatinCase::Dative,
+    LatinCase::Accusative,
+    LatinCase::Ablative,
+    LatinCase::Vocative,
+    LatinCase::Locative,
+];
+
+const TENSE_ORDER: [LatinTense; 6] = [
+    LatinTense::Present,
+    LatinTense::Imperfect,
+    LatinTense::Future,
+    LatinTense::Perfect,
+    LatinTense::Pluperfect,
+    LatinTense::FuturePerfect,
+];
+
+const MOOD_ORDER: [LatinMood; 7] = [
+    LatinMood::Indicative,
+    LatinMood::Subjunctive,
+    LatinMood::Imperative,
+    LatinMood::Participle,
+    LatinMood::Gerundive,
+    LatinMood::Infinitive,
+    LatinMood::Supine,
+];
+
+/// The forms in a single row of a table, by number.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NumberCells {
+    pub singular: Vec<String>,
+    pub plural: Vec<String>,
+    /// Forms that aren't marked for number, like supines.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unmarked: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaseRow {
+    pub case: LatinCase,
+    #[serde(flatten)]
+    pub forms: NumberCells,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonRow {
+    pub person: LatinPerson,
+    #[serde(flatten)]
+    pub forms: NumberCells,
+}
+
+/// The forms of a single gender (and degree) laid out by case and number.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeclensionTable {
+    /// The degree of the forms, or `None` for the positive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degree: Option<LatinDegree>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gender: Option<LatinGender>,
+    pub rows: Vec<CaseRow>,
+}
+
+/// The finite forms of a single mood, tense, and voice laid out by person and number.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConjugationTable {
+    pub mood: LatinMood,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tense: Option<LatinTense>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<LatinVoice>,
+    pub rows: Vec<PersonRow>,
+}
+
+/// The participles, gerundives, infinitives, or supines of a single tense and voice.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NonFiniteGroup {
+    pub mood: LatinMood,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tense: Option<LatinTense>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<LatinVoice>,
+    /// Forms that don't decline, like infinitives.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub forms: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub declensions: Vec<DeclensionTable>,
+}
+
+/// A form that doesn't fit in any table, like an adverb or an indeclinable form.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableForm {
+    pub form: String,
+    #[serde(flatten)]
+    pub data: ExpandedInflectionData,
+}
+
+/// The forms of a lemma laid out in declension and conjugation tables. Forms are
+/// displayed with macra (but not breves), and a form with multiple cases or genders
+/// appears in each of the matching cells.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParadigmTables {
+    pub lemma: String,
+    pub is_verb: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub declensions: Vec<DeclensionTable>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conjugations: Vec<ConjugationTable>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub non_finite: Vec<NonFiniteGroup>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub other: Vec<TableForm>,
+}
+
+fn display_form(form: &str) -> String {
+    form.replace(['-', '+', '^'], "").replace('_', "\u{0304}")
+}
+
+fn push_unique(forms: &mut Vec<String>, form: &str) {
+    if !forms.iter().any(|f| f == form) {
+        forms.push(form.to_string());
+    }
+}
+
+/// Returns the item matching the predicate, adding a new one if there are none.
+fn find_or_insert<T>(
+    items: &mut Vec<T>,
+    predicate: impl Fn(&T) -> bool,
+    make: impl FnOnce() -> T,
+) -> &mut T {
+    let index = match items.iter().position(predicate) {
+        Some(index) => index,
+        None => {
+            items.push(make());
+            items.len() - 1
+        }
+    };
+    &mut items[index]
+}
+
+fn position<T: PartialEq>(order: &[T], value: Option<T>) -> usize {
+    value
+        .and_then(|v| order.iter().position(|o| *o == v))
+        .unwrap_or(order.len())
+}
+
+impl NumberCells {
+    fn add(&mut self, number: Option<LatinNumber>, form: &str) {
+        let cell = match number {
+            Some(LatinNumber::Singular) => &mut self.singular,
+            Some(LatinNumber::Plural) => &mut self.plural,
+            None => &mut self.unmarked,
+        };
+        push_unique(cell, form);
+    }
+}
+
+fn add_declined(tables: &mut Vec<DeclensionTable>, data: &ExpandedInflectionData, form: &str) {
+    let degree = data.degree.filter(|d| *d != LatinDegree::Positive);
+    let genders = match data.genders.is_empty() {
+        true => vec![None],
+        false => data.genders.iter().copied().map(Some).collect(),
+    };
+    for gender in genders {
+        let table = find_or_insert(
+            tables,
+            |t| t.degree == degree && t.gender == gender,
+            || DeclensionTable {
+                degree,
+                gender,
+                rows: vec![],
+            },
+        );
+        for &case in &data.cases {
+            let row = find_or_insert(
+                &mut table.rows,
+                |r| r.case == case,
+                || CaseRow {
+                    case,
+                    forms: NumberCells::default(),
+                },
+            );
+            row.forms.add(data.number, form);
+        }
+    }
+}
+
+fn sort_declensions(tables: &mut [DeclensionTable]) {
+    tables.sort_by_key(|t| (t.degree.map(|d| d as u32), t.gender.map(|g| g as u32)));
+    for table in tables.iter_mut() {
+        table
+            .rows
+            .sort_by_key(|r| position(&CASE_ORDER, Some(r.case)));
+    }
+}
+
+impl ParadigmTables {
+    fn add(&mut self, data: ExpandedInflectionData, form: &str) {
+        match (data.mood, data.person) {
+            (
+                Some(
+                    mood @ (LatinMood::Participle
+                    | LatinMood::Gerundive
+                    | LatinMood::Infinitive
+                    | LatinMood::Supine),
+                ),
+                _,
+            ) => {
+                let group = find_or_insert(
+                    &mut self.non_finite,
+                    |g| g.mood == mood && g.tense == data.tense && g.voice == data.voice,
+                    || NonFiniteGroup {
+                        mood,
+                        tense: data.tense,
+                        voice: data.voice,
+                        forms: vec![],
+                        declensions: vec![],
+                    },
+                );
+                match data.cases.is_empty() {
+                    true => push_unique(&mut group.forms, form),
+                    false => add_declined(&mut group.declensions, &data, form),
+                }
+            }
+            _ if !data.cases.is_empty() => add_declined(&mut self.declensions, &data, form),
+            (Some(mood), Some(person)) => {
+                let table = find_or_insert(
+                    &mut self.conjugations,
+                    |t| t.mood == mood && t.tense == data.tense && t.voice == data.voice,
+                    || ConjugationTable {
+                        mood,
+                        tense: data.tense,
+                        voice: data.voice,
+                        rows: vec![],
+                    },
+                );
+                let row = find_or_insert(
+                    &mut table.rows,
+                    |r| r.person == person,
+                    || PersonRow {
+                        person,
+                        forms: NumberCells::default(),
+                    },
+                );
+                row.forms.add(data.number, form);
+            }
+            _ => self.other.push(TableForm {
+                form: form.to_string(),
+                data,
+            }),
+        }
+    }
+
+    fn sort(&mut self) {
+        sort_declensions(&mut self.declensions);
+        self.conjugations.sort_by_key(|t| {
+            (
+                t.voice.map(|v| v as u32),
+                position(&MOOD_ORDER, Some(t.mood)),
+                position(&TENSE_ORDER, t.tense),
+            )
+        });
+        for table in &mut self.conjugations {
+            table.rows.sort_by_key(|r| r.person as u32);
+        }
+        self.non_finite.sort_by_key(|g| {
+            (
+                position(&MOOD_ORDER, Some(g.mood)),
+                position(&TENSE_ORDER, g.tense),
+                g.voice.map(|v| v as u32),
+            )
+        });
+        for group in &mut self.non_finite {
+            sort_declensions(&mut group.declensions);
+        }
+    }
+}
+
+impl Paradigm {
+    /// Lays out the forms of this paradigm in declension and conjugation tables.
+    pub fn to_tables(&self) -> ParadigmTables {
+        let mut tables = ParadigmTables {
+            lemma: self.lemma.clone(),
+            is_verb: self.is_verb,
+            declensions: vec![],
+            conjugations: vec![],
+            non_finite: vec![],
+            other: vec![],
+        };
+        for form in &self.forms {
+            let data = expand_inflection_data(form.context.grammatical_data);
+            tables.add(data, &display_form(&form.form));
+        }
+        tables.sort();
+        tables
+    }
+}
+
+fn tense_name(tense: LatinTense) -> &'static str {
+    match tense {
+        LatinTense::Present => "Present",
+        LatinTense::Imperfect => "Imperfect",
+        LatinTense::Perfect => "Perfect",
+        LatinTense::FuturePerfect => "Future Perfect",
+        LatinTense::Future => "Future",
+        LatinTense::Pluperfect => "Pluperfect",
+    }
+}
+
+fn person_name(person: LatinPerson) -> &'static str {
+    match person {
+        LatinPerson::First => "1st",
+        LatinPerson::Second => "2nd",
+        LatinPerson::Third => "3rd",
+    }
+}
+
+fn title(parts: &[Option<String>]) -> String {
+    parts
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The width of the text in a terminal, ignoring combining characters.
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .filter(|c| !('\u{0300}'..='\u{036f}').contains(c))
+        .count()
+}
+
+fn write_grid(f: &mut fmt::Formatter, title: &str, rows: &[(&str, &NumberCells)]) -> fmt::Result {
+    let has_unmarked = rows.iter().any(|(_, cells)| !cells.unmarked.is_empty());
+    let join = |forms: &[String]| match forms.is_empty() {
+        true => "-".to_string(),
+        false => forms.join(", "),
+    };
+    let mut lines = vec![vec![String::new(), "Singular".into(), "Plural".into()]];
+    if has_unmarked {
+        lines[0].push("Other".into());
+    }
+    for (label, cells) in rows {
+        let mut line = vec![
+            label.to_string(),
+            join(&cells.singular),
+            join(&cells.plural),
+        ];
+        if has_unmarked {
+            line.push(join(&cells.unmarked));
+        }
+        lines.push(line);
+    }
+    let mut widths = vec![0; lines[0].len()];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(display_width(cell));
+        }
+    }
+    writeln!(f, "\n  {title}")?;
+    for line in &lines {
+        let mut text = String::from("    ");
+        for (cell, width) in line.iter().zip(&widths) {
+            text.push_str(cell);
+            text.push_str(&" ".repeat(width - display_width(cell) + 2));
+        }
+        writeln!(f, "{}", text.trim_end())?;
+    }
+    Ok(())
+}
+
+fn write_declension(
+    f: &mut fmt::Formatter,
+    prefix: Option<String>,
+    table: &DeclensionTable,
+) -> fmt::Result {
+    let name = title(&[
+        prefix,
+        table.degree.map(|d| format!("{d:?}")),
+        table.gender.map(|g| format!("{g:?}")),
+    ]);
+    let name = if name.is_empty() {
+        "Declension".to_string()
+    } else {
+        name
+    };
+    let rows = table
+        .rows
+        .iter()
+        .map(|r| (format!("{:?}", r.case), &r.forms))
+        .collect::<Vec<_>>();
+    let rows = rows
+        .iter()
+        .map(|(l, c)| (l.as_str(), *c))
+        .collect::<Vec<_>>();
+    write_grid(f, &name, &rows)
+}
+
+impl fmt::Display for ParadigmTables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", display_form(&self.lemma))?;
+        for table in &self.declensions {
+            write_declension(f, None, table)?;
+        }
+        for table in &self.conjugations {
+            let name = title(&[
+                table.tense.map(|t| tense_name(t).to_string()),
+                Some(format!("{:?}", table.mood)),
+                table.voice.map(|v| format!("{v:?}")),
+            ]);
+            let rows = table
+                .rows
+                .iter()
+                .map(|r| (person_name(r.person), &r.forms))
+                .collect::<Vec<_>>();
+            write_grid(f, &name, &rows)?;
+        }
+        for group in &self.non_finite {
+            let name = title(&[
+                group.tense.map(|t| tense_name(t).to_string()),
+                group.voice.map(|v| format!("{v:?}")),
+                Some(format!("{:?}", group.mood)),
+            ]);
+            if !group.forms.is_empty() {
+                writeln!(f, "\n  {name}: {}", group.forms.join(", "))?;
+            }
+            for table in &group.declensions {
+                write_declension(f, Some(name.clone()), table)?;
+            }
+        }
+        if !self.other.is_empty() {
+            let forms = self.other.iter().map(|o| o.form.as_str());
+            writeln!(f, "\n  Other: {}", forms.collect::<Vec<_>>().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{indices::InflectionContext, paradigms::ParadigmForm};
+
+    const SG: u32 = 1;
+    const PL: u32 = 2;
+    const FIRST: u32 = 1 << 2;
+    const SECOND: u32 = 2 << 2;
+    const THIRD: u32 = 3 << 2;
+    const ACT: u32 = 1 << 4;
+    const PASS: u32 = 2 << 4;
+    const PRES: u32 = 1 << 8;
+    const IND: u32 = 1 << 11;
+    const PTCP: u32 = 4 << 11;
+    const INF: u32 = 6 << 11;
+    const NOM: u32 = 1 << 17;
+    const DAT: u32 = 1 << 19;
+    const GEN: u32 = 1 << 20;
+    const ABL: u32 = 1 << 21;
+    const MASC: u32 = 1 << 25;
+    const FEM: u32 = 1 << 26;
+
+    fn paradigm(lemma: &str, is_verb: bool, forms: &[(&str, u32)]) -> Paradigm {
+        let forms = forms
+            .iter()
+            .map(|(form, data)| ParadigmForm {
+                form: form.to_string(),
+                stem: None,
+                context: InflectionContext {
+                    grammatical_data: *data,
+                    tags: None,
+                    internal_tags: None,
+                },
+            })
+            .collect();
+        Paradigm {
+            lemma: lemma.to_string(),
+            is_verb,
+            forms,
+        }
+    }
+
+    fn rosa() -> Paradigm {
+        paradigm(
+            "rosa",
+            false,
+            &[
+                ("ro^sa_", ABL | SG | FEM),
+                ("ro^sa^", NOM | SG | FEM),
+                ("ro^sae", GEN | DAT | SG | FEM),
+                ("ro^sae", NOM | PL | FEM),
+                ("ro^si_s", DAT | ABL | PL | FEM),
+            ],
+        )
+    }
+
+    fn amo() -> Paradigm {
+        paradigm(
+            "a_mo",
+            true,
+            &[
+                ("a_ma_tur", THIRD | SG | PRES | IND | PASS),
+                ("a_ma_re", PRES | INF | ACT),
+                ("a_mo_", FIRST | SG | PRES | IND | ACT),
+                ("a_ma_s", SECOND | SG | PRES | IND | ACT),
+                ("a_ma_mus", FIRST | PL | PRES | IND | ACT),
+                ("a_ma^ns", NOM | SG | MASC | FEM | PRES | PTCP | ACT),
+            ],
+        )
+    }
+
+    #[test]
+    fn declines_by_case_and_number() {
+        let tables = rosa().to_tables();
+        assert!(tables.conjugations.is_empty());
+        assert_eq!(tables.declensions.len(), 1);
+        let table = &tables.declensions[0];
+        assert_eq!(table.gender, Some(LatinGender::Feminine));
+        let cases = table.rows.iter().map(|r| r.case).collect::<Vec<_>>();
+        assert_eq!(
+            cases,
+            vec![
+                LatinCase::Nominative,
+                LatinCase::Genitive,
+                LatinCase::Dative,
+                LatinCase::Ablative
+            ]
+        );
+        let dative = &table.rows[2].forms;
+        assert_eq!(dative.singular, vec!["rosae"]);
+        assert_eq!(dative.plural, vec!["rosi\u{304}s"]);
+    }
+
+    #[test]
+    fn conjugates_by_person_and_number() {
+        let tables = amo().to_tables();
+        let names = tables
+            .conjugations
+            .iter()
+            .map(|t| (t.mood, t.tense, t.voice))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                (
+                    LatinMood::Indicative,
+                    Some(LatinTense::Present),
+                    Some(LatinVoice::Active)
+                ),
+                (
+                    LatinMood::Indicative,
+                    Some(LatinTense::Present),
+                    Some(LatinVoice::Passive)
+                ),
+            ]
+        );
+        let active = &tables.conjugations[0].rows;
+        assert_eq!(active[0].person, LatinPerson::First);
+        assert_eq!(active[0].forms.singular, vec!["a\u{304}mo\u{304}"]);
+        assert_eq!(active[0].forms.plural, vec!["a\u{304}ma\u{304}mus"]);
+        assert_eq!(active[1].person, LatinPerson::Second);
+    }
+
+    #[test]
+    fn groups_non_finite_forms() {
+        let tables = amo().to_tables();
+        let moods = tables.non_finite.iter().map(|g| g.mood).collect::<Vec<_>>();
+        assert_eq!(moods, vec![LatinMood::Participle, LatinMood::Infinitive]);
+
+        let participle = &tables.non_finite[0];
+        assert!(participle.forms.is_empty());
+        let genders = participle
+            .declensions
+            .iter()
+            .map(|t| t.gender)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            genders,
+            vec![Some(LatinGender::Masculine), Some(LatinGender::Feminine)]
+        );
+        assert_eq!(tables.non_finite[1].forms, vec!["a\u{304}ma\u{304}re"]);
+        assert!(tables.declensions.is_empty());
+    }
+
+    #[test]
+    fn renders_as_text() {
+        let text = amo().to_tables().to_string();
+        let lines = text.lines().map(|l| l.trim()).collect::<Vec<_>>();
+        assert_eq!(lines[0], "a\u{304}mo");
+        assert!(lines.contains(&"Present Indicative Active"));
+        assert!(lines.contains(&"Present Active Infinitive: a\u{304}ma\u{304}re"));
+        assert!(lines.contains(&"Present Active Participle Masculine"));
+        let first = lines.iter().find(|l| l.starts_with("1st")).unwrap();
+        assert_eq!(
+            first.split_whitespace().collect::<Vec<_>>(),
+            vec!["1st", "a\u{304}mo\u{304}", "a\u{304}ma\u{304}mus"]
+        );
+        let third = lines.iter().find(|l| l.starts_with("3rd")).unwrap();
+        assert_eq!(
+            third.split_whitespace().collect::<Vec<_>>(),
+            vec!["3rd", "a\u{304}ma\u{304}tur", "-"]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Builds the `CruncherTables` directly from the `morceus-data` stem and ending files.
+//!
+//! This mirrors the tables generated in the Javascript code (via
+//! `./morcus.sh build --morceus_tables`), including the order of the inflection tables
+//! and of the stems and irregular forms, so that the output can be compared against
+//! (and used in place of) `morceusTables.json`.
+//!
+//! Compound verbs are already expanded in `vbs.compounds.latin`, so no preverb
+//! handling is needed here.
+
+mod inflection_parsing;
+mod stems;
+mod table_diff;
+mod templates;
+
+use std::{collections::HashMap, fs, path::Path};
+
+#[cfg(feature = "crunch")]
+use crate::indices::StemMapValue;
+use crate::indices::{
+    CruncherTables, GroupedInflectionTable, InflectionTable, InflectionTableKey, Lemma,
+};
+
+use stems::{ParsedLemma, TableIndices, parse_irregular_stems, parse_regular_stems};
+pub use table_diff::diff_tables;
+
+pub type TableBuildError = String;
+
+const ENDS_SUBDIR: &str = "latin/ends";
+const STEMS_SUBDIR: &str = "latin/stems";
+const NOM_IRREGS: &str = "latin/stems/nominals/irregs/irreg.nom.src";
+const VERB_IRREGS: &str = "latin/stems/verbs/irregs/irreg.vbs.src";
+
+fn read_file(path: &Path) -> Result<String, TableBuildError> {
+    fs::read_to_string(path).map_err(|e| format!("Error reading {}: {e}", path.display()))
+}
+
+/// Returns the stem files (but not the irregular stems) of the given type,
+/// sorted by file name.
+fn stem_files(data_root: &Path, parent: &str) -> Result<Vec<std::path::PathBuf>, TableBuildError> {
+    let dir = data_root.join(STEMS_SUBDIR).join(parent);
+    let entries = fs::read_dir(&dir)
+        .and_then(|dir| dir.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Error reading {}: {e}", dir.display()))?;
+    let mut files: Vec<_> = entries
+        .into_iter()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn load_lemmata(
+    data_root: &Path,
+    table_indices: &TableIndices,
+) -> Result<Vec<ParsedLemma>, TableBuildError> {
+    let mut lemmata = Vec::new();
+    for (parent, irregs, is_verb) in [
+        ("nominals", NOM_IRREGS, false),
+        ("verbs", VERB_IRREGS, true),
+    ] {
+        for path in stem_files(data_root, parent)? {
+            let contents = read_file(&path)?;
+            let parsed = parse_regular_stems(&contents, is_verb, table_indices)
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            lemmata.extend(parsed);
+        }
+        let path = data_root.join(irregs);
+        let parsed = parse_irregular_stems(&read_file(&path)?, is_verb, table_indices)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        lemmata.extend(parsed);
+    }
+    Ok(lemmata)
+}
+
+/// Builds the tables from the `morceus-data` directory at `data_root`.
+pub fn build_tables(data_root: &Path) -> Result<CruncherTables, TableBuildError> {
+    let ends_root = data_root.join(ENDS_SUBDIR);
+    let template_dirs = [ends_root.join("target"), ends_root.join("dependency")];
+    if let Some(missing) = template_dirs.iter().find(|dir| !dir.is_dir()) {
+        return Err(format!(
+            "Missing the ending templates in {}. The tables can't be built from a copy of \
+             morceus-data without the `latin/ends/target` and `latin/ends/dependency` templates.",
+            missing.display()
+        ));
+    }
+    let tables = templates::expand_templates(&templates::load_templates(&template_dirs)?)?;
+    let table_indices = make_table_indices(&tables)?;
+    let lemmata = load_lemmata(data_root, &table_indices)?;
+    assemble_tables(tables, lemmata)
+}
+
+fn make_table_indices(tables: &[InflectionTable]) -> Result<TableIndices, TableBuildError> {
+    let mut indices = HashMap::new();
+    for (i, table) in tables.iter().enumerate() {
+        let key = InflectionTableKey::try_from(i)
+            .map_err(|_| format!("Too many inflection tables: {}", tables.len()))?;
+        indices.insert(table.name.clone(), key);
+    }
+    Ok(indices)
+}
+
+/// Removes vowel length markings so that lookups can be made from un-macronized words.
+/// The lengths are still preserved in the endings themselves.
+fn clean_ending(ending: &str) -> String {
+    ending.replace(['_', '^'], "")
+}
+
+#[cfg(feature = "crunch")]
+fn make_ends_map(tables: &[InflectionTable]) -> HashMap<String, Vec<InflectionTableKey>> {
+    let mut ends_map: HashMap<String, Vec<InflectionTableKey>> = HashMap::new();
+    for (i, table) in tables.iter().enumerate() {
+        for end in &table.endings {
+            // This can't overflow, since `make_table_indices` has already checked it.
+            ends_map
+                .entry(clean_ending(&end.ending))
+                .or_default()
+                .push(i as InflectionTableKey);
+        }
+    }
+    for indices in ends_map.values_mut() {
+        indices.sort();
+        indices.dedup();
+    }
+    ends_map
+}
+
+fn group_endings(table: &InflectionTable) -> GroupedInflectionTable {
+    let mut grouped: GroupedInflectionTable = HashMap::new();
+    for end in &table.endings {
+        grouped
+            .entry(clean_ending(&end.ending))
+            .or_default()
+            .push(end.clone());
+    }
+    grouped
+}
+
+fn sort_key(stem_or_form: &str) -> String {
+    stem_or_form
+        .to_lowercase()
+        .replace(['+', '-', '_', '^'], "")
+}
+
+#[cfg(feature = "crunch")]
+fn stem_map_key(stem_or_form: &str) -> String {
+    stem_or_form.replace(['^', '_', '-', '+'], "")
+}
+
+/// Collects the items of each lemma (in the given lemma order) into a list sorted by
+/// `key`, and returns the sorted list along with the index of each item in each lemma.
+fn sort_items<T: Clone>(
+    lemmata: &[ParsedLemma],
+    order: &[usize],
+    items: impl Fn(&ParsedLemma) -> &[T],
+    key: impl Fn(&T) -> &str,
+) -> Result<(Vec<T>, Vec<Vec<u32>>), TableBuildError> {
+    let mut keyed: Vec<(String, usize, usize)> = Vec::new();
+    for &i in order {
+        for (j, item) in items(&lemmata[i]).iter().enumerate() {
+            keyed.push((sort_key(key(item)), i, j));
+        }
+    }
+    // This must be stable so that items with the same key keep the lemma order.
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut indices: Vec<Vec<u32>> = lemmata.iter().map(|l| vec![0; items(l).len()]).collect();
+    let mut sorted = Vec::with_capacity(keyed.len());
+    for (index, (_, i, j)) in keyed.into_iter().enumerate() {
+        indices[i][j] =
+            u32::try_from(index).map_err(|_| format!("Too many items: {}", sorted.len()))?;
+        sorted.push(items(&lemmata[i])[j].clone());
+    }
+    Ok((sorted, indices))
+}
+
+fn assemble_tables(
+    tables: Vec<InflectionTable>,
+    lemmata: Vec<ParsedLemma>,
+) -> Result<CruncherTables, TableBuildError> {
+    make_table_indices(&tables)?;
+
+    // Lemmata with the same name are grouped together, in order of first appearance.
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_indices: HashMap<&str, usize> = HashMap::new();
+    for (i, lemma) in lemmata.iter().enumerate() {
+        let group = *group_indices
+            .entry(lemma.lemma.as_str())
+            .or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+        groups[group].push(i);
+    }
+    let raw_order: Vec<usize> = groups.into_iter().flatten().collect();
+
+    let (all_stems, stem_indices) = sort_items(&lemmata, &raw_order, |l| &l.stems, |s| &s.stem)?;
+    let (all_irregs, irreg_indices) =
+        sort_items(&lemmata, &raw_order, |l| &l.irregular_forms, |f| &f.form)?;
+    let to_lemma = |i: usize| Lemma {
+        lemma: lemmata[i].lemma.clone(),
+        stems: (!stem_indices[i].is_empty()).then(|| stem_indices[i].clone()),
+        irregular_forms: (!irreg_indices[i].is_empty()).then(|| irreg_indices[i].clone()),
+        is_verb: lemmata[i].is_verb,
+    };
+
+    #[cfg(feature = "crunch")]
+    let stem_map = {
+        let mut stem_map: HashMap<String, Vec<StemMapValue>> = HashMap::new();
+        for (i, lemma) in lemmata.iter().enumerate() {
+            let stems = lemma.stems.iter().map(|s| &s.stem).zip(&stem_indices[i]);
+            let irregs = lemma.irregular_forms.iter().map(|f| &f.form);
+            let irregs = irregs.zip(&irreg_indices[i]);
+            let keyed = stems
+                .map(|(key, index)| (key, index, true))
+                .chain(irregs.map(|(key, index)| (key, index, false)));
+            for (key, index, is_stem) in keyed {
+                stem_map
+                    .entry(stem_map_key(key))
+                    .or_default()
+                    .push(StemMapValue {
+                        index: *index,
+                        lemma: lemma.lemma.clone(),
+                        is_verb: lemma.is_verb,
+                        is_stem,
+                    });
+            }
+        }
+        stem_map
+    };
+
+    #[cfg(feature = "extra")]
+    let numerals = {
+        let is_numeral = |l: &ParsedLemma| {
+            let stems = l.stems.iter().map(|s| &s.context);
+            let mut contexts = stems.chain(l.irregular_forms.iter().map(|f| &f.context));
+            contexts.any(|c| {
+                c.internal_tags
+                    .as_ref()
+                    .is_some_and(|tags| tags.iter().any(|t| t == "numeral"))
+            })
+        };
+        (0..lemmata.len())
+            .filter(|i| is_numeral(&lemmata[*i]))
+            .map(to_lemma)
+            .collect()
+    };
+
+    Ok(CruncherTables {
+        #[cfg(feature = "crunch")]
+        ends_map: make_ends_map(&tables),
+        #[cfg(feature = "crunch")]
+        stem_map,
+        inflection_lookup: tables.iter().map(group_endings).collect(),
+        #[cfg(feature = "extra")]
+        numerals,
+        raw_lemmata: raw_order.iter().map(|i| to_lemma(*i)).collect(),
+        #[cfg(feature = "extra")]
+        raw_tables: tables
+            .into_iter()
+            .map(|table| (table.name.clone(), table))
+            .collect(),
+        all_stems,
+        all_irregs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stems::parse_regular_stems;
+
+    fn build(stems: &str) -> CruncherTables {
+        let parsed = [
+            templates::parse_template("a_ae", "a nom sg\nae gen sg\nae dat sg\n").unwrap(),
+            templates::parse_template("us_i", "us nom sg\ni_ gen sg\n").unwrap(),
+        ];
+        let tables = templates::expand_templates(&parsed).unwrap();
+        let lemmata = parse_regular_stems(stems, false, &make_table_indices(&tables).unwrap());
+        assemble_tables(tables, lemmata.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn assembles_sorted_stems_and_lemmata() {
+        let tables = build(
+            ":le:rosa\n:no:ro^s a_ae fem\n:le:Marcus\n:no:Marc us_i masc\n\
+             :le:rosa\n:no:ros-ul a_ae fem\n:wd:rosa_tim adverb\n",
+        );
+        let stems: Vec<&str> = tables.all_stems.iter().map(|s| s.stem.as_str()).collect();
+        assert_eq!(stems, vec!["Marc", "ro^s", "ros-ul"]);
+        assert_eq!(tables.all_irregs[0].form, "rosa_tim");
+
+        // Lemmata with the same name are grouped together.
+        let lemmata: Vec<(&str, Option<Vec<u32>>)> = tables
+            .raw_lemmata
+            .iter()
+            .map(|l| (l.lemma.as_str(), l.stems.clone()))
+            .collect();
+        assert_eq!(
+            lemmata,
+            vec![
+                ("rosa", Some(vec![1])),
+                ("rosa", Some(vec![2])),
+                ("Marcus", Some(vec![0]))
+            ]
+        );
+        assert_eq!(tables.raw_lemmata[1].irregular_forms, Some(vec![0]));
+        assert_eq!(tables.inflection_lookup[0]["ae"].len(), 2);
+
+        #[cfg(feature = "crunch")]
+        {
+            assert_eq!(tables.ends_map["ae"], vec![0]);
+            assert_eq!(tables.ends_map["i"], vec![1]);
+            let rosul = &tables.stem_map["rosul"][0];
+            assert_eq!((rosul.index, rosul.is_stem), (2, true));
+            assert!(!tables.stem_map["rosatim"][0].is_stem);
+        }
+    }
+    #[test]
+    fn reports_missing_ending_templates() {
+        let error = build_tables(Path::new("no/such/morceus-data")).unwrap_err();
+        assert!(error.starts_with(
+            "Missing the ending templates in no/such/morceus-data/latin/ends/target."
+        ));
+    }
+}
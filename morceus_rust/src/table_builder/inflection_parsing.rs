@@ -0,0 +1,256 @@
+use crate::{
+    indices::InflectionContext,
+    inflection_data::{
+        ExpandedInflectionData, LatinCase, LatinDegree, LatinGender, LatinMood, LatinNumber,
+        LatinPerson, LatinTense, LatinVoice, pack_inflection_data,
+    },
+};
+
+use super::TableBuildError;
+
+/// Tags with usage notes that are shown to users.
+const SEMANTIC_TAGS: &[&str] = &[
+    "abbrev",
+    "adverb",
+    "archaic",
+    "conj", // Is this conjunction or conjugation?
+    "contr",
+    "dep",
+    "early",
+    "late",
+    "old",
+    "orth",
+    "exclam",
+    "poetic",
+    "rare",
+    "syncop",
+    "prep",
+    "pers_name",
+    "is_ethnic",
+    "ethnic",
+    "geog_name",
+    "is_group",
+    "group_name",
+    "is_month",
+    "place_name",
+    "town",
+    "syncope",
+    "group",
+    "is_festival",
+    "pname",
+    "poet",
+    "interrog",
+    // This should be a case
+    "locative",
+    "variant",
+    "greek",
+    "disputed",
+    "card",     // cardinal numeral
+    "ord",      // ordinal numeral
+    "distr",    // distributive numeral
+    "advnum",   // adverbial numeral
+    "multipl",  // multiplicative numeral
+    "proport",  // proportional numeral
+    "tempnum",  // temporal numeral
+    "partnum",  // partitive numeral
+    "othernum", // other numeral
+    "enclitic",
+];
+
+/// Tags that are only used by the inflection engine.
+const INTERNAL_TAGS: &[&str] = &[
+    "are_vb",
+    "comp_only",
+    "conj1",
+    "conj2",
+    "conj3",
+    "demonstr",
+    "has_redupl",
+    "indecl",
+    "indef",
+    "ire_vb",
+    "irreg_adj2",
+    "irreg_adj3",
+    "irreg_decl3",
+    "irreg_comp",
+    "irreg_nom2",
+    "irreg_nom3",
+    "irreg_pp1",
+    "irreg_pp2",
+    "irreg_superl",
+    "no_comp",
+    "no_fut",
+    "no_fut_part",
+    "numeral",
+    "perfstem",
+    "pp4",
+    "pron",
+    "pron1",
+    "pron2",
+    "pron3",
+    "relative",
+    "rel_pron",
+];
+
+fn is_semantic_tag(tag: &str) -> bool {
+    if SEMANTIC_TAGS.contains(&tag) {
+        return true;
+    }
+    tag.strip_prefix("arabic")
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Sets a field that can only be given once.
+fn set_single<T>(field: &mut Option<T>, value: T, chunk: &str) -> Result<(), TableBuildError> {
+    if field.is_some() {
+        return Err(format!("Repeated value for `{chunk}`"));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+/// Sets a repeatable field to a group of values, which can't be combined with others.
+fn set_all<T: Copy>(field: &mut Vec<T>, values: &[T], chunk: &str) -> Result<(), TableBuildError> {
+    if !field.is_empty() {
+        return Err(format!("`{chunk}` can't be combined with other values"));
+    }
+    field.extend_from_slice(values);
+    Ok(())
+}
+
+fn parse_chunk(chunk: &str, data: &mut ExpandedInflectionData) -> Result<bool, TableBuildError> {
+    use LatinCase::*;
+    use LatinGender::*;
+
+    match chunk {
+        // Cases
+        "nom" => data.cases.push(Nominative),
+        "acc" => data.cases.push(Accusative),
+        "abl" => data.cases.push(Ablative),
+        "gen" => set_all(&mut data.cases, &[Genitive], chunk)?,
+        "dat" => data.cases.push(Dative),
+        "voc" => data.cases.push(Vocative),
+        "abl/dat" => set_all(&mut data.cases, &[Ablative, Dative], chunk)?,
+        "dat/abl" => set_all(&mut data.cases, &[Dative, Ablative], chunk)?,
+        "nom/voc" => data.cases.extend([Nominative, Vocative]),
+        "nom/acc" => set_all(&mut data.cases, &[Nominative, Accusative], chunk)?,
+        "nom/voc/acc" => set_all(&mut data.cases, &[Nominative, Vocative, Accusative], chunk)?,
+        // Persons
+        "1st" => set_single(&mut data.person, LatinPerson::First, chunk)?,
+        "2nd" => set_single(&mut data.person, LatinPerson::Second, chunk)?,
+        "3rd" => set_single(&mut data.person, LatinPerson::Third, chunk)?,
+        // Numbers
+        "sg" => set_single(&mut data.number, LatinNumber::Singular, chunk)?,
+        "pl" => set_single(&mut data.number, LatinNumber::Plural, chunk)?,
+        // Voices
+        "act" => set_single(&mut data.voice, LatinVoice::Active, chunk)?,
+        "pass" => set_single(&mut data.voice, LatinVoice::Passive, chunk)?,
+        // Genders
+        "masc" => data.genders.push(Masculine),
+        "fem" => data.genders.push(Feminine),
+        "neut" => data.genders.push(Neuter),
+        "masc/neut" => set_all(&mut data.genders, &[Masculine, Neuter], chunk)?,
+        "masc/fem" => set_all(&mut data.genders, &[Masculine, Feminine], chunk)?,
+        "adverbial" => set_all(&mut data.genders, &[Adverbial], chunk)?,
+        "masc/fem/neut" => set_all(&mut data.genders, &[Masculine, Feminine, Neuter], chunk)?,
+        // Degrees
+        "superl" => set_single(&mut data.degree, LatinDegree::Superlative, chunk)?,
+        "comp" => set_single(&mut data.degree, LatinDegree::Comparative, chunk)?,
+        // Tenses
+        "imperf" => set_single(&mut data.tense, LatinTense::Imperfect, chunk)?,
+        "fut" => set_single(&mut data.tense, LatinTense::Future, chunk)?,
+        "pres" => set_single(&mut data.tense, LatinTense::Present, chunk)?,
+        "perf" => set_single(&mut data.tense, LatinTense::Perfect, chunk)?,
+        "plup" => set_single(&mut data.tense, LatinTense::Pluperfect, chunk)?,
+        "futperf" => set_single(&mut data.tense, LatinTense::FuturePerfect, chunk)?,
+        // Moods
+        "ind" | "indic" => set_single(&mut data.mood, LatinMood::Indicative, chunk)?,
+        "subj" => set_single(&mut data.mood, LatinMood::Subjunctive, chunk)?,
+        "part" => set_single(&mut data.mood, LatinMood::Participle, chunk)?,
+        "gerundive" => set_single(&mut data.mood, LatinMood::Gerundive, chunk)?,
+        "imperat" => set_single(&mut data.mood, LatinMood::Imperative, chunk)?,
+        "inf" => set_single(&mut data.mood, LatinMood::Infinitive, chunk)?,
+        "supine" => set_single(&mut data.mood, LatinMood::Supine, chunk)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Parses the grammatical data and tags from the given chunks of a line
+/// in the stem or ending files, like `pres ind act 1st sg` or `masc poetic`.
+pub(super) fn parse_inflection_context(
+    chunks: &[&str],
+) -> Result<InflectionContext, TableBuildError> {
+    let mut data = ExpandedInflectionData::default();
+    let mut tags: Vec<String> = Vec::new();
+    let mut internal_tags: Vec<String> = Vec::new();
+    for chunk in chunks {
+        let result =
+            parse_chunk(chunk, &mut data).map_err(|e| format!("{e} in [{}]", chunks.join(" ")))?;
+        if result {
+            continue;
+        }
+        if is_semantic_tag(chunk) {
+            tags.push(chunk.to_string());
+        } else if INTERNAL_TAGS.contains(chunk) {
+            internal_tags.push(chunk.to_string());
+        } else {
+            return Err(format!("Unexpected: {chunk} from [{}]", chunks.join(" ")));
+        }
+    }
+    Ok(InflectionContext {
+        grammatical_data: pack_inflection_data(&data),
+        tags: (!tags.is_empty()).then_some(tags),
+        internal_tags: (!internal_tags.is_empty()).then_some(internal_tags),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflection_data::expand_inflection_data;
+
+    #[test]
+    fn parses_grammatical_data_and_tags() {
+        let context =
+            parse_inflection_context(&["pres", "ind", "act", "1st", "sg", "poetic", "pp4"])
+                .unwrap();
+        let data = expand_inflection_data(context.grammatical_data);
+        assert_eq!(data.tense, Some(LatinTense::Present));
+        assert_eq!(data.mood, Some(LatinMood::Indicative));
+        assert_eq!(data.voice, Some(LatinVoice::Active));
+        assert_eq!(data.person, Some(LatinPerson::First));
+        assert_eq!(data.number, Some(LatinNumber::Singular));
+        assert_eq!(context.tags, Some(vec!["poetic".to_string()]));
+        assert_eq!(context.internal_tags, Some(vec!["pp4".to_string()]));
+    }
+
+    #[test]
+    fn combines_repeatable_fields() {
+        let context =
+            parse_inflection_context(&["nom/voc", "acc", "masc", "fem", "arabic12"]).unwrap();
+        let data = expand_inflection_data(context.grammatical_data);
+        assert_eq!(
+            data.cases,
+            vec![
+                LatinCase::Nominative,
+                LatinCase::Accusative,
+                LatinCase::Vocative
+            ]
+        );
+        assert_eq!(
+            data.genders,
+            vec![LatinGender::Masculine, LatinGender::Feminine]
+        );
+        assert_eq!(context.tags, Some(vec!["arabic12".to_string()]));
+        assert_eq!(context.internal_tags, None);
+    }
+
+    #[test]
+    fn rejects_conflicting_and_unknown_chunks() {
+        assert!(parse_inflection_context(&["sg", "pl"]).is_err());
+        assert!(parse_inflection_context(&["nom", "gen"]).is_err());
+        assert!(parse_inflection_context(&["blah"]).is_err());
+        assert!(parse_inflection_context(&["arabic"]).is_err());
+    }
+}
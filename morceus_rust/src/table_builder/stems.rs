@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+
+use crate::{
+    indices::{InflectionTableKey, IrregularForm, Stem, StemCode},
+    inflection_data::extract_gender_bits,
+};
+
+use super::{TableBuildError, inflection_parsing::parse_inflection_context};
+
+/// Maps the names of the inflection tables to their indices.
+pub(super) type TableIndices = HashMap<String, InflectionTableKey>;
+
+/// A lemma along with all its stems and irregular forms, before they
+/// have been assigned indices.
+#[derive(Debug)]
+pub(super) struct ParsedLemma {
+    pub(super) lemma: String,
+    pub(super) stems: Vec<Stem>,
+    pub(super) irregular_forms: Vec<IrregularForm>,
+    pub(super) is_verb: bool,
+}
+
+impl ParsedLemma {
+    fn new(lemma: &str, is_verb: bool) -> ParsedLemma {
+        ParsedLemma {
+            lemma: lemma.to_string(),
+            stems: Vec::new(),
+            irregular_forms: Vec::new(),
+            is_verb,
+        }
+    }
+}
+
+/// Parses the stem code from the start of a chunk like `:no:ros`.
+fn parse_stem_code(chunk: &str) -> Option<StemCode> {
+    match chunk.get(0..4)? {
+        ":no:" => Some(StemCode::No),
+        ":aj:" => Some(StemCode::Aj),
+        ":wd:" => Some(StemCode::Wd),
+        ":vs:" => Some(StemCode::Vs),
+        ":vb:" => Some(StemCode::Vb),
+        ":de:" => Some(StemCode::De),
+        _ => None,
+    }
+}
+
+fn table_index(name: &str, tables: &TableIndices) -> Result<InflectionTableKey, TableBuildError> {
+    tables
+        .get(name)
+        .copied()
+        .ok_or_else(|| format!("No index for inflection table {name}"))
+}
+
+fn process_regular_lemma(
+    lines: &[&str],
+    is_verb: bool,
+    tables: &TableIndices,
+) -> Result<ParsedLemma, TableBuildError> {
+    let lemma = lines[0]
+        .strip_prefix(":le:")
+        .ok_or_else(|| format!("Expected a lemma: {}", lines[0]))?
+        .trim_end();
+    if lines.len() < 2 {
+        return Err(format!("No stems for lemma: {lemma}"));
+    }
+    let mut result = ParsedLemma::new(lemma, is_verb);
+    for line in &lines[1..] {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let code = parse_stem_code(parts[0]).ok_or_else(|| format!("Invalid stem code: {line}"))?;
+        if parts.len() < 2 {
+            return Err(format!("Incomplete line for lemma {lemma}: {line}"));
+        }
+        let stem_or_form = parts[0][4..].to_string();
+        if code.is_indeclinable() {
+            result.irregular_forms.push(IrregularForm {
+                code,
+                form: stem_or_form,
+                context: parse_inflection_context(&parts[1..])?,
+            });
+        } else {
+            result.stems.push(Stem {
+                code,
+                stem: stem_or_form,
+                inflection: table_index(parts[1], tables)?,
+                context: parse_inflection_context(&parts[2..])?,
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Parses a file of regular stems, like `nom.01` or `vbs.latin`.
+pub(super) fn parse_regular_stems(
+    contents: &str,
+    is_verb: bool,
+    tables: &TableIndices,
+) -> Result<Vec<ParsedLemma>, TableBuildError> {
+    let mut lemmata = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with(":le:") && !current.is_empty() {
+            lemmata.push(process_regular_lemma(&current, is_verb, tables)?);
+            current.clear();
+        }
+        if line.starts_with(':') {
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        lemmata.push(process_regular_lemma(&current, is_verb, tables)?);
+    }
+    Ok(lemmata)
+}
+
+fn process_irregular_lemma(
+    entry: &[&str],
+    is_verb: bool,
+    tables: &TableIndices,
+) -> Result<ParsedLemma, TableBuildError> {
+    let lemma = entry[0]
+        .strip_prefix(":le:")
+        .ok_or_else(|| format!("Expected a lemma: {}", entry[0]))?;
+    let mut result = ParsedLemma::new(lemma, is_verb);
+    for line in &entry[1..] {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+
+        // If we have something that should be expanded by templates - e.g:
+        // `discord@decl3_i	irreg_adj3 masc fem neut`
+        if let Some((stem, template)) = parts[0].split_once('@') {
+            if stem.starts_with(':') || template.contains('@') {
+                return Err(format!("Invalid template invocation: {line}"));
+            }
+            result.stems.push(Stem {
+                code: StemCode::None,
+                stem: stem.to_string(),
+                inflection: table_index(template, tables)?,
+                context: parse_inflection_context(&parts[1..])?,
+            });
+            continue;
+        }
+
+        // Analysis depends on the stem code. See `StemCode` for the
+        // requirements of each stem code.
+        let code = match parse_stem_code(parts[0]) {
+            Some(code) if !code.is_indeclinable() => code,
+            code => {
+                let form = if code.is_some() {
+                    &parts[0][4..]
+                } else {
+                    parts[0]
+                };
+                result.irregular_forms.push(IrregularForm {
+                    code: code.unwrap_or(StemCode::None),
+                    form: form.replace('-', ""),
+                    context: parse_inflection_context(&parts[1..])?,
+                });
+                continue;
+            }
+        };
+
+        // Nouns / Adjectives / Verb stems must have an inflectional class.
+        let the_rest = &parts[1..];
+        let template = the_rest
+            .iter()
+            .find(|chunk| tables.contains_key(**chunk))
+            .ok_or_else(|| format!("No inflection class: {line}"))?;
+        let grammatical_data: Vec<&str> = the_rest
+            .iter()
+            .filter(|chunk| *chunk != template)
+            .copied()
+            .collect();
+        if grammatical_data.len() != the_rest.len() - 1 {
+            return Err(format!("Repeated inflection class: {line}"));
+        }
+        let context = parse_inflection_context(&grammatical_data)?;
+        // Nouns must have a gender.
+        if matches!(code, StemCode::No) && extract_gender_bits(context.grammatical_data) == 0 {
+            return Err(format!("No gender for noun: {line}"));
+        }
+        result.stems.push(Stem {
+            code,
+            stem: parts[0][4..].replace('-', ""),
+            inflection: table_index(template, tables)?,
+            context,
+        });
+    }
+    Ok(result)
+}
+
+/// Parses a file of irregular lemmata, like `irreg.nom.src`.
+pub(super) fn parse_irregular_stems(
+    contents: &str,
+    is_verb: bool,
+    tables: &TableIndices,
+) -> Result<Vec<ParsedLemma>, TableBuildError> {
+    let mut entries: Vec<Vec<&str>> = Vec::new();
+    let lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for line in lines {
+        match entries.last_mut() {
+            Some(entry) if !line.starts_with(":le:") => entry.push(line),
+            _ => entries.push(vec![line]),
+        }
+    }
+    entries
+        .iter()
+        .map(|entry| process_irregular_lemma(entry, is_verb, tables))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflection_data::{
+        ExpandedInflectionData, LatinCase, LatinGender, LatinMood, LatinNumber, LatinPerson,
+        LatinTense, LatinVoice, expand_inflection_data,
+    };
+
+    fn tables() -> TableIndices {
+        HashMap::from([
+            ("a_ae".to_string(), 0),
+            ("decl3".to_string(), 1),
+            ("conj1".to_string(), 2),
+        ])
+    }
+
+    #[test]
+    fn parses_regular_stems() {
+        let contents = "\
+:le:rosa
+:no:ros a_ae fem
+:wd:rosatim adverb
+
+# Some comment
+:le:amo
+:vs:am conj1
+";
+        let lemmata = parse_regular_stems(contents, true, &tables()).unwrap();
+        assert_eq!(lemmata.len(), 2);
+        assert_eq!(lemmata[0].lemma, "rosa");
+        assert!(lemmata[0].is_verb);
+        assert_eq!(lemmata[0].stems[0].stem, "ros");
+        assert_eq!(lemmata[0].stems[0].inflection, 0);
+        assert!(matches!(lemmata[0].stems[0].code, StemCode::No));
+        assert_eq!(lemmata[0].irregular_forms[0].form, "rosatim");
+        assert_eq!(
+            lemmata[0].irregular_forms[0].context.tags,
+            Some(vec!["adverb".to_string()])
+        );
+        assert_eq!(lemmata[1].stems[0].inflection, 2);
+    }
+
+    #[test]
+    fn rejects_unknown_tables() {
+        assert!(parse_regular_stems(":le:rosa\n:no:ros blah fem\n", false, &tables()).is_err());
+        assert!(parse_regular_stems(":le:rosa\n", false, &tables()).is_err());
+    }
+
+    #[test]
+    fn parses_irregular_stems() {
+        let contents = "\
+# Irregulars
+:le:par
+pa^r@decl3\tirreg_adj3 masc fem neut
+pa_r\t\tirreg_adj3 neut acc sg
+:le:vis
+:no:vi_-r decl3 fem pl
+:vb:vi-s nom sg
+";
+        let lemmata = parse_irregular_stems(contents, false, &tables()).unwrap();
+        assert_eq!(lemmata.len(), 2);
+        assert!(matches!(lemmata[0].stems[0].code, StemCode::None));
+        assert_eq!(lemmata[0].stems[0].stem, "pa^r");
+        assert_eq!(lemmata[0].stems[0].inflection, 1);
+        assert!(matches!(lemmata[0].irregular_forms[0].code, StemCode::None));
+        assert_eq!(lemmata[0].irregular_forms[0].form, "pa_r");
+        assert_eq!(lemmata[1].stems[0].stem, "vi_r");
+        assert_eq!(lemmata[1].stems[0].inflection, 1);
+        assert_eq!(lemmata[1].irregular_forms[0].form, "vis");
+        assert!(matches!(lemmata[1].irregular_forms[0].code, StemCode::Vb));
+    }
+
+    #[test]
+    fn requires_genders_for_irregular_nouns() {
+        let contents = ":le:vis\n:no:vi_r decl3 pl\n";
+        assert!(parse_irregular_stems(contents, false, &tables()).is_err());
+    }
+
+    /// Reads one of the stem files used by the Javascript tests in `stem_parsing.test.ts`.
+    fn js_test_file(path: &str) -> String {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        std::fs::read_to_string(root.join(path)).unwrap()
+    }
+
+    /// Assigns an index to every table that the stems in the contents could use.
+    fn tables_used_in(contents: &str) -> TableIndices {
+        let mut tables = TableIndices::new();
+        for line in contents.lines().filter(|line| line.starts_with(':')) {
+            if let Some(name) = line.split_whitespace().nth(1) {
+                let next = tables.len() as InflectionTableKey;
+                tables.entry(name.to_string()).or_insert(next);
+            }
+        }
+        tables
+    }
+
+    type StemSummary = (
+        String,
+        String,
+        String,
+        ExpandedInflectionData,
+        Option<Vec<String>>,
+    );
+
+    fn summarize(lemma: &ParsedLemma, tables: &TableIndices) -> Vec<StemSummary> {
+        lemma
+            .stems
+            .iter()
+            .map(|stem| {
+                let (table, _) = tables.iter().find(|(_, i)| **i == stem.inflection).unwrap();
+                (
+                    format!("{:?}", stem.code).to_lowercase(),
+                    stem.stem.clone(),
+                    table.clone(),
+                    expand_inflection_data(stem.context.grammatical_data),
+                    stem.context.internal_tags.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn summary(code: &str, stem: &str, table: &str, internal_tag: Option<&str>) -> StemSummary {
+        (
+            code.to_string(),
+            stem.to_string(),
+            table.to_string(),
+            ExpandedInflectionData::default(),
+            internal_tag.map(|tag| vec![tag.to_string()]),
+        )
+    }
+
+    #[test]
+    fn parses_javascript_noun_fixture_like_javascript() {
+        let contents = js_test_file("src/morceus/stems/nom.01");
+        let tables = tables_used_in(&contents);
+        let lemmata = parse_regular_stems(&contents, false, &tables).unwrap();
+        assert_eq!(lemmata.len(), 31);
+        let find = |name: &str| {
+            let lemma = lemmata.iter().find(|l| l.lemma == name).unwrap();
+            assert!(lemma.irregular_forms.is_empty());
+            summarize(lemma, &tables)
+        };
+
+        assert_eq!(
+            find("nullus"),
+            vec![summary("aj", "nu_ll", "us_ius_adj", None)]
+        );
+        assert_eq!(
+            find("Judaicus"),
+            vec![summary("aj", "Ju_da^i+c", "us_a_um", None)]
+        );
+        assert_eq!(
+            find("bonus"),
+            vec![
+                summary("aj", "bon", "us_a_um", Some("no_comp")),
+                summary("aj", "mel", "ior_ius_comp", None),
+                summary("aj", "optim", "us_a_um", Some("irreg_superl")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_javascript_verb_fixture_like_javascript() {
+        let contents = js_test_file("src/morceus/testdata/latin/stems/verbs/vbs.latin");
+        let tables = tables_used_in(&contents);
+        let lemmata = parse_regular_stems(&contents, true, &tables).unwrap();
+        assert_eq!(lemmata.len(), 6);
+        assert!(lemmata.iter().all(|lemma| lemma.is_verb));
+
+        let acclamo = lemmata.iter().find(|l| l.lemma == "acclamo").unwrap();
+        assert_eq!(
+            summarize(acclamo, &tables),
+            vec![summary("de", "ac-cla_m", "are_vb", None)]
+        );
+        assert!(acclamo.irregular_forms.is_empty());
+
+        let caveo = lemmata.iter().find(|l| l.lemma == "caveo").unwrap();
+        assert_eq!(
+            summarize(caveo, &tables),
+            vec![summary("vs", "ca^v", "conj2", None)]
+        );
+        let irregular = &caveo.irregular_forms;
+        assert_eq!(irregular.len(), 1);
+        assert!(matches!(irregular[0].code, StemCode::Vb));
+        assert_eq!(irregular[0].form, "ca^ve^");
+        assert_eq!(
+            expand_inflection_data(irregular[0].context.grammatical_data),
+            ExpandedInflectionData {
+                person: Some(LatinPerson::Second),
+                number: Some(LatinNumber::Singular),
+                tense: Some(LatinTense::Present),
+                mood: Some(LatinMood::Imperative),
+                voice: Some(LatinVoice::Active),
+                ..ExpandedInflectionData::default()
+            }
+        );
+        assert_eq!(
+            irregular[0].context.internal_tags,
+            Some(vec!["irreg_pp1".to_string()])
+        );
+    }
+
+    #[test]
+    fn parses_javascript_irregular_fixtures_like_javascript() {
+        // The `DO` and `CERES` entries from `irregular_stems.test.ts`.
+        let contents = "\
+:le:do
+:vb:da^ri_\tirreg_pp1 pres inf pass
+dui@basvb2\tirreg_pp1 pres subj act early
+:vs:de^d perfstem no_comp
+:le:Ceres
+Cere_s\t\tirreg_nom3 fem nom sg
+Cere^r@decl3\tirreg_nom3 fem sg
+";
+        let tables = TableIndices::from([
+            ("basvb2".to_string(), 0),
+            ("perfstem".to_string(), 1),
+            ("decl3".to_string(), 2),
+        ]);
+        let lemmata = parse_irregular_stems(contents, true, &tables).unwrap();
+        assert_eq!(lemmata.len(), 2);
+
+        let mut dui = summary("none", "dui", "basvb2", Some("irreg_pp1"));
+        dui.3 = ExpandedInflectionData {
+            tense: Some(LatinTense::Present),
+            mood: Some(LatinMood::Subjunctive),
+            voice: Some(LatinVoice::Active),
+            ..ExpandedInflectionData::default()
+        };
+        assert_eq!(
+            summarize(&lemmata[0], &tables),
+            vec![dui, summary("vs", "de^d", "perfstem", Some("no_comp"))]
+        );
+        assert_eq!(
+            lemmata[0].stems[0].context.tags,
+            Some(vec!["early".to_string()])
+        );
+        let dari = &lemmata[0].irregular_forms;
+        assert_eq!(dari.len(), 1);
+        assert!(matches!(dari[0].code, StemCode::Vb));
+        assert_eq!(dari[0].form, "da^ri_");
+        assert_eq!(
+            expand_inflection_data(dari[0].context.grammatical_data),
+            ExpandedInflectionData {
+                tense: Some(LatinTense::Present),
+                mood: Some(LatinMood::Infinitive),
+                voice: Some(LatinVoice::Passive),
+                ..ExpandedInflectionData::default()
+            }
+        );
+
+        let mut cerer = summary("none", "Cere^r", "decl3", Some("irreg_nom3"));
+        cerer.3 = ExpandedInflectionData {
+            genders: vec![LatinGender::Feminine],
+            number: Some(LatinNumber::Singular),
+            ..ExpandedInflectionData::default()
+        };
+        assert_eq!(summarize(&lemmata[1], &tables), vec![cerer]);
+        let ceres = &lemmata[1].irregular_forms;
+        assert_eq!(ceres.len(), 1);
+        assert!(matches!(ceres[0].code, StemCode::None));
+        assert_eq!(ceres[0].form, "Cere_s");
+        assert_eq!(
+            expand_inflection_data(ceres[0].context.grammatical_data),
+            ExpandedInflectionData {
+                cases: vec![LatinCase::Nominative],
+                genders: vec![LatinGender::Feminine],
+                number: Some(LatinNumber::Singular),
+                ..ExpandedInflectionData::default()
+            }
+        );
+        assert_eq!(
+            ceres[0].context.internal_tags,
+            Some(vec!["irreg_nom3".to_string()])
+        );
+    }
+}
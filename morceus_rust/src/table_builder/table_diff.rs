@@ -0,0 +1,153 @@
+//! Compares built tables against the tables generated by the Javascript code.
+
+use serde_json::Value;
+
+use crate::indices::CruncherTables;
+
+/// The maximum number of differences reported by `diff_tables`.
+const MAX_DIFFS: usize = 100;
+
+fn describe(value: &Value) -> String {
+    let described = value.to_string();
+    match described.char_indices().nth(80) {
+        Some((end, _)) => format!("{}...", &described[..end]),
+        None => described,
+    }
+}
+
+fn diff_values(path: &str, expected: &Value, actual: &Value, diffs: &mut Vec<String>) {
+    if diffs.len() >= MAX_DIFFS || expected == actual {
+        return;
+    }
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let mut keys: Vec<&String> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = format!("{path}.{key}");
+                match (expected.get(key), actual.get(key)) {
+                    (Some(e), Some(a)) => diff_values(&child, e, a, diffs),
+                    (Some(e), None) => diffs.push(format!("{child}: missing {}", describe(e))),
+                    (None, Some(a)) => diffs.push(format!("{child}: unexpected {}", describe(a))),
+                    (None, None) => {}
+                }
+                if diffs.len() >= MAX_DIFFS {
+                    return;
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            if expected.len() != actual.len() {
+                diffs.push(format!(
+                    "{path}: expected {} items, got {}",
+                    expected.len(),
+                    actual.len()
+                ));
+            }
+            for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+                diff_values(&format!("{path}[{i}]"), e, a, diffs);
+            }
+        }
+        _ => diffs.push(format!(
+            "{path}: expected {}, got {}",
+            describe(expected),
+            describe(actual)
+        )),
+    }
+}
+
+/// Compares the built tables against the expected ones (usually the tables generated
+/// by the Javascript code), and returns a description of the first differences found.
+/// If the tables are identical, the result is empty.
+pub fn diff_tables(
+    expected: &CruncherTables,
+    actual: &CruncherTables,
+) -> Result<Vec<String>, String> {
+    let expected = serde_json::to_value(expected).map_err(|e| e.to_string())?;
+    let actual = serde_json::to_value(actual).map_err(|e| e.to_string())?;
+    let mut diffs = Vec::new();
+    diff_values("tables", &expected, &actual, &mut diffs);
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table_builder::{assemble_tables, make_table_indices, stems, templates};
+    use serde_json::json;
+
+    fn build(stems: &str) -> CruncherTables {
+        let parsed = [
+            templates::parse_template("a_ae", "a nom sg\nae gen sg\n").unwrap(),
+            templates::parse_template("us_i", "us nom sg\ni_ gen sg\n").unwrap(),
+        ];
+        let tables = templates::expand_templates(&parsed).unwrap();
+        let indices = make_table_indices(&tables).unwrap();
+        let lemmata = stems::parse_regular_stems(stems, false, &indices).unwrap();
+        assemble_tables(tables, lemmata).unwrap()
+    }
+
+    fn diffs_between(expected: Value, actual: Value) -> Vec<String> {
+        let mut diffs = Vec::new();
+        diff_values("tables", &expected, &actual, &mut diffs);
+        diffs
+    }
+
+    #[test]
+    fn identical_tables_have_no_diffs() {
+        let tables = build(":le:rosa\n:no:ro^s a_ae fem\n");
+        assert_eq!(
+            diff_tables(&tables, &tables.clone()).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn reports_changed_stems_and_lemmata() {
+        let expected = build(":le:rosa\n:no:ro^s a_ae fem\n");
+        let actual = build(":le:Marcus\n:no:Marc us_i masc\n");
+        let diffs = diff_tables(&expected, &actual).unwrap();
+        assert!(
+            diffs.contains(&"tables.allStems[0].stem: expected \"ro^s\", got \"Marc\"".to_string())
+        );
+        assert!(diffs.contains(
+            &"tables.rawLemmata[0].lemma: expected \"rosa\", got \"Marcus\"".to_string()
+        ));
+    }
+
+    #[test]
+    fn reports_missing_and_unexpected_keys() {
+        let diffs = diffs_between(json!({"a": 1, "b": [1]}), json!({"b": [1], "c": "x"}));
+        assert_eq!(
+            diffs,
+            vec!["tables.a: missing 1", "tables.c: unexpected \"x\""]
+        );
+    }
+
+    #[test]
+    fn reports_array_lengths_and_items() {
+        let diffs = diffs_between(json!({"a": [1, 2, 3]}), json!({"a": [1, 5]}));
+        assert_eq!(
+            diffs,
+            vec![
+                "tables.a: expected 3 items, got 2",
+                "tables.a[1]: expected 2, got 5"
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_long_values_and_limits_diffs() {
+        let long = "x".repeat(100);
+        let diffs = diffs_between(json!(long), json!(1));
+        assert_eq!(
+            diffs,
+            vec![format!("tables: expected \"{}..., got 1", "x".repeat(79))]
+        );
+
+        let expected = Value::Array((0..200).map(|i| json!(i)).collect());
+        let actual = Value::Array((0..200).map(|i| json!(-i - 1)).collect());
+        assert_eq!(diffs_between(expected, actual).len(), MAX_DIFFS);
+    }
+}
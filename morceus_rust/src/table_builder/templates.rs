@@ -0,0 +1,421 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    indices::{InflectionContext, InflectionEnding, InflectionTable},
+    inflection_data::{
+        DEGREE_SHIFT, TWO_BITS, WordInflectionData, extract_degree, merge_inflection_data,
+    },
+};
+
+use super::{TableBuildError, inflection_parsing::parse_inflection_context};
+
+/// An invocation of another template, like `a_@imperf imperf ind act`.
+#[derive(Debug)]
+struct TemplateDependency {
+    name: String,
+    /// The prefix to add to each of the endings of the other template.
+    prefix: String,
+    context: InflectionContext,
+}
+
+/// An inflection template contains inflectional endings and grammatical data,
+/// and may invoke other templates to compute endings for some inflections.
+#[derive(Debug)]
+pub(super) struct InflectionTemplate {
+    name: String,
+    endings: Vec<InflectionEnding>,
+    templates: Vec<TemplateDependency>,
+}
+
+/// Returns all the files in the given paths, in the same order as the Javascript
+/// implementation (which determines the order of the inflection tables).
+fn files_in_paths(input_paths: &[PathBuf]) -> Result<Vec<PathBuf>, TableBuildError> {
+    let mut queue = input_paths.to_vec();
+    let mut files = Vec::new();
+    while let Some(current) = queue.pop() {
+        if current.is_file() {
+            files.push(current);
+            continue;
+        }
+        let mut entries = fs::read_dir(&current)
+            .and_then(|dir| dir.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Error reading {}: {e}", current.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if path.is_file() {
+                files.push(path);
+            } else if path.is_dir() {
+                queue.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Parses a single template, where `name` is the file name (without the extension).
+pub(super) fn parse_template(
+    name: &str,
+    contents: &str,
+) -> Result<InflectionTemplate, TableBuildError> {
+    let mut endings = Vec::new();
+    let mut templates = Vec::new();
+    let lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for line in lines {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let first = parts[0];
+
+        // This means it's invoking another template
+        if let Some((prefix, dependency)) = first.split_once('@') {
+            if dependency.contains('@') {
+                return Err(format!("Invalid template invocation in {name}: {line}"));
+            }
+            templates.push(TemplateDependency {
+                name: dependency.to_string(),
+                prefix: if prefix == "*" { "" } else { prefix }.to_string(),
+                context: parse_inflection_context(&parts[1..])?,
+            });
+            continue;
+        }
+
+        let context = parse_inflection_context(&parts[1..])?;
+        if context.grammatical_data == 0 {
+            return Err(format!("No grammatical data in {name}: {line}"));
+        }
+        endings.push(InflectionEnding {
+            ending: first.to_string(),
+            context,
+        });
+    }
+    if endings.is_empty() && templates.is_empty() {
+        return Err(format!("Template {name} is empty!"));
+    }
+    Ok(InflectionTemplate {
+        name: name.to_string(),
+        endings,
+        templates,
+    })
+}
+
+/// Loads all the templates in the given directories.
+pub(super) fn load_templates(
+    template_dirs: &[PathBuf],
+) -> Result<Vec<InflectionTemplate>, TableBuildError> {
+    let mut templates = Vec::new();
+    let mut names = HashSet::new();
+    for path in files_in_paths(template_dirs)? {
+        let name = template_name(&path)?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+        let template = parse_template(&name, &contents)?;
+        if !names.insert(name) {
+            return Err(format!(
+                "Template {} has already been loaded!",
+                template.name
+            ));
+        }
+        templates.push(template);
+    }
+    Ok(templates)
+}
+
+fn template_name(path: &Path) -> Result<String, TableBuildError> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| format!("Invalid template path: {}", path.display()))
+}
+
+/// Merges the data from an ending with the data from the context that invokes it.
+///
+/// Unlike `merge_inflection_data`, conflicting degrees drop the degree entirely
+/// rather than rejecting the ending, which matches the original table generation.
+fn merge_template_data(
+    ending: WordInflectionData,
+    context: WordInflectionData,
+) -> Option<WordInflectionData> {
+    let degree = match (extract_degree(ending), extract_degree(context)) {
+        (0, degree) | (degree, 0) => degree,
+        (first, second) if first == second => first,
+        _ => 0,
+    };
+    let degree_mask = TWO_BITS << DEGREE_SHIFT;
+    let merged = merge_inflection_data(ending & !degree_mask, context & !degree_mask)?;
+    Some(merged | (degree << DEGREE_SHIFT))
+}
+
+fn merge_tags(first: &Option<Vec<String>>, second: &Option<Vec<String>>) -> Option<Vec<String>> {
+    let mut merged: Vec<String> = Vec::new();
+    for tag in first.iter().chain(second.iter()).flatten() {
+        if !merged.contains(tag) {
+            merged.push(tag.clone());
+        }
+    }
+    (!merged.is_empty()).then_some(merged)
+}
+
+fn expand_single_ending(
+    prefix: &str,
+    context: &InflectionContext,
+    ending: &InflectionEnding,
+) -> Option<InflectionEnding> {
+    let grammatical_data =
+        merge_template_data(ending.context.grammatical_data, context.grammatical_data)?;
+    Some(InflectionEnding {
+        ending: format!("{prefix}{}", ending.ending),
+        context: InflectionContext {
+            grammatical_data,
+            tags: merge_tags(&ending.context.tags, &context.tags),
+            internal_tags: merge_tags(&ending.context.internal_tags, &context.internal_tags),
+        },
+    })
+}
+
+fn expand_template(
+    template: &InflectionTemplate,
+    expanded: &HashMap<String, InflectionTable>,
+) -> Result<InflectionTable, TableBuildError> {
+    let mut endings = template.endings.clone();
+    for dependency in &template.templates {
+        // In `ta_t@decl3_i	gen pl`, this would be the expanded @decl3_i table.
+        let table = expanded
+            .get(&dependency.name)
+            .ok_or_else(|| format!("No expanded template {} in registry!", dependency.name))?;
+        endings.extend(
+            table
+                .endings
+                .iter()
+                .filter_map(|e| expand_single_ending(&dependency.prefix, &dependency.context, e)),
+        );
+    }
+    if endings.is_empty() {
+        return Err(format!("Empty endings for {}", template.name));
+    }
+    Ok(InflectionTable {
+        name: template.name.clone(),
+        endings,
+    })
+}
+
+/// Expands the templates into full inflection tables. Tables are returned in the order
+/// that they were expanded, which is the order used for the inflection table indices.
+pub(super) fn expand_templates(
+    templates: &[InflectionTemplate],
+) -> Result<Vec<InflectionTable>, TableBuildError> {
+    let mut expanded: HashMap<String, InflectionTable> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+    let mut pending: Vec<&InflectionTemplate> = templates.iter().collect();
+    while !pending.is_empty() {
+        // Find anything where all the dependencies have been expanded.
+        let (expandable, blocked): (Vec<_>, Vec<_>) = pending.into_iter().partition(|t| {
+            t.templates
+                .iter()
+                .all(|dep| expanded.contains_key(&dep.name))
+        });
+        // If there's nothing to expand, we would have an infinite loop.
+        if expandable.is_empty() {
+            let names: Vec<&str> = blocked.iter().map(|t| t.name.as_str()).collect();
+            return Err(format!("Unable to expand templates: {}", names.join(", ")));
+        }
+        for template in expandable {
+            let table = expand_template(template, &expanded)?;
+            order.push(table.name.clone());
+            expanded.insert(table.name.clone(), table);
+        }
+        pending = blocked;
+    }
+    Ok(order
+        .into_iter()
+        .filter_map(|name| expanded.remove(&name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflection_data::{
+        ExpandedInflectionData, LatinCase, LatinMood, LatinNumber, LatinTense, LatinVoice,
+        expand_inflection_data,
+    };
+
+    /// The template fixtures of the Javascript tests in `templates.test.ts`.
+    fn js_testdata(subdir: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../src/morceus/tables/lat/core/testdata")
+            .join(subdir)
+    }
+
+    fn data(cases: &[LatinCase], number: LatinNumber) -> ExpandedInflectionData {
+        ExpandedInflectionData {
+            cases: cases.to_vec(),
+            number: Some(number),
+            ..ExpandedInflectionData::default()
+        }
+    }
+
+    #[test]
+    fn parses_endings_and_dependencies() {
+        let template = parse_template(
+            "conj1",
+            "@decl1\n# Comment\no_\tpres ind act 1st sg\n\na_@imperf imperf ind act\n*@future fut\n",
+        )
+        .unwrap();
+        assert_eq!(template.endings.len(), 1);
+        assert_eq!(template.endings[0].ending, "o_");
+        let dependencies: Vec<(&str, &str)> = template
+            .templates
+            .iter()
+            .map(|t| (t.name.as_str(), t.prefix.as_str()))
+            .collect();
+        assert_eq!(
+            dependencies,
+            vec![("decl1", ""), ("imperf", "a_"), ("future", "")]
+        );
+    }
+
+    #[test]
+    fn rejects_endings_without_data() {
+        assert!(parse_template("bad", "o_ poetic\n").is_err());
+        assert!(parse_template("empty", "# Nothing here\n").is_err());
+    }
+
+    #[test]
+    fn expands_templates_in_dependency_order() {
+        let templates = vec![
+            parse_template(
+                "conj",
+                "o_ pres ind act 1st sg\na_@imperf ind\na_@imperf subj\n",
+            )
+            .unwrap(),
+            parse_template(
+                "imperf",
+                "bam imperf ind act 1st sg\nbar imperf ind pass 1st sg\n",
+            )
+            .unwrap(),
+        ];
+        let tables = expand_templates(&templates).unwrap();
+        let names: Vec<&str> = tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["imperf", "conj"]);
+
+        let endings: Vec<&str> = tables[1]
+            .endings
+            .iter()
+            .map(|e| e.ending.as_str())
+            .collect();
+        // The subjunctive invocation is incompatible with all the endings.
+        assert_eq!(endings, vec!["o_", "a_bam", "a_bar"]);
+        let data = expand_inflection_data(tables[1].endings[1].context.grammatical_data);
+        assert_eq!(data.tense, Some(LatinTense::Imperfect));
+        assert_eq!(data.mood, Some(LatinMood::Indicative));
+        assert_eq!(data.number, Some(LatinNumber::Singular));
+    }
+
+    #[test]
+    fn drops_conflicting_degrees() {
+        let templates = vec![
+            parse_template("adj", "@base comp\n").unwrap(),
+            parse_template("base", "us superl nom sg\n").unwrap(),
+        ];
+        let tables = expand_templates(&templates).unwrap();
+        let data = expand_inflection_data(tables[1].endings[0].context.grammatical_data);
+        assert_eq!(data.degree, None);
+    }
+
+    #[test]
+    fn rejects_missing_dependencies() {
+        let templates = vec![parse_template("conj", "a_@missing ind\n").unwrap()];
+        assert!(expand_templates(&templates).is_err());
+    }
+
+    #[test]
+    fn loads_javascript_fixtures_like_javascript() {
+        let templates = load_templates(&[js_testdata("testTemplates")]).unwrap();
+        assert_eq!(templates.len(), 1);
+        let ambsub = &templates[0];
+        assert_eq!(ambsub.name, "ambsub");
+
+        let future = ExpandedInflectionData {
+            tense: Some(LatinTense::Future),
+            mood: Some(LatinMood::Indicative),
+            voice: Some(LatinVoice::Active),
+            ..ExpandedInflectionData::default()
+        };
+        let endings: Vec<(&str, ExpandedInflectionData)> = ambsub
+            .endings
+            .iter()
+            .map(|e| {
+                (
+                    e.ending.as_str(),
+                    expand_inflection_data(e.context.grammatical_data),
+                )
+            })
+            .collect();
+        assert_eq!(endings, vec![("am", future.clone()), ("a_s", future)]);
+
+        assert_eq!(ambsub.templates.len(), 1);
+        let dependency = &ambsub.templates[0];
+        assert_eq!(
+            (dependency.name.as_str(), dependency.prefix.as_str()),
+            ("amsub", "foo")
+        );
+        assert_eq!(
+            expand_inflection_data(dependency.context.grammatical_data),
+            ExpandedInflectionData {
+                tense: Some(LatinTense::Future),
+                mood: Some(LatinMood::Indicative),
+                ..ExpandedInflectionData::default()
+            }
+        );
+    }
+
+    #[test]
+    fn expands_javascript_fixtures_like_javascript() {
+        let templates = load_templates(&[
+            js_testdata("targetTemplates"),
+            js_testdata("dependencyTemplates"),
+        ])
+        .unwrap();
+        let tables = expand_templates(&templates).unwrap();
+        let names: Vec<&str> = tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["decl1", "a_ae"]);
+
+        let endings: Vec<(&str, ExpandedInflectionData, Option<Vec<String>>)> = tables[1]
+            .endings
+            .iter()
+            .map(|e| {
+                let data = expand_inflection_data(e.context.grammatical_data);
+                (e.ending.as_str(), data, e.context.tags.clone())
+            })
+            .collect();
+        use LatinCase::{Genitive, Nominative, Vocative};
+        assert_eq!(
+            endings,
+            vec![
+                (
+                    "a",
+                    data(&[Nominative, Vocative], LatinNumber::Singular),
+                    None
+                ),
+                ("ae", data(&[Genitive], LatinNumber::Singular), None),
+                (
+                    "ae",
+                    data(&[Nominative, Vocative], LatinNumber::Plural),
+                    None
+                ),
+                ("a_rum", data(&[Genitive], LatinNumber::Plural), None),
+                (
+                    "a_i_",
+                    data(&[Genitive], LatinNumber::Singular),
+                    Some(vec!["poetic".to_string()])
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+/// The quantity of a vowel, as marked in the input or in the tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Quantity {
+    Unmarked,
+    Long,
+    Short,
+    /// Marked as both long and short, which is compatible with either.
+    Ambiguous,
+}
+
+impl Quantity {
+    fn mark(self, other: Quantity) -> Quantity {
+        match (self, other) {
+            (Quantity::Unmarked, _) => other,
+            (current, other) if current == other => current,
+            _ => Quantity::Ambiguous,
+        }
+    }
+}
+
+const MACRON_COMBINER: char = '\u{0304}';
+const BREVE_COMBINER: char = '\u{0306}';
+/// Combining diaeresis, acute, grave, and circumflex, which don't affect the quantity.
+const IGNORED_COMBINERS: [char; 4] = ['\u{0308}', '\u{0301}', '\u{0300}', '\u{0302}'];
+
+/// For each vowel: the precomposed forms marked long, marked short, and with other
+/// diacritics (like diaereses and accents) that don't affect the quantity.
+const MARKED_VOWELS: [(char, &str, &str, &str); 12] = [
+    ('a', "ā", "ă", "äáàâ"),
+    ('e', "ē", "ĕ", "ëéèê"),
+    ('i', "ī", "ĭ", "ïíìî"),
+    ('o', "ō", "ŏ", "öóòô"),
+    ('u', "ū", "ŭ", "üúùû"),
+    ('y', "ȳ", "", "ÿýỳŷ"),
+    ('A', "Ā", "Ă", "ÄÁÀÂ"),
+    ('E', "Ē", "Ĕ", "ËÉÈÊ"),
+    ('I', "Ī", "Ĭ", "ÏÍÌÎ"),
+    ('O', "Ō", "Ŏ", "ÖÓÒÔ"),
+    ('U', "Ū", "Ŭ", "ÜÚÙÛ"),
+    ('Y', "Ȳ", "", "ŸÝỲŶ"),
+];
+
+/// Splits a (possibly precomposed) character into its base letter and marked quantity.
+fn split_char(c: char) -> Option<(char, Quantity)> {
+    if c.is_ascii_alphabetic() {
+        return Some((c, Quantity::Unmarked));
+    }
+    for (base, long, short, other) in MARKED_VOWELS {
+        if long.contains(c) {
+            return Some((base, Quantity::Long));
+        }
+        if short.contains(c) {
+            return Some((base, Quantity::Short));
+        }
+        if other.contains(c) {
+            return Some((base, Quantity::Unmarked));
+        }
+    }
+    None
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(
+        c,
+        'a' | 'e' | 'i' | 'o' | 'u' | 'y' | 'A' | 'E' | 'I' | 'O' | 'U' | 'Y'
+    )
+}
+
+/// A word with its diacritics removed, along with the quantity marked on each character.
+#[derive(Debug, PartialEq)]
+pub(crate) struct NormalizedWord {
+    pub(crate) text: String,
+    pub(crate) quantities: Vec<Quantity>,
+}
+
+/// Removes precomposed and combining diacritics from the word, keeping track of
+/// the vowel lengths marked by macrons and breves.
+///
+/// @returns `None` if the word has characters besides (possibly marked) Latin letters.
+pub(crate) fn normalize_word(word: &str) -> Option<NormalizedWord> {
+    let mut text = String::with_capacity(word.len());
+    let mut quantities: Vec<Quantity> = Vec::new();
+    for c in word.chars() {
+        let combined = match c {
+            MACRON_COMBINER => Quantity::Long,
+            BREVE_COMBINER => Quantity::Short,
+            c if IGNORED_COMBINERS.contains(&c) => continue,
+            c => {
+                let (base, quantity) = split_char(c)?;
+                text.push(base);
+                quantities.push(quantity);
+                continue;
+            }
+        };
+        // Combiners that aren't on a vowel are just skipped.
+        if let (Some(last), Some(quantity)) = (text.chars().last(), quantities.last_mut())
+            && is_vowel(last)
+        {
+            *quantity = quantity.mark(combined);
+        }
+    }
+    Some(NormalizedWord { text, quantities })
+}
+
+/// Returns the quantity of each character of a form from the tables, where vowel
+/// lengths are marked by a following `_` (long) or `^` (short).
+fn table_quantities(form: &str) -> Vec<Quantity> {
+    let mut quantities: Vec<Quantity> = Vec::new();
+    for c in form.chars() {
+        let marked = match c {
+            '_' => Quantity::Long,
+            '^' => Quantity::Short,
+            // Morpheus uses these for diaereses and morpheme boundaries.
+            '+' | '-' => continue,
+            c => {
+                quantities.push(split_char(c).map_or(Quantity::Unmarked, |(_, q)| q));
+                continue;
+            }
+        };
+        if let Some(last) = quantities.last_mut() {
+            *last = last.mark(marked);
+        }
+    }
+    quantities
+}
+
+/// Returns whether the vowel lengths marked in the input are compatible with
+/// those in the form from the tables. Unmarked vowels are compatible with anything,
+/// and any characters of the input past the end of the form (like an enclitic) are
+/// ignored.
+pub(crate) fn has_compatible_lengths(input: &[Quantity], form: &str) -> bool {
+    input.iter().zip(table_quantities(form)).all(|pair| {
+        !matches!(
+            pair,
+            (Quantity::Long, Quantity::Short) | (Quantity::Short, Quantity::Long)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Quantity::*;
+
+    #[test]
+    fn normalizes_precomposed_vowels() {
+        let normalized = normalize_word("Rōmă").unwrap();
+        assert_eq!(normalized.text, "Roma");
+        assert_eq!(normalized.quantities, vec![Unmarked, Long, Unmarked, Short]);
+    }
+
+    #[test]
+    fn normalizes_combining_marks() {
+        let normalized = normalize_word("ro\u{0304}sa\u{0306}s").unwrap();
+        assert_eq!(normalized.text, "rosas");
+        assert_eq!(
+            normalized.quantities,
+            vec![Unmarked, Long, Unmarked, Short, Unmarked]
+        );
+    }
+
+    #[test]
+    fn ignores_other_diacritics() {
+        let normalized = normalize_word("poëta\u{0301}").unwrap();
+        assert_eq!(normalized.text, "poeta");
+        assert!(normalized.quantities.iter().all(|q| *q == Unmarked));
+    }
+
+    #[test]
+    fn marks_both_lengths_as_ambiguous() {
+        let normalized = normalize_word("a\u{0304}\u{0306}").unwrap();
+        assert_eq!(normalized.quantities, vec![Ambiguous]);
+    }
+
+    #[test]
+    fn skips_combiners_on_consonants() {
+        let normalized = normalize_word("r\u{0304}osa").unwrap();
+        assert_eq!(normalized.text, "rosa");
+        assert!(normalized.quantities.iter().all(|q| *q == Unmarked));
+    }
+
+    #[test]
+    fn rejects_non_latin_characters() {
+        assert_eq!(normalize_word("rosa!"), None);
+        assert_eq!(normalize_word("λόγος"), None);
+    }
+
+    #[test]
+    fn checks_lengths_against_tables() {
+        let input = normalize_word("rosā").unwrap().quantities;
+        assert!(has_compatible_lengths(&input, "ro^sa_"));
+        assert!(has_compatible_lengths(&input, "rosa"));
+        assert!(!has_compatible_lengths(&input, "ro^sa^"));
+
+        let unmarked = normalize_word("rosa").unwrap().quantities;
+        assert!(has_compatible_lengths(&unmarked, "ro^sa^"));
+    }
+
+    #[test]
+    fn ignores_table_markers_and_enclitics() {
+        let input = normalize_word("rosăque").unwrap().quantities;
+        assert!(has_compatible_lengths(&input, "ro^+sa^"));
+        assert!(!has_compatible_lengths(&input, "ro-sa_"));
+    }
+}
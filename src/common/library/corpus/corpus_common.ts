@@ -40,6 +40,8 @@ export interface CorpusQueryMatchMetadata {
   workName: string;
   author: string;
   leaders: [string, number, number][];
+  /** For queries with `@form:` atoms, the lemmata of those forms in the match. */
+  lemmata?: string[];
 }
 
 const isCorpusQueryMatchMetadata = matchesObject<CorpusQueryMatchMetadata>({
@@ -47,17 +49,83 @@ const isCorpusQueryMatchMetadata = matchesObject<CorpusQueryMatchMetadata>({
   workName: isString,
   author: isString,
   leaders: isArray(isTriplet(isString, isNumber, isNumber)),
+  lemmata: maybeUndefined(isArray(isString)),
+});
+
+/**
+ * One possible analysis of a token. Categories that don't apply are omitted,
+ * and a category can have several values (like `["dat", "abl"]`).
+ */
+export interface ExpandedAnalysis {
+  lemma: string;
+  case?: string[];
+  number?: string[];
+  gender?: string[];
+  person?: string[];
+  mood?: string[];
+  voice?: string[];
+  tense?: string[];
+}
+
+const isExpandedAnalysis = matchesObject<ExpandedAnalysis>({
+  lemma: isString,
+  case: maybeUndefined(isArray(isString)),
+  number: maybeUndefined(isArray(isString)),
+  gender: maybeUndefined(isArray(isString)),
+  person: maybeUndefined(isArray(isString)),
+  mood: maybeUndefined(isArray(isString)),
+  voice: maybeUndefined(isArray(isString)),
+  tense: maybeUndefined(isArray(isString)),
+});
+
+/** A single token of a match, with its possible analyses. */
+export interface MatchToken {
+  id: number;
+  text: string;
+  isMatch: boolean;
+  analyses: ExpandedAnalysis[];
+}
+
+const isMatchToken = matchesObject<MatchToken>({
+  id: isNumber,
+  text: isString,
+  isMatch: isBoolean,
+  analyses: isArray(isExpandedAnalysis),
+});
+
+/** A token of a match that satisfied a labeled term (like `verb:@case:dat`). */
+export interface MatchCapture {
+  label: string;
+  id: number;
+  section: string;
+  /** The offset (in tokens) of the token within the section. */
+  offset: number;
+  text: string;
+}
+
+const isMatchCapture = matchesObject<MatchCapture>({
+  label: isString,
+  id: isNumber,
+  section: isString,
+  offset: isNumber,
+  text: isString,
 });
 
 export interface CorpusQueryMatch {
   metadata: CorpusQueryMatchMetadata;
   text: [content: string, isMatchText: boolean][];
+  /** The analyses of the tokens, if requested with the `analyses` option. */
+  tokens?: MatchToken[];
+  /** The tokens matched by labeled terms, if the query has any. */
+  captures?: MatchCapture[];
 }
 
 export namespace CorpusQueryMatch {
   export const isMatch = matchesObject<CorpusQueryMatch>({
     metadata: isCorpusQueryMatchMetadata,
     text: isArray(isPair(isString, isBoolean)),
+    tokens: maybeUndefined(isArray(isMatchToken)),
+    captures: maybeUndefined(isArray(isMatchCapture)),
   });
 }
 
@@ -65,20 +133,26 @@ export interface PageData {
   resultIndex: number;
   resultId: number;
   candidateIndex: number;
+  /** The seed for a random sample of the results, if any. */
+  sampleSeed?: number;
 }
 
 export const isPageData = matchesObject<PageData>({
   resultIndex: isNumber,
   resultId: isNumber,
   candidateIndex: isNumber,
+  sampleSeed: maybeUndefined(isNumber),
 });
 
 export interface QueryGlobalInfo {
   estimatedResults: number;
+  /** For sampled results, the seed that was used to choose the sample. */
+  sampleSeed?: number;
 }
 
 const isQueryGlobalInfo = matchesObject<QueryGlobalInfo>({
   estimatedResults: isNumber,
+  sampleSeed: maybeUndefined(isNumber),
 });
 
 // Replaced: CorpusQueryResult now matches Rust shape (omitting timing)
@@ -98,6 +172,38 @@ export namespace CorpusQueryResult {
   });
 }
 
+/**
+ * A term of a query that can never match, because some of its atoms contradict
+ * each other (like `@mood:subjunctive` and `@tense:future`).
+ */
+export interface QueryContradiction {
+  /** The index of the term in the query. */
+  term: number;
+  /** The atoms of the term that contradict each other. */
+  atoms: string[];
+  reason: string;
+}
+
+/** An error from the Rust engine for a query that couldn't be run. */
+export interface CorpusQueryError {
+  message: string;
+  /** If the query can never match, the term and atoms that contradict each other. */
+  contradiction?: QueryContradiction;
+}
+
+export namespace CorpusQueryError {
+  export const isMatch = matchesObject<CorpusQueryError>({
+    message: isString,
+    contradiction: maybeUndefined(
+      matchesObject<QueryContradiction>({
+        term: isNumber,
+        atoms: isArray(isString),
+        reason: isString,
+      })
+    ),
+  });
+}
+
 // // // // // // // // // //
 // Corpus Interface Types  //
 // // // // // // // // // //
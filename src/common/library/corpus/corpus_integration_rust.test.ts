@@ -6,7 +6,10 @@ import {
   type CorpusInputWork,
   type PageData,
 } from "@/common/library/corpus/corpus_common";
-import { RustCorpusQueryEngine } from "@/common/library/corpus/corpus_rust";
+import {
+  RustCorpusQueryEngine,
+  RustQueryError,
+} from "@/common/library/corpus/corpus_rust";
 import fs from "fs";
 
 console.debug = jest.fn();
@@ -75,6 +78,15 @@ describe("Corpus Integration Test", () => {
     return assertType(parsed, CorpusQueryResult.isMatch);
   }
 
+  function queryError(query: string): RustQueryError {
+    try {
+      queryCorpus(query);
+    } catch (error) {
+      return error as RustQueryError;
+    }
+    throw new Error(`Expected query to fail: ${query}`);
+  }
+
   beforeAll(async () => {
     if (fs.existsSync(TEST_CORPUS_DIR)) {
       fs.rmSync(TEST_CORPUS_DIR, { recursive: true, force: true });
@@ -100,6 +112,25 @@ describe("Corpus Integration Test", () => {
     expect(() => queryCorpus(query)).toThrow();
   });
 
+  it("should reject contradictory queries with the conflicting atoms", () => {
+    const error = queryError("Gallus (@mood:subj and @tense:fut)");
+    expect(error).toBeInstanceOf(RustQueryError);
+    expect(error.message).toMatch(
+      /^Term 2 can never match \(@mood:subjunctive, @tense:future\): /
+    );
+    expect(error.contradiction).toEqual({
+      term: 1,
+      atoms: ["@mood:subjunctive", "@tense:future"],
+      reason: "subjunctives have no future tenses",
+    });
+  });
+
+  it("should reject invalid queries without a contradiction", () => {
+    const error = queryError("[word:servum]");
+    expect(error).toBeInstanceOf(RustQueryError);
+    expect(error.contradiction).toBeUndefined();
+  });
+
   it("should find a single word", () => {
     const query = "@word:servum";
     const results = queryCorpus(query);
@@ -499,4 +530,33 @@ describe("Corpus Integration Test", () => {
     expect(match.metadata.leaders).toEqual([["1.1", 0, 2]]);
     expect(getMatchText(match)).toEqual(["Canis servum"]);
   });
+
+  it("round trips sample seeds through the page data and random sort", () => {
+    function sampleQuery(sampleSeed?: number, sort?: string) {
+      const raw = queryEngine.queryCorpus({
+        query: "et",
+        pageSize: 10,
+        sampleSize: 2,
+        sort,
+        pageData:
+          sampleSeed === undefined
+            ? undefined
+            : { resultIndex: 0, resultId: 0, candidateIndex: 0, sampleSeed },
+      });
+      return assertType(JSON.parse(raw), CorpusQueryResult.isMatch);
+    }
+
+    const first = sampleQuery();
+    const seed = first.resultStats.sampleSeed;
+    expect(Number.isSafeInteger(seed)).toBe(true);
+    const repeated = sampleQuery(seed);
+    expect(repeated.resultStats.sampleSeed).toBe(seed);
+    expect(repeated.matches).toEqual(first.matches);
+
+    const sorted = sampleQuery(seed, `random:${seed}`);
+    expect(sorted.resultStats.sampleSeed).toBe(seed);
+    expect(sorted.matches.map((m) => m.metadata.leaders).sort()).toEqual(
+      first.matches.map((m) => m.metadata.leaders).sort()
+    );
+  });
 });
@@ -1,6 +1,8 @@
 import {
   CORPUS_DIR,
   CORPUS_SUGGESTION_PREFIX,
+  CorpusQueryError,
+  type QueryContradiction,
 } from "@/common/library/corpus/corpus_common";
 import { singletonOf } from "@/common/misc_utils";
 import { timed } from "@/common/timing/timed_invocation";
@@ -9,9 +11,50 @@ import type {
   GetCorpusSuggestionsRequest,
 } from "@/web/api_routes";
 import type { RequestData } from "@/web/utils/rpc/server_rpc";
+import { existsSync } from "fs";
 import { readFile } from "fs/promises";
 import zlib from "zlib";
 
+/** The tables used to resolve `@form:` atoms in queries, if present. */
+const MORCEUS_TABLES_FILE = "build/morceus/processed/morceusTables.json";
+
+/**
+ * An error thrown for a query that the Rust engine couldn't run. For queries that
+ * can never match, `contradiction` points at the term and atoms that conflict.
+ */
+export class RustQueryError extends Error {
+  readonly contradiction?: QueryContradiction;
+
+  constructor(error: CorpusQueryError) {
+    super(error.message);
+    this.name = "RustQueryError";
+    this.contradiction = error.contradiction;
+  }
+}
+
+/**
+ * Runs a call to the Rust engine. Query errors are thrown by the engine as JSON
+ * strings, so these are converted to `RustQueryError`s.
+ */
+function runEngine(call: () => string): string {
+  try {
+    return call();
+  } catch (error) {
+    if (typeof error !== "string") {
+      throw error;
+    }
+    let parsed: unknown = undefined;
+    try {
+      parsed = JSON.parse(error);
+    } catch {
+      // Other errors (like panics) are thrown as plain messages.
+    }
+    throw new RustQueryError(
+      CorpusQueryError.isMatch(parsed) ? parsed : { message: error }
+    );
+  }
+}
+
 /**
  * A query engine that uses Rust for querying the corpus.
  * This is a wrapper around the Rust implementation that allows it to be used in JavaScript.
@@ -24,11 +67,11 @@ import zlib from "zlib";
 export class RustCorpusQueryEngine {
   private readonly engine: any;
 
-  constructor(corpusDir: string) {
+  constructor(corpusDir: string, tablesPath?: string) {
     try {
       // eslint-disable-next-line @typescript-eslint/no-require-imports
       const query_engine = require(`${process.cwd()}/build/corpus-rust-bindings`);
-      this.engine = new query_engine.QueryEngineWrapper(corpusDir);
+      this.engine = new query_engine.QueryEngineWrapper(corpusDir, tablesPath);
     } catch (error) {
       throw "Missing Rust corpus bindings. Run `npm run setup-node-bindgen`.";
     }
@@ -40,12 +83,80 @@ export class RustCorpusQueryEngine {
     }
     const contextLen = Math.max(1, Math.min(100, request.contextLen ?? 25));
     const pageData = request.pageData;
-    return this.engine.query(
-      request.query,
-      pageData === undefined ? undefined : JSON.stringify(pageData),
-      request.pageSize ?? 50,
-      contextLen,
-      request.strictMode ?? false
+    return runEngine(() =>
+      this.engine.query(
+        request.query,
+        pageData === undefined ? undefined : JSON.stringify(pageData),
+        request.pageSize ?? 50,
+        contextLen,
+        request.strictMode ?? false,
+        request.scope,
+        request.sort,
+        request.sampleSize,
+        request.analyses,
+        request.contextMode
+      )
+    );
+  }
+
+  /**
+   * Returns the exact number of matches for the query, grouped by author, work,
+   * and section. Paging and context options in the request are ignored.
+   */
+  countCorpus(request: CorpusQueryRequest): string {
+    if (request.query.length > 100) {
+      throw new Error("Query is too long");
+    }
+    return runEngine(() =>
+      this.engine.count(
+        request.query,
+        request.strictMode ?? false,
+        request.scope
+      )
+    );
+  }
+
+  /**
+   * Explains how the query is executed: the pruned query, the size bounds of its
+   * atoms, the candidates after each step for each span, how the spans are combined,
+   * and the timing of each phase. Paging and context options in the request are ignored.
+   */
+  explainQuery(request: CorpusQueryRequest): string {
+    if (request.query.length > 100) {
+      throw new Error("Query is too long");
+    }
+    return runEngine(() =>
+      this.engine.explain(
+        request.query,
+        request.strictMode ?? false,
+        request.scope
+      )
+    );
+  }
+
+  /**
+   * Returns the most frequent words and lemmata at each position within
+   * `window` tokens of the matches for the query, with association scores.
+   * Paging and context options in the request are ignored.
+   */
+  collocations(
+    request: CorpusQueryRequest,
+    window: number = 5,
+    maxResults: number = 25,
+    minCount: number = 2
+  ): string {
+    if (request.query.length > 100) {
+      throw new Error("Query is too long");
+    }
+    return runEngine(() =>
+      this.engine.collocations(
+        request.query,
+        request.strictMode ?? false,
+        request.scope,
+        window,
+        maxResults,
+        minCount
+      )
     );
   }
 }
@@ -57,7 +168,14 @@ export interface CorpusQueryHandler {
 
 export function rustCorpusApiHandler(): CorpusQueryHandler {
   const engine = singletonOf(() =>
-    timed(() => new RustCorpusQueryEngine(CORPUS_DIR), "Rust corpus init")
+    timed(
+      () =>
+        new RustCorpusQueryEngine(
+          CORPUS_DIR,
+          existsSync(MORCEUS_TABLES_FILE) ? MORCEUS_TABLES_FILE : undefined
+        ),
+      "Rust corpus init"
+    )
   );
   return {
     initialize: () => engine.get(),
@@ -13,6 +13,8 @@ const TABLES_FILE = "build/morceus/processed/morceusTables.json";
  *
  * To build the tables, run:
  * `./morcus.sh build --morceus_tables`
+ * or, to build them without Node, run (from the repo root):
+ * `cargo run --package morceus --release --features full cli build-tables morceus-data`
  */
 export class CruncherRust {
   private readonly cruncher: any;
@@ -30,4 +32,21 @@ export class CruncherRust {
   crunchWord(word: string): string {
     return this.cruncher.crunch(word);
   }
+
+  /**
+   * Returns the paradigms of all lemmata with the given name, as JSON. If a filter
+   * is given (like `abl pl` or `3rd sg perfect subjunctive passive`), only the
+   * forms with those inflections are returned.
+   */
+  paradigm(lemma: string, filter?: string): string {
+    return this.cruncher.paradigm(lemma, filter);
+  }
+
+  /**
+   * Returns the same forms as `paradigm`, but laid out as declension and conjugation
+   * tables (with participles, infinitives, gerundives, and supines grouped separately).
+   */
+  paradigmTables(lemma: string, filter?: string): string {
+    return this.cruncher.paradigmTables(lemma, filter);
+  }
 }
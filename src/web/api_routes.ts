@@ -138,6 +138,29 @@ export interface CorpusQueryRequest {
   pageSize?: number;
   contextLen?: number;
   strictMode?: boolean;
+  /** An extra scope for the query, like `Vergil: Aeneid 4`. */
+  scope?: string;
+  /**
+   * The order of the results: `position` (the default), `left`, `match`,
+   * `right`, `work`, or `random:<seed>`.
+   */
+  sort?: string;
+  /**
+   * If set, the results are a random sample of this many matches. The seed
+   * for the sample is carried in the `pageData`.
+   */
+  sampleSize?: number;
+  /**
+   * Which tokens to include lemmata and inflections for: `none` (the default),
+   * `match`, or `context` (the match and its context).
+   */
+  analyses?: string;
+  /**
+   * How far the context extends: `tokens` (the default), `sentence`, or
+   * `section`. For sentences and sections, `contextLen` is the maximum number
+   * of tokens of context on each side.
+   */
+  contextMode?: string;
 }
 
 export const QueryCorpusApi: ApiRoute<CorpusQueryRequest, CorpusQueryResult> = {
@@ -150,6 +173,11 @@ export const QueryCorpusApi: ApiRoute<CorpusQueryRequest, CorpusQueryResult> = {
     pageSize: maybeUndefined(isNumber),
     contextLen: maybeUndefined(isNumber),
     strictMode: maybeUndefined(isBoolean),
+    scope: maybeUndefined(isString),
+    sort: maybeUndefined(isString),
+    sampleSize: maybeUndefined(isNumber),
+    analyses: maybeUndefined(isString),
+    contextMode: maybeUndefined(isString),
   }),
   outputValidator: CorpusQueryResult.isMatch,
 };
@@ -120,6 +120,10 @@ describe("optionsForInput", () => {
     expect(opts.length).toBeGreaterThan(0);
     expect(opts.some((o) => o.help?.includes("within 5 words"))).toBe(true);
     expect(opts.some((o) => o.option === "> " && o.prefix === "~")).toBe(true);
+    const sentenceOpt = opts.find(
+      (o) => o.option === ". " && o.prefix === "~"
+    );
+    expect(sentenceOpt?.help).toBe("anywhere in the same sentence");
   });
 
   test("tilde with valid number returns directional option", () => {
@@ -144,15 +148,15 @@ describe("optionsForInput", () => {
   });
 
   test("tilde with out-of-range number returns error", () => {
-    const opts = optionsForInput("~20");
+    const opts = optionsForInput("~300");
     expect(opts.length).toBe(1);
-    expect(opts[0].help).toContain("range after ~ must be 1-15");
+    expect(opts[0].help).toContain("range after ~ must be 1-255");
   });
 
   test("tilde with zero returns error", () => {
     const opts = optionsForInput("~0");
     expect(opts.length).toBe(1);
-    expect(opts[0].help).toContain("range after ~ must be 1-15");
+    expect(opts[0].help).toContain("range after ~ must be 1-255");
   });
 
   test("tilde with invalid characters after number returns error", () => {
@@ -162,6 +166,27 @@ describe("optionsForInput", () => {
     expect(opts[0].help).toContain("expected ~, ~N, or ~N>");
   });
 
+  test("tilde with distance over 15 is accepted", () => {
+    const opts = optionsForInput("~20");
+    expect(opts.some((o) => o.help?.includes("within 20 words of"))).toBe(
+      true
+    );
+  });
+
+  test("tilde with trailing period restricts to the same sentence", () => {
+    const opts = optionsForInput("~3>.");
+    expect(opts.length).toBe(1);
+    expect(opts[0].help).toContain(
+      "within 3 words before in the same sentence"
+    );
+  });
+
+  test("bare tilde with period matches anywhere in the sentence", () => {
+    const opts = optionsForInput("~.");
+    expect(opts.length).toBe(1);
+    expect(opts[0].help).toContain("in the same sentence as");
+  });
+
   test("completed proximity token allows new token suggestions", () => {
     const opts = optionsForInput("habeo ~5> ");
     const optStrings = opts.map((o) => o.option);
@@ -120,6 +120,7 @@ const TILDE_HELP: CorpusAutocompleteOption[] = [
   { option: "3 ", prefix: "~", help: "within 3 words of" },
   { option: "10 ", prefix: "~", help: "within 10 words of" },
   { option: "15 ", prefix: "~", help: "within 15 words of" },
+  { option: ". ", prefix: "~", help: "anywhere in the same sentence" },
 ];
 
 const WORD_HELP = informationalWithPlaceholder("match exact word", "word");
@@ -271,20 +272,32 @@ function parseProximityToken(
   if (afterTilde.length === 0) {
     return [TILDE_HELP, false];
   }
+  const withinSentence = afterTilde.endsWith(".");
+  const relation = withinSentence ? afterTilde.slice(0, -1) : afterTilde;
+  const sentenceSuffix = withinSentence ? " in the same sentence" : "";
   let numbers = "";
-  for (const char of afterTilde) {
+  for (const char of relation) {
     if (char >= "0" && char <= "9") {
       numbers += char;
     } else {
       break;
     }
   }
-  const distance = numbers.length === 0 ? 5 : safeParseInt(numbers);
-  if (distance === undefined || distance < 1 || distance > 15) {
-    return [[informational("❌ range after ~ must be 1-15")], true];
+  const defaultDistance = withinSentence && relation.length === 0 ? 255 : 5;
+  const distance =
+    numbers.length === 0 ? defaultDistance : safeParseInt(numbers);
+  if (distance === undefined || distance < 1 || distance > 255) {
+    return [[informational("❌ range after ~ must be 1-255")], true];
   }
-  const afterNumber = afterTilde.slice(numbers.length);
+  const afterNumber = relation.slice(numbers.length);
   if (afterNumber.length === 0) {
+    if (withinSentence) {
+      const help =
+        relation.length === 0
+          ? "in the same sentence as"
+          : `within ${distance} words of${sentenceSuffix}`;
+      return [[informational(help)], false];
+    }
     // Exact match.
     return [
       [
@@ -294,15 +307,27 @@ function parseProximityToken(
           prefix: token,
           help: `within ${distance} words before`,
         },
+        {
+          option: ".",
+          prefix: token,
+          help: `within ${distance} words in the same sentence`,
+        },
       ],
       false,
     ];
   }
   if (afterNumber === ">") {
-    return [[informational(`within ${distance} words before`)], false];
+    return [
+      [informational(`within ${distance} words before${sentenceSuffix}`)],
+      false,
+    ];
   }
   return [
-    [informational(`❌ invalid \`${token}\`: expected ~, ~N, or ~N>`)],
+    [
+      informational(
+        `❌ invalid \`${token}\`: expected ~, ~N, or ~N>, optionally followed by .`
+      ),
+    ],
     true,
   ];
 }
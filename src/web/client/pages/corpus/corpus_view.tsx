@@ -562,16 +562,29 @@ function QueryHelpSection() {
                   You can specify the exact proximity by adding a number; for
                   example, <code>@lemma:amo ~3 @case:genitive</code> would match{" "}
                   <code>amo</code> within 3 words of any genitive word. The
-                  number must be 15 or lower.
+                  number must be 255 or lower.
                 </li>
                 <li>
                   You can specify a direction by adding <code>{">"}</code>; for
                   example, <code>@lemma:amo ~3{">"} @case:genitive</code> would
                   match <code>amo</code> 3 or fewer words before any genitive
-                  word. The number must be 15 or lower.
+                  word. The number must be 255 or lower.
+                </li>
+                <li>
+                  You can require both items to be in the same sentence by
+                  adding <code>.</code> at the end; for example,{" "}
+                  <code>@lemma:amo ~3. @case:genitive</code> would not match a
+                  genitive word across a period. On its own,{" "}
+                  <code>~.</code> matches anywhere in the same sentence.
                 </li>
               </ul>
             </li>
+            <li>
+              <code>label:</code> - Names the word matched by an item. For
+              example, <code>noun:@case:dat verb:@lemma:do</code> reports which
+              word is the <code>noun</code> and which is the <code>verb</code>{" "}
+              in each result, and exports have a column for each label.
+            </li>
           </ul>
         </details>
         <details>